@@ -0,0 +1,493 @@
+//! Runs osu!sync without a window, so a songs folder can be shared from an always-on machine, or
+//! synced from a terminal
+
+use std::collections::HashSet;
+use std::io::{self, BufRead, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex};
+use osu_mapsync::{diff, file_manager, networking};
+use osu_mapsync::diff::SyncPlan;
+use osu_mapsync::file_manager::{DownloadEstimate, MapRequest, SongFolder};
+use osu_mapsync::file_manager::assets::TransferProfile;
+use osu_mapsync::file_manager::cache::SongCache;
+use osu_mapsync::networking::bandwidth::BandwidthSettings;
+use osu_mapsync::networking::discovery::DiscoveredPeers;
+use osu_mapsync::networking::download::DownloadDestination;
+use osu_mapsync::networking::listener::{ListenerSettings, shareable_addr};
+use osu_mapsync::networking::packets::{MapListRequestPacket, PacketManager, PeerId};
+use osu_mapsync::networking::planner::pull_maps;
+use osu_mapsync::networking::secure::Identity;
+use osu_mapsync::networking::trust::TrustedPeers;
+use osu_mapsync::ui::UiBridge;
+use tokio::sync::mpsc;
+
+const USAGE: &str = "\
+Usage: osu-mapsync-cli [options] <command>
+
+Commands:
+    serve <songs dir>                   Share a songs folder with peers until stopped
+    list <address>                      List the songs a peer has
+    diff <songs dir> <address>          Compare a songs folder with the songs a peer has
+    download <songs dir> <address> [song folder...]
+                                        Download the given songs from a peer, or every song
+                                        we don't have the same copy of
+    sync <songs dir> <address>          Send a peer the songs only we have, and get the songs
+                                        only it has
+
+Options:
+    --data-dir <dir>            Where our identity, trusted peers and song cache are kept
+    --name <name>               The name peers see us as
+    --pair <code>               Pair with the peer we connect to, using the code it's showing
+    --bind <address>            The address to listen on when serving
+    --port <port>               The port to listen on when serving
+    --save <dir>                Save downloaded songs as .osz files here instead of installing them
+    --profile <profile>         Which files of each song to download: full, no-video,
+                                no-storyboard or audio (just the audio and .osu files)
+    --upload-limit <KiB/s>      Send map data no faster than this
+    --download-limit <KiB/s>    Receive map data no faster than this
+    --yes                       Answer yes to every question, like whether to let in a peer";
+
+/// What was passed on the command line
+#[derive(Debug, Default)]
+struct Options {
+    data_dir: Option<PathBuf>,
+    display_name: Option<String>,
+    pairing_code: Option<String>,
+    bind_address: Option<IpAddr>,
+    port: Option<u16>,
+    save_dir: Option<PathBuf>,
+    profile: TransferProfile,
+    /// Bytes per second
+    upload_limit: Option<u64>,
+    /// Bytes per second
+    download_limit: Option<u64>,
+    assume_yes: bool,
+    /// The command, followed by its arguments
+    args: Vec<String>
+}
+
+impl Options {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut options = Self::default();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(format!("{arg} needs a value"));
+            match arg.as_str() {
+                "--data-dir" => options.data_dir = Some(PathBuf::from(value()?)),
+                "--name" => options.display_name = Some(value()?),
+                "--pair" => options.pairing_code = Some(value()?),
+                "--bind" => options.bind_address = Some(value()?.parse().map_err(|_| "--bind needs an IP address".to_string())?),
+                "--port" => options.port = Some(value()?.parse().map_err(|_| "--port needs a port number".to_string())?),
+                "--save" => options.save_dir = Some(PathBuf::from(value()?)),
+                "--profile" => options.profile = parse_profile(&value()?)?,
+                "--upload-limit" => options.upload_limit = Some(parse_rate(&value()?)?),
+                "--download-limit" => options.download_limit = Some(parse_rate(&value()?)?),
+                "--yes" | "-y" => options.assume_yes = true,
+                _ if arg.starts_with('-') => return Err(format!("Unknown option {arg}")),
+                _ => options.args.push(arg)
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// Turns a rate in KiB/s into bytes per second
+fn parse_rate(rate: &str) -> Result<u64, String> {
+    rate.parse::<u64>()
+        .map(|kib| kib * 1024)
+        .map_err(|_| format!("{rate} isn't a rate in KiB/s"))
+}
+
+/// Turns the name of a profile, as written on the command line, into the profile
+fn parse_profile(profile: &str) -> Result<TransferProfile, String> {
+    match profile {
+        "full" => Ok(TransferProfile::Full),
+        "no-video" => Ok(TransferProfile::NoVideo),
+        "no-storyboard" => Ok(TransferProfile::NoStoryboard),
+        "audio" => Ok(TransferProfile::AudioAndDifficulties),
+        _ => Err(format!("{profile} isn't a profile, use full, no-video, no-storyboard or audio"))
+    }
+}
+
+/// Where to keep our data if no folder is given, next to where the app keeps its own
+fn default_data_dir() -> PathBuf {
+    let base_dir = std::env::var_os("APPDATA")
+        .or_else(|| std::env::var_os("XDG_DATA_HOME"))
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local").join("share")))
+        .unwrap_or_else(|| PathBuf::from("."));
+    base_dir.join("osu-mapsync-cli")
+}
+
+/// Talks to the user through the terminal. Every event is also passed on, so commands can wait
+/// for the ones they're interested in.
+struct CliUi {
+    assume_yes: bool,
+    events: mpsc::UnboundedSender<(String, String)>
+}
+
+impl UiBridge for CliUi {
+    fn emit_json(&self, event: &str, payload: String) {
+        match event {
+            // Only the end of each map is worth a line
+            "download-progress" => {
+                let progress: serde_json::Value = serde_json::from_str(&payload).unwrap();
+                if progress["map_progress"] == 100 {
+                    println!("Received {} ({}/{})", progress["map"], progress["maps_done"].as_u64().unwrap_or(0) + 1,
+                             progress["map_count"]);
+                }
+            },
+            _ => println!("[{event}] {payload}")
+        }
+        let _ = self.events.send((event.to_string(), payload));
+    }
+
+    fn ask(&self, title: &str, message: &str) -> bool {
+        println!("{title}: {message}");
+        if self.assume_yes {
+            println!("Answering yes");
+            return true;
+        }
+
+        // Without anyone at the terminal to answer, nothing gets agreed to
+        print!("[y/N] ");
+        let _ = io::stdout().flush();
+        let mut answer = String::new();
+        match io::stdin().lock().read_line(&mut answer) {
+            Ok(_) => matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"),
+            Err(_) => false
+        }
+    }
+
+    fn pick_folder(&self, title: &str) -> Option<PathBuf> {
+        print!("{title}: ");
+        let _ = io::stdout().flush();
+        let mut folder = String::new();
+        io::stdin().lock().read_line(&mut folder).ok()?;
+        let folder = folder.trim();
+        (!folder.is_empty()).then(|| PathBuf::from(folder))
+    }
+}
+
+/// Everything the commands share, set up the same way the app sets up its state
+struct Cli {
+    options: Options,
+    data_dir: PathBuf,
+    local_path: Arc<Mutex<Option<PathBuf>>>,
+    local_songs: Arc<Mutex<Vec<SongFolder>>>,
+    song_cache: Arc<Mutex<SongCache>>,
+    trusted_peers: Arc<Mutex<TrustedPeers>>,
+    packet_manager: Arc<Mutex<PacketManager>>,
+    ui: Arc<dyn UiBridge>,
+    events: mpsc::UnboundedReceiver<(String, String)>
+}
+
+impl Cli {
+    fn new(options: Options) -> Result<Self, String> {
+        let data_dir = options.data_dir.clone().unwrap_or_else(default_data_dir);
+
+        // Keep the same identity between runs, so peers can recognize us
+        let mut packet_manager = PacketManager::new();
+        let identity = Identity::load_or_generate(&data_dir.join("identity.json"))
+            .map_err(|err| format!("Unable to load our identity: {err}"))?;
+        packet_manager.set_identity(identity);
+        if let Some(display_name) = &options.display_name {
+            packet_manager.set_display_name(display_name.clone());
+        }
+
+        // Limits given on the command line only last for this run
+        let mut bandwidth = BandwidthSettings::load(data_dir.join("bandwidth_settings.json"));
+        if let Some(upload_limit) = options.upload_limit {
+            bandwidth.upload_limit = Some(upload_limit);
+        }
+        if let Some(download_limit) = options.download_limit {
+            bandwidth.download_limit = Some(download_limit);
+        }
+        packet_manager.bandwidth().set_settings(bandwidth);
+
+        let local_path = Arc::new(Mutex::new(None));
+        let local_songs = Arc::new(Mutex::new(Vec::new()));
+        let song_cache = Arc::new(Mutex::new(SongCache::load(data_dir.join("song_cache.json"))));
+        let (sender, events) = mpsc::unbounded_channel();
+        let ui: Arc<dyn UiBridge> = Arc::new(CliUi { assume_yes: options.assume_yes, events: sender });
+        packet_manager.connect_to_app(local_path.clone(), local_songs.clone(), song_cache.clone(), ui.clone());
+
+        Ok(Self {
+            trusted_peers: Arc::new(Mutex::new(TrustedPeers::load(data_dir.join("trusted_peers.json")))),
+            packet_manager: Arc::new(Mutex::new(packet_manager)),
+            options,
+            data_dir,
+            local_path,
+            local_songs,
+            song_cache,
+            ui,
+            events
+        })
+    }
+
+    /// Reads every song in the folder, which is where downloads get installed to from then on
+    async fn read_songs(&self, songs_dir: &Path) -> Result<(), String> {
+        *self.local_path.lock().unwrap() = Some(songs_dir.to_path_buf());
+        let songs = file_manager::read_local_files(songs_dir, &self.song_cache).await
+            .map_err(|err| format!("An error occurred while trying to read the files: {err}"))?;
+        println!("Read {} songs from {}", songs.len(), songs_dir.display());
+        *self.local_songs.lock().unwrap() = songs;
+        Ok(())
+    }
+
+    async fn connect(&self, addr: &str) -> Result<PeerId, String> {
+        networking::connect_to_server(addr.to_string(), self.options.pairing_code.clone(), &self.packet_manager, &self.trusted_peers).await
+            .map_err(|err| format!("An error occurred: {err}"))?
+            .ok_or(format!("{addr} didn't let us in"))
+    }
+
+    /// Waits for the given event about the peer. Fails if the peer disconnects first, unless
+    /// that's what we were waiting for.
+    async fn wait_for(&mut self, event: &str, peer: PeerId) -> Result<(), String> {
+        self.wait_for_any(&[event], peer).await.map(|_| ())
+    }
+
+    /// Waits for any of the given events about the peer, returning the one that came. Fails if
+    /// the peer disconnects first, unless that's what we were waiting for.
+    async fn wait_for_any(&mut self, events: &[&str], peer: PeerId) -> Result<String, String> {
+        let peer = peer.to_string();
+        while let Some((received, payload)) = self.events.recv().await {
+            if payload != peer {
+                continue;
+            }
+            if events.contains(&received.as_str()) {
+                return Ok(received);
+            }
+            if received == "peer-disconnected" {
+                return Err("The peer disconnected".to_string());
+            }
+        }
+        Err("Stopped receiving events".to_string())
+    }
+
+    /// Asks the peer for its songs, and waits for them to arrive
+    async fn remote_songs(&mut self, peer: PeerId) -> Result<Vec<SongFolder>, String> {
+        self.packet_manager.lock().unwrap().send_packet(peer, Box::new(MapListRequestPacket::new()));
+        self.wait_for("remote-songs-updated", peer).await?;
+        Ok(self.packet_manager.lock().unwrap().remote_songs(peer).unwrap_or_default())
+    }
+
+    /// Closes the connection to the peer once everything we sent has gone out
+    async fn disconnect(&mut self, peer: PeerId) {
+        self.packet_manager.lock().unwrap().disconnect(peer);
+        let _ = self.wait_for("peer-disconnected", peer).await;
+    }
+
+    async fn serve(&mut self, songs_dir: &Path) -> Result<(), String> {
+        self.read_songs(songs_dir).await?;
+
+        let mut settings = ListenerSettings::load(self.data_dir.join("listener_settings.json"));
+        settings.enabled = true;
+        if let Some(bind_address) = self.options.bind_address {
+            settings.bind_address = Some(bind_address);
+        }
+        if let Some(port) = self.options.port {
+            settings.port = port;
+        }
+
+        let listen_addr = Arc::new(Mutex::new(None));
+        let _server = networking::start_listening_server(&settings, self.ui.clone(), self.packet_manager.clone(),
+                                                         self.trusted_peers.clone(), listen_addr.clone()).await
+            .map_err(|err| format!("An error occurred: {err}"))?;
+        networking::discovery::start_discovery(self.ui.clone(), self.packet_manager.clone(), self.local_songs.clone(),
+                                               listen_addr.clone(), Arc::new(Mutex::new(DiscoveredPeers::default())));
+
+        if let Some(addr) = *listen_addr.lock().unwrap() {
+            println!("Peers can connect to {}", shareable_addr(addr));
+        }
+        let pairing_code = self.trusted_peers.lock().unwrap().start_pairing();
+        println!("Pairing code for new peers: {pairing_code}");
+
+        // Everything happens on the server's tasks, the events only need draining until we're stopped
+        loop {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => break,
+                _ = self.events.recv() => {}
+            }
+        }
+        Ok(())
+    }
+
+    async fn list(&mut self, addr: &str) -> Result<(), String> {
+        let peer = self.connect(addr).await?;
+        let remote_songs = self.remote_songs(peer).await?;
+        self.disconnect(peer).await;
+
+        for song in &remote_songs {
+            println!("{} ({} difficulties, {} files, {})", song.folder, song.difficulties.len(), song.file_count,
+                     file_manager::format_size(song.size));
+        }
+        println!("{} songs", remote_songs.len());
+        Ok(())
+    }
+
+    async fn diff(&mut self, songs_dir: &Path, addr: &str) -> Result<(), String> {
+        self.read_songs(songs_dir).await?;
+        let peer = self.connect(addr).await?;
+        let remote_songs = self.remote_songs(peer).await?;
+        self.disconnect(peer).await;
+
+        let diff = diff::diff_songs(&self.local_songs.lock().unwrap(), &remote_songs);
+        println!("Only the peer has:");
+        for song in &diff.missing_locally {
+            println!("    {}", song.folder);
+        }
+        println!("Both have, but with different content:");
+        for pair in &diff.different {
+            println!("    {}", pair.remote.folder);
+        }
+        println!("Only we have:");
+        for song in &diff.missing_remotely {
+            println!("    {}", song.folder);
+        }
+        println!("{} identical, {} different, {} only on the peer, {} only here",
+                 diff.identical.len(), diff.different.len(), diff.missing_locally.len(), diff.missing_remotely.len());
+        Ok(())
+    }
+
+    async fn download(&mut self, songs_dir: &Path, addr: &str, folders: &[String]) -> Result<bool, String> {
+        self.read_songs(songs_dir).await?;
+        let peer = self.connect(addr).await?;
+        let remote_songs = self.remote_songs(peer).await?;
+
+        // Without any songs given, get everything we don't have the same copy of
+        let songs_to_request: Vec<SongFolder> = if folders.is_empty() {
+            let diff = diff::diff_songs(&self.local_songs.lock().unwrap(), &remote_songs);
+            diff.missing_locally.into_iter()
+                .chain(diff.different.into_iter().map(|pair| pair.remote))
+                .collect()
+        } else {
+            let folders: HashSet<&str> = folders.iter().map(String::as_str).collect();
+            let songs: Vec<SongFolder> = remote_songs.into_iter()
+                .filter(|song| folders.contains(song.folder.as_str()))
+                .collect();
+            if songs.len() < folders.len() {
+                let found: HashSet<&str> = songs.iter().map(|song| song.folder.as_str()).collect();
+                let missing: Vec<&str> = folders.difference(&found).copied().collect();
+                self.disconnect(peer).await;
+                return Err(format!("The peer doesn't have {}", missing.join(", ")));
+            }
+            songs
+        };
+        if songs_to_request.is_empty() {
+            println!("Nothing to download");
+            self.disconnect(peer).await;
+            return Ok(true);
+        }
+
+        let requested_maps = MapRequest::for_songs(songs_to_request, &self.local_songs.lock().unwrap(), self.options.profile);
+        let estimate = DownloadEstimate::for_requests(&requested_maps);
+        let should_download = self.ui.ask("Download Maps",
+                                          &format!("You are about to download {}. Continue?", estimate.describe()));
+        if !should_download {
+            self.disconnect(peer).await;
+            return Err("The download was canceled.".to_string());
+        }
+        let destination = match &self.options.save_dir {
+            Some(save_dir) => DownloadDestination::Save(save_dir.clone()),
+            None => DownloadDestination::Install(songs_dir.to_path_buf())
+        };
+
+        let requested_maps = requested_maps.into_iter().map(|request| request.with_format(destination.map_format())).collect();
+        let summary = pull_maps(&self.packet_manager, requested_maps, destination).await;
+        self.disconnect(peer).await;
+
+        println!("Downloaded {} songs", summary.downloaded.len());
+        for (folder, reason) in &summary.unavailable {
+            println!("Unable to download {folder}: {reason}");
+        }
+        Ok(summary.unavailable.is_empty())
+    }
+
+    async fn sync(&mut self, songs_dir: &Path, addr: &str) -> Result<bool, String> {
+        self.read_songs(songs_dir).await?;
+        let peer = self.connect(addr).await?;
+        let remote_songs = self.remote_songs(peer).await?;
+
+        let plan = SyncPlan::between(&self.local_songs.lock().unwrap(), &remote_songs);
+        if plan.is_empty() {
+            println!("Nothing to sync");
+            self.disconnect(peer).await;
+            return Ok(true);
+        }
+        if !plan.confirm(self.ui.as_ref()) {
+            self.disconnect(peer).await;
+            return Err("The sync was canceled.".to_string());
+        }
+
+        // The connection can only be closed once both directions are done
+        let mut waiting_for = Vec::new();
+        if !plan.to_receive.is_empty() {
+            waiting_for.push("download-finished");
+        }
+        if !plan.to_send.is_empty() {
+            waiting_for.push("upload-finished");
+        }
+        let destination = match &self.options.save_dir {
+            Some(save_dir) => DownloadDestination::Save(save_dir.clone()),
+            None => DownloadDestination::Install(songs_dir.to_path_buf())
+        };
+        self.packet_manager.lock().unwrap().start_sync(peer, plan, destination);
+
+        if self.wait_for_any(&["sync-accepted", "sync-declined"], peer).await? == "sync-declined" {
+            self.disconnect(peer).await;
+            return Err("The peer didn't agree to the sync.".to_string());
+        }
+        while !waiting_for.is_empty() {
+            let event = self.wait_for_any(&waiting_for, peer).await?;
+            waiting_for.retain(|waiting| *waiting != event);
+        }
+        self.disconnect(peer).await;
+        Ok(true)
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let options = match Options::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("{err}\n\n{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let args = options.args.clone();
+    let mut cli = match Cli::new(options) {
+        Ok(cli) => cli,
+        Err(err) => {
+            eprintln!("{err}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let result = match args.as_slice() {
+        ["serve", songs_dir] => cli.serve(Path::new(songs_dir)).await.map(|_| true),
+        ["list", addr] => cli.list(addr).await.map(|_| true),
+        ["diff", songs_dir, addr] => cli.diff(Path::new(songs_dir), addr).await.map(|_| true),
+        ["download", songs_dir, addr, folders @ ..] => {
+            let folders: Vec<String> = folders.iter().map(|folder| folder.to_string()).collect();
+            cli.download(Path::new(songs_dir), addr, &folders).await
+        },
+        ["sync", songs_dir, addr] => cli.sync(Path::new(songs_dir), addr).await,
+        _ => {
+            eprintln!("{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match result {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(err) => {
+            eprintln!("{err}");
+            ExitCode::FAILURE
+        }
+    }
+}
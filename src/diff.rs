@@ -0,0 +1,129 @@
+use std::collections::{HashMap, HashSet};
+use crate::file_manager::{format_size, SongFolder};
+use crate::ui::UiBridge;
+
+/// A song that both sides have, as each side sees it
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct SongPair {
+    pub local: SongFolder,
+    pub remote: SongFolder
+}
+
+/// How our songs line up with the songs of a peer
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct SongDiff {
+    /// Songs both sides have with the same checksum
+    pub identical: Vec<SongPair>,
+    /// Songs both sides have, but with different content (e.g. a difficulty was added or updated)
+    pub different: Vec<SongPair>,
+    /// Songs only the peer has
+    pub missing_locally: Vec<SongFolder>,
+    /// Songs only we have
+    pub missing_remotely: Vec<SongFolder>
+}
+
+/// Two songs are treated as the same song if they have the same beatmap set id and name
+fn song_key(song: &SongFolder) -> (u64, &str) {
+    (song.id, song.name.as_str())
+}
+
+/// Works out which songs we share with a peer, and which ones each side is missing.
+/// If either side has the same song more than once, an identical copy is preferred when pairing.
+pub fn diff_songs(local_songs: &[SongFolder], remote_songs: &[SongFolder]) -> SongDiff {
+    let mut remote_by_key: HashMap<(u64, &str), Vec<&SongFolder>> = HashMap::new();
+    for remote in remote_songs {
+        remote_by_key.entry(song_key(remote)).or_default().push(remote);
+    }
+
+    let mut diff = SongDiff::default();
+    for local in local_songs {
+        let candidates = remote_by_key.get(&song_key(local));
+        let identical = candidates
+            .and_then(|candidates| candidates.iter().find(|remote| remote.checksum == local.checksum));
+
+        match (identical, candidates.and_then(|candidates| candidates.first())) {
+            (Some(remote), _) => diff.identical.push(SongPair { local: local.clone(), remote: (*remote).clone() }),
+            (None, Some(remote)) => diff.different.push(SongPair { local: local.clone(), remote: (*remote).clone() }),
+            (None, None) => diff.missing_remotely.push(local.clone())
+        }
+    }
+
+    let local_keys: HashSet<(u64, &str)> = local_songs.iter().map(song_key).collect();
+    diff.missing_locally = remote_songs.iter()
+        .filter(|remote| !local_keys.contains(&song_key(remote)))
+        .cloned()
+        .collect();
+
+    diff
+}
+
+/// The songs a two-way sync would swap with a peer, so each side ends up with every song
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct SyncPlan {
+    /// Songs only the peer has, which it sends us
+    pub to_receive: Vec<SongFolder>,
+    /// Songs only we have, which we send the peer
+    pub to_send: Vec<SongFolder>
+}
+
+/// How much a two-way sync moves in each direction, for the user to agree to before it starts
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize)]
+pub struct SyncSummary {
+    pub receive_count: usize,
+    /// Bytes, going by the sizes in the map lists
+    pub receive_size: u64,
+    pub send_count: usize,
+    /// Bytes, going by the sizes in the map lists
+    pub send_size: u64
+}
+
+impl SyncPlan {
+    /// Works out which songs each side is missing. Songs both sides have different copies of
+    /// are left alone, since neither copy is clearly the one to keep.
+    pub fn between(local_songs: &[SongFolder], remote_songs: &[SongFolder]) -> Self {
+        let diff = diff_songs(local_songs, remote_songs);
+        Self { to_receive: diff.missing_locally, to_send: diff.missing_remotely }
+    }
+
+    /// Keeps only the songs the peer asked to swap in its sync request, so a peer can narrow a
+    /// sync down but never widen it past what the two map lists say each side is missing
+    pub fn agreed_with(self, offered: &[SongFolder], wanted: &[SongFolder]) -> Self {
+        let listed = |song: &SongFolder, songs: &[SongFolder]| songs.iter()
+            .any(|listed| song_key(listed) == song_key(song) && listed.checksum == song.checksum);
+        Self {
+            to_receive: self.to_receive.into_iter().filter(|song| listed(song, offered)).collect(),
+            to_send: self.to_send.into_iter().filter(|song| listed(song, wanted)).collect()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.to_receive.is_empty() && self.to_send.is_empty()
+    }
+
+    /// Asks the user whether to go ahead with the sync, showing how much goes each way
+    pub fn confirm(&self, ui: &dyn UiBridge) -> bool {
+        ui.ask("Two-way Sync", &format!("You are about to {}. Continue?", self.summary().describe()))
+    }
+
+    pub fn summary(&self) -> SyncSummary {
+        SyncSummary {
+            receive_count: self.to_receive.len(),
+            receive_size: self.to_receive.iter().map(|song| song.size).sum(),
+            send_count: self.to_send.len(),
+            send_size: self.to_send.iter().map(|song| song.size).sum()
+        }
+    }
+}
+
+impl SyncSummary {
+    /// Describes both directions for a confirmation dialog, e.g. "receive 2 maps (3.5 MiB) and
+    /// send 1 map (800.0 KiB)"
+    pub fn describe(&self) -> String {
+        format!("receive {} ({}) and send {} ({})", maps(self.receive_count), format_size(self.receive_size),
+                maps(self.send_count), format_size(self.send_size))
+    }
+}
+
+fn maps(count: usize) -> String {
+    if count == 1 { "1 map".to_string() } else { format!("{count} maps") }
+}
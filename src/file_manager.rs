@@ -1,115 +1,397 @@
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::{fs, io};
-use std::io::{BufWriter, Cursor, Seek, Write};
+use std::io::{Cursor, Read, Seek, Write};
 use data_encoding::HEXUPPER;
 use sha2::{Digest, Sha256};
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use regex::Regex;
 use lazy_static::lazy_static;
-use tempfile::tempfile;
 use thiserror::Error;
 use tokio::{sync, task};
-use walkdir::WalkDir;
+use walkdir::{DirEntry, WalkDir};
+use zip::{CompressionMethod, DateTime};
 use zip::write::FileOptions;
+use assets::{AssetIndex, AssetSizes, TransferProfile};
+use cache::SongCache;
+use osu_file::{DifficultySettings, OsuFile};
+
+pub mod assets;
+pub mod cache;
+pub mod osu_file;
+
+/// Files that are already compressed, which deflating again only costs time for. Nested .osz
+/// files are zips themselves.
+const STORED_EXTENSIONS: &[&str] = &["mp3", "ogg", "m4a", "jpg", "jpeg", "png", "mp4", "avi", "flv", "webm", "osz"];
+
+lazy_static! {
+    static ref FOLDER_FORMAT: Regex = Regex::new(r"^([0-9]*) (.+ - .+)$").unwrap();
+}
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct SongFolder {
     pub id: u64,
     pub name: String,
+    /// Name of the folder on disk, which can differ from "{id} {name}" for renamed folders
+    #[serde(default)]
+    pub folder: String,
     pub checksum: String,
+    #[serde(default)]
+    pub difficulties: Vec<Difficulty>,
+    /// Total size of every file in the folder in bytes, as of when its .osu files last changed
+    #[serde(default)]
+    pub size: u64,
+    /// How many files are in the folder, including the ones in subfolders
+    #[serde(default)]
+    pub file_count: u32,
+    /// How much of `size` and `file_count` goes to the assets a `TransferProfile` can leave out
+    #[serde(default)]
+    pub assets: AssetSizes,
     #[serde(skip)]
     pub path: Option<PathBuf>,
 }
 
+/// A single .osu file inside of a song folder
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Difficulty {
+    pub beatmap_id: u64,
+    pub name: String,
+    pub creator: String,
+    pub file_name: String,
+    pub checksum: String,
+    pub settings: DifficultySettings,
+    /// Size of the .osu file in bytes
+    #[serde(default)]
+    pub size: u64
+}
+
+/// A song folder to send to a peer. If `difficulties` is set, only the .osu files with those
+/// checksums are sent, along with all the shared assets (audio, backgrounds, etc.).
+/// Otherwise, the whole folder is sent. Either way, `profile` can leave out some of the assets.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct MapRequest {
+    pub song: SongFolder,
+    pub difficulties: Option<Vec<String>>,
+    #[serde(default)]
+    pub profile: TransferProfile,
+    #[serde(default)]
+    pub format: MapFormat
+}
+
+/// How a map is sent to the peer that asked for it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum MapFormat {
+    /// Zipped up into an .osz, which can be saved as is or unpacked into the songs folder
+    #[default]
+    Osz,
+    /// As the files of the folder, read straight from the sender's songs folder and written
+    /// straight into the receiver's, without zipping anything up on either side
+    Folder
+}
+
+/// Roughly how much a download brings over, going by the sizes peers put in their map lists.
+/// The .osz files sent are a little smaller, since they're compressed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize)]
+pub struct DownloadEstimate {
+    pub map_count: usize,
+    pub file_count: u64,
+    /// Bytes
+    pub size: u64
+}
+
+/// SHA-256 checksum of a single file in a song folder
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct FileHash {
+    /// Where the file is in the folder, as it's named in the .osz
+    pub path: String,
+    pub checksum: String
+}
+
+/// What a map should look like once it arrives, sent along with its .osz so the receiver can
+/// tell if it got damaged on the way
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct MapManifest {
+    /// `SongFolder::checksum` of the folder, if the whole folder was sent
+    pub checksum: Option<String>,
+    /// Every file in the .osz
+    pub files: Vec<FileHash>
+}
+
 #[derive(Error, Debug)]
 pub enum SongFolderError {
     #[error("The path {0} does not correspond to a valid song folder.")]
     InvalidPath(PathBuf),
-    #[error("Unable to parse folder name: {0}")]
-    InvalidFolderName(String),
+    #[error("{0} doesn't match what the peer sent")]
+    Damaged(String),
     #[error("An IO error occurred: {0}")]
-    IOError(#[from] io::Error)
+    IOError(#[from] io::Error),
+    #[error("Unable to read zip archive: {0}")]
+    ZipError(#[from] zip::result::ZipError)
 }
 
 impl SongFolder{
-    fn new(path: PathBuf) -> Result<Self, SongFolderError> {
+    /// Reads the song folder at the given path, returning it along with what each of its files
+    /// is for. Will block as it reads from the file system.
+    fn new(path: PathBuf) -> Result<(Self, AssetIndex), SongFolderError> {
         if !SongFolder::is_song_folder(&path) {
             return Err(SongFolderError::InvalidPath(path));
         }
 
-        lazy_static! {
-            static ref FOLDER_FORMAT: Regex = Regex::new(r"^([0-9]*) (.+ - .+)$").unwrap();
-        }
-        let folder_name = path.file_name().unwrap().to_str().unwrap();
-        let groups = FOLDER_FORMAT.captures(folder_name)
-            .ok_or(SongFolderError::InvalidFolderName(folder_name.to_string()))?;
+        let folder = path.file_name().unwrap().to_string_lossy().to_string();
+        let (checksum, osu_files) = SongFolder::read_osu_files(&path)?;
+        let assets = AssetIndex::new(&path, osu_files.iter().map(|(_, osu_file)| osu_file))?;
+        let (size, file_count, asset_sizes) = SongFolder::folder_size(&path, &assets)?;
+
+        // Prefer what the .osu files say, and only fall back to the folder name if none of them
+        // have the metadata. Folders that don't follow the "{id} {name}" format use the whole
+        // folder name as the song name.
+        let folder_groups = FOLDER_FORMAT.captures(&folder);
+        let id = osu_files.iter()
+            .find_map(|(_, osu_file)| osu_file.beatmap_set_id)
+            .or_else(|| folder_groups.as_ref().and_then(|groups| groups[1].parse::<u64>().ok()))
+            .unwrap_or(0);
+        let name = osu_files.iter()
+            .find_map(|(_, osu_file)| {
+                Some(format!("{} - {}", osu_file.artist.as_ref()?, osu_file.title.as_ref()?))
+            })
+            .or_else(|| folder_groups.as_ref().map(|groups| groups[2].to_string()))
+            .unwrap_or_else(|| folder.clone());
 
-        Ok(SongFolder {
-            id: groups[1].parse::<u64>().unwrap_or(0),
-            name: groups[2].to_string(),
-            checksum: SongFolder::calculate_checksum(&path)?,
+        let song = SongFolder {
+            id,
+            name,
+            folder,
+            checksum,
+            difficulties: osu_files.into_iter().map(|(difficulty, _)| difficulty).collect(),
+            size,
+            file_count,
+            assets: asset_sizes,
             path: Some(path),
-        })
+        };
+        Ok((song, assets))
     }
 
     /// Check if the given path is a valid song folder. Requires that it is a valid directory and
-    /// that it follows the format "{Beatmap number} {Artist} - {Song Title}"
+    /// that it has at least one .osu file in it. Will block as it reads from the file system.
     fn is_song_folder(path: &Path) -> bool {
-        lazy_static! {
-            static ref FOLDER_FORMAT: Regex = Regex::new(r"^([0-9]*) (.+ - .+)$").unwrap();
-        }
-        let folder_name = path.file_name();
         path.is_dir() &&
-            folder_name.is_some() &&
-            FOLDER_FORMAT.is_match(folder_name.unwrap().to_str().unwrap())
+            path.file_name().is_some() &&
+            path.read_dir()
+                .map(|mut entries| entries.any(|entry| {
+                    entry.map(|entry| entry.file_name().to_string_lossy().ends_with(".osu")).unwrap_or(false)
+                }))
+                .unwrap_or(false)
     }
 
-    /// Calculates a checksum of the given song folder by using just the .osu files
-    /// to avoid reading too much from disk. Will block as it reads from the file system.
-    fn calculate_checksum(path: &Path) -> Result<String, SongFolderError> {
-        let mut hasher = Sha256::new();
+    /// Adds up the size of every file in the song folder, including the ones in subfolders,
+    /// returning it along with how many files there are and how much goes to each kind of
+    /// asset. Will block as it reads from the file system.
+    fn folder_size(path: &Path, assets: &AssetIndex) -> io::Result<(u64, u32, AssetSizes)> {
+        let (mut size, mut file_count, mut asset_sizes) = (0, 0, AssetSizes::default());
+        for entry in WalkDir::new(path) {
+            let entry = entry?;
+            if entry.file_type().is_file() {
+                let file_size = entry.metadata()?.len();
+                let name = entry.path().strip_prefix(path).unwrap().to_string_lossy();
+                asset_sizes.add(assets.kind(&name), file_size);
+                size += file_size;
+                file_count += 1;
+            }
+        }
+        Ok((size, file_count, asset_sizes))
+    }
 
+    /// Calculates a checksum of the given song folder by using just the .osu files
+    /// to avoid reading too much from disk, and parses each .osu file into a difficulty.
+    /// Will block as it reads from the file system.
+    fn read_osu_files(path: &Path) -> Result<(String, Vec<(Difficulty, OsuFile)>), SongFolderError> {
+        let mut files = Vec::new();
         for entry in path.read_dir()? {
             let file = entry?;
 
             // Ignore file if it doesn't end in .osu
-            if !file.file_name().to_str().unwrap_or("").ends_with(".osu") {
+            let file_name = file.file_name().to_str().unwrap_or("").to_string();
+            if !file_name.ends_with(".osu") {
                 continue;
             }
+            files.push((file_name, fs::read(file.path())?));
+        }
+
+        // Keep the checksum and the list stable no matter what order the file system gives us
+        // the files in, so the same folder gets the same checksum on every machine
+        files.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let checksum = osu_checksum(files.iter().map(|(_, data)| data.as_slice()));
+
+        let osu_files = files.into_iter()
+            .map(|(file_name, data)| {
+                let osu_file = OsuFile::parse(&String::from_utf8_lossy(&data));
+                (Difficulty::new(file_name, &osu_file, &data), osu_file)
+            })
+            .collect();
+        Ok((checksum, osu_files))
+    }
+}
+
+impl Difficulty {
+    fn new(file_name: String, osu_file: &OsuFile, data: &[u8]) -> Self {
+        // Fall back to the "[Difficulty]" at the end of the file name
+        let name = osu_file.version.clone().unwrap_or_else(|| {
+            file_name.rsplit_once('[')
+                .and_then(|(_, rest)| rest.rsplit_once(']'))
+                .map(|(name, _)| name.to_string())
+                .unwrap_or_default()
+        });
+
+        Self {
+            beatmap_id: osu_file.beatmap_id.unwrap_or(0),
+            name,
+            creator: osu_file.creator.clone().unwrap_or_default(),
+            file_name,
+            checksum: HEXUPPER.encode(Sha256::digest(data).as_ref()),
+            settings: osu_file.difficulty,
+            size: data.len() as u64
+        }
+    }
+}
+
+impl MapRequest {
+    /// Requests the whole song folder
+    pub fn full(song: SongFolder) -> Self {
+        Self { song, difficulties: None, profile: TransferProfile::Full, format: MapFormat::Osz }
+    }
+
+    /// Requests only the difficulties of the remote song that we don't already have locally.
+    /// If we don't have the song at all, the whole folder is requested.
+    pub fn missing_from(remote: SongFolder, local: Option<&SongFolder>) -> Self {
+        match local {
+            Some(local) => {
+                let missing = remote.difficulties.iter()
+                    .filter(|difficulty| !local.difficulties.iter().any(|d| d.checksum == difficulty.checksum))
+                    .map(|difficulty| difficulty.checksum.clone())
+                    .collect();
+                Self { song: remote, difficulties: Some(missing), profile: TransferProfile::Full, format: MapFormat::Osz }
+            },
+            None => Self::full(remote)
+        }
+    }
+
+    /// Leaves out the assets the given profile doesn't include
+    pub fn with_profile(self, profile: TransferProfile) -> Self {
+        Self { profile, ..self }
+    }
+
+    /// Has the map sent in the given format
+    pub fn with_format(self, format: MapFormat) -> Self {
+        Self { format, ..self }
+    }
+
+    /// Roughly how much of the song this brings over. Difficulties that are left out only take
+    /// their .osu file with them, and assets the profile leaves out don't count.
+    pub fn estimate(&self) -> DownloadEstimate {
+        let (sent, skipped): (Vec<&Difficulty>, Vec<&Difficulty>) = self.song.difficulties.iter()
+            .partition(|difficulty| self.difficulties.as_ref().is_none_or(|difficulties| difficulties.contains(&difficulty.checksum)));
+        let (size, file_count) = (self.song.size, self.song.file_count as u64);
+        let (size, file_count) = match self.profile {
+            TransferProfile::Full => (size, file_count),
+            TransferProfile::NoVideo => (size.saturating_sub(self.song.assets.video.size),
+                                         file_count.saturating_sub(self.song.assets.video.file_count as u64)),
+            TransferProfile::NoStoryboard => (size.saturating_sub(self.song.assets.storyboard.size),
+                                              file_count.saturating_sub(self.song.assets.storyboard.file_count as u64)),
+            // Nothing is left to take the skipped difficulties away from
+            TransferProfile::AudioAndDifficulties => {
+                let audio = self.song.assets.audio;
+                return DownloadEstimate {
+                    map_count: 1,
+                    file_count: audio.file_count as u64 + sent.len() as u64,
+                    size: audio.size + sent.iter().map(|difficulty| difficulty.size).sum::<u64>()
+                };
+            }
+        };
+        DownloadEstimate {
+            map_count: 1,
+            file_count: file_count.saturating_sub(skipped.len() as u64),
+            size: size.saturating_sub(skipped.iter().map(|difficulty| difficulty.size).sum())
+        }
+    }
 
-            // Read file and update the hasher
-            let f = fs::read(file.path())?;
-            hasher.update(f);
+    /// Works out what to ask for to get the given songs. For songs we already have some of, we
+    /// only ask for the difficulties we're missing.
+    pub fn for_songs(songs_to_request: Vec<SongFolder>, local_songs: &[SongFolder], profile: TransferProfile) -> Vec<Self> {
+        songs_to_request.into_iter()
+            .map(|song| {
+                let local_song = local_songs.iter()
+                    .find(|local_song| local_song.id == song.id && local_song.name == song.name);
+                Self::missing_from(song, local_song).with_profile(profile)
+            })
+            .collect()
+    }
+}
+
+impl DownloadEstimate {
+    /// Describes the estimate for a confirmation dialog, e.g. "3 maps (12.5 MiB)"
+    pub fn describe(&self) -> String {
+        match self.map_count {
+            1 => format!("1 map ({})", format_size(self.size)),
+            map_count => format!("{map_count} maps ({})", format_size(self.size))
         }
+    }
 
-        let digest = hasher.finalize();
-        Ok(HEXUPPER.encode(digest.as_ref()))
+    /// Adds up the estimates of every request
+    pub fn for_requests(requests: &[MapRequest]) -> Self {
+        requests.iter().map(MapRequest::estimate).fold(Self::default(), |total, estimate| Self {
+            map_count: total.map_count + estimate.map_count,
+            file_count: total.file_count + estimate.file_count,
+            size: total.size + estimate.size
+        })
+    }
+}
+
+/// Formats a number of bytes in the largest unit it's at least one of
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
     }
+    format!("{size:.1} {}", UNITS[unit])
 }
 
 /// Reads all the beatmap folders in the given directory.
-/// Splits the job across 4 threads to speed up operation.
-pub async fn read_local_files(songs_dir: &Path) -> Result<Vec<SongFolder>, SongFolderError> {
+/// Splits the job across 4 threads to speed up operation. Folders whose .osu files haven't
+/// changed since they were cached reuse their cached checksum, and the cache is updated to
+/// match what was read.
+pub async fn read_local_files(songs_dir: &Path, cache: &Mutex<SongCache>) -> Result<Vec<SongFolder>, SongFolderError> {
     // Get all the paths that we should read
     let mut song_paths = Vec::new();
     let mut entries = tokio::fs::read_dir(songs_dir).await?;
     while let Some(entry) = entries.next_entry().await? {
-        let path = entry.path();
-        if SongFolder::is_song_folder(&path) {
-            song_paths.push(path);
+        // Whether it actually has any beatmaps in it gets checked when the folder is read
+        if entry.file_type().await?.is_dir() {
+            song_paths.push(entry.path());
         }
     }
 
     println!("Reading {} songs...", song_paths.len());
 
+    // Workers only need to read from the cache, so give them a snapshot of it instead of
+    // fighting over the lock
+    let cache_snapshot = Arc::new(cache.lock().unwrap().clone());
+
     // Split paths between 4 threads
     let (sender, mut receiver) = sync::mpsc::channel(100);
     for chunk in song_paths.chunks(4) {
         let chunk = chunk.to_owned();
         let sender = sender.clone();
+        let cache_snapshot = cache_snapshot.clone();
         task::spawn_blocking(move || {
             for path in chunk {
-                sender.blocking_send(SongFolder::new(path)).unwrap();
+                sender.blocking_send(cache_snapshot.read_song_folder(path)).unwrap();
             }
         });
     }
@@ -118,84 +400,316 @@ pub async fn read_local_files(songs_dir: &Path) -> Result<Vec<SongFolder>, SongF
 
     // Read everything in the channel, until it is closed
     let mut songs = Vec::new();
-    while let Some(song) = receiver.recv().await {
-        songs.push(song?);
+    let mut cached_folders = HashMap::new();
+    while let Some(cached_folder) = receiver.recv().await {
+        let cached_folder = match cached_folder {
+            Ok(cached_folder) => cached_folder,
+            // Not every folder in the songs directory has to be a song
+            Err(SongFolderError::InvalidPath(path)) => {
+                println!("Skipping {path:?} since it has no beatmaps");
+                continue;
+            },
+            Err(err) => return Err(err)
+        };
+        let song = cached_folder.song().clone();
+        cached_folders.insert(song.path.clone().unwrap(), cached_folder);
+        songs.push(song);
+    }
+
+    let mut cache = cache.lock().unwrap();
+    cache.replace(cached_folders);
+    if let Err(err) = cache.save() {
+        println!("Unable to save the song cache: {err:?}");
     }
+
     Ok(songs)
 }
 
-fn song_to_osz(song: &SongFolder) -> io::Result<Vec<u8>> {
+/// Options used for every file in the .osz files we create. Timestamps are left out so zipping
+/// the same files always gives the same bytes, which is what lets an interrupted download be
+/// resumed.
+fn zip_options(name: &str) -> FileOptions {
+    FileOptions::default()
+        .compression_method(compression_method(name))
+        .last_modified_time(DateTime::default())
+}
+
+/// How to compress a file going into an .osz, going by its name. Media is stored as is, since it
+/// barely shrinks. Everything else, mostly text like .osu and .osb files, is deflated: osu! can't
+/// open an .osz that uses anything newer, like zstd.
+pub fn compression_method(name: &str) -> CompressionMethod {
+    let extension = name.rsplit_once('.').map_or(String::new(), |(_, extension)| extension.to_lowercase());
+    if STORED_EXTENSIONS.contains(&extension.as_str()) {
+        CompressionMethod::Stored
+    } else {
+        CompressionMethod::Deflated
+    }
+}
+
+/// Zips the song folder into .osz format. If `difficulties` is set, only the .osu files with
+/// those checksums are included, but all other files still are. Will block as it reads from
+/// the file system.
+pub fn song_to_osz(song: &SongFolder, difficulties: Option<&Vec<String>>) -> io::Result<Vec<u8>> {
+    song_to_osz_with_manifest(song, difficulties, TransferProfile::Full).map(|(osz_data, _)| osz_data)
+}
+
+/// Zips the song folder like `song_to_osz`, leaving out the assets `profile` doesn't include and
+/// hashing each file on the way so the receiver can check what it got. Will block as it reads
+/// from the file system.
+pub fn song_to_osz_with_manifest(song: &SongFolder, difficulties: Option<&Vec<String>>, profile: TransferProfile) -> io::Result<(Vec<u8>, MapManifest)> {
     let zip_data = Cursor::new(Vec::<u8>::new());
     let mut zip = zip::ZipWriter::new(zip_data);
-    let zip_options = FileOptions::default();
 
     // Root path of the folder
     let root = song.path.as_ref().unwrap();
     println!("Zipping {root:?} to an osz file");
 
+    // Only the whole folder has the same checksum as the song
+    let mut manifest = MapManifest {
+        checksum: difficulties.is_none().then(|| song.checksum.clone()),
+        files: Vec::new()
+    };
+    for entry in map_entries(song, difficulties, profile)? {
+        let path = entry.path();
+        let name = path.strip_prefix(root).unwrap().to_string_lossy();
+
+        if path.is_file() {
+            let f = fs::read(path)?;
+            manifest.files.push(FileHash { path: name.to_string(), checksum: checksum_reader(f.as_slice())? });
+
+            zip.start_file(name.as_ref(), zip_options(&name))?;
+            zip.write_all(&f)?;
+        } else {
+            zip.add_directory(name.as_ref(), zip_options(&name))?;
+        }
+    }
+    let zip_data = zip.finish()?;
+
+    Ok((zip_data.into_inner(), manifest))
+}
+
+/// Lists the files of the song folder to send as they are, like `song_to_osz_with_manifest`
+/// would zip them up, hashing each one so the receiver can check what it got. Returns the size
+/// of each file in the manifest along with it. Will block as it reads from the file system.
+pub fn song_folder_manifest(song: &SongFolder, difficulties: Option<&Vec<String>>, profile: TransferProfile) -> io::Result<(MapManifest, Vec<u64>)> {
+    let root = song.path.as_ref().unwrap();
+    let mut manifest = MapManifest {
+        checksum: difficulties.is_none().then(|| song.checksum.clone()),
+        files: Vec::new()
+    };
+    let mut file_sizes = Vec::new();
+    for entry in map_entries(song, difficulties, profile)? {
+        let path = entry.path();
+        if path.is_file() {
+            let name = path.strip_prefix(root).unwrap().to_string_lossy().to_string();
+            manifest.files.push(FileHash { path: name, checksum: checksum_reader(File::open(path)?)? });
+            file_sizes.push(fs::metadata(path)?.len());
+        }
+    }
+    Ok((manifest, file_sizes))
+}
+
+/// Every file and subfolder in the song folder that goes to the peer, in file name order. Only
+/// the .osu files of the given difficulties are included, if they're set, and only the assets
+/// the profile includes. Will block as it reads from the file system.
+fn map_entries(song: &SongFolder, difficulties: Option<&Vec<String>>, profile: TransferProfile) -> io::Result<Vec<DirEntry>> {
+    let root = song.path.as_ref().unwrap();
+    let skipped_files: HashSet<&str> = match difficulties {
+        Some(difficulties) => song.difficulties.iter()
+            .filter(|difficulty| !difficulties.contains(&difficulty.checksum))
+            .map(|difficulty| difficulty.file_name.as_str())
+            .collect(),
+        None => HashSet::new()
+    };
+
+    // Only work out what each file is for if some of them are left out
+    let assets = match profile {
+        TransferProfile::Full => None,
+        _ => Some(AssetIndex::read(root)?)
+    };
+
     // Get iterator that goes over all entries in directory
     let mut files = WalkDir::new(&root)
+        .sort_by_file_name()
         .into_iter().filter_map(|e| e.ok());
 
     // Skip the first entry since it's the root directory
     files.next();
 
+    let mut entries = Vec::new();
     for entry in files {
-        let path = entry.path();
-        let name = path.strip_prefix(root).unwrap().to_string_lossy();
+        let name = entry.path().strip_prefix(root).unwrap().to_string_lossy();
+        if skipped_files.contains(name.as_ref()) {
+            continue;
+        }
+        if entry.path().is_file() && assets.as_ref().is_some_and(|assets| !profile.includes(assets.kind(&name))) {
+            continue;
+        }
+        entries.push(entry);
+    }
+    Ok(entries)
+}
 
-        if path.is_file() {
-            zip.start_file(name, zip_options)?;
+/// Works out `SongFolder::checksum` from the contents of a folder's .osu files, in file name order
+fn osu_checksum<'a>(osu_files: impl Iterator<Item = &'a [u8]>) -> String {
+    let mut hasher = Sha256::new();
+    for data in osu_files {
+        hasher.update(data);
+    }
+    HEXUPPER.encode(hasher.finalize().as_ref())
+}
 
-            let f = fs::read(path)?;
-            zip.write_all(&f)?;
-        } else {
-            zip.add_directory(name, zip_options)?;
+/// Checks a received .osz against the manifest it was sent with. Will block as it reads the .osz.
+pub fn verify_osz<R: Read + Seek>(osz_data: R, manifest: &MapManifest) -> Result<(), SongFolderError> {
+    let mut osz = zip::ZipArchive::new(osz_data)?;
+    for file in &manifest.files {
+        let entry = osz.by_name(&file.path)
+            .map_err(|_| SongFolderError::Damaged(file.path.clone()))?;
+        if checksum_reader(entry)? != file.checksum {
+            return Err(SongFolderError::Damaged(file.path.clone()));
         }
     }
-    let zip_data = zip.finish()?;
 
-    Ok(zip_data.into_inner())
+    if let Some(checksum) = &manifest.checksum {
+        // The .osu files are the ones at the top of the folder
+        let mut osu_names: Vec<String> = osz.file_names()
+            .filter(|name| name.ends_with(".osu") && !name.contains(['/', '\\']))
+            .map(|name| name.to_string())
+            .collect();
+        osu_names.sort();
+        let mut osu_files = Vec::new();
+        for name in osu_names {
+            let mut data = Vec::new();
+            osz.by_name(&name)?.read_to_end(&mut data)?;
+            osu_files.push(data);
+        }
+        if osu_checksum(osu_files.iter().map(|data| data.as_slice())) != *checksum {
+            return Err(SongFolderError::Damaged("the song's checksum".to_string()));
+        }
+    }
+    Ok(())
 }
 
-pub async fn zip_local_files(songs_to_zip: Vec<SongFolder>) -> io::Result<File> {
-    let zip_file = tempfile()?;
-    let mut zip = zip::ZipWriter::new(BufWriter::new(zip_file));
-    let zip_options = FileOptions::default();
-
-    // Split work across 4 threads to speed up performance
-    let (sender, mut receiver) = sync::mpsc::channel(24);
-    for chunk in songs_to_zip.chunks(4) {
-        let chunk = chunk.to_owned();
-        let sender = sender.clone();
-        task::spawn_blocking(move || {
-            for song in chunk {
-                // Zip each song into .osz format, and send it through the channel
-                let mut name = song.path.as_ref().unwrap().file_name().unwrap().to_os_string();
-                name.push(".osz");
-                let name = name.to_string_lossy().to_string();
-                let osz_data = song_to_osz(&song).unwrap();
-
-                sender.blocking_send((name, osz_data)).unwrap();
-            }
-        });
+/// Checks a map that was unpacked into the given folder against the manifest it was sent with.
+/// Will block as it reads from the file system.
+pub fn verify_folder(folder_path: &Path, manifest: &MapManifest) -> Result<(), SongFolderError> {
+    for file in &manifest.files {
+        let matches = File::open(folder_path.join(&file.path))
+            .and_then(checksum_reader)
+            .is_ok_and(|checksum| checksum == file.checksum);
+        if !matches {
+            return Err(SongFolderError::Damaged(file.path.clone()));
+        }
     }
-    // Drop main thread's reference to allow channel to close properly
-    drop(sender);
 
-    // Add each zipped song as a file in the zip
-    let mut processed = 0;
-    while let Some((name, song_data)) = receiver.recv().await {
-        zip.start_file(name, zip_options)?;
-        zip.write_all(&song_data[..])?;
-        processed += 1;
-        println!("Processed {processed} / {} songs", songs_to_zip.len());
+    if let Some(checksum) = &manifest.checksum {
+        if SongFolder::read_osu_files(folder_path)?.0 != *checksum {
+            return Err(SongFolderError::Damaged("the song's checksum".to_string()));
+        }
     }
+    Ok(())
+}
+
+/// Calculates the SHA-256 checksum of everything the reader gives back
+pub fn checksum_reader<R: Read>(mut reader: R) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut reader, &mut hasher)?;
+    Ok(HEXUPPER.encode(hasher.finalize().as_ref()))
+}
+
+/// Unpacks an .osz file, as created by `song_to_osz`, directly into the songs directory.
+/// It's extracted into a folder with the same name the sender had for it, with a numbered
+/// suffix if that folder already exists. If `existing_folder` is given, the .osz holds extra
+/// difficulties and gets added to that folder instead. Will block as it writes to the file system.
+pub fn install_osz<R: Read + Seek>(osz_data: R, osz_name: &str, songs_dir: &Path, existing_folder: Option<&Path>) -> Result<PathBuf, SongFolderError> {
+    // Only take the file name so a malicious name can't escape the songs directory
+    let folder_name = Path::new(osz_name).file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.strip_suffix(".osz"))
+        .filter(|folder_name| !folder_name.is_empty())
+        .ok_or_else(|| SongFolderError::InvalidPath(PathBuf::from(osz_name)))?;
+
+    let folder_path = match existing_folder {
+        Some(existing_folder) => existing_folder.to_path_buf(),
+        None => unique_folder_path(songs_dir, folder_name)
+    };
+    println!("Installing {osz_name} to {folder_path:?}");
+    extract_osz(osz_data, &folder_path)?;
+    Ok(folder_path)
+}
+
+/// Moves a map that was received as a folder, see `MapFormat::Folder`, from where it was staged
+/// into the songs directory. Like `install_osz`, it gets a numbered suffix if a folder with the
+/// same name already exists, or is added to `existing_folder` if that's given. The staging
+/// folder has to be on the same drive as the songs directory, so the whole map shows up at once.
+/// Will block as it writes to the file system.
+pub fn install_folder(staging_path: &Path, folder_name: &str, songs_dir: &Path, existing_folder: Option<&Path>) -> Result<PathBuf, SongFolderError> {
+    // Only take the file name so a malicious name can't escape the songs directory
+    let folder_name = Path::new(folder_name).file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| SongFolderError::InvalidPath(PathBuf::from(folder_name)))?;
 
-    // Get back our original file handle
-    let mut zip_file = zip.finish()?.into_inner()?;
+    let existing_folder = match existing_folder {
+        Some(existing_folder) => existing_folder,
+        None => {
+            let folder_path = unique_folder_path(songs_dir, folder_name);
+            println!("Installing {folder_name} to {folder_path:?}");
+            fs::rename(staging_path, &folder_path)?;
+            return Ok(folder_path);
+        }
+    };
 
-    // Rewind position in file to beginning to match expected behavior
-    zip_file.rewind()?;
-    Ok(zip_file)
+    // The extra difficulties and whatever they use have to be moved over one by one
+    println!("Adding {folder_name} to {existing_folder:?}");
+    for entry in WalkDir::new(staging_path).min_depth(1) {
+        let entry = entry.map_err(io::Error::from)?;
+        let out_path = existing_folder.join(entry.path().strip_prefix(staging_path).unwrap());
+        if entry.file_type().is_dir() {
+            fs::create_dir_all(&out_path)?;
+        } else {
+            fs::rename(entry.path(), &out_path)?;
+        }
+    }
+    fs::remove_dir_all(staging_path)?;
+    Ok(existing_folder.to_path_buf())
+}
+
+/// Finds a path for the given folder name that doesn't already exist in the songs directory,
+/// adding " (1)", " (2)", ... to the name until one is free.
+fn unique_folder_path(songs_dir: &Path, folder_name: &str) -> PathBuf {
+    let mut folder_path = songs_dir.join(folder_name);
+    let mut copy = 1;
+    while folder_path.exists() {
+        folder_path = songs_dir.join(format!("{folder_name} ({copy})"));
+        copy += 1;
+    }
+    folder_path
 }
+
+fn extract_osz<R: Read + Seek>(osz_data: R, folder_path: &Path) -> Result<(), SongFolderError> {
+    let mut osz = zip::ZipArchive::new(osz_data)?;
+    fs::create_dir_all(folder_path)?;
+
+    for i in 0..osz.len() {
+        let mut entry = osz.by_index(i)?;
+
+        // Skip any entries that would end up outside of the song folder
+        let relative_path = match entry.enclosed_name() {
+            Some(relative_path) => relative_path.to_owned(),
+            None => continue
+        };
+        let out_path = folder_path.join(relative_path);
+
+        if entry.is_dir() {
+            fs::create_dir_all(&out_path)?;
+        } else {
+            if let Some(parent) = out_path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let mut out_file = File::create(&out_path)?;
+            io::copy(&mut entry, &mut out_file)?;
+        }
+    }
+
+    Ok(())
+}
\ No newline at end of file
@@ -0,0 +1,152 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+use crate::file_manager::osu_file::OsuFile;
+
+/// Extensions osu! plays as background videos
+const VIDEO_EXTENSIONS: &[&str] = &["mp4", "avi", "flv", "mkv", "webm", "m4v", "mov", "mpg", "mpeg", "wmv"];
+
+/// Which files of each map a download brings over. Whatever is left out is skipped by the sender
+/// when it zips the map up, so it never goes over the network.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum TransferProfile {
+    /// Every file in the folder
+    #[default]
+    Full,
+    /// Everything but the background video
+    NoVideo,
+    /// Everything but the storyboard's images and sounds
+    NoStoryboard,
+    /// Just the song's audio and the .osu files, which is all that's needed to play the map
+    AudioAndDifficulties
+}
+
+/// What a file in a song folder is used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AssetKind {
+    /// A .osu file
+    Difficulty,
+    /// The song's audio
+    Audio,
+    Video,
+    /// A storyboard's .osb file, or an image or sound only the storyboard uses
+    Storyboard,
+    /// Backgrounds, hitsounds, skin elements and anything else
+    Other
+}
+
+/// How much of a song folder goes to one kind of asset
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct AssetSize {
+    /// Bytes
+    pub size: u64,
+    pub file_count: u32
+}
+
+/// How much of a song folder goes to the assets transfer profiles can leave out, so downloads can
+/// be estimated before they're asked for
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct AssetSizes {
+    pub audio: AssetSize,
+    pub video: AssetSize,
+    pub storyboard: AssetSize
+}
+
+impl TransferProfile {
+    /// Whether files of the given kind are sent with this profile
+    pub fn includes(&self, kind: AssetKind) -> bool {
+        match self {
+            TransferProfile::Full => true,
+            TransferProfile::NoVideo => kind != AssetKind::Video,
+            TransferProfile::NoStoryboard => kind != AssetKind::Storyboard,
+            TransferProfile::AudioAndDifficulties => matches!(kind, AssetKind::Difficulty | AssetKind::Audio)
+        }
+    }
+}
+
+impl AssetSizes {
+    /// Counts a file of the given kind towards the totals
+    pub fn add(&mut self, kind: AssetKind, size: u64) {
+        let asset_size = match kind {
+            AssetKind::Audio => &mut self.audio,
+            AssetKind::Video => &mut self.video,
+            AssetKind::Storyboard => &mut self.storyboard,
+            AssetKind::Difficulty | AssetKind::Other => return
+        };
+        asset_size.size += size;
+        asset_size.file_count += 1;
+    }
+}
+
+/// Works out what each file in a song folder is for, going by what its .osu and .osb files use.
+/// Paths are compared the way osu! does on Windows, ignoring case and which slash is used.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct AssetIndex {
+    audio: HashSet<String>,
+    video: HashSet<String>,
+    /// Backgrounds, which are kept with the map even if the storyboard uses them too
+    backgrounds: HashSet<String>,
+    storyboard: HashSet<String>
+}
+
+impl AssetIndex {
+    /// Builds the index from the folder's already parsed .osu files, reading its .osb files
+    /// for the rest of the storyboard. Will block as it reads from the file system.
+    pub fn new<'a>(folder: &Path, osu_files: impl IntoIterator<Item = &'a OsuFile>) -> io::Result<Self> {
+        let mut index = Self::default();
+        for osu_file in osu_files {
+            index.add(osu_file);
+        }
+        for entry in folder.read_dir()? {
+            let entry = entry?;
+            if entry.file_name().to_string_lossy().to_lowercase().ends_with(".osb") {
+                index.add(&OsuFile::parse(&String::from_utf8_lossy(&fs::read(entry.path())?)));
+            }
+        }
+        Ok(index)
+    }
+
+    /// Builds the index from scratch, reading every .osu and .osb file in the folder. Will block
+    /// as it reads from the file system.
+    pub fn read(folder: &Path) -> io::Result<Self> {
+        let mut osu_files = Vec::new();
+        for entry in folder.read_dir()? {
+            let entry = entry?;
+            if entry.file_name().to_string_lossy().ends_with(".osu") {
+                osu_files.push(OsuFile::parse(&String::from_utf8_lossy(&fs::read(entry.path())?)));
+            }
+        }
+        Self::new(folder, &osu_files)
+    }
+
+    fn add(&mut self, osu_file: &OsuFile) {
+        self.audio.extend(osu_file.audio_filename.as_deref().map(normalize));
+        self.video.extend(osu_file.video.as_deref().map(normalize));
+        self.backgrounds.extend(osu_file.background.as_deref().map(normalize));
+        self.storyboard.extend(osu_file.storyboard_files.iter().map(|file| normalize(file)));
+    }
+
+    /// What the file at the given path, relative to the song folder, is used for
+    pub fn kind(&self, path: &str) -> AssetKind {
+        let path = normalize(path);
+        let extension = path.rsplit_once('.').map_or("", |(_, extension)| extension);
+        if extension == "osu" {
+            AssetKind::Difficulty
+        } else if self.audio.contains(&path) {
+            AssetKind::Audio
+        } else if self.video.contains(&path) || VIDEO_EXTENSIONS.contains(&extension) {
+            AssetKind::Video
+        } else if self.backgrounds.contains(&path) {
+            AssetKind::Other
+        } else if extension == "osb" || self.storyboard.contains(&path) {
+            AssetKind::Storyboard
+        } else {
+            AssetKind::Other
+        }
+    }
+}
+
+fn normalize(path: &str) -> String {
+    path.replace('\\', "/").trim_start_matches("./").to_lowercase()
+}
@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use crate::file_manager::{SongFolder, SongFolderError};
+use crate::file_manager::assets::AssetIndex;
+
+/// Bump this whenever `SongFolder` or the cache layout changes, so old caches get rebuilt
+/// instead of handing out stale data.
+const CACHE_VERSION: u32 = 8;
+
+/// Size and modification time of a single .osu or .osb file, used to tell if a folder needs rehashing
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct CachedFile {
+    name: String,
+    size: u64,
+    modified: u64
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct CachedFolder {
+    files: Vec<CachedFile>,
+    /// What each file in the folder is for, so its sizes can be added up again without parsing
+    /// the .osu files
+    assets: AssetIndex,
+    song: SongFolder
+}
+
+/// Persistent index of every song folder we've read, so rescanning a library only has to hash
+/// the folders that changed since the last scan.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct SongCache {
+    version: u32,
+    folders: HashMap<PathBuf, CachedFolder>,
+    /// Where the cache gets saved to. If unset, the cache only lives in memory.
+    #[serde(skip)]
+    path: Option<PathBuf>
+}
+
+impl SongCache {
+    /// Loads the cache saved at the given path. If it is missing, unreadable or from an older
+    /// version, we start over with an empty cache.
+    pub fn load(path: PathBuf) -> Self {
+        let cache = fs::read(&path).ok()
+            .and_then(|data| serde_json::from_slice::<SongCache>(&data).ok())
+            .filter(|cache| cache.version == CACHE_VERSION);
+
+        match cache {
+            Some(mut cache) => {
+                println!("Loaded {} cached songs from {path:?}", cache.folders.len());
+                cache.path = Some(path);
+                cache
+            },
+            None => Self { version: CACHE_VERSION, folders: HashMap::new(), path: Some(path) }
+        }
+    }
+
+    /// Writes the cache to disk, if it was loaded from a path
+    pub fn save(&self) -> Result<(), SongFolderError> {
+        if let Some(path) = &self.path {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let data = serde_json::to_vec(self)
+                .map_err(|err| SongFolderError::IOError(err.into()))?;
+            fs::write(path, data)?;
+        }
+        Ok(())
+    }
+
+    /// Forgets every cached folder, forcing the next scan to rehash the whole library
+    pub fn clear(&mut self) {
+        self.folders.clear();
+    }
+
+    /// Replaces the cached folders with the results of a new scan. Folders that no longer
+    /// exist are dropped.
+    pub(super) fn replace(&mut self, folders: HashMap<PathBuf, CachedFolder>) {
+        self.version = CACHE_VERSION;
+        self.folders = folders;
+    }
+
+    /// Reads the song folder at the given path, only calculating a new checksum if its .osu or
+    /// .osb files changed since they were cached. Audio, videos and storyboard images can come
+    /// and go without those changing, so the folder's size is always added up again.
+    /// Will block as it reads from the file system.
+    pub(super) fn read_song_folder(&self, path: PathBuf) -> Result<CachedFolder, SongFolderError> {
+        let files = osu_file_stats(&path)?;
+
+        if let Some(cached) = self.folders.get(&path) {
+            if cached.files == files {
+                let mut song = cached.song.clone();
+                (song.size, song.file_count, song.assets) = SongFolder::folder_size(&path, &cached.assets)?;
+                song.path = Some(path);
+                return Ok(CachedFolder { files, assets: cached.assets.clone(), song });
+            }
+        }
+
+        let (song, assets) = SongFolder::new(path)?;
+        Ok(CachedFolder { files, assets, song })
+    }
+}
+
+impl CachedFolder {
+    pub fn song(&self) -> &SongFolder {
+        &self.song
+    }
+}
+
+/// Gets the size and modification time of every .osu and .osb file in the folder, sorted by name
+/// so the order the file system lists them in doesn't matter
+fn osu_file_stats(path: &Path) -> Result<Vec<CachedFile>, SongFolderError> {
+    let mut files = Vec::new();
+    for entry in path.read_dir()? {
+        let file = entry?;
+        let name = file.file_name().to_string_lossy().to_string();
+        if !name.ends_with(".osu") && !name.to_lowercase().ends_with(".osb") {
+            continue;
+        }
+
+        let metadata = file.metadata()?;
+        let modified = metadata.modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_nanos() as u64)
+            .unwrap_or(0);
+        files.push(CachedFile { name, size: metadata.len(), modified });
+    }
+
+    files.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(files)
+}
@@ -0,0 +1,171 @@
+/// The difficulty settings of a beatmap that go into its star rating
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct DifficultySettings {
+    pub hp_drain_rate: f32,
+    pub circle_size: f32,
+    pub overall_difficulty: f32,
+    pub approach_rate: f32,
+    pub slider_multiplier: f32,
+    pub slider_tick_rate: f32
+}
+
+impl Default for DifficultySettings {
+    /// The values osu! uses when a setting is missing from the file
+    fn default() -> Self {
+        Self {
+            hp_drain_rate: 5.0,
+            circle_size: 5.0,
+            overall_difficulty: 5.0,
+            approach_rate: 5.0,
+            slider_multiplier: 1.4,
+            slider_tick_rate: 1.0
+        }
+    }
+}
+
+/// Animations with more frames than this are treated as broken, rather than listing every frame
+const MAX_ANIMATION_FRAMES: usize = 10_000;
+
+/// The parts of a .osu file we care about, from the [General], [Metadata], [Difficulty] and
+/// [Events] sections. Everything else (timing points, hit objects, ...) is skipped over.
+/// Storyboard .osb files only have [Events], so they can be parsed the same way.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OsuFile {
+    pub format_version: Option<u32>,
+    pub audio_filename: Option<String>,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub creator: Option<String>,
+    pub version: Option<String>,
+    pub beatmap_id: Option<u64>,
+    pub beatmap_set_id: Option<u64>,
+    pub difficulty: DifficultySettings,
+    /// Background image, relative to the song folder
+    pub background: Option<String>,
+    /// Background video, relative to the song folder
+    pub video: Option<String>,
+    /// Every image and sound the storyboard uses, relative to the song folder
+    pub storyboard_files: Vec<String>
+}
+
+#[derive(PartialEq)]
+enum Section {
+    General,
+    Metadata,
+    Difficulty,
+    Events,
+    Other
+}
+
+impl OsuFile {
+    /// Parses the contents of a .osu file. Parsing never fails: anything missing or malformed
+    /// is just left unset, since old and hand-edited files are common.
+    pub fn parse(data: &str) -> Self {
+        let mut osu_file = OsuFile::default();
+        let mut approach_rate = None;
+        let mut section = Section::Other;
+
+        for (i, line) in data.lines().enumerate() {
+            let line = line.trim();
+
+            if i == 0 {
+                // The first line is the format version, possibly after a byte order mark
+                let header = line.trim_start_matches('\u{feff}');
+                if let Some(version) = header.strip_prefix("osu file format v") {
+                    osu_file.format_version = version.trim().parse().ok();
+                    continue;
+                }
+            }
+
+            if line.is_empty() || line.starts_with("//") {
+                continue;
+            }
+
+            if line.starts_with('[') && line.ends_with(']') {
+                section = match &line[1..line.len() - 1] {
+                    "General" => Section::General,
+                    "Metadata" => Section::Metadata,
+                    "Difficulty" => Section::Difficulty,
+                    "Events" => Section::Events,
+                    _ => Section::Other
+                };
+                continue;
+            }
+
+            if section == Section::Events {
+                osu_file.parse_event(line);
+                continue;
+            }
+            if section == Section::Other {
+                continue;
+            }
+            let (key, value) = match line.split_once(':') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => continue
+            };
+
+            let settings = &mut osu_file.difficulty;
+            match (&section, key) {
+                (Section::General, "AudioFilename") => osu_file.audio_filename = non_empty(value),
+                (Section::Metadata, "Title") => osu_file.title = non_empty(value),
+                (Section::Metadata, "Artist") => osu_file.artist = non_empty(value),
+                (Section::Metadata, "Creator") => osu_file.creator = non_empty(value),
+                (Section::Metadata, "Version") => osu_file.version = non_empty(value),
+                // Unsubmitted maps use 0 or -1 for their ids, which we treat as not having one
+                (Section::Metadata, "BeatmapID") => osu_file.beatmap_id = value.parse().ok().filter(|id| *id > 0),
+                (Section::Metadata, "BeatmapSetID") => osu_file.beatmap_set_id = value.parse().ok().filter(|id| *id > 0),
+                (Section::Difficulty, "HPDrainRate") => parse_setting(value, &mut settings.hp_drain_rate),
+                (Section::Difficulty, "CircleSize") => parse_setting(value, &mut settings.circle_size),
+                (Section::Difficulty, "OverallDifficulty") => parse_setting(value, &mut settings.overall_difficulty),
+                (Section::Difficulty, "ApproachRate") => approach_rate = value.parse().ok(),
+                (Section::Difficulty, "SliderMultiplier") => parse_setting(value, &mut settings.slider_multiplier),
+                (Section::Difficulty, "SliderTickRate") => parse_setting(value, &mut settings.slider_tick_rate),
+                _ => {}
+            }
+        }
+
+        // Old maps don't have a separate approach rate, and use the overall difficulty instead
+        osu_file.difficulty.approach_rate = approach_rate.unwrap_or(osu_file.difficulty.overall_difficulty);
+        osu_file
+    }
+
+    /// Picks out the file a line in the [Events] section uses, if any. Events can be written
+    /// with either their name or their number. The commands that move sprites around are
+    /// indented under them, and don't use any files.
+    fn parse_event(&mut self, line: &str) {
+        let fields: Vec<&str> = line.split(',').map(|field| field.trim().trim_matches('"')).collect();
+        match fields.as_slice() {
+            ["0", _, file, ..] => self.background = non_empty(file),
+            ["Video" | "1", _, file, ..] => self.video = non_empty(file),
+            ["Sprite" | "4", _, _, file, ..] | ["Sample" | "5", _, _, file, ..] if !file.is_empty() => {
+                self.storyboard_files.push(file.to_string());
+            },
+            // Each frame of an animation is its own file, numbered just before the extension
+            ["Animation" | "6", _, _, file, _, _, frame_count, ..] if !file.is_empty() => {
+                let frame_count = frame_count.parse::<usize>().unwrap_or(0).min(MAX_ANIMATION_FRAMES);
+                let (stem, extension) = match file.rsplit_once('.') {
+                    Some((stem, extension)) => (stem, format!(".{extension}")),
+                    None => (*file, String::new())
+                };
+                for frame in 0..frame_count {
+                    self.storyboard_files.push(format!("{stem}{frame}{extension}"));
+                }
+            },
+            _ => {}
+        }
+    }
+}
+
+fn non_empty(value: &str) -> Option<String> {
+    if value.is_empty() {
+        None
+    } else {
+        Some(value.to_string())
+    }
+}
+
+fn parse_setting(value: &str, setting: &mut f32) {
+    if let Ok(value) = value.parse() {
+        *setting = value;
+    }
+}
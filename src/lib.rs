@@ -0,0 +1,9 @@
+//! Everything both the Tauri app and the command line tool need to read a songs folder and sync
+//! it with peers
+
+pub mod networking;
+pub mod file_manager;
+pub mod diff;
+pub mod ui;
+#[cfg(test)]
+mod test;
@@ -3,44 +3,95 @@ all(not(debug_assertions), target_os = "windows"),
 windows_subsystem = "windows"
 )]
 
+use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 use std::time::Instant;
-use file_manager::SongFolder;
-use tauri::api::dialog::blocking::FileDialogBuilder;
-use tauri::Manager;
-use networking::packets::PacketManager;
-use crate::networking::packets::{DownloadRequestPacket, MapListRequestPacket};
+use osu_mapsync::{diff, file_manager, networking};
+use osu_mapsync::diff::{SyncPlan, SyncSummary};
+use osu_mapsync::file_manager::{DownloadEstimate, MapRequest, SongFolder};
+use osu_mapsync::file_manager::assets::TransferProfile;
+use osu_mapsync::file_manager::cache::SongCache;
+use osu_mapsync::networking::bandwidth::BandwidthSettings;
+use osu_mapsync::networking::download::DownloadDestination;
+use osu_mapsync::networking::packets::{DownloadMode, MapListRequestPacket, PacketManager, PeerId, PeerInfo};
+use osu_mapsync::networking::planner::PullSummary;
+use osu_mapsync::networking::queue::{Transfer, TransferId, TransferQueue};
+use osu_mapsync::networking::discovery::{DiscoveredPeer, DiscoveredPeers};
+use osu_mapsync::networking::listener::{ListenerSettings, shareable_addr};
+use osu_mapsync::networking::secure::Identity;
+use osu_mapsync::networking::trust::{TrustedPeer, TrustedPeers, UnknownPeerPolicy};
+use osu_mapsync::ui::UiBridge;
+use tauri::api::dialog::blocking::{ask, FileDialogBuilder};
+use tauri::{Manager, Window, Wry};
+use tokio::task;
+use tokio::task::JoinHandle;
 
-mod networking;
-mod file_manager;
-#[cfg(test)]
-mod test;
+/// Talks to the user through the main window
+#[derive(Debug, Clone)]
+struct TauriUi {
+    window: Window<Wry>
+}
+
+impl UiBridge for TauriUi {
+    fn emit_json(&self, event: &str, payload: String) {
+        // Tauri serializes the payload itself, so it has to be turned back into a value first.
+        // An event that can't be delivered, e.g. because the window has closed, is dropped.
+        let emitted = serde_json::from_str::<serde_json::Value>(&payload)
+            .map_err(|err| err.to_string())
+            .and_then(|payload| self.window.emit(event, payload).map_err(|err| err.to_string()));
+        if let Err(err) = emitted {
+            println!("Unable to emit {event}: {err}");
+        }
+    }
+
+    fn ask(&self, title: &str, message: &str) -> bool {
+        ask(Some(&self.window), title, message)
+    }
+
+    fn pick_folder(&self, title: &str) -> Option<PathBuf> {
+        FileDialogBuilder::new()
+            .set_title(title)
+            .pick_folder()
+    }
+}
 
 #[derive(Debug)]
 struct SynchronizerState {
-    local_path: Mutex<Option<PathBuf>>,
+    local_path: Arc<Mutex<Option<PathBuf>>>,
     local_songs: Arc<Mutex<Vec<SongFolder>>>,
-    remote_songs: Arc<Mutex<Vec<SongFolder>>>,
-    packet_manager: Arc<Mutex<PacketManager>>
+    song_cache: Arc<Mutex<SongCache>>,
+    trusted_peers: Arc<Mutex<TrustedPeers>>,
+    listener_settings: Arc<Mutex<ListenerSettings>>,
+    /// Locked for as long as the server is being restarted, so only one can ever be running
+    listening_server: tokio::sync::Mutex<Option<JoinHandle<()>>>,
+    listen_addr: Arc<Mutex<Option<SocketAddr>>>,
+    discovered_peers: Arc<Mutex<DiscoveredPeers>>,
+    packet_manager: Arc<Mutex<PacketManager>>,
+    transfer_queue: Arc<Mutex<TransferQueue>>
 }
 
 impl SynchronizerState {
     fn new() -> Self {
+        let packet_manager = Arc::new(Mutex::new(PacketManager::new()));
         Self {
-            local_path: Mutex::new(None),
+            local_path: Arc::new(Mutex::new(None)),
             local_songs: Arc::new(Mutex::new(Vec::new())),
-            remote_songs: Arc::new(Mutex::new(Vec::new())),
-            packet_manager: Arc::new(Mutex::new(PacketManager::new()))
+            song_cache: Arc::new(Mutex::new(SongCache::default())),
+            trusted_peers: Arc::new(Mutex::new(TrustedPeers::default())),
+            listener_settings: Arc::new(Mutex::new(ListenerSettings::default())),
+            listening_server: tokio::sync::Mutex::new(None),
+            listen_addr: Arc::new(Mutex::new(None)),
+            discovered_peers: Arc::new(Mutex::new(DiscoveredPeers::default())),
+            transfer_queue: Arc::new(Mutex::new(TransferQueue::new(packet_manager.clone()))),
+            packet_manager
         }
     }
 }
 
 #[tauri::command]
-async fn get_local_path(state: tauri::State<'_, SynchronizerState>) -> Result<String, ()> {
-    let folder_path = FileDialogBuilder::new()
-        .set_title("Choose your osu! Song directory")
-        .pick_folder();
+async fn get_local_path(window: Window<Wry>, state: tauri::State<'_, SynchronizerState>) -> Result<String, ()> {
+    let folder_path = TauriUi { window }.pick_folder("Choose your osu! Song directory");
 
     if folder_path.is_some() {
         println!("New folder path: {:?}", folder_path);
@@ -59,7 +110,7 @@ async fn read_local_files(state: tauri::State<'_, SynchronizerState>) -> Result<
     if let Some(path) = local_path {
         println!("Reading all songs from {:?}", path);
         let now = Instant::now();
-        let read_songs = file_manager::read_local_files(&path).await;
+        let read_songs = file_manager::read_local_files(&path, &state.song_cache).await;
         let dur = now.elapsed().as_secs_f64();
         println!("Took {:?} s", dur);
 
@@ -77,26 +128,292 @@ async fn read_local_files(state: tauri::State<'_, SynchronizerState>) -> Result<
     Err("No local path specified.".to_string())
 }
 
+/// Throws away the song cache and rereads every song, for when the cache has gone out of sync
+#[tauri::command]
+async fn rebuild_local_files(state: tauri::State<'_, SynchronizerState>) -> Result<Vec<SongFolder>, String> {
+    state.song_cache.lock().unwrap().clear();
+    read_local_files(state).await
+}
+
+#[tauri::command]
+async fn get_local_files(state: tauri::State<'_, SynchronizerState>) -> Result<Vec<SongFolder>, ()> {
+    let local_songs = state.local_songs.lock().unwrap();
+    Ok(local_songs.clone())
+}
+
+#[tauri::command]
+async fn get_remote_files(peer: PeerId, state: tauri::State<'_, SynchronizerState>) -> Result<Vec<SongFolder>, ()> {
+    let remote_songs = state.packet_manager.lock().unwrap().remote_songs(peer);
+    remote_songs.ok_or(())
+}
+
+/// Compares our songs against the songs of the given peer, or against nothing if no peer is given
+#[tauri::command]
+fn get_song_diff(peer: Option<PeerId>, state: tauri::State<'_, SynchronizerState>) -> diff::SongDiff {
+    let remote_songs = peer
+        .and_then(|peer| state.packet_manager.lock().unwrap().remote_songs(peer))
+        .unwrap_or_default();
+    let local_songs = state.local_songs.lock().unwrap();
+    diff::diff_songs(&local_songs, &remote_songs)
+}
+
+/// Connects to the peer at `addr`, returning its id if it let us in
+#[tauri::command]
+async fn connect_to_server(addr: String, pairing_code: Option<String>, state: tauri::State<'_, SynchronizerState>) -> Result<Option<PeerId>, String> {
+    networking::connect_to_server(addr, pairing_code, &state.packet_manager, &state.trusted_peers).await
+        .map_err(|err| { format!("An error occurred: {err}") })
+}
+
+/// Makes up a code to show to the user, which a peer can enter to pair with us
+#[tauri::command]
+fn start_pairing(state: tauri::State<'_, SynchronizerState>) -> String {
+    state.trusted_peers.lock().unwrap().start_pairing()
+}
+
+#[tauri::command]
+fn get_trusted_peers(state: tauri::State<'_, SynchronizerState>) -> Vec<TrustedPeer> {
+    state.trusted_peers.lock().unwrap().peers()
+}
+
+#[tauri::command]
+fn forget_trusted_peer(public_key: String, state: tauri::State<'_, SynchronizerState>) -> bool {
+    state.trusted_peers.lock().unwrap().forget(&public_key)
+}
+
+#[tauri::command]
+fn get_unknown_peer_policy(state: tauri::State<'_, SynchronizerState>) -> UnknownPeerPolicy {
+    state.trusted_peers.lock().unwrap().unknown_peer_policy()
+}
+
+#[tauri::command]
+fn set_unknown_peer_policy(policy: UnknownPeerPolicy, state: tauri::State<'_, SynchronizerState>) {
+    state.trusted_peers.lock().unwrap().set_unknown_peer_policy(policy);
+}
+
+/// The other instances we've heard announce themselves on the LAN recently
+#[tauri::command]
+fn get_discovered_peers(state: tauri::State<'_, SynchronizerState>) -> Vec<DiscoveredPeer> {
+    state.discovered_peers.lock().unwrap().peers()
+}
+
+/// Stops the listening server if it's running, and starts it again with the given settings.
+/// Returns the address we're now listening on, if any.
+async fn restart_listening_server(ui: Arc<dyn UiBridge>, settings: &ListenerSettings, state: &SynchronizerState)
+    -> Result<Option<SocketAddr>, String> {
+    let mut listening_server = state.listening_server.lock().await;
+    if let Some(server) = listening_server.take() {
+        server.abort();
+        // Wait for the old listener to be dropped, so its port is free to bind again
+        let _ = server.await;
+    }
+    *state.listen_addr.lock().unwrap() = None;
+
+    *listening_server = networking::start_listening_server(settings, ui, state.packet_manager.clone(),
+                                                           state.trusted_peers.clone(), state.listen_addr.clone()).await
+        .map_err(|err| format!("An error occurred: {err}"))?;
+
+    let listen_addr = *state.listen_addr.lock().unwrap();
+    Ok(listen_addr)
+}
+
+#[tauri::command]
+fn get_listener_settings(state: tauri::State<'_, SynchronizerState>) -> ListenerSettings {
+    state.listener_settings.lock().unwrap().clone()
+}
+
+/// Restarts the listening server with the new settings, saving them once it's listening again.
+/// If the server can't start with them, it goes back to the old settings. Returns the address
+/// peers can now reach us at.
+#[tauri::command]
+async fn set_listener_settings(settings: ListenerSettings, window: Window<Wry>, state: tauri::State<'_, SynchronizerState>) -> Result<Option<String>, String> {
+    let ui: Arc<dyn UiBridge> = Arc::new(TauriUi { window });
+    let old_settings = state.listener_settings.lock().unwrap().clone();
+    let listen_addr = match restart_listening_server(ui.clone(), &settings, &state).await {
+        Ok(listen_addr) => listen_addr,
+        Err(err) => {
+            if let Err(restore_err) = restart_listening_server(ui, &old_settings, &state).await {
+                println!("Unable to restart the listening server with the old settings: {restore_err}");
+            }
+            return Err(err);
+        }
+    };
+
+    state.listener_settings.lock().unwrap().update(settings)
+        .map_err(|err| format!("Unable to save the listener settings: {err}"))?;
+    Ok(listen_addr.map(|addr| shareable_addr(addr).to_string()))
+}
+
+#[tauri::command]
+fn get_bandwidth_settings(state: tauri::State<'_, SynchronizerState>) -> BandwidthSettings {
+    state.packet_manager.lock().unwrap().bandwidth().settings()
+}
+
+/// Saves the new limits, which apply straight away, even to transfers that are already running
+#[tauri::command]
+fn set_bandwidth_settings(settings: BandwidthSettings, state: tauri::State<'_, SynchronizerState>) -> Result<(), String> {
+    state.packet_manager.lock().unwrap().bandwidth().update_settings(settings)
+        .map_err(|err| format!("Unable to save the bandwidth settings: {err}"))
+}
+
+/// The address peers can reach us at, or None if we aren't listening for connections
+#[tauri::command]
+fn get_listen_address(state: tauri::State<'_, SynchronizerState>) -> Option<String> {
+    let listen_addr = *state.listen_addr.lock().unwrap();
+    listen_addr.map(|addr| shareable_addr(addr).to_string())
+}
+
+/// Every peer we're currently connected to
+#[tauri::command]
+fn get_peers(state: tauri::State<'_, SynchronizerState>) -> Vec<PeerInfo> {
+    state.packet_manager.lock().unwrap().peers()
+}
+
+#[tauri::command]
+fn get_peer_info(peer: PeerId, state: tauri::State<'_, SynchronizerState>) -> Option<PeerInfo> {
+    state.packet_manager.lock().unwrap().peer(peer)
+}
+
+#[tauri::command]
+fn disconnect_peer(peer: PeerId, state: tauri::State<'_, SynchronizerState>) {
+    state.packet_manager.lock().unwrap().disconnect(peer);
+}
+
+#[tauri::command]
+fn set_display_name(display_name: String, state: tauri::State<'_, SynchronizerState>) {
+    state.packet_manager.lock().unwrap().set_display_name(display_name);
+}
+
+#[tauri::command]
+fn request_remote_files(peer: PeerId, state: tauri::State<'_, SynchronizerState>) {
+    state.packet_manager.lock().unwrap().send_packet(peer, Box::new(MapListRequestPacket::new()));
+}
+
+/// Asks the user to agree to a download, and where its maps should go
+fn choose_destination(message: String, mode: DownloadMode, window: &Window<Wry>, state: &SynchronizerState) -> Result<DownloadDestination, String> {
+    let songs_dir = state.local_path.lock().unwrap().clone();
+    DownloadDestination::choose(&TauriUi { window: window.clone() }, &message, mode, songs_dir)
+        .ok_or("The download was canceled.".to_string())
+}
+
+/// Estimates how much downloading the given songs would bring over, before asking for them.
+/// Only the difficulties we don't already have, and the assets the profile includes, are counted.
+#[tauri::command]
+fn estimate_download(songs_to_request: Vec<SongFolder>, profile: TransferProfile,
+                     state: tauri::State<'_, SynchronizerState>) -> DownloadEstimate {
+    let requested_maps = MapRequest::for_songs(songs_to_request, &state.local_songs.lock().unwrap(), profile);
+    DownloadEstimate::for_requests(&requested_maps)
+}
+
+/// Adds the given songs to the transfer queue, to be downloaded from the given peer one by one
+#[tauri::command]
+async fn request_download(peer: PeerId, songs_to_request: Vec<SongFolder>, mode: DownloadMode, profile: TransferProfile,
+                          window: Window<Wry>, state: tauri::State<'_, SynchronizerState>) -> Result<Vec<TransferId>, String> {
+    let requested_maps = MapRequest::for_songs(songs_to_request, &state.local_songs.lock().unwrap(), profile);
+
+    // The peer won't ask, so the user agrees to every map up front
+    let estimate = DownloadEstimate::for_requests(&requested_maps);
+    let destination = choose_destination(format!("You are about to download {}. Continue?", estimate.describe()),
+                                         mode, &window, &state)?;
+    let requested_maps = requested_maps.into_iter().map(|request| request.with_format(destination.map_format())).collect();
+    Ok(state.transfer_queue.lock().unwrap().enqueue(peer, requested_maps, destination))
+}
+
+/// Downloads the given songs from every connected peer that has the same copy of them at once,
+/// getting any that fail from another peer
 #[tauri::command]
-async fn get_remote_files(state: tauri::State<'_, SynchronizerState>) -> Result<Vec<SongFolder>, ()> {
-    let remote_songs = state.remote_songs.lock().unwrap();
-    Ok(remote_songs.clone())
+async fn pull_download(songs_to_request: Vec<SongFolder>, mode: DownloadMode, profile: TransferProfile, window: Window<Wry>,
+                       state: tauri::State<'_, SynchronizerState>) -> Result<PullSummary, String> {
+    let requested_maps = MapRequest::for_songs(songs_to_request, &state.local_songs.lock().unwrap(), profile);
+    let peer_count = state.packet_manager.lock().unwrap().peers().len();
+    let estimate = DownloadEstimate::for_requests(&requested_maps);
+
+    // The peers won't ask, so the user agrees to the whole pull up front
+    let destination = choose_destination(
+        format!("You are about to download {} from {peer_count} peers. Continue?", estimate.describe()),
+        mode, &window, &state
+    )?;
+
+    let requested_maps = requested_maps.into_iter().map(|request| request.with_format(destination.map_format())).collect();
+    Ok(networking::planner::pull_maps(&state.packet_manager, requested_maps, destination).await)
 }
 
+/// Swaps songs with the peer so both sides end up with every song either of them has, once the
+/// user and then the peer agree to how much goes each way. The songs we get are installed into
+/// our songs folder.
 #[tauri::command]
-async fn connect_to_server(addr: String, state: tauri::State<'_, SynchronizerState>) -> Result<bool, String> {
-    networking::connect_to_server(addr, &state.packet_manager).await
-        .map_err(|err| { format!("An error occurred: {err:?} ") })
+async fn two_way_sync(peer: PeerId, window: Window<Wry>, state: tauri::State<'_, SynchronizerState>) -> Result<SyncSummary, String> {
+    let songs_dir = state.local_path.lock().unwrap().clone().ok_or("No local path specified.".to_string())?;
+    let remote_songs = state.packet_manager.lock().unwrap().remote_songs(peer).ok_or("Not connected to the peer.".to_string())?;
+    let plan = SyncPlan::between(&state.local_songs.lock().unwrap(), &remote_songs);
+    if plan.is_empty() {
+        return Err("Both sides already have the same songs.".to_string());
+    }
+
+    let summary = plan.summary();
+    if !plan.confirm(&TauriUi { window }) {
+        return Err("The sync was canceled.".to_string());
+    }
+    if !state.packet_manager.lock().unwrap().start_sync(peer, plan, DownloadDestination::Install(songs_dir)) {
+        return Err("Not connected to the peer.".to_string());
+    }
+    Ok(summary)
 }
 
+/// Every transfer in the queue, in the order they get started in
 #[tauri::command]
-fn request_remote_files(state: tauri::State<'_, SynchronizerState>) {
-    state.packet_manager.lock().unwrap().send_packet(Box::new(MapListRequestPacket::new()));
+fn get_transfers(state: tauri::State<'_, SynchronizerState>) -> Vec<Transfer> {
+    state.transfer_queue.lock().unwrap().transfers()
 }
 
 #[tauri::command]
-fn request_download(songs_to_request: Vec<SongFolder>, state: tauri::State<'_, SynchronizerState>) {
-    state.packet_manager.lock().unwrap().send_packet(Box::new(DownloadRequestPacket::new(songs_to_request)));
+fn pause_transfer(id: TransferId, state: tauri::State<'_, SynchronizerState>) -> bool {
+    state.transfer_queue.lock().unwrap().pause(id)
+}
+
+#[tauri::command]
+fn resume_transfer(id: TransferId, state: tauri::State<'_, SynchronizerState>) -> bool {
+    state.transfer_queue.lock().unwrap().resume(id)
+}
+
+#[tauri::command]
+fn cancel_transfer(id: TransferId, state: tauri::State<'_, SynchronizerState>) -> bool {
+    state.transfer_queue.lock().unwrap().cancel(id)
+}
+
+#[tauri::command]
+fn move_transfer(id: TransferId, index: usize, state: tauri::State<'_, SynchronizerState>) -> bool {
+    state.transfer_queue.lock().unwrap().move_to(id, index)
+}
+
+/// Sets whether maps that arrive damaged are asked for again automatically
+#[tauri::command]
+fn set_retry_damaged_transfers(retry: bool, state: tauri::State<'_, SynchronizerState>) {
+    state.transfer_queue.lock().unwrap().set_retry_damaged(retry);
+}
+
+/// Picks up the download from the given peer that got cut off when we last lost our connection
+/// to it
+#[tauri::command]
+async fn resume_download(peer: PeerId, state: tauri::State<'_, SynchronizerState>) -> Result<(), String> {
+    let partial_download = {
+        let packet_manager = state.packet_manager.lock().unwrap();
+        packet_manager.peer(peer)
+            .and_then(|peer| packet_manager.partial_download(&peer.public_key))
+    };
+    let partial_download = partial_download.ok_or("There is no download to resume.".to_string())?;
+
+    let (partial_download, resume_from) = task::spawn_blocking(move || {
+        let resume_from = partial_download.resume_from();
+        (partial_download, resume_from)
+    }).await.unwrap();
+    let resume_from = resume_from
+        .map_err(|err| format!("Unable to resume the download: {err}"))?;
+
+    let resumed = state.packet_manager.lock().unwrap().resume_download(peer, partial_download, resume_from, None);
+    if !resumed {
+        return Err("Not connected to the peer, or it's still sending us another download.".to_string());
+    }
+    Ok(())
 }
 
 
@@ -105,19 +422,49 @@ async fn main() {
     tauri::Builder::default()
         .manage(SynchronizerState::new())
         .invoke_handler(tauri::generate_handler![
-            get_local_path, read_local_files, get_remote_files,
-            connect_to_server, request_remote_files, request_download
+            get_local_path, read_local_files, rebuild_local_files, get_local_files, get_remote_files, get_song_diff,
+            connect_to_server, get_peers, get_peer_info, disconnect_peer, set_display_name, request_remote_files, request_download, pull_download,
+            estimate_download, resume_download, start_pairing, get_trusted_peers, forget_trusted_peer, get_unknown_peer_policy,
+            set_unknown_peer_policy, get_discovered_peers, get_listener_settings, set_listener_settings, get_listen_address,
+            get_bandwidth_settings, set_bandwidth_settings, get_transfers, pause_transfer, resume_transfer, cancel_transfer,
+            move_transfer, set_retry_damaged_transfers, two_way_sync
         ])
         .setup(|app| {
             let state = app.state::<SynchronizerState>();
-            let main_window = app.get_window("main").unwrap();
+            let ui: Arc<dyn UiBridge> = Arc::new(TauriUi { window: app.get_window("main").unwrap() });
+
+            // Load the checksums of the songs we read last time, so rescanning is quick
+            if let Some(data_dir) = app.path_resolver().app_data_dir() {
+                *state.song_cache.lock().unwrap() = SongCache::load(data_dir.join("song_cache.json"));
+                *state.trusted_peers.lock().unwrap() = TrustedPeers::load(data_dir.join("trusted_peers.json"));
+                *state.listener_settings.lock().unwrap() = ListenerSettings::load(data_dir.join("listener_settings.json"));
+                state.packet_manager.lock().unwrap().bandwidth()
+                    .set_settings(BandwidthSettings::load(data_dir.join("bandwidth_settings.json")));
+
+                // Keep the same identity between runs, so peers can recognize us
+                match Identity::load_or_generate(&data_dir.join("identity.json")) {
+                    Ok(identity) => state.packet_manager.lock().unwrap().set_identity(identity),
+                    Err(err) => println!("Unable to load our identity, using a temporary one: {err:?}")
+                }
+            }
 
             // Pass in the main window to our server listener for message emitting
-            networking::start_listening_server(main_window.clone(), state.packet_manager.clone());
+            let app_handle = app.app_handle();
+            let server_ui = ui.clone();
+            tokio::spawn(async move {
+                let state = app_handle.state::<SynchronizerState>();
+                let settings = state.listener_settings.lock().unwrap().clone();
+                if let Err(err) = restart_listening_server(server_ui, &settings, &state).await {
+                    println!("Unable to start the listening server: {err}");
+                }
+            });
+            networking::discovery::start_discovery(ui.clone(), state.packet_manager.clone(), state.local_songs.clone(),
+                                                   state.listen_addr.clone(), state.discovered_peers.clone());
 
             // Let the packet manager know about our app so it can communicate with it
             state.packet_manager.lock().unwrap()
-                .connect_to_app(state.local_songs.clone(), state.remote_songs.clone(), main_window);
+                .connect_to_app(state.local_path.clone(), state.local_songs.clone(), state.song_cache.clone(), ui.clone());
+            TransferQueue::start(&state.transfer_queue, ui);
 
             Ok(())
         })
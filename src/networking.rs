@@ -1,13 +1,33 @@
+use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
-use tauri::{Window, Wry};
-use tauri::api::dialog::blocking::ask;
+use std::time::Duration;
 use thiserror::Error;
 use tokio::io;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
-use crate::networking::packets::PacketManager;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+use crate::networking::codec::{CodecError, Frame, read_frame, write_frame};
+use crate::networking::listener::ListenerSettings;
+use crate::networking::packets::{HelloPacket, Packet, PacketManager, PairingPacket, PeerId};
+use crate::networking::secure::SecureStream;
+use crate::networking::trust::{Pairing, PairingRole, PeerDecision, TrustedPeers};
+use crate::ui::UiBridge;
 
+pub mod codec;
+pub mod bandwidth;
 pub mod packets;
+pub mod download;
+pub mod secure;
+pub mod trust;
+pub mod discovery;
+pub mod listener;
+pub mod planner;
+pub mod queue;
+
+/// How long a peer connecting to us gets to get through the handshake, the hellos and pairing, so
+/// one that goes quiet partway through doesn't hold on to the connection forever
+const ACCEPT_TIMEOUT: Duration = Duration::from_secs(30);
 
 #[repr(u8)]
 enum ServerConnectMessage {
@@ -25,72 +45,238 @@ pub enum NetworkingError {
     WriteError(io::Error),
     #[error("An unexpected message was read from the socket: {0}")]
     UnexpectedMessage(String),
+    #[error("Unable to complete the handshake with the remote address: {0}")]
+    HandshakeError(CodecError),
+    #[error("Unable to set up an encrypted connection with the remote address: {0}")]
+    EncryptionError(io::Error),
+    #[error("Unable to find our address on the network, are you connected to it? {0}")]
+    NoLocalAddress(String),
+    #[error("Unable to listen for connections on {0}: {1}")]
+    BindError(SocketAddr, io::Error),
+    #[error("Unable to pair with the peer. Check that the code is the one the peer is showing, and that it hasn't expired.")]
+    PairingFailed,
+    #[error("The peer took too long to finish connecting")]
+    TimedOut,
+    #[error("{peer_name} is using protocol version {remote} (app version {remote_app}), which is incompatible with our version {local}. Both sides need to use the same version of osu!sync.")]
+    IncompatibleVersion { local: u16, remote: u16, remote_app: String, peer_name: String },
     #[error("An unexpected IO error occurred: {0}")]
     IOError(#[from] io::Error)
 }
 
-async fn handle_incoming_connection(listener: &TcpListener, app_window: &Window<Wry>, packet_manager: &Mutex<PacketManager>) -> Result<(), NetworkingError> {
-    let (mut socket, addr) = listener.accept().await?;
+/// Sends our hello and reads the peer's, failing if the peer speaks a protocol we don't.
+/// Both sides send before reading, so neither has to wait on the other.
+pub async fn exchange_hello<S: AsyncRead + AsyncWrite + Unpin>(socket: &mut S, local_hello: &HelloPacket) -> Result<HelloPacket, NetworkingError> {
+    let frame = Frame::from_packet(local_hello).map_err(NetworkingError::HandshakeError)?;
+    write_frame(socket, &frame).await
+        .map_err(|err| NetworkingError::HandshakeError(err))?;
+    socket.flush().await?;
 
-    // Ask user to see if we should allow connection from addr
-    let accept = ask(Some(app_window), "Accept connection",
-                     format!("Accept incoming connection from {addr}?"));
+    let frame = read_frame(socket).await
+        .map_err(|err| NetworkingError::HandshakeError(err))?;
+    if frame.header != HelloPacket::HEADER {
+        return Err(NetworkingError::UnexpectedMessage(format!("Expected a hello, but got packet {}", frame.header)));
+    }
+    let peer_hello = HelloPacket::deserialize(frame.payload)
+        .map_err(|err| NetworkingError::HandshakeError(err))?;
 
-    if accept {
-        socket.write_u8(ServerConnectMessage::ALLOWED as u8).await
-            .map_err(|err| NetworkingError::WriteError(err))?;
+    if !local_hello.is_compatible_with(&peer_hello) {
+        return Err(NetworkingError::IncompatibleVersion {
+            local: local_hello.protocol_version,
+            remote: peer_hello.protocol_version,
+            remote_app: peer_hello.app_version,
+            peer_name: peer_hello.display_name
+        });
+    }
 
-        // Pass connection to app.state.packet_server
-        packet_manager.lock().unwrap().connect(socket);
-    } else {
-        socket.write_u8(ServerConnectMessage::DENIED as u8).await
-            .map_err(|err| NetworkingError::WriteError(err))?;
+    Ok(peer_hello)
+}
+
+/// Runs everything between a peer connecting to us and us letting it in: the encrypted
+/// handshake, the hellos, and checking whether we trust the peer or it's pairing with us. Peers we
+/// don't know are left up to `ask_user`, which is given the peer's hello and public key.
+/// Everything up to asking the user has to be done within `ACCEPT_TIMEOUT`.
+/// Returns the connection if the peer was let in.
+pub async fn accept_peer<F>(socket: TcpStream, packet_manager: &Mutex<PacketManager>, trusted_peers: &Mutex<TrustedPeers>,
+                            ask_user: F) -> Result<Option<(SecureStream, HelloPacket, PeerDecision)>, NetworkingError>
+    where F: FnOnce(&HelloPacket, &str) -> bool {
+    let (mut socket, peer_hello, paired) = timeout(ACCEPT_TIMEOUT, greet_peer(socket, packet_manager, trusted_peers)).await
+        .map_err(|_| NetworkingError::TimedOut)??;
+
+    let decision = trusted_peers.lock().unwrap().check_peer(socket.remote_public_key(), &peer_hello.display_name, paired);
+    let accept = match decision {
+        PeerDecision::Trusted | PeerDecision::Paired => true,
+        PeerDecision::Ask => ask_user(&peer_hello, socket.remote_public_key()),
+        PeerDecision::Rejected => false
+    };
+
+    let message = if accept { ServerConnectMessage::ALLOWED } else { ServerConnectMessage::DENIED };
+    socket.write_u8(message as u8).await
+        .map_err(|err| NetworkingError::WriteError(err))?;
+    socket.flush().await
+        .map_err(|err| NetworkingError::WriteError(err))?;
+
+    // If the socket isn't handed back, it will be dropped and automatically closed here
+    Ok(accept.then_some((socket, peer_hello, decision)))
+}
+
+/// The part of `accept_peer` that's up to the peer: the handshake, the hellos, and pairing if
+/// the peer wants to. Returns whether the peer paired with us.
+async fn greet_peer(socket: TcpStream, packet_manager: &Mutex<PacketManager>, trusted_peers: &Mutex<TrustedPeers>)
+    -> Result<(SecureStream, HelloPacket, bool), NetworkingError> {
+    // Everything from here on is encrypted
+    let identity = packet_manager.lock().unwrap().identity();
+    let mut socket = SecureStream::accept(socket, &identity).await
+        .map_err(|err| NetworkingError::EncryptionError(err))?;
+
+    // If the peer can't talk to us, there's no point in asking the user about it
+    let local_hello = packet_manager.lock().unwrap().hello();
+    let peer_hello = exchange_hello(&mut socket, &local_hello).await?;
+
+    // A peer pairing with us has to prove it knows the code we're showing, and we prove the same
+    // back. If we aren't showing a code, our answer has no share and the peer gives up.
+    let pairing = read_pairing(&mut socket).await?;
+    let paired = match pairing.share {
+        Some(share) => {
+            let answer = trusted_peers.lock().unwrap().answer_pairing(&share, socket.handshake_hash());
+            let reply = match &answer {
+                Some((share, confirmation)) => PairingPacket::new(Some(share.clone()), Some(confirmation.ours.clone())),
+                None => PairingPacket::default()
+            };
+            write_pairing(&mut socket, &reply).await?;
+            match answer {
+                Some((_, confirmation)) => {
+                    let peer_confirmation = read_pairing(&mut socket).await?.confirmation;
+                    trusted_peers.lock().unwrap().finish_pairing(&confirmation, peer_confirmation.as_deref())
+                },
+                None => false
+            }
+        },
+        None => false
+    };
+
+    Ok((socket, peer_hello, paired))
+}
+
+async fn read_pairing(socket: &mut SecureStream) -> Result<PairingPacket, NetworkingError> {
+    let frame = read_frame(socket).await
+        .map_err(|err| NetworkingError::HandshakeError(err))?;
+    if frame.header != PairingPacket::HEADER {
+        return Err(NetworkingError::UnexpectedMessage(format!("Expected a pairing packet, but got packet {}", frame.header)));
     }
+    PairingPacket::deserialize(frame.payload)
+        .map_err(|err| NetworkingError::HandshakeError(err))
+}
 
-    // If the socket hasn't been passed to the packet server, it will be
-    // dropped and automatically closed here
+async fn write_pairing(socket: &mut SecureStream, pairing: &PairingPacket) -> Result<(), NetworkingError> {
+    let frame = Frame::from_packet(pairing).map_err(NetworkingError::HandshakeError)?;
+    write_frame(socket, &frame).await
+        .map_err(|err| NetworkingError::HandshakeError(err))?;
+    socket.flush().await
+        .map_err(|err| NetworkingError::WriteError(err))
+}
+
+async fn handle_incoming_connection(socket: TcpStream, addr: SocketAddr, ui: &dyn UiBridge, packet_manager: &Mutex<PacketManager>,
+                                    trusted_peers: &Mutex<TrustedPeers>) -> Result<(), NetworkingError> {
+    let accepted = accept_peer(socket, packet_manager, trusted_peers, |peer_hello, public_key| {
+        // The start of the peer's key lets the user check it's who they think it is
+        ui.ask("Accept connection",
+               &format!("Accept incoming connection from {} ({addr})?\nKey: {}", peer_hello.display_name, &public_key[..16]))
+    }).await?;
+
+    if let Some((socket, peer_hello, decision)) = accepted {
+        if decision == PeerDecision::Paired {
+            ui.emit("trusted-peers-updated", ());
+        }
+
+        // Pass connection to app.state.packet_server
+        packet_manager.lock().unwrap().connect(socket, peer_hello);
+    }
     Ok(())
 }
 
-pub fn start_listening_server(app_window: Window<Wry>, packet_manager: Arc<Mutex<PacketManager>>) {
-    tokio::spawn(async move {
-        // Start listening on port
-        let local_ip = local_ip_address::local_ip();
-        if local_ip.is_err() {
-            println!("Unable to start server... are you connected to the internet?");
-            return
+/// Starts accepting connections from peers, storing the address we end up listening on in
+/// `listen_addr` so it can be announced to the LAN. Returns the task running the server, which
+/// can be aborted to stop it, or None if the listener is turned off.
+pub async fn start_listening_server(settings: &ListenerSettings, ui: Arc<dyn UiBridge>, packet_manager: Arc<Mutex<PacketManager>>,
+                                    trusted_peers: Arc<Mutex<TrustedPeers>>, listen_addr: Arc<Mutex<Option<SocketAddr>>>)
+    -> Result<Option<JoinHandle<()>>, NetworkingError> {
+    let listener = match settings.bind().await? {
+        Some(listener) => listener,
+        None => {
+            *listen_addr.lock().unwrap() = None;
+            return Ok(None);
         }
-        let server_ip = format!("{}:0", local_ip.unwrap());
-        let listener = TcpListener::bind(server_ip).await.unwrap();
-        println!("Server started at {:?}", listener.local_addr());
-        // TODO: Send this address to front end (maybe by storing in struct -> front-end queries?)
+    };
+    println!("Server started at {:?}", listener.local_addr());
+    *listen_addr.lock().unwrap() = listener.local_addr().ok();
 
+    Ok(Some(tokio::spawn(async move {
         // Start listening loop
         loop {
-            let connection_result = handle_incoming_connection(&listener, &app_window, &packet_manager).await;
+            let (socket, addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    println!("An error occurred in the listening server: {err:?}");
+                    continue;
+                }
+            };
 
-            // Handle any errors that occur in the listening loop so they don't stop
-            // the server
-            if let Err(err) = connection_result {
-                println!("An error occurred in the listening server: {err:?}");
-            }
+            // Each peer is let in on its own, so one that's slow to connect, or that the user
+            // hasn't decided on yet, doesn't hold up the rest
+            let (ui, packet_manager, trusted_peers) = (ui.clone(), packet_manager.clone(), trusted_peers.clone());
+            tokio::spawn(async move {
+                if let Err(err) = handle_incoming_connection(socket, addr, ui.as_ref(), &packet_manager, &trusted_peers).await {
+                    println!("Unable to let in the peer at {addr}: {err:?}");
+                }
+            });
         }
-    });
+    })))
 }
 
-pub async fn connect_to_server(addr: String, packet_manager: &Mutex<PacketManager>) -> Result<bool, NetworkingError> {
-    let mut connection = TcpStream::connect(&addr).await
+/// Connects to the peer at `addr`, returning the id it can be addressed with if it let us in.
+/// If we're given the pairing code the peer is showing, we pair with it, so from then on either
+/// side can connect to the other without being asked.
+pub async fn connect_to_server(addr: String, pairing_code: Option<String>, packet_manager: &Mutex<PacketManager>,
+                               trusted_peers: &Mutex<TrustedPeers>) -> Result<Option<PeerId>, NetworkingError> {
+    let connection = TcpStream::connect(&addr).await
         .map_err(|_| NetworkingError::ConnectionError(addr))?;
 
+    // Everything from here on is encrypted
+    let identity = packet_manager.lock().unwrap().identity();
+    let mut connection = SecureStream::connect(connection, &identity).await
+        .map_err(|err| NetworkingError::EncryptionError(err))?;
+
+    let local_hello = packet_manager.lock().unwrap().hello();
+    let peer_hello = exchange_hello(&mut connection, &local_hello).await?;
+
+    let pairing = pairing_code.as_deref().map(|code| Pairing::new(code, PairingRole::Client));
+    write_pairing(&mut connection, &PairingPacket::new(pairing.as_ref().map(Pairing::share), None)).await?;
+    let paired = pairing.is_some();
+    if let Some(pairing) = pairing {
+        // The peer has to prove it knows the code before we prove we do, and before we trust it
+        let answer = read_pairing(&mut connection).await?;
+        let confirmation = answer.share.and_then(|share| pairing.finish(&share, connection.handshake_hash()));
+        match confirmation {
+            Some(confirmation) if answer.confirmation.as_deref() == Some(confirmation.theirs.as_str()) => {
+                write_pairing(&mut connection, &PairingPacket::new(None, Some(confirmation.ours))).await?;
+            },
+            _ => return Err(NetworkingError::PairingFailed)
+        }
+    }
+
     // Check if this connection is allowed
     let allowed = connection.read_u8().await
         .map_err(|err| NetworkingError::ReadError(err))?;
     if allowed == ServerConnectMessage::ALLOWED as u8 {
+        if paired {
+            trusted_peers.lock().unwrap().trust(connection.remote_public_key(), &peer_hello.display_name);
+        }
+
         // Pass connection to packet server
-        packet_manager.lock().unwrap().connect(connection);
-        Ok(true)
+        let peer = packet_manager.lock().unwrap().connect(connection, peer_hello);
+        Ok(Some(peer))
     } else if allowed == ServerConnectMessage::DENIED as u8 {
-        Ok(false)
+        Ok(None)
     } else {
         Err(NetworkingError::UnexpectedMessage(allowed.to_string()))
     }
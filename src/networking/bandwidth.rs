@@ -0,0 +1,201 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const MINUTES_PER_DAY: i64 = 24 * 60;
+
+/// Which way map data is going
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Upload,
+    Download
+}
+
+/// A daily window during which transfers run at full speed, like overnight when nobody else is
+/// using the connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Schedule {
+    /// Minutes after local midnight the window starts at
+    pub start_minute: u16,
+    /// Minutes after local midnight the window ends at. If this is before the start, the window
+    /// wraps around midnight.
+    pub end_minute: u16,
+    /// How far local time is ahead of UTC in minutes, as the front-end saw it when the schedule
+    /// was set, since we have no way of knowing the time zone ourselves
+    pub utc_offset_minutes: i32
+}
+
+impl Schedule {
+    /// Whether the given time, in seconds since the Unix epoch, falls inside the window
+    pub fn contains(&self, unix_secs: u64) -> bool {
+        let minute = (unix_secs as i64 / 60 + self.utc_offset_minutes as i64).rem_euclid(MINUTES_PER_DAY);
+        let (start, end) = (self.start_minute as i64, self.end_minute as i64);
+        if start <= end {
+            start <= minute && minute < end
+        } else {
+            minute >= start || minute < end
+        }
+    }
+}
+
+/// How fast map data can be sent and received. Control packets like map lists are never limited,
+/// so the app stays responsive while a transfer is being held back. Saved between runs.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct BandwidthSettings {
+    /// Bytes per second we send to all peers combined, or None for no limit
+    pub upload_limit: Option<u64>,
+    /// Bytes per second we receive from all peers combined, or None for no limit
+    pub download_limit: Option<u64>,
+    /// Bytes per second we send to any one peer, or None for no limit
+    pub peer_upload_limit: Option<u64>,
+    /// Bytes per second we receive from any one peer, or None for no limit
+    pub peer_download_limit: Option<u64>,
+    /// If set, the limits are lifted while inside this window
+    pub full_speed: Option<Schedule>,
+    /// Where the settings get saved to. If unset, the settings only live in memory.
+    #[serde(skip)]
+    path: Option<PathBuf>
+}
+
+impl BandwidthSettings {
+    /// Loads the settings saved at the given path, falling back to no limits if they are
+    /// missing or unreadable
+    pub fn load(path: PathBuf) -> Self {
+        let settings = fs::read(&path).ok()
+            .and_then(|data| serde_json::from_slice::<BandwidthSettings>(&data).ok());
+
+        match settings {
+            Some(settings) => Self { path: Some(path), ..settings },
+            None => Self { path: Some(path), ..Self::default() }
+        }
+    }
+
+    /// Writes the settings to disk, if they were loaded from a path
+    pub fn save(&self) -> io::Result<()> {
+        if let Some(path) = &self.path {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(path, serde_json::to_vec(self)?)?;
+        }
+        Ok(())
+    }
+
+    /// Replaces the settings with the given ones, and saves them
+    pub fn update(&mut self, settings: BandwidthSettings) -> io::Result<()> {
+        *self = Self { path: self.path.take(), ..settings };
+        self.save()
+    }
+
+    /// The overall and per peer limits in the given direction at the given time, in seconds
+    /// since the Unix epoch
+    pub fn limits_at(&self, direction: Direction, unix_secs: u64) -> (Option<u64>, Option<u64>) {
+        if self.full_speed.is_some_and(|schedule| schedule.contains(unix_secs)) {
+            return (None, None);
+        }
+        match direction {
+            Direction::Upload => (self.upload_limit, self.peer_upload_limit),
+            Direction::Download => (self.download_limit, self.peer_download_limit)
+        }
+    }
+}
+
+/// Spaces out chunks of data so they average out to a given rate. Each chunk is let through
+/// straight away if the chunks before it are done, so short bursts aren't held back.
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// When the data let through so far will have been sent at the current rate
+    next_free: Instant
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self { next_free: Instant::now() }
+    }
+
+    /// Books `bytes` of data at `rate` bytes per second, returning how long to wait before
+    /// sending it. Without a rate, nothing is ever held back.
+    pub fn reserve(&mut self, bytes: usize, rate: Option<u64>, now: Instant) -> Duration {
+        let rate = match rate {
+            Some(rate) if rate > 0 => rate,
+            _ => {
+                self.next_free = now;
+                return Duration::ZERO;
+            }
+        };
+
+        let wait = self.next_free.saturating_duration_since(now);
+        self.next_free = self.next_free.max(now) + Duration::from_secs_f64(bytes as f64 / rate as f64);
+        wait
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The limits shared by every peer, along with what's been sent and received across all of them
+#[derive(Debug, Default)]
+pub struct Bandwidth {
+    settings: Mutex<BandwidthSettings>,
+    upload: Mutex<RateLimiter>,
+    download: Mutex<RateLimiter>
+}
+
+impl Bandwidth {
+    pub fn settings(&self) -> BandwidthSettings {
+        self.settings.lock().unwrap().clone()
+    }
+
+    /// Uses the given settings from now on, without saving them
+    pub fn set_settings(&self, settings: BandwidthSettings) {
+        *self.settings.lock().unwrap() = settings;
+    }
+
+    /// Replaces the settings with the given ones, and saves them
+    pub fn update_settings(&self, settings: BandwidthSettings) -> io::Result<()> {
+        self.settings.lock().unwrap().update(settings)
+    }
+
+    fn limiter(&self, direction: Direction) -> &Mutex<RateLimiter> {
+        match direction {
+            Direction::Upload => &self.upload,
+            Direction::Download => &self.download
+        }
+    }
+}
+
+/// Holds map data for one peer back to both the overall limits and the limits for that peer
+#[derive(Debug)]
+pub struct PeerBandwidth {
+    shared: Arc<Bandwidth>,
+    upload: Mutex<RateLimiter>,
+    download: Mutex<RateLimiter>
+}
+
+impl PeerBandwidth {
+    pub fn new(shared: Arc<Bandwidth>) -> Self {
+        Self { shared, upload: Mutex::new(RateLimiter::new()), download: Mutex::new(RateLimiter::new()) }
+    }
+
+    /// Waits until `bytes` of map data can go through in the given direction
+    pub async fn throttle(&self, direction: Direction, bytes: usize) {
+        let unix_secs = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_secs());
+        let (limit, peer_limit) = self.shared.settings.lock().unwrap().limits_at(direction, unix_secs);
+
+        let now = Instant::now();
+        let peer_limiter = match direction {
+            Direction::Upload => &self.upload,
+            Direction::Download => &self.download
+        };
+        let wait = self.shared.limiter(direction).lock().unwrap().reserve(bytes, limit, now)
+            .max(peer_limiter.lock().unwrap().reserve(bytes, peer_limit, now));
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
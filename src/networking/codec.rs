@@ -0,0 +1,88 @@
+use thiserror::Error;
+use tokio::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use crate::networking::packets::Packet;
+
+/// The version of the frame layout written by this build. Frames with any other version are
+/// rejected rather than guessed at.
+pub const FRAME_VERSION: u8 = 1;
+/// Largest payload we are willing to read in a single frame. Anything bigger is treated as a
+/// malformed frame so a bad length can't make us allocate an unbounded buffer.
+pub const MAX_PAYLOAD_SIZE: u32 = 64 * 1024 * 1024;
+
+#[derive(Error, Debug)]
+pub enum CodecError {
+    #[error("The connection was closed")]
+    Disconnected,
+    #[error("Received a frame with unsupported version {0}")]
+    UnsupportedVersion(u8),
+    #[error("Received a frame with a payload of {0} bytes, which is over the limit")]
+    PayloadTooLarge(u32),
+    #[error("Unable to parse the packet payload: {0}")]
+    InvalidPayload(String),
+    #[error("Unable to encode the packet payload: {0}")]
+    UnencodablePayload(String),
+    #[error("The peer stopped sending the download, as we asked it to")]
+    DownloadStopped,
+    #[error("An IO error occurred while reading or writing a frame: {0}")]
+    IOError(#[from] io::Error)
+}
+
+/// A single packet as it travels over the socket:
+///
+/// | header (u8) | version (u8) | payload length (u32, big endian) | payload |
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub header: u8,
+    pub version: u8,
+    pub payload: Vec<u8>
+}
+
+impl Frame {
+    pub fn new(header: u8, payload: Vec<u8>) -> Self {
+        Self { header, version: FRAME_VERSION, payload }
+    }
+
+    pub fn from_packet(packet: &dyn Packet) -> Result<Self, CodecError> {
+        Ok(Self::new(packet.get_header(), packet.get_data()?))
+    }
+}
+
+/// Reads the next frame from the stream. A stream that closes cleanly between frames returns
+/// `CodecError::Disconnected`, while one that closes partway through a frame is an IO error.
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Frame, CodecError> {
+    let header = match reader.read_u8().await {
+        Ok(header) => header,
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Err(CodecError::Disconnected),
+        Err(err) => return Err(err.into())
+    };
+
+    let version = reader.read_u8().await?;
+    if version != FRAME_VERSION {
+        return Err(CodecError::UnsupportedVersion(version));
+    }
+
+    let payload_len = reader.read_u32().await?;
+    if payload_len > MAX_PAYLOAD_SIZE {
+        return Err(CodecError::PayloadTooLarge(payload_len));
+    }
+
+    let mut payload = vec![0; payload_len as usize];
+    reader.read_exact(&mut payload).await?;
+
+    Ok(Frame { header, version, payload })
+}
+
+/// Writes a frame to the stream. Doesn't flush, so callers writing through a buffer should do so
+/// once they are done.
+pub async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, frame: &Frame) -> Result<(), CodecError> {
+    if frame.payload.len() > MAX_PAYLOAD_SIZE as usize {
+        return Err(CodecError::PayloadTooLarge(frame.payload.len() as u32));
+    }
+
+    writer.write_u8(frame.header).await?;
+    writer.write_u8(frame.version).await?;
+    writer.write_u32(frame.payload.len() as u32).await?;
+    writer.write_all(&frame.payload).await?;
+    Ok(())
+}
@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use crate::file_manager::SongFolder;
+use crate::networking::packets::{PacketManager, PROTOCOL_VERSION};
+use crate::ui::UiBridge;
+
+/// UDP port every instance listens for announcements on
+pub const DISCOVERY_PORT: u16 = 47321;
+/// How often we announce ourselves
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5);
+/// How long a peer can go without announcing itself before we assume it's gone
+const PEER_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Broadcast to the LAN every `ANNOUNCE_INTERVAL` so other instances know where to find us
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Announcement {
+    pub protocol_version: u16,
+    /// Our public key. This is only a hint, as anyone can claim any key here; the key is checked
+    /// for real during the handshake when connecting.
+    pub public_key: String,
+    pub display_name: String,
+    /// Port our listening server is on
+    pub port: u16,
+    pub song_count: u32
+}
+
+/// Another instance we've heard announce itself on the LAN
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct DiscoveredPeer {
+    pub public_key: String,
+    pub display_name: String,
+    /// Address to pass to `connect_to_server`
+    pub address: String,
+    pub song_count: u32,
+    /// Whether the peer speaks the same protocol as us, so connecting to it can work
+    pub compatible: bool
+}
+
+/// The peers we've heard from recently, keyed by their public key
+#[derive(Debug, Default)]
+pub struct DiscoveredPeers {
+    peers: HashMap<String, (DiscoveredPeer, Instant)>
+}
+
+impl DiscoveredPeers {
+    /// Every peer we've heard from recently, sorted by name
+    pub fn peers(&self) -> Vec<DiscoveredPeer> {
+        let mut peers: Vec<DiscoveredPeer> = self.peers.values().map(|(peer, _)| peer.clone()).collect();
+        peers.sort_by(|a, b| a.display_name.cmp(&b.display_name).then_with(|| a.address.cmp(&b.address)));
+        peers
+    }
+
+    /// Records an announcement heard from `source`, returning the peer if it's new or something
+    /// about it changed
+    pub fn record(&mut self, announcement: Announcement, source: IpAddr, now: Instant) -> Option<DiscoveredPeer> {
+        let peer = DiscoveredPeer {
+            address: SocketAddr::new(source, announcement.port).to_string(),
+            compatible: announcement.protocol_version == PROTOCOL_VERSION,
+            public_key: announcement.public_key,
+            display_name: announcement.display_name,
+            song_count: announcement.song_count
+        };
+
+        let changed = self.peers.get(&peer.public_key)
+            .map_or(true, |(known_peer, _)| *known_peer != peer);
+        self.peers.insert(peer.public_key.clone(), (peer.clone(), now));
+        changed.then_some(peer)
+    }
+
+    /// Forgets the peers we haven't heard from in a while, returning their public keys
+    pub fn prune(&mut self, now: Instant) -> Vec<String> {
+        let mut lost: Vec<String> = self.peers.iter()
+            .filter(|(_, (_, last_seen))| now.duration_since(*last_seen) > PEER_TIMEOUT)
+            .map(|(public_key, _)| public_key.clone())
+            .collect();
+        lost.sort();
+        for public_key in &lost {
+            self.peers.remove(public_key);
+        }
+        lost
+    }
+}
+
+/// Starts announcing ourselves on the LAN, and listening for everyone else's announcements.
+/// We only announce once the listening server has an address, since there's nothing to connect
+/// to before then.
+pub fn start_discovery(ui: Arc<dyn UiBridge>, packet_manager: Arc<Mutex<PacketManager>>, local_songs: Arc<Mutex<Vec<SongFolder>>>,
+                       listen_addr: Arc<Mutex<Option<SocketAddr>>>, discovered_peers: Arc<Mutex<DiscoveredPeers>>) {
+    let bind_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), DISCOVERY_PORT);
+    let announce_to = SocketAddr::new(IpAddr::V4(Ipv4Addr::BROADCAST), DISCOVERY_PORT);
+    tokio::spawn(async move {
+        let socket = match UdpSocket::bind(bind_addr).await {
+            Ok(socket) => socket,
+            Err(err) => {
+                println!("Unable to start discovery, is another instance already running? {err:?}");
+                return;
+            }
+        };
+        if let Err(err) = socket.set_broadcast(true) {
+            println!("Unable to broadcast announcements: {err:?}");
+        }
+
+        let own_public_key = packet_manager.lock().unwrap().identity().public_key();
+        let announcement = move || {
+            let port = (*listen_addr.lock().unwrap())?.port();
+            let packet_manager = packet_manager.lock().unwrap();
+            Some(Announcement {
+                protocol_version: PROTOCOL_VERSION,
+                public_key: packet_manager.identity().public_key(),
+                display_name: packet_manager.hello().display_name,
+                port,
+                song_count: local_songs.lock().unwrap().len() as u32
+            })
+        };
+        run_discovery(socket, announce_to, own_public_key, announcement, &discovered_peers, ui.as_ref()).await;
+    });
+}
+
+/// Announces ourselves to `announce_to` every `ANNOUNCE_INTERVAL`, and records the announcements
+/// that arrive on `socket`. Lets the front-end know whenever a peer shows up or goes away.
+pub async fn run_discovery<F>(socket: UdpSocket, announce_to: SocketAddr, own_public_key: String, announcement: F,
+                              discovered_peers: &Mutex<DiscoveredPeers>, ui: &dyn UiBridge)
+    where F: Fn() -> Option<Announcement> {
+    let mut interval = tokio::time::interval(ANNOUNCE_INTERVAL);
+    let mut buf = vec![0; 2048];
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                if let Some(announcement) = announcement() {
+                    if let Err(err) = socket.send_to(&serde_json::to_vec(&announcement).unwrap(), announce_to).await {
+                        println!("Unable to announce ourselves: {err:?}");
+                    }
+                }
+
+                let lost = discovered_peers.lock().unwrap().prune(Instant::now());
+                for public_key in lost {
+                    ui.emit("peer-lost", public_key);
+                }
+            },
+            received = socket.recv_from(&mut buf) => {
+                let (len, source) = match received {
+                    Ok(received) => received,
+                    Err(err) => {
+                        println!("Unable to receive an announcement: {err:?}");
+                        continue;
+                    }
+                };
+
+                // Anything else on the port isn't meant for us, and we hear our own broadcasts too
+                let announcement = match serde_json::from_slice::<Announcement>(&buf[..len]) {
+                    Ok(announcement) if announcement.public_key != own_public_key => announcement,
+                    _ => continue
+                };
+                let discovered = discovered_peers.lock().unwrap().record(announcement, source.ip(), Instant::now());
+                if let Some(peer) = discovered {
+                    ui.emit("peer-discovered", peer);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,274 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+use crate::file_manager::{checksum_reader, install_folder, install_osz, MapFormat, MapManifest, MapRequest, SongFolderError, verify_folder, verify_osz};
+use crate::networking::packets::{DownloadMode, ResumeFrom};
+use crate::ui::UiBridge;
+
+/// Where maps sent as folders, and .osz files to install, are written to while they're being
+/// received, inside the folder they end up in
+const STAGING_FOLDER: &str = ".osu-mapsync";
+
+/// Where the maps of a download end up as they arrive
+#[derive(Debug, Clone)]
+pub enum DownloadDestination {
+    /// Save each .osz into the given folder
+    Save(PathBuf),
+    /// Unpack each map into the given songs folder
+    Install(PathBuf)
+}
+
+/// The .osz or folder of a map that is partway through being received
+#[derive(Debug, Clone)]
+pub struct PartialMap {
+    pub name: String,
+    pub received: u64,
+    pub format: MapFormat
+}
+
+/// A download that is being received, or that got cut off and can be resumed.
+/// Each .osz is written to a part file as it arrives, and only moved to its destination once
+/// all of it is here.
+#[derive(Debug, Clone)]
+pub struct PartialDownload {
+    /// Maps we're still waiting on, in the order the peer sends them
+    pub requested_maps: Vec<MapRequest>,
+    pub destination: DownloadDestination,
+    pub current_map: Option<PartialMap>
+}
+
+impl PartialDownload {
+    pub fn new(requested_maps: Vec<MapRequest>, destination: DownloadDestination) -> Self {
+        Self { requested_maps, destination, current_map: None }
+    }
+
+    /// Where the given .osz is written to while it's being received
+    pub fn part_path(&self, osz_name: &str) -> PathBuf {
+        match &self.destination {
+            // Keep the part file next to the .osz, so it only has to be renamed when it's done
+            DownloadDestination::Save(folder) => folder.join(format!("{osz_name}.part")),
+            // Keep the part file out of the way in the songs folder, so a download into another
+            // songs folder can't get mixed up with it
+            DownloadDestination::Install(songs_dir) => songs_dir.join(STAGING_FOLDER).join(format!("{osz_name}.part"))
+        }
+    }
+
+    /// Deletes the part file of the given .osz, along with the staging folder it was kept in if
+    /// nothing else is being staged in it
+    fn remove_part(&self, osz_name: &str) {
+        let part_path = self.part_path(osz_name);
+        let _ = fs::remove_file(&part_path);
+        if let DownloadDestination::Install(_) = &self.destination {
+            let _ = fs::remove_dir(part_path.parent().unwrap());
+        }
+    }
+
+    /// Where the given folder is written to while it's being received. It's kept in the folder
+    /// the map ends up in, so it only has to be renamed when it's done.
+    pub fn staging_path(&self, folder_name: &str) -> PathBuf {
+        self.destination.folder().join(STAGING_FOLDER).join(folder_name)
+    }
+
+    /// Works out where to pick the download back up. The maps that fully arrived have already
+    /// been taken off `requested_maps`, so this is only the part of the map that was cut off,
+    /// if any of it arrived. Maps sent as folders start over. Will block as it reads from the
+    /// file system.
+    pub fn resume_from(&self) -> io::Result<Option<ResumeFrom>> {
+        match &self.current_map {
+            Some(map) if map.received > 0 && map.format == MapFormat::Osz => Ok(Some(ResumeFrom {
+                offset: map.received,
+                checksum: checksum_reader(File::open(self.part_path(&map.name))?.take(map.received))?
+            })),
+            _ => Ok(None)
+        }
+    }
+
+    /// Moves a fully received .osz to its destination, checks it against the manifest it was
+    /// sent with, and takes it off the list of maps we're waiting on. A map that turns out to be
+    /// damaged is deleted again, unless it was added to a folder we already had. Will block as
+    /// it writes to the file system.
+    pub fn complete_map(&mut self, osz_name: &str, existing_folder: Option<&Path>, manifest: &MapManifest) -> Result<PathBuf, SongFolderError> {
+        let part_path = self.part_path(osz_name);
+        let (path, verified) = match &self.destination {
+            DownloadDestination::Save(folder) => {
+                let path = folder.join(osz_name);
+                fs::rename(&part_path, &path)?;
+                let verified = verify_osz(BufReader::new(File::open(&path)?), manifest);
+                if verified.is_err() {
+                    let _ = fs::remove_file(&path);
+                }
+                (path, verified)
+            },
+            DownloadDestination::Install(songs_dir) => {
+                let installed = File::open(&part_path).map_err(SongFolderError::from)
+                    .and_then(|file| install_osz(BufReader::new(file), osz_name, songs_dir, existing_folder));
+                self.remove_part(osz_name);
+                let installed = installed?;
+                let verified = verify_folder(&installed, manifest);
+                if verified.is_err() && existing_folder.is_none() {
+                    let _ = fs::remove_dir_all(&installed);
+                }
+                (installed, verified)
+            }
+        };
+
+        self.next_map();
+        verified.map(|()| path)
+    }
+
+    /// Checks a fully received folder against the manifest it was sent with, and moves it to its
+    /// destination if it's all there. Either way, the map is taken off the list of maps we're
+    /// waiting on. Will block as it writes to the file system.
+    pub fn complete_folder(&mut self, folder_name: &str, existing_folder: Option<&Path>, manifest: &MapManifest) -> Result<PathBuf, SongFolderError> {
+        let staging_path = self.staging_path(folder_name);
+        let installed = verify_folder(&staging_path, manifest).and_then(|()| match &self.destination {
+            DownloadDestination::Save(folder) => install_folder(&staging_path, folder_name, folder, None),
+            DownloadDestination::Install(songs_dir) => install_folder(&staging_path, folder_name, songs_dir, existing_folder)
+        });
+        if installed.is_err() {
+            let _ = fs::remove_dir_all(&staging_path);
+        }
+        // Only goes if nothing else is being staged in it
+        let _ = fs::remove_dir(staging_path.parent().unwrap());
+
+        self.next_map();
+        installed
+    }
+
+    /// Takes a map the peer wasn't able to send off the list of maps we're waiting on, along with
+    /// any part of it we got before
+    pub fn skip_map(&mut self) {
+        self.discard();
+        self.next_map();
+    }
+
+    /// Deletes the part of the current map that has been downloaded so far
+    pub fn discard(&self) {
+        match &self.current_map {
+            Some(map) if map.format == MapFormat::Folder => {
+                let staging_path = self.staging_path(&map.name);
+                let _ = fs::remove_dir_all(&staging_path);
+                let _ = fs::remove_dir(staging_path.parent().unwrap());
+            },
+            Some(map) => self.remove_part(&map.name),
+            None => {}
+        }
+    }
+
+    /// Moves on from the current map, which is the first one we're waiting on
+    fn next_map(&mut self) {
+        if !self.requested_maps.is_empty() {
+            self.requested_maps.remove(0);
+        }
+        self.current_map = None;
+    }
+}
+
+impl DownloadDestination {
+    /// Asks the user to agree to a download, and where its maps should go. In install mode, the
+    /// maps go into `songs_dir`. Otherwise, or if we don't have a songs folder, the user picks a
+    /// folder to save them to. Returns None if the user backed out.
+    pub fn choose(ui: &dyn UiBridge, message: &str, mode: DownloadMode, songs_dir: Option<PathBuf>) -> Option<Self> {
+        if !ui.ask("Download Maps", message) {
+            return None;
+        }
+        let install_dir = match mode {
+            DownloadMode::Install => songs_dir,
+            DownloadMode::Save => None
+        };
+        install_dir.map(DownloadDestination::Install)
+            .or_else(|| ui.pick_folder("Choose where to save the maps").map(DownloadDestination::Save))
+    }
+
+    /// The folder the maps end up in
+    pub fn folder(&self) -> &Path {
+        match self {
+            DownloadDestination::Save(folder) => folder,
+            DownloadDestination::Install(songs_dir) => songs_dir
+        }
+    }
+
+    /// The format to ask for maps in. Maps to install are sent as folders, so they can be
+    /// written straight into the songs folder, while maps to save are sent as .osz files.
+    pub fn map_format(&self) -> MapFormat {
+        match self {
+            DownloadDestination::Save(_) => MapFormat::Osz,
+            DownloadDestination::Install(_) => MapFormat::Folder
+        }
+    }
+}
+
+/// Writes the files of a map sent as a folder into its staging folder as their contents arrive,
+/// one after the other in the order they're listed in
+#[derive(Debug)]
+pub struct FolderWriter {
+    staging_path: PathBuf,
+    /// Files still to come, with their sizes
+    files: VecDeque<(PathBuf, u64)>,
+    /// The file being written, and how much more of it is coming
+    current: Option<(tokio::fs::File, u64)>
+}
+
+impl FolderWriter {
+    /// Starts receiving a folder into the given staging folder, replacing anything left in it
+    /// from before. The paths have to be relative to the folder.
+    pub async fn create(staging_path: PathBuf, files: impl IntoIterator<Item = (PathBuf, u64)>) -> io::Result<Self> {
+        match tokio::fs::remove_dir_all(&staging_path).await {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+        tokio::fs::create_dir_all(&staging_path).await?;
+        Ok(Self { staging_path, files: files.into_iter().collect(), current: None })
+    }
+
+    /// Writes the next part of the folder, moving on to the next file whenever one is done
+    pub async fn write(&mut self, mut data: &[u8]) -> io::Result<()> {
+        while !data.is_empty() {
+            let (file, remaining) = match &mut self.current {
+                Some((file, remaining)) if *remaining > 0 => (file, remaining),
+                _ => {
+                    self.next_file().await?;
+                    continue;
+                }
+            };
+            let length = data.len().min(*remaining as usize);
+            file.write_all(&data[..length]).await?;
+            *remaining -= length as u64;
+            data = &data[length..];
+        }
+        Ok(())
+    }
+
+    /// Writes out what's left of the current file, and creates any empty files that came last
+    pub async fn finish(&mut self) -> io::Result<()> {
+        self.flush().await?;
+        while !self.files.is_empty() {
+            self.next_file().await?;
+        }
+        self.flush().await
+    }
+
+    /// Makes sure everything written so far is on disk
+    pub async fn flush(&mut self) -> io::Result<()> {
+        match &mut self.current {
+            Some((file, _)) => file.flush().await,
+            None => Ok(())
+        }
+    }
+
+    async fn next_file(&mut self) -> io::Result<()> {
+        self.flush().await?;
+        let (path, size) = self.files.pop_front()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Received more data than the folder holds"))?;
+        let path = self.staging_path.join(path);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        self.current = Some((tokio::fs::File::create(&path).await?, size));
+        Ok(())
+    }
+}
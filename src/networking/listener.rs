@@ -0,0 +1,91 @@
+use std::fs;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use tokio::net::TcpListener;
+use crate::networking::NetworkingError;
+
+/// The address a peer should use to reach a server listening on `listen_addr`. A server
+/// listening on every interface can't be reached at the unspecified address it's bound to, so our
+/// address on the LAN is used instead.
+pub fn shareable_addr(listen_addr: SocketAddr) -> SocketAddr {
+    match local_ip_address::local_ip() {
+        Ok(ip) if listen_addr.ip().is_unspecified() => SocketAddr::new(ip, listen_addr.port()),
+        _ => listen_addr
+    }
+}
+
+/// How the server that peers connect to is set up. Saved between runs, so a fixed port can be
+/// forwarded or let through a firewall once.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ListenerSettings {
+    /// Whether peers can connect to us at all. We can still connect to them when this is off.
+    pub enabled: bool,
+    /// The address to listen on, which can also be an unspecified address like `0.0.0.0` or `::`
+    /// to listen on every interface. If unset, we listen on our address on the LAN.
+    pub bind_address: Option<IpAddr>,
+    /// The port to listen on, or 0 to have the OS pick a free one every time
+    pub port: u16,
+    /// Where the settings get saved to. If unset, the settings only live in memory.
+    #[serde(skip)]
+    path: Option<PathBuf>
+}
+
+impl Default for ListenerSettings {
+    fn default() -> Self {
+        Self { enabled: true, bind_address: None, port: 0, path: None }
+    }
+}
+
+impl ListenerSettings {
+    /// Loads the settings saved at the given path, falling back to the defaults if they are
+    /// missing or unreadable
+    pub fn load(path: PathBuf) -> Self {
+        let settings = fs::read(&path).ok()
+            .and_then(|data| serde_json::from_slice::<ListenerSettings>(&data).ok());
+
+        match settings {
+            Some(settings) => Self { path: Some(path), ..settings },
+            None => Self { path: Some(path), ..Self::default() }
+        }
+    }
+
+    /// Writes the settings to disk, if they were loaded from a path
+    pub fn save(&self) -> io::Result<()> {
+        if let Some(path) = &self.path {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(path, serde_json::to_vec(self)?)?;
+        }
+        Ok(())
+    }
+
+    /// Replaces the settings with the given ones, and saves them
+    pub fn update(&mut self, settings: ListenerSettings) -> io::Result<()> {
+        *self = Self { path: self.path.take(), ..settings };
+        self.save()
+    }
+
+    /// Works out the address to listen on
+    pub fn socket_addr(&self) -> Result<SocketAddr, NetworkingError> {
+        let ip = match self.bind_address {
+            Some(ip) => ip,
+            None => local_ip_address::local_ip()
+                .map_err(|err| NetworkingError::NoLocalAddress(err.to_string()))?
+        };
+        Ok(SocketAddr::new(ip, self.port))
+    }
+
+    /// Starts listening with these settings, or returns None if the listener is turned off
+    pub async fn bind(&self) -> Result<Option<TcpListener>, NetworkingError> {
+        if !self.enabled {
+            return Ok(None);
+        }
+
+        let addr = self.socket_addr()?;
+        let listener = TcpListener::bind(addr).await
+            .map_err(|err| NetworkingError::BindError(addr, err))?;
+        Ok(Some(listener))
+    }
+}
@@ -1,66 +1,99 @@
 use std::any::Any;
-use std::io::Write;
+use std::ffi::OsStr;
+use std::collections::{HashMap, VecDeque};
 use std::fmt::Formatter;
+use std::io;
+use std::path::{Component, Path, PathBuf};
 use std::sync::{Arc, Mutex};
-use tauri::{Window, Wry};
-use tauri::api::dialog::blocking::{FileDialogBuilder};
-use tokio::fs::File;
-use tokio::io;
-use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter, sink};
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
-use tokio::net::TcpStream;
-use tokio::sync::{mpsc};
-use crate::file_manager::{SongFolder, zip_local_files};
-
-// Testing stuff
-use cfg_if::cfg_if;
-cfg_if! {
-    if #[cfg(test)] {
-        use crate::test;
-        use test::MockWindow;
-        use test::ask;
-    } else {
-        use tauri::api::dialog::blocking::ask;
-    }
-}
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
+use tokio::sync::{mpsc, Notify};
+use tokio::task;
+use crate::diff::SyncPlan;
+use crate::file_manager::{checksum_reader, MapFormat, MapManifest, MapRequest, read_local_files, song_folder_manifest, song_to_osz_with_manifest, SongFolder, SongFolderError};
+use crate::file_manager::cache::SongCache;
+use crate::networking::bandwidth::{Bandwidth, Direction, PeerBandwidth};
+use crate::networking::codec::{CodecError, Frame, read_frame, write_frame};
+use crate::networking::download::{DownloadDestination, FolderWriter, PartialDownload, PartialMap};
+use crate::networking::planner::{DownloadOutcome, MapError};
+use crate::networking::secure::{Identity, SecureReadHalf, SecureStream, SecureWriteHalf};
+use crate::ui::UiBridge;
+
+/// How much of an .osz file, or of a file in a folder, is sent in each `DownloadDataPacket`
+const DOWNLOAD_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Version of the packet set spoken by this build. Bump this whenever packets change in a way
+/// that older builds can't understand.
+pub const PROTOCOL_VERSION: u16 = 12;
+/// Optional features this build supports, announced to peers in the `HelloPacket`
+pub const CAPABILITIES: &[&str] = &["map-list", "map-download", "partial-download", "resume-download", "pairing",
+                                     "cancel-download", "verify-maps", "two-way-sync", "transfer-profiles",
+                                     "folder-transfer"];
 
 pub trait Packet: Send + Sync {
     /// A distinct header to identify the packet type
-    fn get_header(&self) -> &'static str;
-    /// A string representation of the important data associated with the packet.
-    /// Should **NOT** include newlines, as they are used to mark the end of the data segment.
-    fn get_data(&self) -> String;
-    /// A way to get a packet struct with easy-to-manipulate data based on the string
-    /// representation received over the socket connection.
-    fn deserialize(raw_data: String) -> Self where Self:Sized;
+    fn get_header(&self) -> u8;
+    /// A binary representation of the important data associated with the packet.
+    /// This becomes the payload of the frame written to the socket. Fails if the data can't be encoded.
+    fn get_data(&self) -> Result<Vec<u8>, CodecError>;
+    /// A way to get a packet struct with easy-to-manipulate data based on the payload
+    /// received over the socket connection. Fails if the payload is malformed.
+    fn deserialize(raw_data: Vec<u8>) -> Result<Self, CodecError> where Self:Sized;
     /// Allows recasting from dyn Packet to a specific packet
     fn as_any(&mut self) -> &mut dyn Any;
 }
 impl std::fmt::Debug for dyn Packet {
+    // Only the header, since getting the size would mean serializing the whole packet again
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Packet {{ header: {}, data: {} }}", self.get_header(), self.get_data())
+        write!(f, "Packet {{ header: {} }}", self.get_header())
+    }
+}
+
+/// Encodes a JSON payload, turning any serde errors into a `CodecError`
+fn to_json<T: serde::Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, CodecError> {
+    serde_json::to_vec(value).map_err(|err| CodecError::UnencodablePayload(err.to_string()))
+}
+
+/// Parses a JSON payload, turning any serde errors into a `CodecError`
+fn from_json<T: serde::de::DeserializeOwned>(raw_data: &[u8]) -> Result<T, CodecError> {
+    serde_json::from_slice(raw_data).map_err(|err| CodecError::InvalidPayload(err.to_string()))
+}
+
+/// Parses the payload of a frame, skipping any packets we can't make sense of and letting the
+/// peer know about them
+async fn parse_packet<P: Packet>(frame: Frame, packet_queue: &mpsc::Sender<Box<dyn Packet>>) -> Option<P> {
+    let header = frame.header;
+    match P::deserialize(frame.payload) {
+        Ok(packet) => Some(packet),
+        Err(err) => {
+            println!("Skipping malformed packet with header {header}: {err:?}");
+            let error = ErrorPacket::new(ErrorCode::MalformedPacket, format!("Unable to parse packet {header}: {err}"));
+            let _ = packet_queue.send(Box::new(error)).await;
+            None
+        }
     }
 }
 
 pub struct MapListRequestPacket;
 impl MapListRequestPacket {
-    const HEADER: &'static str = "MapListRequestPacket";
+    pub const HEADER: u8 = 1;
 
     pub fn new() -> Self {
         Self {}
     }
 }
 impl Packet for MapListRequestPacket {
-    fn get_header(&self) -> &'static str {
+    fn get_header(&self) -> u8 {
         Self::HEADER
     }
 
-    fn get_data(&self) -> String {
-        String::new()
+    fn get_data(&self) -> Result<Vec<u8>, CodecError> {
+        Ok(Vec::new())
     }
 
-    fn deserialize(_: String) -> Self {
-        Self {}
+    fn deserialize(_: Vec<u8>) -> Result<Self, CodecError> {
+        Ok(Self {})
     }
 
     fn as_any(&mut self) -> &mut dyn Any {
@@ -72,25 +105,25 @@ pub struct MapListPacket {
     pub map_list: Vec<SongFolder>
 }
 impl MapListPacket {
-    const HEADER: &'static str = "MapListPacket";
+    pub const HEADER: u8 = 2;
 
     pub fn new(map_list: Vec<SongFolder>) -> Self {
         Self { map_list }
     }
 }
 impl Packet for MapListPacket {
-    fn get_header(&self) -> &'static str {
+    fn get_header(&self) -> u8 {
         Self::HEADER
     }
 
-    fn get_data(&self) -> String {
-        serde_json::to_string(&self.map_list).unwrap()
+    fn get_data(&self) -> Result<Vec<u8>, CodecError> {
+        to_json(&self.map_list)
     }
 
-    fn deserialize(raw_data: String) -> Self {
-        Self {
-            map_list: serde_json::from_str(&raw_data).unwrap()
-        }
+    fn deserialize(raw_data: Vec<u8>) -> Result<Self, CodecError> {
+        Ok(Self {
+            map_list: from_json(&raw_data)?
+        })
     }
 
     fn as_any(&mut self) -> &mut dyn Any {
@@ -98,29 +131,44 @@ impl Packet for MapListPacket {
     }
 }
 
+/// Where to pick up the first map of a download that got cut off
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ResumeFrom {
+    /// How many bytes of the .osz we already have
+    pub offset: u64,
+    /// SHA-256 checksum of those bytes, so the sender can tell if its .osz still starts the same way
+    pub checksum: String
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct DownloadRequestPacket {
-    pub requested_maps: Vec<SongFolder>
+    pub requested_maps: Vec<MapRequest>,
+    /// Set when resuming a download, so the sender can skip the part of the first map we already have
+    #[serde(default)]
+    pub resume_from: Option<ResumeFrom>
 }
 impl DownloadRequestPacket {
-    const HEADER: &'static str = "DownloadRequestPacket";
+    pub const HEADER: u8 = 3;
+
+    pub fn new(requested_maps: Vec<MapRequest>) -> Self {
+        Self { requested_maps, resume_from: None }
+    }
 
-    pub fn new(requested_maps: Vec<SongFolder>) -> Self {
-        Self { requested_maps }
+    pub fn resume(requested_maps: Vec<MapRequest>, resume_from: Option<ResumeFrom>) -> Self {
+        Self { requested_maps, resume_from }
     }
 }
 impl Packet for DownloadRequestPacket {
-    fn get_header(&self) -> &'static str {
+    fn get_header(&self) -> u8 {
         Self::HEADER
     }
 
-    fn get_data(&self) -> String {
-        serde_json::to_string(&self.requested_maps).unwrap()
+    fn get_data(&self) -> Result<Vec<u8>, CodecError> {
+        to_json(self)
     }
 
-    fn deserialize(raw_data: String) -> Self {
-        Self {
-            requested_maps: serde_json::from_str(&raw_data).unwrap()
-        }
+    fn deserialize(raw_data: Vec<u8>) -> Result<Self, CodecError> {
+        from_json(&raw_data)
     }
 
     fn as_any(&mut self) -> &mut dyn Any {
@@ -128,31 +176,63 @@ impl Packet for DownloadRequestPacket {
     }
 }
 
+/// Announces that the requested maps are on their way. Each map follows as a
+/// `DownloadMapPacket`, in the order they were requested, or as an `ErrorPacket` if it
+/// couldn't be zipped up.
 pub struct DownloadResponsePacket {
-    pub zipped_maps: Option<File>,
-    zip_size: u64
+    pub map_count: u32,
+    /// The maps to send, handed over one at a time as they're zipped up
+    pub maps: Option<mpsc::Receiver<Result<DownloadMapPacket, ErrorPacket>>>
 }
 impl DownloadResponsePacket {
-    const HEADER: &'static str = "DownloadResponsePacket";
+    pub const HEADER: u8 = 4;
 
-    pub async fn new(zipped_maps: File) -> Self {
-        Self {
-            zip_size: zipped_maps.metadata().await.unwrap().len(),
-            zipped_maps: Some(zipped_maps)
-        }
+    pub fn new(map_count: u32, maps: mpsc::Receiver<Result<DownloadMapPacket, ErrorPacket>>) -> Self {
+        Self { map_count, maps: Some(maps) }
     }
 }
 impl Packet for DownloadResponsePacket {
-    fn get_header(&self) -> &'static str {
+    fn get_header(&self) -> u8 {
+        Self::HEADER
+    }
+
+    fn get_data(&self) -> Result<Vec<u8>, CodecError> {
+        Ok(self.map_count.to_be_bytes().to_vec())
+    }
+
+    fn deserialize(raw_data: Vec<u8>) -> Result<Self, CodecError> {
+        let map_count = raw_data.try_into()
+            .map_err(|_| CodecError::InvalidPayload("Expected a 4 byte map count".to_string()))?;
+        Ok(Self { map_count: u32::from_be_bytes(map_count), maps: None })
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// A chunk of the .osz file or folder announced by a `DownloadMapPacket`
+pub struct DownloadDataPacket {
+    pub data: Vec<u8>
+}
+impl DownloadDataPacket {
+    pub const HEADER: u8 = 5;
+
+    pub fn new(data: Vec<u8>) -> Self {
+        Self { data }
+    }
+}
+impl Packet for DownloadDataPacket {
+    fn get_header(&self) -> u8 {
         Self::HEADER
     }
 
-    fn get_data(&self) -> String {
-        self.zip_size.to_string()
+    fn get_data(&self) -> Result<Vec<u8>, CodecError> {
+        Ok(self.data.clone())
     }
 
-    fn deserialize(raw_data: String) -> Self {
-        Self { zip_size: raw_data.parse::<u64>().unwrap(), zipped_maps: None }
+    fn deserialize(raw_data: Vec<u8>) -> Result<Self, CodecError> {
+        Ok(Self { data: raw_data })
     }
 
     fn as_any(&mut self) -> &mut dyn Any {
@@ -162,23 +242,334 @@ impl Packet for DownloadResponsePacket {
 
 pub struct DisconnectPacket;
 impl DisconnectPacket {
-    const HEADER: &'static str = "DisconnectPacket";
+    pub const HEADER: u8 = 6;
 
     pub fn new() -> Self {
         Self {}
     }
 }
 impl Packet for DisconnectPacket{
-    fn get_header(&self) -> &'static str {
+    fn get_header(&self) -> u8 {
+        Self::HEADER
+    }
+
+    fn get_data(&self) -> Result<Vec<u8>, CodecError> {
+        Ok(Vec::new())
+    }
+
+    fn deserialize(_: Vec<u8>) -> Result<Self, CodecError> {
+        Ok(DisconnectPacket {})
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// Exchanged by both sides right after connecting, before any other packets are sent, so each
+/// side knows who it's talking to and whether they speak the same protocol.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct HelloPacket {
+    pub protocol_version: u16,
+    pub app_version: String,
+    pub display_name: String,
+    pub capabilities: Vec<String>
+}
+impl HelloPacket {
+    pub const HEADER: u8 = 7;
+
+    pub fn new(display_name: String) -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            app_version: env!("CARGO_PKG_VERSION").to_string(),
+            display_name,
+            capabilities: CAPABILITIES.iter().map(|capability| capability.to_string()).collect()
+        }
+    }
+
+    pub fn is_compatible_with(&self, other: &HelloPacket) -> bool {
+        self.protocol_version == other.protocol_version
+    }
+}
+impl Packet for HelloPacket {
+    fn get_header(&self) -> u8 {
+        Self::HEADER
+    }
+
+    fn get_data(&self) -> Result<Vec<u8>, CodecError> {
+        to_json(self)
+    }
+
+    fn deserialize(raw_data: Vec<u8>) -> Result<Self, CodecError> {
+        from_json(&raw_data)
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// Announces a single map of a download, as an .osz file. The .osz itself follows in
+/// `DownloadDataPacket`s, starting from `offset` (which is only non-zero when resuming) until
+/// the end of the file. Maps sent as folders are announced the same way, and followed by the
+/// contents of each file in the manifest, one after the other.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct DownloadMapPacket {
+    /// File name of the .osz, which is the name of the song folder it came from. For a map sent
+    /// as a folder, it's just the name of the folder.
+    pub name: String,
+    pub size: u64,
+    pub offset: u64,
+    /// What the map should look like once it's stored, which is checked before it counts as received
+    #[serde(default)]
+    pub manifest: MapManifest,
+    /// Set if the map is sent as a folder, to the size of each file in `manifest`
+    #[serde(default)]
+    pub file_sizes: Option<Vec<u64>>,
+    #[serde(skip)]
+    pub osz_data: Option<Vec<u8>>,
+    /// The song folder the files of a map sent as a folder are read from as they're sent
+    #[serde(skip)]
+    pub folder: Option<PathBuf>
+}
+impl DownloadMapPacket {
+    pub const HEADER: u8 = 8;
+
+    pub fn new(name: String, osz_data: Vec<u8>, offset: u64, manifest: MapManifest) -> Self {
+        Self { name, size: osz_data.len() as u64, offset, manifest, file_sizes: None, osz_data: Some(osz_data), folder: None }
+    }
+
+    /// Announces the files of the given song folder, which are only read once they're sent
+    pub fn folder(name: String, folder: PathBuf, manifest: MapManifest, file_sizes: Vec<u64>) -> Self {
+        Self { name, size: file_sizes.iter().sum(), offset: 0, manifest, file_sizes: Some(file_sizes), osz_data: None, folder: Some(folder) }
+    }
+
+    /// The name of the song folder the map came from
+    pub fn folder_name(&self) -> &str {
+        match self.file_sizes {
+            Some(_) => &self.name,
+            None => self.name.strip_suffix(".osz").unwrap_or(&self.name)
+        }
+    }
+}
+impl Packet for DownloadMapPacket {
+    fn get_header(&self) -> u8 {
+        Self::HEADER
+    }
+
+    fn get_data(&self) -> Result<Vec<u8>, CodecError> {
+        to_json(self)
+    }
+
+    fn deserialize(raw_data: Vec<u8>) -> Result<Self, CodecError> {
+        let packet: Self = from_json(&raw_data)?;
+        // The name ends up as a file name on our side, so make sure it can't point anywhere else
+        let is_file_name = Path::new(&packet.name).file_name() == Some(OsStr::new(&packet.name));
+        match &packet.file_sizes {
+            None if !is_file_name || !packet.name.ends_with(".osz") => {
+                return Err(CodecError::InvalidPayload(format!("{:?} is not a valid .osz file name", packet.name)));
+            },
+            None => {},
+            Some(file_sizes) => {
+                if !is_file_name {
+                    return Err(CodecError::InvalidPayload(format!("{:?} is not a valid folder name", packet.name)));
+                }
+                // Same goes for the files, which have to stay inside the folder
+                let outside = packet.manifest.files.iter().find(|file| {
+                    file.path.is_empty() || !Path::new(&file.path).components().all(|component| matches!(component, Component::Normal(_)))
+                });
+                if let Some(file) = outside {
+                    return Err(CodecError::InvalidPayload(format!("{:?} is not a valid file in {}", file.path, packet.name)));
+                }
+                let size = file_sizes.iter().try_fold(0u64, |size, file_size| size.checked_add(*file_size));
+                if file_sizes.len() != packet.manifest.files.len() || size != Some(packet.size) || packet.offset != 0 {
+                    return Err(CodecError::InvalidPayload(format!("The files of {} don't add up to its size", packet.name)));
+                }
+            }
+        }
+        if packet.offset > packet.size {
+            return Err(CodecError::InvalidPayload(format!("Offset {} is past the end of {}", packet.offset, packet.name)));
+        }
+        Ok(packet)
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// Why a request from the peer couldn't be fulfilled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum ErrorCode {
+    /// A packet's payload couldn't be parsed
+    MalformedPacket,
+    /// A packet with a header we don't know about was received
+    UnexpectedPacket,
+    /// A requested map isn't in our songs
+    MapNotFound,
+    /// A requested map couldn't be read from the file system
+    MapUnavailable,
+    /// The connection to the peer failed partway through, which is never sent over the wire
+    ConnectionLost
+}
+
+/// Sent to the peer whenever one of its requests can't be fulfilled. Also sent in place of a
+/// `DownloadMapPacket` for maps that couldn't be zipped up, so the rest of the download can
+/// carry on.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ErrorPacket {
+    pub code: ErrorCode,
+    pub message: String
+}
+impl ErrorPacket {
+    pub const HEADER: u8 = 9;
+
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self { code, message: message.into() }
+    }
+}
+impl Packet for ErrorPacket {
+    fn get_header(&self) -> u8 {
+        Self::HEADER
+    }
+
+    fn get_data(&self) -> Result<Vec<u8>, CodecError> {
+        to_json(self)
+    }
+
+    fn deserialize(raw_data: Vec<u8>) -> Result<Self, CodecError> {
+        from_json(&raw_data)
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// Sent by the side that opened the connection right after the hellos, to say whether it's
+/// pairing with the peer it connected to. If it is, the peer answers with its own share and
+/// confirmation, and if that checks out, we send back our confirmation. See `trust::Pairing`.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct PairingPacket {
+    /// Our SPAKE2 message for the pairing exchange, which depends on the pairing code
+    pub share: Option<String>,
+    /// Proof that we ended up with the same key as the peer, and so used the same code
+    pub confirmation: Option<String>
+}
+impl PairingPacket {
+    pub const HEADER: u8 = 10;
+
+    pub fn new(share: Option<String>, confirmation: Option<String>) -> Self {
+        Self { share, confirmation }
+    }
+}
+impl Packet for PairingPacket {
+    fn get_header(&self) -> u8 {
+        Self::HEADER
+    }
+
+    fn get_data(&self) -> Result<Vec<u8>, CodecError> {
+        to_json(self)
+    }
+
+    fn deserialize(raw_data: Vec<u8>) -> Result<Self, CodecError> {
+        from_json(&raw_data)
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// Asks the peer to stop sending the download it's in the middle of, so it doesn't zip up and
+/// send maps we no longer want. The peer sends one back in place of the rest of the download,
+/// so we know where it ends. If the peer isn't sending us anything, it's ignored.
+pub struct CancelDownloadPacket;
+impl CancelDownloadPacket {
+    pub const HEADER: u8 = 11;
+
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+impl Packet for CancelDownloadPacket {
+    fn get_header(&self) -> u8 {
+        Self::HEADER
+    }
+
+    fn get_data(&self) -> Result<Vec<u8>, CodecError> {
+        Ok(Vec::new())
+    }
+
+    fn deserialize(_: Vec<u8>) -> Result<Self, CodecError> {
+        Ok(Self {})
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// Asks the peer to sync both ways, with each side sending the other the songs it's missing.
+/// It follows a `MapListPacket` of our songs, so the peer can work out what's missing itself and
+/// only go along with the songs listed here. The peer asks its user first, and answers with a
+/// `SyncResponsePacket`.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct SyncRequestPacket {
+    /// Songs we'll send the peer, which it asks us for once it agrees
+    pub offered: Vec<SongFolder>,
+    /// Songs of the peer we'll ask it for once it agrees
+    pub wanted: Vec<SongFolder>
+}
+impl SyncRequestPacket {
+    pub const HEADER: u8 = 12;
+
+    pub fn new(offered: Vec<SongFolder>, wanted: Vec<SongFolder>) -> Self {
+        Self { offered, wanted }
+    }
+}
+impl Packet for SyncRequestPacket {
+    fn get_header(&self) -> u8 {
+        Self::HEADER
+    }
+
+    fn get_data(&self) -> Result<Vec<u8>, CodecError> {
+        to_json(self)
+    }
+
+    fn deserialize(raw_data: Vec<u8>) -> Result<Self, CodecError> {
+        from_json(&raw_data)
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// Whether we agree to the sync the peer asked for in a `SyncRequestPacket`. If we do, it's
+/// followed by our request for the songs the peer offered.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct SyncResponsePacket {
+    pub accepted: bool
+}
+impl SyncResponsePacket {
+    pub const HEADER: u8 = 13;
+
+    pub fn new(accepted: bool) -> Self {
+        Self { accepted }
+    }
+}
+impl Packet for SyncResponsePacket {
+    fn get_header(&self) -> u8 {
         Self::HEADER
     }
 
-    fn get_data(&self) -> String {
-        String::new()
+    fn get_data(&self) -> Result<Vec<u8>, CodecError> {
+        to_json(self)
     }
 
-    fn deserialize(_: String) -> Self {
-        DisconnectPacket {}
+    fn deserialize(raw_data: Vec<u8>) -> Result<Self, CodecError> {
+        from_json(&raw_data)
     }
 
     fn as_any(&mut self) -> &mut dyn Any {
@@ -186,91 +577,852 @@ impl Packet for DisconnectPacket{
     }
 }
 
-cfg_if! {
-    if #[cfg(test)] {
-        #[derive(Debug)]
-        struct AppState {
-            local_songs: Arc<Mutex<Vec<SongFolder>>>,
-            remote_songs: Arc<Mutex<Vec<SongFolder>>>,
-            app_window: MockWindow
+/// What to do with the maps we receive in a `DownloadResponsePacket`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum DownloadMode {
+    /// Ask the user for a folder to save the .osz files to
+    Save,
+    /// Unpack each map straight into the local songs folder
+    Install
+}
+
+/// Identifies one of the peers we're connected to. Ids aren't reused, so a peer that connects
+/// again gets a new one.
+pub type PeerId = u32;
+
+/// A peer we're connected to, as shown to the front-end
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct PeerInfo {
+    pub id: PeerId,
+    /// The key the peer proved it holds when connecting, see `SecureStream::remote_public_key`
+    pub public_key: String,
+    #[serde(flatten)]
+    pub hello: HelloPacket
+}
+
+/// Sent to the front-end when something goes wrong with the connection to a peer
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct PeerError {
+    pub peer: PeerId,
+    #[serde(flatten)]
+    pub error: ErrorPacket
+}
+
+/// Sent to the front-end as the maps of a download arrive
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct DownloadProgress {
+    /// The peer the download is coming from
+    pub peer: PeerId,
+    /// The .osz currently being received
+    pub map: String,
+    pub maps_done: u32,
+    pub map_count: u32,
+    /// How much of the current map has arrived, as a percentage
+    pub map_progress: u64
+}
+
+/// The parts of the app the packet manager needs, and the user it can talk to
+#[derive(Debug, Clone)]
+struct AppState {
+    local_path: Arc<Mutex<Option<PathBuf>>>,
+    local_songs: Arc<Mutex<Vec<SongFolder>>>,
+    song_cache: Arc<Mutex<SongCache>>,
+    ui: Arc<dyn UiBridge>
+}
+
+impl AppState {
+    /// Receives the maps announced by a `DownloadResponsePacket`, saving or installing each one
+    /// as soon as it arrives. If the connection drops partway through, the part of the map that
+    /// arrived is kept so the download can be resumed later. The same goes for when the peer
+    /// stops partway through because we asked it to. If the download is part of a pull or the
+    /// transfer queue, `outcomes` is told how each map went.
+    async fn receive_download<R: AsyncRead + Unpin>(&self, peer: PeerId, response: DownloadResponsePacket, reader: &mut R,
+                                                   download_mode: DownloadMode, transfers: &Transfers) -> Result<(), CodecError> {
+        let ui = &self.ui;
+        let requested_maps = transfers.requested_maps.lock().unwrap().clone();
+        let partial_download = transfers.partial_download.as_ref();
+        let outcomes = transfers.download_outcomes.lock().unwrap().clone();
+        let pulling = transfers.pulling.load(Ordering::SeqCst);
+        // Each map is marked as finished before anyone hears about it, so they can ask for the
+        // next download straight away
+        let maps_reported = AtomicU32::new(0);
+        let report_outcome = |maps_done: u32, result: Result<(), MapError>| {
+            if let Some(request) = requested_maps.get(maps_done as usize) {
+                maps_reported.fetch_add(1, Ordering::SeqCst);
+                transfers.maps_finished(1);
+                if let Some(outcomes) = &outcomes {
+                    let _ = outcomes.send(DownloadOutcome::Map { peer, folder: request.song.folder.clone(), result });
+                }
+            }
+        };
+
+        // If we asked to resume a download, the user already agreed to it. Otherwise, ask the
+        // user where to store the maps before any of them are read
+        let resumed_download = partial_download.lock().unwrap().take();
+        let mut download = match resumed_download {
+            Some(download) => Some(download),
+            None => {
+                let message = format!("You are about to download {} maps. Continue?", response.map_count);
+                let songs_dir = self.local_path.lock().unwrap().clone();
+                DownloadDestination::choose(ui.as_ref(), &message, download_mode, songs_dir)
+                    .map(|destination| PartialDownload::new(requested_maps.clone(), destination))
+            }
+        };
+
+        if download.is_some() {
+            ui.emit("download-started", peer);
+        }
+
+        // Maps we only asked for some difficulties of get added to our existing folder
+        let existing_folders: HashMap<String, PathBuf> = {
+            let local_songs = self.local_songs.lock().unwrap();
+            requested_maps.iter()
+                .filter(|request| request.difficulties.is_some())
+                .filter_map(|request| {
+                    let local_song = local_songs.iter()
+                        .find(|local_song| request.song.id == local_song.id && request.song.name == local_song.name)?;
+                    Some((request.song.folder.clone(), local_song.path.clone()?))
+                })
+                .collect()
+        };
+
+        // Each map follows as a DownloadMapPacket and its DownloadDataPackets. We have to read
+        // all of them even if the user canceled, so the stream lines back up with the next packet
+        let mut completed_maps = 0;
+        let mut stopped_at = None;
+        for maps_done in 0..response.map_count {
+            let map = read_frame(reader).await
+                .and_then(|frame| match frame.header {
+                    DownloadMapPacket::HEADER => DownloadMapPacket::deserialize(frame.payload).map(Ok),
+                    ErrorPacket::HEADER => ErrorPacket::deserialize(frame.payload).map(Err),
+                    CancelDownloadPacket::HEADER => Err(CodecError::DownloadStopped),
+                    header => Err(CodecError::InvalidPayload(format!("Expected a map, but got packet with header {header}")))
+                });
+            let map = match map {
+                Ok(Ok(map)) => map,
+                Ok(Err(error)) => {
+                    // The peer couldn't send this map, but the rest are still on their way
+                    self.report_error(peer, &error);
+                    if let Some(download) = download.as_mut() {
+                        download.skip_map();
+                    }
+                    report_outcome(maps_done, Err(MapError::Failed(error.message)));
+                    continue;
+                },
+                Err(CodecError::DownloadStopped) => {
+                    stopped_at = Some(maps_done);
+                    break;
+                },
+                Err(err) => return Err(self.interrupt_download(peer, download, partial_download, pulling, err))
+            };
+
+            let received = self.receive_map(transfers, &map, reader, download.as_mut(), maps_done, response.map_count).await;
+            let mut current_download = match (received, download.take()) {
+                (Err(CodecError::DownloadStopped), current_download) => {
+                    download = current_download;
+                    stopped_at = Some(maps_done);
+                    break;
+                },
+                (Err(err), current_download) => return Err(self.interrupt_download(peer, current_download, partial_download, pulling, err)),
+                (Ok(()), Some(current_download)) => current_download,
+                (Ok(()), None) => continue
+            };
+
+            let existing_folder = existing_folders.get(map.folder_name()).cloned();
+            let map_name = map.name.clone();
+            let stored = task::spawn_blocking(move || {
+                let completed = match map.file_sizes {
+                    Some(_) => current_download.complete_folder(&map.name, existing_folder.as_deref(), &map.manifest),
+                    None => current_download.complete_map(&map.name, existing_folder.as_deref(), &map.manifest)
+                };
+                (current_download, completed)
+            }).await;
+            let (current_download, completed) = match stored {
+                Ok(stored) => stored,
+                // The download went down with the task, so there's nothing left of it to hand over
+                Err(err) => return Err(self.interrupt_download(peer, None, partial_download, pulling, CodecError::IOError(
+                    io::Error::other(format!("Unable to store {map_name}: {err}"))
+                )))
+            };
+            download = Some(current_download);
+            match completed {
+                Ok(path) => {
+                    println!("Downloaded map to {path:?}");
+                    completed_maps += 1;
+                    report_outcome(maps_done, Ok(()));
+                },
+                Err(err @ SongFolderError::Damaged(_)) => {
+                    println!("The downloaded map is damaged: {err}");
+                    report_outcome(maps_done, Err(MapError::Damaged(format!("The map arrived damaged: {err}"))));
+                },
+                Err(err) => {
+                    println!("Unable to store the downloaded map: {err:?}");
+                    report_outcome(maps_done, Err(MapError::Failed(format!("Unable to store the map: {err}"))));
+                }
+            }
+        }
+
+        if let Some(download) = download {
+            if let DownloadDestination::Install(songs_dir) = &download.destination {
+                if completed_maps > 0 {
+                    self.rescan_songs(songs_dir).await;
+                }
+            }
+            if stopped_at.is_some() {
+                // Whoever stopped the download decides whether it gets picked back up, so it has
+                // to be there for them before they hear that it stopped
+                *partial_download.lock().unwrap() = Some(download);
+                ui.emit("download-stopped", peer);
+            } else {
+                ui.emit("download-finished", peer);
+            }
+        }
+
+        // Nothing more is coming for the maps the peer didn't get to
+        if let Some(stopped_at) = stopped_at {
+            for maps_done in stopped_at..response.map_count {
+                report_outcome(maps_done, Err(MapError::Failed("The download was stopped".to_string())));
+            }
+        }
+
+        // Maps the peer never sent, or that the user didn't want, are done with too
+        transfers.maps_finished((requested_maps.len() as u32).saturating_sub(maps_reported.load(Ordering::SeqCst)));
+        Ok(())
+    }
+
+    /// Reads the .osz announced by a `DownloadMapPacket` into its part file, picking up from the
+    /// end of the part file if the peer resumed the map. A map sent as a folder is written into
+    /// its staging folder instead. If `download` isn't set, the map is read and thrown away. The
+    /// map is read no faster than our download limits allow. If the peer stops partway through,
+    /// what we got of the map is left in the part file.
+    async fn receive_map<R: AsyncRead + Unpin>(&self, transfers: &Transfers, map: &DownloadMapPacket, reader: &mut R, mut download: Option<&mut PartialDownload>,
+                                               maps_done: u32, map_count: u32) -> Result<(), CodecError> {
+        let peer = transfers.peer;
+        let mut file = None;
+        let mut folder = None;
+        if let (Some(download), Some(file_sizes)) = (download.as_mut(), &map.file_sizes) {
+            let files = map.manifest.files.iter().map(|file| PathBuf::from(&file.path)).zip(file_sizes.iter().copied());
+            folder = Some(FolderWriter::create(download.staging_path(&map.name), files).await?);
+            download.current_map = Some(PartialMap { name: map.name.clone(), received: 0, format: MapFormat::Folder });
+        } else if let Some(download) = download.as_mut() {
+            let already_received = download.current_map.as_ref()
+                .filter(|current_map| current_map.name == map.name)
+                .map_or(0, |current_map| current_map.received);
+            if map.offset > 0 && map.offset != already_received {
+                return Err(CodecError::InvalidPayload(
+                    format!("Peer resumed {} from byte {}, but we have {already_received}", map.name, map.offset)
+                ));
+            }
+
+            let part_path = download.part_path(&map.name);
+            file = Some(if map.offset > 0 {
+                let mut file = OpenOptions::new().write(true).open(&part_path).await?;
+                file.set_len(map.offset).await?;
+                file.seek(io::SeekFrom::End(0)).await?;
+                file
+            } else {
+                if let Some(parent) = part_path.parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+                File::create(&part_path).await?
+            });
+            download.current_map = Some(PartialMap { name: map.name.clone(), received: map.offset, format: MapFormat::Osz });
+        }
+
+        let mut received = map.offset;
+        let result = async {
+            let mut progress = 100 * received / map.size.max(1);
+            while received < map.size {
+                let frame = read_frame(reader).await?;
+                if frame.header == CancelDownloadPacket::HEADER {
+                    return Err(CodecError::DownloadStopped);
+                }
+                if frame.header != DownloadDataPacket::HEADER {
+                    return Err(CodecError::InvalidPayload(
+                        format!("Expected map data, but got packet with header {}", frame.header)
+                    ));
+                }
+
+                let new_received = received + frame.payload.len() as u64;
+                if new_received > map.size {
+                    return Err(CodecError::InvalidPayload(format!("Received more data than expected for {}", map.name)));
+                }
+                // Holding off on the next read slows the peer down once the socket's buffers fill up
+                transfers.bandwidth.throttle(Direction::Download, frame.payload.len()).await;
+                if let Some(folder) = folder.as_mut() {
+                    folder.write(&frame.payload).await?;
+                } else if let Some(file) = file.as_mut() {
+                    file.write_all(&frame.payload).await?;
+                }
+                if file.is_some() || folder.is_some() {
+                    let new_progress = 100 * new_received / map.size;
+                    if progress < new_progress {
+                        progress = new_progress;
+                        self.ui.emit("download-progress", DownloadProgress {
+                            peer,
+                            map: map.name.clone(),
+                            maps_done,
+                            map_count,
+                            map_progress: progress
+                        });
+                    }
+                }
+                received = new_received;
+            }
+            if let Some(folder) = folder.as_mut() {
+                folder.finish().await?;
+            }
+            Ok(())
+        }.await;
+
+        let flushed = match (file.as_mut(), folder.as_mut()) {
+            (Some(file), _) => file.flush().await.map_err(CodecError::from),
+            (_, Some(folder)) => folder.flush().await.map_err(CodecError::from),
+            (None, None) => Ok(())
+        };
+        if let Some(current_map) = download.and_then(|download| download.current_map.as_mut()) {
+            current_map.received = received;
+        }
+        result.and(flushed)
+    }
+
+    /// Holds on to what we got of a download that failed partway through, so it can be resumed
+    /// once we reconnect. The rest of a pull is fetched from other peers instead, so what we got
+    /// of it is thrown away.
+    fn interrupt_download(&self, peer: PeerId, download: Option<PartialDownload>, partial_download: &Mutex<Option<PartialDownload>>,
+                          pulling: bool, err: CodecError) -> CodecError {
+        if let Some(download) = download {
+            if pulling {
+                download.discard();
+            } else {
+                *partial_download.lock().unwrap() = Some(download);
+            }
+            self.ui.emit("download-interrupted", peer);
+        }
+        err
+    }
+
+    /// Lets the front-end know that something went wrong with the connection to a peer
+    fn report_error(&self, peer: PeerId, error: &ErrorPacket) {
+        self.ui.emit("connection-error", PeerError { peer, error: error.clone() });
+    }
+
+    /// Rescans the songs folder so newly installed maps show up in our local list
+    async fn rescan_songs(&self, songs_dir: &Path) {
+        match read_local_files(songs_dir, &self.song_cache).await {
+            Ok(songs) => {
+                *self.local_songs.lock().unwrap() = songs;
+                self.ui.emit("local-songs-updated", ());
+            },
+            Err(err) => println!("Unable to rescan the songs folder: {err:?}")
+        }
+    }
+}
+
+/// Zips up a requested map to send. If we're resuming the map and our .osz still starts with
+/// the bytes the peer already has, we carry on from there instead of sending the whole thing.
+/// Maps sent as folders are only hashed, and always sent whole. Will block as it reads from the
+/// file system.
+fn zip_map(request: &MapRequest, resume_from: Option<&ResumeFrom>) -> io::Result<DownloadMapPacket> {
+    let (folder, mut name) = request.song.path.as_ref()
+        .and_then(|path| Some((path, path.file_name()?.to_os_string())))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "The song has no folder"))?;
+    if request.format == MapFormat::Folder {
+        let (manifest, file_sizes) = song_folder_manifest(&request.song, request.difficulties.as_ref(), request.profile)?;
+        return Ok(DownloadMapPacket::folder(name.to_string_lossy().to_string(), folder.clone(), manifest, file_sizes));
+    }
+    name.push(".osz");
+    let (osz_data, manifest) = song_to_osz_with_manifest(&request.song, request.difficulties.as_ref(), request.profile)?;
+
+    let offset = match resume_from {
+        Some(resume_from) if resume_from.offset <= osz_data.len() as u64 => {
+            let checksum = checksum_reader(&osz_data[..resume_from.offset as usize])?;
+            if checksum == resume_from.checksum { resume_from.offset } else { 0 }
+        },
+        _ => 0
+    };
+    Ok(DownloadMapPacket::new(name.to_string_lossy().to_string(), osz_data, offset, manifest))
+}
+
+/// Sends the next chunk of the map being sent, no faster than our upload limits allow. Returns
+/// false instead if the peer asked us to stop.
+async fn send_map_data<W: AsyncWrite + Unpin>(writer: &mut W, transfers: &Transfers, chunk: &[u8]) -> Result<bool, CodecError> {
+    if transfers.uploads.take_canceled() {
+        return Ok(false);
+    }
+    // Only map data counts towards our upload limits, so other packets are never held up
+    transfers.bandwidth.throttle(Direction::Upload, chunk.len()).await;
+    let data = DownloadDataPacket::new(chunk.to_vec());
+    write_frame(writer, &Frame::from_packet(&data)?).await?;
+    Ok(true)
+}
+
+/// Zips up the requested maps in the background, 4 at a time, handing them over in order as
+/// they finish so the first map can be sent while the rest are still being zipped. Maps we
+/// can't send are handed over as the error to send in their place.
+fn stream_maps(requests: Vec<Result<MapRequest, ErrorPacket>>, resume_from: Option<ResumeFrom>) -> mpsc::Receiver<Result<DownloadMapPacket, ErrorPacket>> {
+    let (sender, receiver) = mpsc::channel(1);
+    tokio::spawn(async move {
+        let mut resume_from = resume_from;
+        let mut zipping = VecDeque::new();
+        let mut requests = requests.into_iter();
+
+        loop {
+            while zipping.len() < 4 {
+                match requests.next() {
+                    Some(request) => {
+                        // Only the first map can have been cut off
+                        let resume_from = resume_from.take();
+                        zipping.push_back(task::spawn_blocking(move || {
+                            let request = request?;
+                            zip_map(&request, resume_from.as_ref()).map_err(|err| {
+                                ErrorPacket::new(ErrorCode::MapUnavailable, format!("Unable to zip up {}: {err}", request.song.folder))
+                            })
+                        }));
+                    },
+                    None => break
+                }
+            }
+
+            let map = match zipping.pop_front() {
+                Some(zipped) => zipped.await.unwrap_or_else(|err| {
+                    Err(ErrorPacket::new(ErrorCode::MapUnavailable, format!("Unable to zip up map: {err}")))
+                }),
+                None => break
+            };
+            if let Err(error) = &map {
+                println!("Unable to send map: {error:?}");
+            }
+            if sender.send(map).await.is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+/// The downloads we're sending to a peer, so the peer can stop the one it's receiving
+#[derive(Debug, Default)]
+struct Uploads {
+    /// Downloads handed to the writing thread that it hasn't finished sending
+    pending: AtomicU32,
+    /// Set when the peer asks us to stop the download we're sending
+    canceled: AtomicBool,
+    /// Wakes the writing thread up if it's waiting on a map to be zipped when the peer asks us to stop
+    wake: Notify
+}
+
+impl Uploads {
+    /// Stops the download being sent, if there is one
+    fn cancel(&self) {
+        if self.pending.load(Ordering::SeqCst) > 0 {
+            self.canceled.store(true, Ordering::SeqCst);
+            self.wake.notify_one();
+        }
+    }
+
+    /// Whether the peer asked us to stop since we last checked
+    fn take_canceled(&self) -> bool {
+        self.canceled.swap(false, Ordering::SeqCst)
+    }
+
+    /// Waits until the peer asks us to stop
+    async fn canceled(&self) {
+        while !self.take_canceled() {
+            self.wake.notified().await;
+        }
+    }
+
+    /// Records that a download is done being sent, one way or another. A request to stop that
+    /// came in too late to make a difference is forgotten about, so it can't stop the next one.
+    fn finished(&self) {
+        self.pending.fetch_sub(1, Ordering::SeqCst);
+        self.canceled.store(false, Ordering::SeqCst);
+    }
+}
+
+/// The transfers we have going with one peer, shared with the threads reading from and writing to it
+#[derive(Debug, Clone)]
+struct Transfers {
+    peer: PeerId,
+    bandwidth: Arc<PeerBandwidth>,
+    uploads: Arc<Uploads>,
+    /// Maps we last asked the peer for
+    requested_maps: Arc<Mutex<Vec<MapRequest>>>,
+    partial_download: Arc<Mutex<Option<PartialDownload>>>,
+    /// Set while the peer is sending us part of a pull or the transfer queue, see `planner::pull_maps`
+    download_outcomes: Arc<Mutex<Option<mpsc::UnboundedSender<DownloadOutcome>>>>,
+    /// Whether the download is part of a pull, whose maps are fetched from other peers if this
+    /// one goes away, so there's no point holding on to what arrived of them
+    pulling: Arc<AtomicBool>,
+    /// How many of the maps we last asked the peer for haven't been stored or given up on yet.
+    /// No other download is asked for until they all have, so it can't take over the one that's
+    /// still coming. An error the peer sends before it answers is taken as its answer.
+    maps_outstanding: Arc<AtomicU32>,
+    /// The download to ask the peer for once it agrees to the sync we asked for
+    pending_sync: Arc<Mutex<Option<PartialDownload>>>
+}
+
+impl Transfers {
+    /// Claims the session for a download of the given maps. Returns false if the peer is still
+    /// sending us another one.
+    fn claim_download(&self, requested_maps: &[MapRequest]) -> bool {
+        self.maps_outstanding.compare_exchange(0, requested_maps.len() as u32, Ordering::SeqCst, Ordering::SeqCst).is_ok()
+    }
+
+    /// Records that some of the maps we asked for have been stored or given up on
+    fn maps_finished(&self, count: u32) {
+        let _ = self.maps_outstanding.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |outstanding| {
+            Some(outstanding.saturating_sub(count))
+        });
+    }
+
+    /// Sets up the download we're about to ask the peer for, so its response is taken as agreed
+    /// to and stored in `destination` without asking the user. Returns false if the peer is
+    /// still sending us another download.
+    fn expect_download(&self, requested_maps: &[MapRequest], destination: DownloadDestination,
+                       outcomes: Option<mpsc::UnboundedSender<DownloadOutcome>>, pulling: bool) -> bool {
+        if !self.claim_download(requested_maps) {
+            return false;
+        }
+        let download = PartialDownload::new(requested_maps.to_vec(), destination);
+        if let Some(partial_download) = self.partial_download.lock().unwrap().replace(download) {
+            partial_download.discard();
+        }
+        *self.requested_maps.lock().unwrap() = requested_maps.to_vec();
+        *self.download_outcomes.lock().unwrap() = outcomes;
+        self.pulling.store(pulling, Ordering::SeqCst);
+        true
+    }
+
+    /// Stops waiting on the download we asked the peer for, so another one can be asked for even
+    /// if the peer never answers. Whatever was set aside for it is thrown away.
+    fn abandon_download(&self) {
+        self.maps_outstanding.store(0, Ordering::SeqCst);
+        self.requested_maps.lock().unwrap().clear();
+        *self.download_outcomes.lock().unwrap() = None;
+        if let Some(partial_download) = self.partial_download.lock().unwrap().take() {
+            partial_download.discard();
+        }
+    }
+
+    /// Lets whoever asked for the download we're waiting on know that none of it is coming,
+    /// since the peer answered with an error instead
+    fn download_refused(&self, error: &ErrorPacket) {
+        if self.maps_outstanding.swap(0, Ordering::SeqCst) == 0 {
+            return;
+        }
+        let outcomes = match self.download_outcomes.lock().unwrap().take() {
+            Some(outcomes) => outcomes,
+            None => return
+        };
+        // Nothing of a pull is worth resuming, since its maps are fetched from other peers
+        if self.pulling.load(Ordering::SeqCst) {
+            if let Some(partial_download) = self.partial_download.lock().unwrap().take() {
+                partial_download.discard();
+            }
         }
-    } else {
-        #[derive(Debug)]
-        struct AppState {
-            local_songs: Arc<Mutex<Vec<SongFolder>>>,
-            remote_songs: Arc<Mutex<Vec<SongFolder>>>,
-            app_window: Window<Wry>
+        for request in self.requested_maps.lock().unwrap().iter() {
+            let result = Err(MapError::Failed(error.message.clone()));
+            let _ = outcomes.send(DownloadOutcome::Map { peer: self.peer, folder: request.song.folder.clone(), result });
         }
     }
 }
 
+/// A connection to one peer, along with the transfers we have going with it
+#[derive(Debug)]
+struct Session {
+    info: PeerInfo,
+    packet_queue: mpsc::Sender<Box<dyn Packet>>,
+    remote_songs: Arc<Mutex<Vec<SongFolder>>>,
+    transfers: Transfers
+}
+
 #[derive(Debug)]
 pub struct PacketManager {
     app_state: Option<AppState>,
-    packet_queue: Option<mpsc::Sender<Box<dyn Packet>>>
+    sessions: Arc<Mutex<HashMap<PeerId, Session>>>,
+    next_peer_id: PeerId,
+    download_mode: Arc<Mutex<DownloadMode>>,
+    bandwidth: Arc<Bandwidth>,
+    /// Downloads that got cut off, keyed by the public key of the peer they were coming from,
+    /// so they outlive the connection and can be resumed once the peer connects again
+    partial_downloads: HashMap<String, Arc<Mutex<Option<PartialDownload>>>>,
+    display_name: String,
+    identity: Identity
 }
 
-// TODO: Better error messages + tests
-//  also add error packet
-
 impl PacketManager {
     pub fn new() -> Self {
-        Self { app_state: None, packet_queue: None}
+        // Default to the name of the logged-in user until one is set
+        let display_name = std::env::var("USERNAME")
+            .or_else(|_| std::env::var("USER"))
+            .unwrap_or_else(|_| "osu!sync user".to_string());
+
+        Self {
+            app_state: None,
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+            next_peer_id: 1,
+            download_mode: Arc::new(Mutex::new(DownloadMode::Save)),
+            bandwidth: Arc::new(Bandwidth::default()),
+            partial_downloads: HashMap::new(),
+            display_name,
+            // Stand-in until the stored identity is loaded, so we can still connect without one
+            identity: Identity::generate()
+        }
     }
 
-    pub fn connect_to_app(&mut self, local_songs: Arc<Mutex<Vec<SongFolder>>>, remote_songs: Arc<Mutex<Vec<SongFolder>>>, app_window: Window<Wry>) {
-        cfg_if! {
-            if #[cfg(test)] {}
-            else {
-                self.app_state = Some(AppState{ local_songs, remote_songs, app_window });
-            }
+    pub fn connect_to_app(&mut self, local_path: Arc<Mutex<Option<PathBuf>>>, local_songs: Arc<Mutex<Vec<SongFolder>>>, song_cache: Arc<Mutex<SongCache>>, ui: Arc<dyn UiBridge>) {
+        self.app_state = Some(AppState{ local_path, local_songs, song_cache, ui });
+    }
+
+    /// The limits on how fast map data is sent and received, shared by every peer
+    pub fn bandwidth(&self) -> Arc<Bandwidth> {
+        self.bandwidth.clone()
+    }
+
+    /// Sets how the next downloads we receive will be handled
+    pub fn set_download_mode(&self, download_mode: DownloadMode) {
+        *self.download_mode.lock().unwrap() = download_mode;
+    }
+
+    /// Asks the peer for the given maps, remembering what was asked for so we know how to
+    /// install the response. Returns false if we aren't connected to the peer, or it's still
+    /// sending us another download.
+    pub fn request_download(&self, peer: PeerId, requested_maps: Vec<MapRequest>) -> bool {
+        match self.sessions.lock().unwrap().get(&peer) {
+            Some(session) if session.transfers.claim_download(&requested_maps) => {
+                // A new download replaces any we were hoping to resume from the same peer
+                if let Some(partial_download) = session.transfers.partial_download.lock().unwrap().take() {
+                    partial_download.discard();
+                }
+                *session.transfers.requested_maps.lock().unwrap() = requested_maps.clone();
+                *session.transfers.download_outcomes.lock().unwrap() = None;
+                session.transfers.pulling.store(false, Ordering::SeqCst);
+            },
+            _ => return false
+        }
+        self.send_packet(peer, Box::new(DownloadRequestPacket::new(requested_maps)));
+        true
+    }
+
+    /// Asks the peer for the given maps on behalf of the transfer queue. The maps are stored in
+    /// `destination` without asking the user, and `outcomes` is told how each one went. If the
+    /// connection drops, what arrived is handed over along with `DownloadOutcome::Disconnected`
+    /// so it can be resumed. Returns false if we aren't connected to the peer, or it's still
+    /// sending us another download.
+    pub fn start_download(&self, peer: PeerId, requested_maps: Vec<MapRequest>, destination: DownloadDestination,
+                          outcomes: mpsc::UnboundedSender<DownloadOutcome>) -> bool {
+        self.expect_and_request(peer, requested_maps, destination, outcomes, false)
+    }
+
+    /// Asks the peer for its share of a pull, like `start_download`. If the connection drops,
+    /// what arrived is thrown away, since the rest of the pull is fetched from other peers.
+    pub fn start_pull(&self, peer: PeerId, requested_maps: Vec<MapRequest>, destination: DownloadDestination,
+                      outcomes: mpsc::UnboundedSender<DownloadOutcome>) -> bool {
+        self.expect_and_request(peer, requested_maps, destination, outcomes, true)
+    }
+
+    fn expect_and_request(&self, peer: PeerId, requested_maps: Vec<MapRequest>, destination: DownloadDestination,
+                          outcomes: mpsc::UnboundedSender<DownloadOutcome>, pulling: bool) -> bool {
+        let expected = self.sessions.lock().unwrap().get(&peer)
+            .is_some_and(|session| session.transfers.expect_download(&requested_maps, destination, Some(outcomes), pulling));
+        if !expected {
+            return false;
+        }
+        self.send_packet(peer, Box::new(DownloadRequestPacket::new(requested_maps)));
+        true
+    }
+
+    /// Asks the peer to sync both ways, swapping the songs in `plan`. If the peer agrees, the
+    /// songs it sends us are stored in `destination` without asking the user again, and
+    /// "sync-accepted" is emitted. Otherwise "sync-declined" is. Returns false if we aren't
+    /// connected to the peer.
+    pub fn start_sync(&self, peer: PeerId, plan: SyncPlan, destination: DownloadDestination) -> bool {
+        let requested_maps: Vec<MapRequest> = plan.to_receive.iter()
+            .map(|song| MapRequest::full(song.clone()).with_format(destination.map_format()))
+            .collect();
+        match self.sessions.lock().unwrap().get(&peer) {
+            Some(session) => *session.transfers.pending_sync.lock().unwrap() = Some(PartialDownload::new(requested_maps, destination)),
+            None => return false
+        }
+        // The peer works out the plan on its side from our map list, so make sure it has ours
+        if let Some(app_state) = &self.app_state {
+            let local_songs = app_state.local_songs.lock().unwrap().clone();
+            self.send_packet(peer, Box::new(MapListPacket::new(local_songs)));
+        }
+        self.send_packet(peer, Box::new(SyncRequestPacket::new(plan.to_send, plan.to_receive)));
+        true
+    }
+
+    /// The download from the peer with the given public key that got cut off when we lost our
+    /// connection to it, if any
+    pub fn partial_download(&self, public_key: &str) -> Option<PartialDownload> {
+        self.partial_downloads.get(public_key)
+            .and_then(|partial_download| partial_download.lock().unwrap().clone())
+    }
+
+    /// Takes the download from the given peer that was stopped or cut off, so the peer's
+    /// session no longer holds on to it
+    pub fn take_partial_download(&self, peer: PeerId) -> Option<PartialDownload> {
+        self.sessions.lock().unwrap().get(&peer)
+            .and_then(|session| session.transfers.partial_download.lock().unwrap().take())
+    }
+
+    /// Asks the peer for the rest of a download that got cut off, starting from the part of the
+    /// map that was cut off given by `PartialDownload::resume_from`. If `outcomes` is set, it's
+    /// told how each map went. Returns false if we aren't connected to the peer, or it's still
+    /// sending us another download.
+    pub fn resume_download(&self, peer: PeerId, partial_download: PartialDownload, resume_from: Option<ResumeFrom>,
+                           outcomes: Option<mpsc::UnboundedSender<DownloadOutcome>>) -> bool {
+        let packet = DownloadRequestPacket::resume(partial_download.requested_maps.clone(), resume_from);
+        match self.sessions.lock().unwrap().get(&peer) {
+            Some(session) if session.transfers.claim_download(&partial_download.requested_maps) => {
+                *session.transfers.requested_maps.lock().unwrap() = partial_download.requested_maps.clone();
+                *session.transfers.partial_download.lock().unwrap() = Some(partial_download);
+                *session.transfers.download_outcomes.lock().unwrap() = outcomes;
+                session.transfers.pulling.store(false, Ordering::SeqCst);
+            },
+            _ => return false
+        }
+        self.send_packet(peer, Box::new(packet));
+        true
+    }
+
+    /// Asks the peer to stop sending the download it's in the middle of. What arrived of it so
+    /// far is kept, see `take_partial_download`.
+    pub fn cancel_download(&self, peer: PeerId) {
+        self.send_packet(peer, Box::new(CancelDownloadPacket::new()));
+    }
+
+    /// Cancels the download we asked the peer for and stops waiting on it, for when the peer has
+    /// stopped answering. Unlike `cancel_download`, the session is free for another download
+    /// straight away, even if the peer never acknowledges the cancel.
+    pub fn abandon_download(&self, peer: PeerId) {
+        self.cancel_download(peer);
+        if let Some(session) = self.sessions.lock().unwrap().get(&peer) {
+            session.transfers.abandon_download();
         }
     }
 
-    #[cfg(test)]
-    pub fn connect_to_test(&mut self, local_songs: Arc<Mutex<Vec<SongFolder>>>, remote_songs: Arc<Mutex<Vec<SongFolder>>>, app_window: MockWindow) {
-        self.app_state = Some(AppState{ local_songs, remote_songs, app_window });
+    pub fn set_display_name(&mut self, display_name: String) {
+        self.display_name = display_name;
+    }
+
+    pub fn set_identity(&mut self, identity: Identity) {
+        self.identity = identity;
+    }
+
+    /// The key pair our connections are encrypted with, which peers know us by
+    pub fn identity(&self) -> Identity {
+        self.identity.clone()
+    }
+
+    /// The hello we introduce ourselves with when connecting to a peer
+    pub fn hello(&self) -> HelloPacket {
+        HelloPacket::new(self.display_name.clone())
     }
 
-    pub fn connect(&mut self, connection: TcpStream) {
-        if self.app_state.is_none() {
-            panic!("[Packet Manager] Connecting to socket before app is connected!");
+    /// Every peer we're currently connected to, in the order they connected
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        let mut peers: Vec<PeerInfo> = self.sessions.lock().unwrap().values()
+            .map(|session| session.info.clone())
+            .collect();
+        peers.sort_by_key(|peer| peer.id);
+        peers
+    }
+
+    pub fn peer(&self, peer: PeerId) -> Option<PeerInfo> {
+        self.sessions.lock().unwrap().get(&peer).map(|session| session.info.clone())
+    }
+
+    /// The songs the peer last told us it has
+    pub fn remote_songs(&self, peer: PeerId) -> Option<Vec<SongFolder>> {
+        self.sessions.lock().unwrap().get(&peer).map(|session| session.remote_songs.lock().unwrap().clone())
+    }
+
+    /// Starts talking to a newly connected peer, alongside any others we're connected to.
+    /// Returns the id the peer can be addressed with from then on.
+    pub fn connect(&mut self, connection: SecureStream, peer: HelloPacket) -> PeerId {
+        let app_state = match &self.app_state {
+            Some(app_state) => app_state.clone(),
+            None => panic!("[Packet Manager] Connecting to socket before app is connected!")
+        };
+        let public_key = connection.remote_public_key().to_string();
+
+        // A peer connecting again replaces its old connection. This will disconnect the old
+        // writing stream, and the peer will then send their own disconnect packet to close the
+        // reading stream. This ensures that if we're in the middle of reading something, it will complete
+        let old_sessions: Vec<PeerId> = self.sessions.lock().unwrap().values()
+            .filter(|session| session.info.public_key == public_key)
+            .map(|session| session.info.id)
+            .collect();
+        for old_session in old_sessions {
+            self.disconnect(old_session);
         }
 
-        // If there is a current connection, this will disconnect the writing stream, and the
-        // connected server will then their own disconnect packet to close the reading stream
-        // This ensures that if we're in the middle of reading something, it will complete
-        self.send_packet(Box::new(DisconnectPacket::new()));
+        let id = self.next_peer_id;
+        self.next_peer_id += 1;
 
         // Create new packet queue
         let (sender, receiver) = mpsc::channel(10);
-        self.packet_queue = Some(sender.clone());
+        let session = Session {
+            info: PeerInfo { id, public_key: public_key.clone(), hello: peer },
+            packet_queue: sender,
+            remote_songs: Arc::new(Mutex::new(Vec::new())),
+            transfers: Transfers {
+                peer: id,
+                bandwidth: Arc::new(PeerBandwidth::new(self.bandwidth.clone())),
+                uploads: Arc::new(Uploads::default()),
+                requested_maps: Arc::new(Mutex::new(Vec::new())),
+                partial_download: self.partial_downloads.entry(public_key).or_default().clone(),
+                download_outcomes: Arc::new(Mutex::new(None)),
+                pulling: Arc::new(AtomicBool::new(false)),
+                maps_outstanding: Arc::new(AtomicU32::new(0)),
+                pending_sync: Arc::new(Mutex::new(None))
+            }
+        };
 
+        // The session has to be known before the reading thread starts, so it can't finish first
         let (read_stream, write_stream) = connection.into_split();
-        self.start_reading_thread(read_stream, sender);
-        self.start_writing_thread(write_stream, receiver);
+        self.start_reading_thread(read_stream, &session);
+        self.start_writing_thread(id, write_stream, receiver, session.transfers.clone());
+        self.sessions.lock().unwrap().insert(id, session);
+        app_state.ui.emit("peer-connected", id);
+        id
+    }
+
+    /// Closes the connection to the peer, letting it finish anything it's in the middle of first
+    pub fn disconnect(&self, peer: PeerId) {
+        self.send_packet(peer, Box::new(DisconnectPacket::new()));
     }
 
-    fn start_reading_thread(&self, stream: OwnedReadHalf, packet_queue: mpsc::Sender<Box<dyn Packet>>) {
-        let local_songs = self.app_state.as_ref().unwrap().local_songs.clone();
-        let remote_songs = self.app_state.as_ref().unwrap().remote_songs.clone();
-        let window = self.app_state.as_ref().unwrap().app_window.clone();
+    fn start_reading_thread(&self, stream: SecureReadHalf, session: &Session) {
+        let app_state = self.app_state.clone().unwrap();
+        let peer = session.info.id;
+        let packet_queue = session.packet_queue.clone();
+        let sessions = self.sessions.clone();
+        let local_songs = app_state.local_songs.clone();
+        let remote_songs = session.remote_songs.clone();
+        let ui = app_state.ui.clone();
+        let download_mode = self.download_mode.clone();
+        let transfers = session.transfers.clone();
 
         tokio::spawn(async move {
             let mut buf_reader = BufReader::new(stream);
 
             loop {
-                // Since read_line includes the newline at the end, we need to pop the last
-                // character off each time
-                let mut packet_header = String::new();
-                buf_reader.read_line(&mut packet_header).await.unwrap();
-                packet_header.pop();
-
-                let mut raw_data = String::new();
-                buf_reader.read_line(&mut raw_data).await.unwrap();
-                raw_data.pop();
+                let frame = match read_frame(&mut buf_reader).await {
+                    Ok(frame) => frame,
+                    Err(err) => {
+                        println!("Unable to read the next packet, disconnecting: {err:?}");
+                        app_state.report_error(peer, &ErrorPacket::new(ErrorCode::ConnectionLost, err.to_string()));
+                        let _ = packet_queue.send(Box::new(DisconnectPacket::new())).await;
+                        break;
+                    }
+                };
 
-                match packet_header.as_str() {
+                match frame.header {
                     MapListRequestPacket::HEADER => {
                         println!("Map List Requested");
                         // Send back packet of currently loaded local songs
@@ -280,78 +1432,124 @@ impl PacketManager {
                     MapListPacket::HEADER => {
                         println!("Map List Received");
                         // Update list of remote songs to what we just received
-                        let new_remote_songs = MapListPacket::deserialize(raw_data);
-                        *remote_songs.lock().unwrap() = new_remote_songs.map_list;
+                        if let Some(new_remote_songs) = parse_packet::<MapListPacket>(frame, &packet_queue).await {
+                            *remote_songs.lock().unwrap() = new_remote_songs.map_list;
 
-                        // Let front-end know that list has been updated
-                        window.emit("remote-songs-updated", {}).unwrap();
+                            // Let front-end know that list has been updated
+                            ui.emit("remote-songs-updated", peer);
+                        }
                     },
                     DownloadRequestPacket::HEADER => {
                         println!("Download Requested");
-                        // Zip up the files requested and send them back in a response packet
-                        let maps_requested = DownloadRequestPacket::deserialize(raw_data);
+                        // Zip up the maps requested and stream them back one by one after a response packet
+                        let maps_requested = match parse_packet::<DownloadRequestPacket>(frame, &packet_queue).await {
+                            Some(maps_requested) => maps_requested,
+                            None => continue
+                        };
 
-                        // Get the corresponding local_song structs
-                        let songs_to_zip = {
+                        // Get the corresponding local_song structs. Any we don't have get an error
+                        // sent in their place, so the peer still gets every map in the order it asked
+                        let songs_to_zip: Vec<Result<MapRequest, ErrorPacket>> = {
                             let local_songs = local_songs.lock().unwrap();
                             maps_requested.requested_maps.iter()
-                                .map(|song| {
-                                    local_songs
+                                .map(|request| {
+                                    let song = local_songs
                                         .iter()
-                                        .find(|local_song| song.id == local_song.id && song.name == local_song.name)
-                                        .unwrap()
-                                        .clone()
+                                        .find(|local_song| request.song.id == local_song.id && request.song.name == local_song.name)
+                                        .ok_or_else(|| ErrorPacket::new(ErrorCode::MapNotFound,
+                                                                        format!("{} isn't in our songs", request.song.folder)))?
+                                        .clone();
+                                    Ok(MapRequest { song, ..request.clone() })
                                 })
                                 .collect()
                         };
 
-                        let zipped_maps = zip_local_files(songs_to_zip).await.unwrap();
-                        let zipped_maps = File::from_std(zipped_maps);
-                        let _ = packet_queue.send(Box::new(DownloadResponsePacket::new(zipped_maps).await)).await;
+                        let map_count = songs_to_zip.len() as u32;
+                        let maps = stream_maps(songs_to_zip, maps_requested.resume_from);
+                        transfers.uploads.pending.fetch_add(1, Ordering::SeqCst);
+                        let _ = packet_queue.send(Box::new(DownloadResponsePacket::new(map_count, maps))).await;
                     },
                     DownloadResponsePacket::HEADER => {
                         println!("Download Received");
-                        // Ask user where to store the files, then read zip file and unzip to folder
-                        let file_size = DownloadResponsePacket::deserialize(raw_data).zip_size;
-                        let mut file_data = buf_reader.take(file_size);
-
-                        let should_download = ask(Some(&window), "Download Zip",
-                            format!("You are about to download a {} MB zip file. Continue?", file_size / 1_000_000));
-                        if should_download {
-                            let file_path = FileDialogBuilder::new()
-                                .add_filter("Zip file", &["zip"])
-                                .save_file();
-
-                            if let Some(file_path) = file_path {
-                                let mut file = File::create(file_path).await.unwrap();
-
-                                window.emit("download-started", {}).unwrap();
-
-                                // Don't try and read the entire file into memory just in case it's large
-                                let mut buf = [0; 1024];
-                                let mut progress = 0;
-                                while file_data.limit() > 0 {
-                                    let n = file_data.read(&mut buf[..]).await.unwrap();
-                                    file.write_all(&buf[..n]).await.unwrap();
-
-                                    let new_progress = 100 - (100 * file_data.limit() / file_size);
-                                    if progress < new_progress {
-                                        progress = new_progress;
-                                        window.emit("download-progress", progress).unwrap();
-                                    }
-                                }
+                        let response = match parse_packet::<DownloadResponsePacket>(frame, &packet_queue).await {
+                            Some(response) => response,
+                            None => continue
+                        };
 
-                                window.emit("download-finished", {}).unwrap();
-                            }
+                        let download_mode = *download_mode.lock().unwrap();
+                        let received = app_state.receive_download(peer, response, &mut buf_reader, download_mode, &transfers).await;
+                        if let Err(err) = received {
+                            println!("Download failed, disconnecting: {err:?}");
+                            app_state.report_error(peer, &ErrorPacket::new(ErrorCode::ConnectionLost, err.to_string()));
+                            let _ = packet_queue.send(Box::new(DisconnectPacket::new())).await;
+                            break;
                         }
+                    },
+                    CancelDownloadPacket::HEADER => {
+                        println!("Download Canceled");
+                        transfers.uploads.cancel();
+                    },
+                    SyncRequestPacket::HEADER => {
+                        println!("Sync Requested");
+                        let sync = match parse_packet::<SyncRequestPacket>(frame, &packet_queue).await {
+                            Some(sync) => sync,
+                            None => continue
+                        };
+
+                        // The plan is worked out from our own songs and the map list the peer sent
+                        // just before the request, rather than taken from the request as is. What the
+                        // peer offers is what we receive, and what it wants is what we send.
+                        // The songs go straight into our songs folder, so there has to be one
+                        let local = local_songs.lock().unwrap().clone();
+                        let plan = SyncPlan::between(&local, &remote_songs.lock().unwrap())
+                            .agreed_with(&sync.offered, &sync.wanted);
+                        let songs_dir = app_state.local_path.lock().unwrap().clone();
+                        let accepted_into = songs_dir.filter(|_| {
+                            let display_name = sessions.lock().unwrap().get(&peer)
+                                .map(|session| session.info.hello.display_name.clone())
+                                .unwrap_or_default();
+                            ui.ask("Two-way Sync", &format!("{display_name} wants to sync with you. You will {}. Continue?",
+                                                            plan.summary().describe()))
+                        });
 
-                        // Throw away any remaining bytes in our Take wrapper
-                        // This is important if they cancel the operation and we don't save the
-                        // bytes to a file. If we have saved the bytes, then this does nothing.
-                        io::copy(&mut file_data, &mut sink()).await.unwrap();
+                        let _ = packet_queue.send(Box::new(SyncResponsePacket::new(accepted_into.is_some()))).await;
+                        if let Some(songs_dir) = accepted_into {
+                            let destination = DownloadDestination::Install(songs_dir);
+                            let requested_maps: Vec<MapRequest> = plan.to_receive.into_iter()
+                                .map(|song| MapRequest::full(song).with_format(destination.map_format()))
+                                .collect();
+                            if !requested_maps.is_empty() {
+                                if transfers.expect_download(&requested_maps, destination, None, false) {
+                                    let _ = packet_queue.send(Box::new(DownloadRequestPacket::new(requested_maps))).await;
+                                } else {
+                                    println!("Still downloading from the peer, so the songs of the sync weren't asked for");
+                                }
+                            }
+                        }
+                    },
+                    SyncResponsePacket::HEADER => {
+                        println!("Sync Response Received");
+                        let response = match parse_packet::<SyncResponsePacket>(frame, &packet_queue).await {
+                            Some(response) => response,
+                            None => continue
+                        };
 
-                        // Once we've done, consume the Take wrapper
-                        buf_reader = file_data.into_inner();
+                        let pending_sync = transfers.pending_sync.lock().unwrap().take();
+                        match (response.accepted, pending_sync) {
+                            (true, Some(download)) => {
+                                if !download.requested_maps.is_empty() {
+                                    if transfers.expect_download(&download.requested_maps, download.destination, None, false) {
+                                        let _ = packet_queue.send(Box::new(DownloadRequestPacket::new(download.requested_maps))).await;
+                                    } else {
+                                        println!("Still downloading from the peer, so the songs of the sync weren't asked for");
+                                    }
+                                }
+                                ui.emit("sync-accepted", peer);
+                            },
+                            (false, Some(_)) => ui.emit("sync-declined", peer),
+                            // We never asked the peer to sync
+                            (_, None) => {}
+                        }
                     },
                     DisconnectPacket::HEADER => {
                         println!("Disconnecting stream");
@@ -360,73 +1558,156 @@ impl PacketManager {
                         let _ = packet_queue.send(Box::new(DisconnectPacket::new())).await;
                         break;
                     },
+                    ErrorPacket::HEADER => {
+                        println!("Error Received");
+                        if let Some(error) = parse_packet::<ErrorPacket>(frame, &packet_queue).await {
+                            app_state.report_error(peer, &error);
+                            transfers.download_refused(&error);
+                        }
+                    },
                     _ => {
-                        println!("Unexpected header received: {:?}", packet_header)
+                        println!("Unexpected header received: {:?}", frame.header);
+                        let error = ErrorPacket::new(ErrorCode::UnexpectedPacket, format!("Unexpected packet {}", frame.header));
+                        let _ = packet_queue.send(Box::new(error)).await;
                     }
                 }
             }
 
             println!("Read stream disconnected");
+            if let Some(outcomes) = transfers.download_outcomes.lock().unwrap().take() {
+                // Whoever asked for the download decides whether what arrived of it gets picked back up
+                let partial_download = transfers.partial_download.lock().unwrap().take();
+                let _ = outcomes.send(DownloadOutcome::Disconnected { peer, partial_download });
+            }
+            sessions.lock().unwrap().remove(&peer);
+            ui.emit("peer-disconnected", peer);
         });
     }
 
-    fn start_writing_thread(&self, stream: OwnedWriteHalf, mut packet_queue: mpsc::Receiver<Box<dyn Packet>>) {
+    fn start_writing_thread(&self, peer: PeerId, stream: SecureWriteHalf, mut packet_queue: mpsc::Receiver<Box<dyn Packet>>,
+                            transfers: Transfers) {
+        let app_state = self.app_state.clone().unwrap();
+
         tokio::spawn(async move {
             let mut buf_writer = BufWriter::new(stream);
 
-            loop {
-                let mut packet = packet_queue.recv().await.unwrap();
-
-                println!("Writing {:?} packet...", packet.get_header());
-                println!("\t{:?}", packet.get_data());
-
-                let mut buf = Vec::new();
-                write!(&mut buf, "{}\n{}\n", packet.get_header(), packet.get_data()).unwrap();
-                buf_writer.write_all(&buf[..]).await.unwrap();
+            while let Some(mut packet) = packet_queue.recv().await {
+                let written = async {
+                    let frame = Frame::from_packet(packet.as_ref())?;
+                    println!("Writing Packet {{ header: {}, size: {} }}...", frame.header, frame.payload.len());
+                    write_frame(&mut buf_writer, &frame).await?;
 
-                let header = packet.get_header();
-                match header {
-                    DownloadResponsePacket::HEADER => {
-                        // Write the zip file to the stream
+                    if packet.get_header() == DownloadResponsePacket::HEADER {
+                        // Write each map to the stream as soon as it's been zipped up
                         let packet = packet.as_any()
                             .downcast_mut::<DownloadResponsePacket>().unwrap();
+                        let maps = packet.maps.as_mut().unwrap();
 
-                        // Don't try and read the entire file into memory just in case it's large
-                        let zip_file = packet.zipped_maps.as_mut().unwrap();
-                        let mut buf = [0; 1024];
-                        loop {
-                            let n = zip_file.read(&mut buf[..]).await.unwrap();
-                            if n == 0 {
-                                break;
+                        let uploads = &transfers.uploads;
+                        let mut maps_sent = 0;
+                        let mut canceled = false;
+                        'maps: loop {
+                            let map = tokio::select! {
+                                map = maps.recv() => map,
+                                _ = uploads.canceled() => {
+                                    canceled = true;
+                                    break;
+                                }
+                            };
+                            let map = match map {
+                                Some(Ok(map)) => map,
+                                Some(Err(error)) => {
+                                    write_frame(&mut buf_writer, &Frame::from_packet(&error)?).await?;
+                                    maps_sent += 1;
+                                    continue;
+                                },
+                                None => break
+                            };
+                            write_frame(&mut buf_writer, &Frame::from_packet(&map)?).await?;
+                            if let (Some(folder), Some(file_sizes)) = (&map.folder, &map.file_sizes) {
+                                // Each file is read from the songs folder as it's sent, a chunk at a time
+                                let mut chunk = vec![0; DOWNLOAD_CHUNK_SIZE];
+                                for (file, size) in map.manifest.files.iter().zip(file_sizes) {
+                                    let mut reader = File::open(folder.join(&file.path)).await?.take(*size);
+                                    let mut sent = 0;
+                                    loop {
+                                        let read = reader.read(&mut chunk).await?;
+                                        if read == 0 {
+                                            break;
+                                        }
+                                        if !send_map_data(&mut buf_writer, &transfers, &chunk[..read]).await? {
+                                            canceled = true;
+                                            break 'maps;
+                                        }
+                                        sent += read as u64;
+                                    }
+                                    if sent < *size {
+                                        return Err(CodecError::IOError(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                            format!("{} got smaller while it was being sent", file.path))));
+                                    }
+                                }
+                            } else {
+                                let osz_data = map.osz_data.as_ref().unwrap();
+                                for chunk in osz_data[map.offset as usize..].chunks(DOWNLOAD_CHUNK_SIZE) {
+                                    if !send_map_data(&mut buf_writer, &transfers, chunk).await? {
+                                        canceled = true;
+                                        break 'maps;
+                                    }
+                                }
                             }
-                            buf_writer.write_all(&buf[..n]).await.unwrap();
+                            buf_writer.flush().await?;
+                            maps_sent += 1;
                         }
-                    },
-                    DisconnectPacket::HEADER => {
-                        break;
-                    },
-                    _ => {
-                        // We don't need to do anything special for other kinds of packets
+                        uploads.finished();
+
+                        if canceled {
+                            // Dropping the maps stops the rest from being zipped, and this lets
+                            // the peer know where the download ends
+                            println!("Stopped sending the download after {maps_sent} of {} maps", packet.map_count);
+                            packet.maps = None;
+                            write_frame(&mut buf_writer, &Frame::from_packet(&CancelDownloadPacket::new())?).await?;
+                        } else if maps_sent < packet.map_count {
+                            // If we stopped handing over maps early, the peer would be left waiting on them forever
+                            return Err(CodecError::IOError(io::Error::new(io::ErrorKind::Other,
+                                format!("Only able to send {maps_sent} of {} maps", packet.map_count))));
+                        }
+                        app_state.ui.emit("upload-finished", peer);
                     }
-                }
 
-                // Flush writer to make sure the entire packet is written
-                buf_writer.flush().await.unwrap();
+                    // Flush writer to make sure the entire packet is written
+                    buf_writer.flush().await?;
+                    Ok::<(), CodecError>(())
+                }.await;
+
+                if let Err(err) = written {
+                    println!("Unable to write packet: {err:?}");
+                    // The peer may well have hung up first if we were only saying goodbye
+                    if packet.get_header() != DisconnectPacket::HEADER {
+                        app_state.report_error(peer, &ErrorPacket::new(ErrorCode::ConnectionLost, err.to_string()));
+                    }
+                    break;
+                }
+                if packet.get_header() == DisconnectPacket::HEADER {
+                    break;
+                }
             }
 
             println!("Write stream disconnected");
         });
     }
 
-    /// Spawn a tokio task to eventually send our packet in the queue
+    /// Spawn a tokio task to eventually send our packet in the queue of the given peer
     /// Here we spawn a task to avoid making send_packet async, which would
     /// make things annoying since every use of PacketManager will be behind a mutex
-    pub fn send_packet(&self, packet: Box<dyn Packet>) {
-        if let Some(packet_queue) = &self.packet_queue {
-            let packet_queue = packet_queue.clone();
+    pub fn send_packet(&self, peer: PeerId, packet: Box<dyn Packet>) {
+        let packet_queue = self.sessions.lock().unwrap().get(&peer)
+            .map(|session| session.packet_queue.clone());
+        if let Some(packet_queue) = packet_queue {
             tokio::spawn(async move {
-                packet_queue.send(packet).await.unwrap();
+                if packet_queue.send(packet).await.is_err() {
+                    println!("Unable to send packet, the connection has already closed");
+                }
             });
         }
     }
-}
\ No newline at end of file
+}
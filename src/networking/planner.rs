@@ -0,0 +1,237 @@
+use std::collections::{BTreeMap, HashSet};
+use std::sync::Mutex;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+use crate::file_manager::{MapRequest, SongFolder};
+use crate::networking::download::{DownloadDestination, PartialDownload};
+use crate::networking::packets::{PacketManager, PeerId};
+
+/// How long a pull waits for any of the peers it's downloading from to finish a map before it
+/// stops waiting on them, so a peer that stops answering can't hold the pull up forever
+const PULL_IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Why a map we asked for didn't end up stored
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum MapError {
+    /// The peer couldn't send the map, we couldn't store it, or the download was stopped
+    #[error("{0}")]
+    Failed(String),
+    /// The map arrived, but doesn't match the manifest the peer sent along with it
+    #[error("{0}")]
+    Damaged(String)
+}
+
+/// Sent by a session as the maps it was asked for as part of a pull or the transfer queue arrive
+#[derive(Debug, Clone)]
+pub enum DownloadOutcome {
+    /// A map was stored, or couldn't be, in which case it's worth asking another peer for it
+    Map { peer: PeerId, folder: String, result: Result<(), MapError> },
+    /// The connection to the peer closed, so any maps it didn't get to won't be coming. What
+    /// arrived of them is handed over, unless the download was part of a pull.
+    Disconnected { peer: PeerId, partial_download: Option<PartialDownload> }
+}
+
+/// How a pull went, once every map has either arrived or run out of peers to get it from
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct PullSummary {
+    /// Folders of the maps we got
+    pub downloaded: Vec<String>,
+    /// Folders of the maps no peer was able to send, along with the last reason why
+    pub unavailable: Vec<(String, String)>
+}
+
+/// A map we still need, and the peers we've already tried getting it from
+#[derive(Debug, Clone)]
+struct PlannedMap {
+    request: MapRequest,
+    tried: HashSet<PeerId>,
+    last_error: Option<String>
+}
+
+/// Splits a set of maps between the peers that have them, so they can be downloaded from several
+/// peers at once. A peer only counts as having a map if its copy has the same checksum as the one
+/// we asked for. Each peer is only sent one request at a time, since a session can only receive
+/// one download at a time.
+#[derive(Debug)]
+pub struct DownloadPlanner {
+    remote_songs: BTreeMap<PeerId, Vec<SongFolder>>,
+    /// Maps waiting to be requested, in the order they were asked for
+    pending: Vec<PlannedMap>,
+    /// Maps that have been requested from each peer, and haven't arrived yet
+    active: BTreeMap<PeerId, Vec<PlannedMap>>,
+    summary: PullSummary
+}
+
+impl DownloadPlanner {
+    pub fn new(requests: Vec<MapRequest>, remote_songs: BTreeMap<PeerId, Vec<SongFolder>>) -> Self {
+        let pending = requests.into_iter()
+            .map(|request| PlannedMap { request, tried: HashSet::new(), last_error: None })
+            .collect();
+        Self { remote_songs, pending, active: BTreeMap::new(), summary: PullSummary::default() }
+    }
+
+    /// Whether the peer has the exact copy of the map we asked for
+    fn has_map(&self, peer: PeerId, request: &MapRequest) -> bool {
+        self.remote_songs.get(&peer).is_some_and(|songs| {
+            songs.iter().any(|song| song.id == request.song.id && song.name == request.song.name
+                && song.checksum == request.song.checksum)
+        })
+    }
+
+    /// Hands the waiting maps out to the peers that aren't busy, spreading them out as evenly as
+    /// possible. Maps that only busy peers have stay waiting until one of them is done, and maps
+    /// no peer we haven't tried has are given up on. Returns the requests to send to each peer.
+    pub fn assign(&mut self) -> Vec<(PeerId, Vec<MapRequest>)> {
+        let mut assigned: BTreeMap<PeerId, Vec<PlannedMap>> = BTreeMap::new();
+        let mut still_pending = Vec::new();
+
+        for map in std::mem::take(&mut self.pending) {
+            let candidates: Vec<PeerId> = self.remote_songs.keys()
+                .copied()
+                .filter(|peer| !map.tried.contains(peer) && self.has_map(*peer, &map.request))
+                .collect();
+            if candidates.is_empty() {
+                let reason = map.last_error.unwrap_or_else(|| "None of the connected peers have this map".to_string());
+                self.summary.unavailable.push((map.request.song.folder, reason));
+                continue;
+            }
+
+            // Give the map to whichever idle peer has the fewest so far
+            let peer = candidates.into_iter()
+                .filter(|peer| !self.active.contains_key(peer))
+                .min_by_key(|peer| assigned.get(peer).map_or(0, |maps| maps.len()));
+            match peer {
+                Some(peer) => assigned.entry(peer).or_default().push(map),
+                None => still_pending.push(map)
+            }
+        }
+        self.pending = still_pending;
+
+        let requests = assigned.iter()
+            .map(|(peer, maps)| (*peer, maps.iter().map(|map| map.request.clone()).collect()))
+            .collect();
+        self.active.extend(assigned);
+        requests
+    }
+
+    /// Records that a map from the given peer arrived, or failed to
+    pub fn map_finished(&mut self, peer: PeerId, folder: &str, result: Result<(), String>) {
+        let maps = match self.active.get_mut(&peer) {
+            Some(maps) => maps,
+            None => return
+        };
+        let mut map = match maps.iter().position(|map| map.request.song.folder == folder) {
+            Some(index) => maps.remove(index),
+            None => return
+        };
+        if maps.is_empty() {
+            self.active.remove(&peer);
+        }
+
+        match result {
+            Ok(()) => self.summary.downloaded.push(map.request.song.folder),
+            Err(err) => {
+                map.tried.insert(peer);
+                map.last_error = Some(err);
+                self.pending.push(map);
+            }
+        }
+    }
+
+    /// Records that we lost the connection to a peer, so everything it didn't send has to come
+    /// from someone else
+    pub fn peer_lost(&mut self, peer: PeerId) {
+        self.drop_peer(peer, "The connection to the peer was lost");
+    }
+
+    /// Records that a peer is still sending us a download that isn't part of the pull, so the
+    /// maps we were going to ask it for have to come from someone else
+    pub fn peer_busy(&mut self, peer: PeerId) {
+        self.drop_peer(peer, "The peer is still sending us another download");
+    }
+
+    /// Stops waiting on every peer that's sending us something, since none of them have finished
+    /// a map in a long while. Returns the peers that were dropped.
+    pub fn timed_out(&mut self) -> Vec<PeerId> {
+        let peers: Vec<PeerId> = self.active.keys().copied().collect();
+        for peer in &peers {
+            self.drop_peer(*peer, "The peer stopped answering");
+        }
+        peers
+    }
+
+    fn drop_peer(&mut self, peer: PeerId, reason: &str) {
+        self.remote_songs.remove(&peer);
+        for mut map in self.active.remove(&peer).unwrap_or_default() {
+            map.tried.insert(peer);
+            map.last_error = Some(reason.to_string());
+            self.pending.push(map);
+        }
+    }
+
+    /// Whether every map has either arrived or been given up on
+    pub fn is_done(&self) -> bool {
+        self.pending.is_empty() && self.active.is_empty()
+    }
+
+    pub fn summary(self) -> PullSummary {
+        self.summary
+    }
+}
+
+/// Downloads the given maps from every connected peer that has them at once, asking another
+/// peer for any map that fails. Peers that go `PULL_IDLE_TIMEOUT` without finishing a map are
+/// given up on too. The user should already have agreed to the download, since none of the
+/// peers will ask.
+pub async fn pull_maps(packet_manager: &Mutex<PacketManager>, requests: Vec<MapRequest>, destination: DownloadDestination) -> PullSummary {
+    let remote_songs = {
+        let packet_manager = packet_manager.lock().unwrap();
+        packet_manager.peers().into_iter()
+            .filter_map(|peer| Some((peer.id, packet_manager.remote_songs(peer.id)?)))
+            .collect()
+    };
+    let mut planner = DownloadPlanner::new(requests, remote_songs);
+    let (sender, mut outcomes) = mpsc::unbounded_channel();
+
+    loop {
+        let mut lost_peer = false;
+        for (peer, maps) in planner.assign() {
+            let packet_manager = packet_manager.lock().unwrap();
+            if packet_manager.start_pull(peer, maps, destination.clone(), sender.clone()) {
+                continue;
+            }
+            if packet_manager.peer(peer).is_some() {
+                planner.peer_busy(peer);
+            } else {
+                planner.peer_lost(peer);
+            }
+            lost_peer = true;
+        }
+        if planner.is_done() {
+            break;
+        }
+        // The maps of a peer that's gone or busy can be handed straight to someone else
+        if lost_peer {
+            continue;
+        }
+
+        // We hold on to a sender, so this only ends once we stop waiting
+        match timeout(PULL_IDLE_TIMEOUT, outcomes.recv()).await {
+            Ok(Some(DownloadOutcome::Map { peer, folder, result })) => {
+                planner.map_finished(peer, &folder, result.map_err(|err| err.to_string()))
+            },
+            Ok(Some(DownloadOutcome::Disconnected { peer, .. })) => planner.peer_lost(peer),
+            Ok(None) => break,
+            Err(_) => {
+                let packet_manager = packet_manager.lock().unwrap();
+                for peer in planner.timed_out() {
+                    packet_manager.abandon_download(peer);
+                }
+            }
+        }
+    }
+
+    planner.summary()
+}
@@ -0,0 +1,349 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tokio::task;
+use crate::file_manager::MapRequest;
+use crate::networking::download::{DownloadDestination, PartialDownload};
+use crate::networking::packets::{PacketManager, PeerId};
+use crate::networking::planner::{DownloadOutcome, MapError};
+use crate::ui::UiBridge;
+
+/// How many times a map that keeps arriving damaged is asked for before it's marked as failed
+const MAX_ATTEMPTS: u32 = 3;
+
+/// Identifies a transfer in the queue. Ids aren't reused.
+pub type TransferId = u64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub enum TransferState {
+    /// Waiting for the peer to be done with the transfers ahead of it
+    Queued,
+    /// Being received from the peer
+    Active,
+    /// Put on hold by the user. If any of the map arrived, it carries on from there once resumed.
+    Paused,
+    Done,
+    Failed
+}
+
+/// A single map to download from a peer, as shown to the front-end
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Transfer {
+    pub id: TransferId,
+    pub peer: PeerId,
+    /// Folder name of the map
+    pub folder: String,
+    pub state: TransferState,
+    /// Why the transfer failed, or why it's being tried again
+    pub error: Option<String>,
+    /// How many times the map has been asked for
+    pub attempts: u32,
+    #[serde(skip)]
+    request: MapRequest,
+    #[serde(skip)]
+    destination: DownloadDestination,
+    /// What arrived of the map before it was paused or the connection dropped
+    #[serde(skip)]
+    partial_download: Option<PartialDownload>,
+    /// Public key of the peer, so the transfer can carry on once the peer reconnects under a new id
+    #[serde(skip)]
+    public_key: Option<String>
+}
+
+/// Maps waiting to be downloaded, one at a time from each peer, in an order the user can change.
+/// Each transfer can be paused, resumed or canceled on its own, and stopping the one a peer is
+/// sending stops the peer from zipping up and sending the rest of it. Maps that arrive damaged
+/// are asked for again, if `retry_damaged` is set.
+#[derive(Debug)]
+pub struct TransferQueue {
+    packet_manager: Arc<Mutex<PacketManager>>,
+    /// Every transfer, including finished ones, in the order they get started in
+    transfers: Vec<Transfer>,
+    next_id: TransferId,
+    /// The transfer each peer is sending us. A transfer that was canceled stays in here until
+    /// the peer is done with it, so nothing else is asked of the peer in the meantime.
+    active: HashMap<PeerId, TransferId>,
+    retry_damaged: bool,
+    outcomes: mpsc::UnboundedSender<DownloadOutcome>,
+    /// Taken once the queue starts handling outcomes, see `TransferQueue::start`
+    outcome_receiver: Option<mpsc::UnboundedReceiver<DownloadOutcome>>,
+    ui: Option<Arc<dyn UiBridge>>
+}
+
+impl TransferQueue {
+    pub fn new(packet_manager: Arc<Mutex<PacketManager>>) -> Self {
+        let (outcomes, outcome_receiver) = mpsc::unbounded_channel();
+        Self {
+            packet_manager,
+            transfers: Vec::new(),
+            next_id: 1,
+            active: HashMap::new(),
+            retry_damaged: true,
+            outcomes,
+            outcome_receiver: Some(outcome_receiver),
+            ui: None
+        }
+    }
+
+    /// Starts keeping track of how the transfers go, letting the user know whenever one of them
+    /// changes. Nothing is downloaded until this is called.
+    pub fn start(queue: &Arc<Mutex<Self>>, ui: Arc<dyn UiBridge>) {
+        let mut outcome_receiver = {
+            let mut queue = queue.lock().unwrap();
+            queue.ui = Some(ui);
+            match queue.outcome_receiver.take() {
+                Some(outcome_receiver) => outcome_receiver,
+                None => return
+            }
+        };
+
+        let handling_queue = queue.clone();
+        tokio::spawn(async move {
+            // The queue holds on to a sender, so this runs for as long as the queue is around
+            while let Some(outcome) = outcome_receiver.recv().await {
+                handling_queue.lock().unwrap().outcome(outcome);
+            }
+        });
+        queue.lock().unwrap().start_next();
+    }
+
+    /// Every transfer, in the order they get started in
+    pub fn transfers(&self) -> Vec<Transfer> {
+        self.transfers.clone()
+    }
+
+    /// Sets whether maps that arrive damaged are asked for again
+    pub fn set_retry_damaged(&mut self, retry_damaged: bool) {
+        self.retry_damaged = retry_damaged;
+    }
+
+    /// Adds the given maps to the end of the queue, returning the ids of their transfers
+    pub fn enqueue(&mut self, peer: PeerId, requests: Vec<MapRequest>, destination: DownloadDestination) -> Vec<TransferId> {
+        let public_key = self.packet_manager.lock().unwrap().peer(peer).map(|info| info.public_key);
+        let mut ids = Vec::new();
+        for request in requests {
+            let id = self.next_id;
+            self.next_id += 1;
+            self.transfers.push(Transfer {
+                id,
+                peer,
+                folder: request.song.folder.clone(),
+                state: TransferState::Queued,
+                error: None,
+                attempts: 0,
+                request,
+                destination: destination.clone(),
+                partial_download: None,
+                public_key: public_key.clone()
+            });
+            self.updated(id);
+            ids.push(id);
+        }
+        self.start_next();
+        ids
+    }
+
+    /// Puts a queued or active transfer on hold. Returns false if it can't be paused.
+    pub fn pause(&mut self, id: TransferId) -> bool {
+        let transfer = match self.transfers.iter_mut().find(|transfer| transfer.id == id) {
+            Some(transfer) => transfer,
+            None => return false
+        };
+        match transfer.state {
+            TransferState::Queued => {},
+            // What arrived so far is picked up once the peer has stopped
+            TransferState::Active => self.packet_manager.lock().unwrap().cancel_download(transfer.peer),
+            _ => return false
+        }
+        transfer.state = TransferState::Paused;
+        self.updated(id);
+        true
+    }
+
+    /// Puts a paused or failed transfer back in the queue, where it was before. A transfer that
+    /// failed because the connection dropped carries on from where it got to once the peer is
+    /// back. Returns false if it can't be resumed.
+    pub fn resume(&mut self, id: TransferId) -> bool {
+        let transfer = match self.transfers.iter_mut().find(|transfer| transfer.id == id) {
+            Some(transfer) => transfer,
+            None => return false
+        };
+        if !matches!(transfer.state, TransferState::Paused | TransferState::Failed) {
+            return false;
+        }
+        transfer.state = TransferState::Queued;
+        transfer.error = None;
+        self.updated(id);
+        self.start_next();
+        true
+    }
+
+    /// Takes a transfer out of the queue, stopping it if it's active and throwing away what
+    /// arrived of it. Finished transfers are just cleared from the list. Returns false if there
+    /// is no such transfer.
+    pub fn cancel(&mut self, id: TransferId) -> bool {
+        let index = match self.transfers.iter().position(|transfer| transfer.id == id) {
+            Some(index) => index,
+            None => return false
+        };
+        let transfer = self.transfers.remove(index);
+        if transfer.state == TransferState::Active {
+            self.packet_manager.lock().unwrap().cancel_download(transfer.peer);
+        }
+        if let Some(partial_download) = transfer.partial_download {
+            partial_download.discard();
+        }
+        if let Some(ui) = &self.ui {
+            ui.emit("transfer-removed", id);
+        }
+        true
+    }
+
+    /// Moves a transfer to the given position in the queue, or to the end if it's past the end.
+    /// Returns false if there is no such transfer.
+    pub fn move_to(&mut self, id: TransferId, index: usize) -> bool {
+        let current = match self.transfers.iter().position(|transfer| transfer.id == id) {
+            Some(current) => current,
+            None => return false
+        };
+        let transfer = self.transfers.remove(current);
+        self.transfers.insert(index.min(self.transfers.len()), transfer);
+        if let Some(ui) = &self.ui {
+            ui.emit("transfers-reordered", self.transfers.iter().map(|transfer| transfer.id).collect::<Vec<_>>());
+        }
+        self.start_next();
+        true
+    }
+
+    /// Asks each idle peer for the first transfer queued for it
+    fn start_next(&mut self) {
+        // Wait for the user to be able to see what's going on
+        if self.ui.is_none() {
+            return;
+        }
+
+        let mut started = Vec::new();
+        {
+            let packet_manager = self.packet_manager.lock().unwrap();
+            for transfer in self.transfers.iter_mut() {
+                if transfer.state != TransferState::Queued {
+                    continue;
+                }
+                // A peer that reconnected comes back under a new id
+                if packet_manager.peer(transfer.peer).is_none() {
+                    let reconnected = packet_manager.peers().into_iter()
+                        .find(|info| transfer.public_key.as_ref() == Some(&info.public_key));
+                    if let Some(info) = reconnected {
+                        transfer.peer = info.id;
+                    }
+                }
+                if self.active.contains_key(&transfer.peer) {
+                    continue;
+                }
+
+                let peer = transfer.peer;
+                transfer.attempts += 1;
+                let asked = match transfer.partial_download.take() {
+                    Some(partial_download) => {
+                        resume_in_background(self.packet_manager.clone(), peer, partial_download, self.outcomes.clone());
+                        true
+                    },
+                    None => packet_manager.start_download(peer, vec![transfer.request.clone()], transfer.destination.clone(),
+                                                          self.outcomes.clone())
+                };
+                if asked {
+                    transfer.state = TransferState::Active;
+                    self.active.insert(peer, transfer.id);
+                } else {
+                    transfer.state = TransferState::Failed;
+                    transfer.error = Some(match packet_manager.peer(peer) {
+                        Some(_) => "The peer is still sending us another download".to_string(),
+                        None => "Not connected to the peer".to_string()
+                    });
+                }
+                started.push(transfer.id);
+            }
+        }
+
+        for id in started {
+            self.updated(id);
+        }
+    }
+
+    /// Records how the transfer a peer was sending went, and moves on to the next one
+    fn outcome(&mut self, outcome: DownloadOutcome) {
+        let (peer, result, partial_download) = match outcome {
+            DownloadOutcome::Map { peer, result, .. } => (peer, result, None),
+            DownloadOutcome::Disconnected { peer, partial_download } => {
+                (peer, Err(MapError::Failed("The connection to the peer was lost".to_string())), partial_download)
+            }
+        };
+        let id = match self.active.remove(&peer) {
+            Some(id) => id,
+            None => return
+        };
+
+        let partial_download = partial_download.or_else(|| self.packet_manager.lock().unwrap().take_partial_download(peer));
+        match self.transfers.iter_mut().find(|transfer| transfer.id == id) {
+            Some(transfer) => {
+                let before = (transfer.state, transfer.error.clone());
+                match (result, transfer.state) {
+                    // The map may have finished arriving before the peer could stop
+                    (Ok(()), _) => {
+                        transfer.state = TransferState::Done;
+                        transfer.error = None;
+                        transfer.partial_download = None;
+                    },
+                    // The user stopped it, and may have already resumed it since
+                    (Err(_), state) if state != TransferState::Active => transfer.partial_download = partial_download,
+                    (Err(MapError::Damaged(err)), _) if self.retry_damaged && transfer.attempts < MAX_ATTEMPTS => {
+                        transfer.state = TransferState::Queued;
+                        transfer.error = Some(err);
+                    },
+                    // Whatever arrived before the connection dropped is kept for when the transfer is resumed
+                    (Err(err), _) => {
+                        transfer.state = TransferState::Failed;
+                        transfer.error = Some(err.to_string());
+                        transfer.partial_download = partial_download;
+                    }
+                }
+                if before != (transfer.state, transfer.error.clone()) {
+                    self.updated(id);
+                }
+            },
+            // The transfer was canceled, so what arrived of it isn't wanted
+            None => {
+                if let Some(partial_download) = partial_download {
+                    partial_download.discard();
+                }
+            }
+        }
+        self.start_next();
+    }
+
+    /// Lets the front-end know about the current state of a transfer
+    fn updated(&self, id: TransferId) {
+        let transfer = self.transfers.iter().find(|transfer| transfer.id == id);
+        if let (Some(ui), Some(transfer)) = (&self.ui, transfer) {
+            ui.emit("transfer-updated", transfer);
+        }
+    }
+}
+
+/// Asks the peer to carry on with a paused transfer. Working out where to carry on from reads
+/// the part file, so it's done in the background. If that fails, the map starts over.
+fn resume_in_background(packet_manager: Arc<Mutex<PacketManager>>, peer: PeerId, partial_download: PartialDownload,
+                        outcomes: mpsc::UnboundedSender<DownloadOutcome>) {
+    tokio::spawn(async move {
+        let (partial_download, resume_from) = task::spawn_blocking(move || {
+            let resume_from = partial_download.resume_from();
+            (partial_download, resume_from)
+        }).await.unwrap();
+
+        let resumed = packet_manager.lock().unwrap()
+            .resume_download(peer, partial_download.clone(), resume_from.unwrap_or(None), Some(outcomes.clone()));
+        if !resumed {
+            let _ = outcomes.send(DownloadOutcome::Disconnected { peer, partial_download: Some(partial_download) });
+        }
+    });
+}
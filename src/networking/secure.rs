@@ -0,0 +1,361 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
+use data_encoding::HEXUPPER;
+use snow::{Builder, StatelessTransportState};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::time::timeout;
+
+/// Noise pattern used for every connection. XX has both sides send their static key during the
+/// handshake, so each side learns who the other is without knowing anything beforehand.
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+/// Largest message Noise will encrypt or decrypt, including the tag
+const MAX_MESSAGE_SIZE: usize = 65535;
+/// Size of the authentication tag added to each encrypted message
+const TAG_SIZE: usize = 16;
+/// Most data that fits in a single encrypted message
+const MAX_PLAINTEXT_SIZE: usize = MAX_MESSAGE_SIZE - TAG_SIZE;
+/// How long the peer has to finish the handshake, so a peer that doesn't speak Noise (e.g. an
+/// older build) can't leave us waiting forever
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn noise_error(err: snow::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
+
+/// The long-term key pair that identifies us to peers
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct Identity {
+    #[serde(with = "hex")]
+    private_key: Vec<u8>,
+    #[serde(with = "hex")]
+    public_key: Vec<u8>
+}
+impl Identity {
+    pub fn generate() -> Self {
+        let keypair = Builder::new(NOISE_PARAMS.parse().unwrap()).generate_keypair().unwrap();
+        Self { private_key: keypair.private, public_key: keypair.public }
+    }
+
+    /// Loads the identity stored at `path`, generating and storing a new one if there isn't a
+    /// valid one there yet
+    pub fn load_or_generate(path: &Path) -> io::Result<Self> {
+        let stored = fs::read(path).ok()
+            .and_then(|stored| serde_json::from_slice(&stored).ok());
+        if let Some(identity) = stored {
+            return Ok(identity);
+        }
+
+        let identity = Self::generate();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        write_private(path, &serde_json::to_vec(&identity)?)?;
+        Ok(identity)
+    }
+
+    /// How peers know us, as a hex string
+    pub fn public_key(&self) -> String {
+        HEXUPPER.encode(&self.public_key)
+    }
+}
+/// Writes a file that only we get to read, such as one holding our private key. It's written to
+/// a temporary file first and then moved into place, so the old file is never left half written.
+pub fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let temp_path = PathBuf::from(temp_path);
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(&temp_path)?;
+    // The mode only applies to new files, so tighten up one left over from before
+    #[cfg(unix)]
+    file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&temp_path, path)
+}
+
+impl std::fmt::Debug for Identity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Keep the private key out of any logs
+        f.debug_struct("Identity").field("public_key", &self.public_key()).finish()
+    }
+}
+
+/// Stores keys as hex strings rather than arrays of numbers
+mod hex {
+    use data_encoding::HEXUPPER;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(key: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&HEXUPPER.encode(key))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let key = String::deserialize(deserializer)?;
+        HEXUPPER.decode(key.as_bytes()).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Writes a handshake message, prefixed with its length like every other Noise message
+async fn write_message<W: AsyncWrite + Unpin>(writer: &mut W, message: &[u8]) -> io::Result<()> {
+    writer.write_u16(message.len() as u16).await?;
+    writer.write_all(message).await?;
+    writer.flush().await
+}
+
+async fn read_message<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let len = reader.read_u16().await? as usize;
+    let mut message = vec![0; len];
+    reader.read_exact(&mut message).await?;
+    Ok(message)
+}
+
+/// A connection to a peer where everything is encrypted and authenticated with Noise. Each
+/// write is sent as one or more encrypted messages, and reads hand back the decrypted data, so
+/// frames can be read and written the same way as over a plain `TcpStream`.
+pub struct SecureStream {
+    reader: SecureReadHalf,
+    writer: SecureWriteHalf,
+    remote_public_key: String,
+    handshake_hash: Vec<u8>
+}
+
+impl SecureStream {
+    /// Runs the handshake as the side that opened the connection
+    pub async fn connect(stream: TcpStream, identity: &Identity) -> io::Result<Self> {
+        Self::handshake(stream, identity, true).await
+    }
+
+    /// Runs the handshake as the side that accepted the connection
+    pub async fn accept(stream: TcpStream, identity: &Identity) -> io::Result<Self> {
+        Self::handshake(stream, identity, false).await
+    }
+
+    async fn handshake(mut stream: TcpStream, identity: &Identity, initiator: bool) -> io::Result<Self> {
+        let builder = Builder::new(NOISE_PARAMS.parse().unwrap())
+            .local_private_key(&identity.private_key);
+        let mut handshake = if initiator { builder.build_initiator() } else { builder.build_responder() }
+            .map_err(noise_error)?;
+
+        let handshake_done = async {
+            let mut buf = vec![0; MAX_MESSAGE_SIZE];
+            while !handshake.is_handshake_finished() {
+                if handshake.is_my_turn() {
+                    let len = handshake.write_message(&[], &mut buf).map_err(noise_error)?;
+                    write_message(&mut stream, &buf[..len]).await?;
+                } else {
+                    let message = read_message(&mut stream).await?;
+                    handshake.read_message(&message, &mut buf).map_err(noise_error)?;
+                }
+            }
+            Ok::<(), io::Error>(())
+        };
+        timeout(HANDSHAKE_TIMEOUT, handshake_done).await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "The peer didn't finish the handshake"))??;
+
+        // XX always sends the static key, so it's there once the handshake is done
+        let remote_public_key = HEXUPPER.encode(handshake.get_remote_static().unwrap());
+        let handshake_hash = handshake.get_handshake_hash().to_vec();
+        let transport = Arc::new(handshake.into_stateless_transport_mode().map_err(noise_error)?);
+        let (read_stream, write_stream) = stream.into_split();
+        Ok(Self {
+            reader: SecureReadHalf::new(read_stream, transport.clone()),
+            writer: SecureWriteHalf::new(write_stream, transport),
+            remote_public_key,
+            handshake_hash
+        })
+    }
+
+    /// The public key the peer proved it holds during the handshake, as a hex string
+    pub fn remote_public_key(&self) -> &str {
+        &self.remote_public_key
+    }
+
+    /// Hash of the whole handshake, which is the same on both sides of this connection and
+    /// different for every other connection
+    pub fn handshake_hash(&self) -> &[u8] {
+        &self.handshake_hash
+    }
+
+    /// Splits the stream so it can be read from and written to by different tasks
+    pub fn into_split(self) -> (SecureReadHalf, SecureWriteHalf) {
+        (self.reader, self.writer)
+    }
+}
+
+impl AsyncRead for SecureStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().reader).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for SecureStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().writer).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().writer).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().writer).poll_shutdown(cx)
+    }
+}
+
+/// The reading half of a `SecureStream`
+pub struct SecureReadHalf {
+    stream: OwnedReadHalf,
+    transport: Arc<StatelessTransportState>,
+    /// Each message from the peer uses the next nonce, starting from 0
+    nonce: u64,
+    /// The encrypted message being read in, starting with its length
+    incoming: Vec<u8>,
+    incoming_len: usize,
+    /// Decrypted data that hasn't been read yet
+    plaintext: Vec<u8>,
+    plaintext_pos: usize
+}
+
+impl SecureReadHalf {
+    fn new(stream: OwnedReadHalf, transport: Arc<StatelessTransportState>) -> Self {
+        Self {
+            stream,
+            transport,
+            nonce: 0,
+            incoming: vec![0; 2 + MAX_MESSAGE_SIZE],
+            incoming_len: 0,
+            plaintext: Vec::new(),
+            plaintext_pos: 0
+        }
+    }
+}
+
+impl AsyncRead for SecureReadHalf {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.plaintext_pos < this.plaintext.len() {
+                let len = buf.remaining().min(this.plaintext.len() - this.plaintext_pos);
+                buf.put_slice(&this.plaintext[this.plaintext_pos..this.plaintext_pos + len]);
+                this.plaintext_pos += len;
+                return Poll::Ready(Ok(()));
+            }
+
+            // Read the length of the next message, then the message itself
+            let message_len = if this.incoming_len < 2 {
+                None
+            } else {
+                Some(u16::from_be_bytes([this.incoming[0], this.incoming[1]]) as usize)
+            };
+            match message_len {
+                Some(message_len) if message_len < TAG_SIZE => {
+                    return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, "Received a message that is too short")));
+                },
+                Some(message_len) if this.incoming_len == 2 + message_len => {
+                    this.plaintext.resize(MAX_MESSAGE_SIZE, 0);
+                    let len = this.transport.read_message(this.nonce, &this.incoming[2..this.incoming_len], &mut this.plaintext)
+                        .map_err(noise_error)?;
+                    this.plaintext.truncate(len);
+                    this.plaintext_pos = 0;
+                    this.nonce += 1;
+                    this.incoming_len = 0;
+                    continue;
+                },
+                _ => {}
+            }
+
+            let wanted = message_len.map_or(2, |message_len| 2 + message_len);
+            let mut read_buf = ReadBuf::new(&mut this.incoming[this.incoming_len..wanted]);
+            ready!(Pin::new(&mut this.stream).poll_read(cx, &mut read_buf))?;
+            let read = read_buf.filled().len();
+            if read == 0 {
+                // The peer hanging up is only clean if it happens between messages
+                return if this.incoming_len == 0 {
+                    Poll::Ready(Ok(()))
+                } else {
+                    Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()))
+                };
+            }
+            this.incoming_len += read;
+        }
+    }
+}
+
+/// The writing half of a `SecureStream`
+pub struct SecureWriteHalf {
+    stream: OwnedWriteHalf,
+    transport: Arc<StatelessTransportState>,
+    /// Each message to the peer uses the next nonce, starting from 0
+    nonce: u64,
+    /// The encrypted message being written out, starting with its length
+    outgoing: Vec<u8>,
+    outgoing_pos: usize
+}
+
+impl SecureWriteHalf {
+    fn new(stream: OwnedWriteHalf, transport: Arc<StatelessTransportState>) -> Self {
+        Self { stream, transport, nonce: 0, outgoing: Vec::new(), outgoing_pos: 0 }
+    }
+
+    /// Writes out whatever is left of the last encrypted message
+    fn poll_write_outgoing(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.outgoing_pos < self.outgoing.len() {
+            let written = ready!(Pin::new(&mut self.stream).poll_write(cx, &self.outgoing[self.outgoing_pos..]))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.outgoing_pos += written;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for SecureWriteHalf {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_outgoing(cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let plaintext = &buf[..buf.len().min(MAX_PLAINTEXT_SIZE)];
+        this.outgoing.resize(2 + plaintext.len() + TAG_SIZE, 0);
+        let len = this.transport.write_message(this.nonce, plaintext, &mut this.outgoing[2..])
+            .map_err(noise_error)?;
+        this.outgoing[..2].copy_from_slice(&(len as u16).to_be_bytes());
+        this.outgoing.truncate(2 + len);
+        this.outgoing_pos = 0;
+        this.nonce += 1;
+
+        // Start sending the message straight away. Whatever doesn't fit goes out on the next
+        // write or flush
+        if let Poll::Ready(Err(err)) = this.poll_write_outgoing(cx) {
+            return Poll::Ready(Err(err));
+        }
+        Poll::Ready(Ok(plaintext.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_outgoing(cx))?;
+        Pin::new(&mut this.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_outgoing(cx))?;
+        Pin::new(&mut this.stream).poll_shutdown(cx)
+    }
+}
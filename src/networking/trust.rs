@@ -0,0 +1,268 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use data_encoding::HEXUPPER;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use spake2::{Ed25519Group, Password, Spake2};
+use crate::networking::secure::write_private;
+
+/// How long a pairing code can be used for after it's shown
+const PAIRING_CODE_LIFETIME: Duration = Duration::from_secs(5 * 60);
+/// How many attempts at pairing we put up with before the code has to be shown again, so it can't
+/// be guessed by trying every one
+const PAIRING_ATTEMPTS: u32 = 3;
+
+/// A peer we've paired with, who can connect without the user being asked about it
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct TrustedPeer {
+    /// The key the peer proves it holds when connecting, see `SecureStream::remote_public_key`
+    pub public_key: String,
+    /// The name the peer last introduced itself with
+    pub display_name: String
+}
+
+/// What to do when a peer we haven't paired with tries to connect
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum UnknownPeerPolicy {
+    /// Ask the user whether to let the peer in, showing its display name
+    #[default]
+    Ask,
+    /// Turn the peer away without asking
+    Reject
+}
+
+/// Whether a peer that is connecting to us gets let in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerDecision {
+    /// We've paired with the peer before
+    Trusted,
+    /// The peer just paired with us using the code we're showing
+    Paired,
+    /// We don't know the peer, so the user has to decide
+    Ask,
+    Rejected
+}
+
+/// A code shown to the user that lets a new peer pair with us
+#[derive(Debug, Clone)]
+struct PairingCode {
+    code: String,
+    expires: Instant,
+    attempts_left: u32
+}
+
+/// Which side of a pairing we're on. The side that opened the connection is the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PairingRole {
+    Client,
+    Server
+}
+
+impl PairingRole {
+    fn peer(self) -> Self {
+        match self {
+            PairingRole::Client => PairingRole::Server,
+            PairingRole::Server => PairingRole::Client
+        }
+    }
+
+    fn label(self) -> &'static [u8] {
+        match self {
+            PairingRole::Client => b"osu!sync pairing client",
+            PairingRole::Server => b"osu!sync pairing server"
+        }
+    }
+}
+
+/// The confirmations that show both sides of a pairing ended up with the same key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PairingConfirmation {
+    /// What we send to the peer
+    pub ours: String,
+    /// What the peer has to send us
+    pub theirs: String
+}
+
+/// Our side of pairing with a peer using the code one of us is showing, done as symmetric SPAKE2.
+/// Only sides that used the same code end up with the same key, which each then proves it has.
+/// Nothing sent gives away the code, so someone pretending to be either side only gets to try one
+/// guess per attempt.
+#[derive(Debug)]
+pub struct Pairing {
+    role: PairingRole,
+    spake: Spake2<Ed25519Group>,
+    share: Vec<u8>
+}
+
+impl Pairing {
+    pub fn new(code: &str, role: PairingRole) -> Self {
+        let (spake, share) = Spake2::<Ed25519Group>::start_symmetric(
+            &Password::new(code.trim().as_bytes()), &spake2::Identity::new(b"osu!sync pairing"));
+        Self { role, spake, share }
+    }
+
+    /// What we send to the peer
+    pub fn share(&self) -> String {
+        HEXUPPER.encode(&self.share)
+    }
+
+    /// Works out the key from the peer's share, and the confirmations both sides send to prove
+    /// they have it. Tying the key to the handshake of the connection means it's useless on any
+    /// other connection. Returns None if the peer's share isn't valid.
+    pub fn finish(self, peer_share: &str, handshake_hash: &[u8]) -> Option<PairingConfirmation> {
+        let peer_share = HEXUPPER.decode(peer_share.as_bytes()).ok()?;
+        let spake_key = self.spake.finish(&peer_share).ok()?;
+        let key = Sha256::new()
+            .chain_update(handshake_hash)
+            .chain_update(spake_key)
+            .finalize();
+        let confirmation = |role: PairingRole| HEXUPPER.encode(&Sha256::new().chain_update(key).chain_update(role.label()).finalize());
+        Some(PairingConfirmation { ours: confirmation(self.role), theirs: confirmation(self.role.peer()) })
+    }
+}
+
+/// Persistent list of the peers we've paired with, keyed by their public key
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct TrustedPeers {
+    peers: HashMap<String, TrustedPeer>,
+    #[serde(default)]
+    unknown_peer_policy: UnknownPeerPolicy,
+    #[serde(skip)]
+    pairing_code: Option<PairingCode>,
+    /// Where the list gets saved to. If unset, the list only lives in memory.
+    #[serde(skip)]
+    path: Option<PathBuf>
+}
+
+impl TrustedPeers {
+    /// Loads the list saved at the given path, starting with an empty one if it is missing or
+    /// unreadable
+    pub fn load(path: PathBuf) -> Self {
+        let trusted_peers = fs::read(&path).ok()
+            .and_then(|data| serde_json::from_slice::<TrustedPeers>(&data).ok());
+
+        match trusted_peers {
+            Some(mut trusted_peers) => {
+                println!("Loaded {} trusted peers from {path:?}", trusted_peers.peers.len());
+                trusted_peers.path = Some(path);
+                trusted_peers
+            },
+            None => Self { path: Some(path), ..Self::default() }
+        }
+    }
+
+    /// Writes the list to disk, if it was loaded from a path
+    pub fn save(&self) -> io::Result<()> {
+        if let Some(path) = &self.path {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            write_private(path, &serde_json::to_vec(self)?)?;
+        }
+        Ok(())
+    }
+
+    fn save_or_log(&self) {
+        if let Err(err) = self.save() {
+            println!("Unable to save the trusted peers: {err:?}");
+        }
+    }
+
+    /// Every peer we've paired with, sorted by name
+    pub fn peers(&self) -> Vec<TrustedPeer> {
+        let mut peers: Vec<TrustedPeer> = self.peers.values().cloned().collect();
+        peers.sort_by(|a, b| a.display_name.cmp(&b.display_name).then_with(|| a.public_key.cmp(&b.public_key)));
+        peers
+    }
+
+    pub fn is_trusted(&self, public_key: &str) -> bool {
+        self.peers.contains_key(public_key)
+    }
+
+    /// Remembers a peer, or updates the name of one we already know
+    pub fn trust(&mut self, public_key: &str, display_name: &str) {
+        let peer = TrustedPeer { public_key: public_key.to_string(), display_name: display_name.to_string() };
+        if self.peers.get(public_key) != Some(&peer) {
+            self.peers.insert(public_key.to_string(), peer);
+            self.save_or_log();
+        }
+    }
+
+    /// Forgets a peer, so it has to pair again before it can connect without asking
+    pub fn forget(&mut self, public_key: &str) -> bool {
+        let forgotten = self.peers.remove(public_key).is_some();
+        if forgotten {
+            self.save_or_log();
+        }
+        forgotten
+    }
+
+    pub fn unknown_peer_policy(&self) -> UnknownPeerPolicy {
+        self.unknown_peer_policy
+    }
+
+    pub fn set_unknown_peer_policy(&mut self, policy: UnknownPeerPolicy) {
+        self.unknown_peer_policy = policy;
+        self.save_or_log();
+    }
+
+    /// Makes up a new pairing code to show to the user, replacing any earlier one
+    pub fn start_pairing(&mut self) -> String {
+        let code = format!("{:06}", rand::thread_rng().gen_range(0..1_000_000));
+        self.pairing_code = Some(PairingCode {
+            code: code.clone(),
+            expires: Instant::now() + PAIRING_CODE_LIFETIME,
+            attempts_left: PAIRING_ATTEMPTS
+        });
+        code
+    }
+
+    /// Answers a peer that wants to pair using the code we're showing, returning our share and
+    /// the confirmations to swap with it. Every answer uses up an attempt, since each one lets
+    /// the peer try a code. Returns None if we aren't showing a code or the peer's share isn't valid.
+    pub fn answer_pairing(&mut self, peer_share: &str, handshake_hash: &[u8]) -> Option<(String, PairingConfirmation)> {
+        let pairing_code = match self.pairing_code.as_mut() {
+            Some(pairing_code) if pairing_code.expires > Instant::now() && pairing_code.attempts_left > 0 => pairing_code,
+            _ => return None
+        };
+
+        // The code stays around until the last attempt is done with, see `finish_pairing`
+        pairing_code.attempts_left -= 1;
+        let pairing = Pairing::new(&pairing_code.code, PairingRole::Server);
+        let share = pairing.share();
+        let confirmation = pairing.finish(peer_share, handshake_hash)?;
+        Some((share, confirmation))
+    }
+
+    /// Checks the confirmation a peer sent back after `answer_pairing`. A code can only be
+    /// used once, so the peer is only paired if the code hasn't been used up in the meantime.
+    pub fn finish_pairing(&mut self, confirmation: &PairingConfirmation, peer_confirmation: Option<&str>) -> bool {
+        let paired = self.pairing_code.is_some() && peer_confirmation == Some(confirmation.theirs.as_str());
+        if paired {
+            self.pairing_code = None;
+        }
+        paired
+    }
+
+    /// Decides whether to let in a peer that is connecting to us. A peer that just proved it
+    /// knows the code we're showing, see `finish_pairing`, is paired with.
+    pub fn check_peer(&mut self, public_key: &str, display_name: &str, paired: bool) -> PeerDecision {
+        if self.is_trusted(public_key) {
+            self.trust(public_key, display_name);
+            return PeerDecision::Trusted;
+        }
+
+        if paired {
+            self.trust(public_key, display_name);
+            return PeerDecision::Paired;
+        }
+
+        match self.unknown_peer_policy {
+            UnknownPeerPolicy::Ask => PeerDecision::Ask,
+            UnknownPeerPolicy::Reject => PeerDecision::Rejected
+        }
+    }
+}
@@ -1,33 +1,49 @@
 use std::fmt::Debug;
-use std::fs::File;
-use std::io;
-use std::path::Path;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 use std::string::String;
-use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use expect_test::{Expect, expect, expect_file, ExpectFile};
-use serde::Serialize;
-use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::time::sleep;
-use crate::file_manager::SongFolderError;
-use crate::networking::packets::{DisconnectPacket, DownloadRequestPacket, DownloadResponsePacket, MapListPacket, MapListRequestPacket, Packet};
-use super::*;
+use tokio::time::{sleep, timeout};
+use crate::diff;
+use crate::diff::SyncPlan;
+use crate::file_manager;
+use crate::file_manager::{DownloadEstimate, FileHash, MapFormat, MapManifest, MapRequest, SongFolder, SongFolderError};
+use crate::file_manager::assets::{AssetIndex, AssetSizes, TransferProfile};
+use crate::file_manager::osu_file::OsuFile;
+use crate::file_manager::cache::SongCache;
+use crate::networking::bandwidth::{BandwidthSettings, Direction, RateLimiter, Schedule};
+use crate::networking::codec::{CodecError, Frame, FRAME_VERSION, MAX_PAYLOAD_SIZE, read_frame, write_frame};
+use crate::networking::{accept_peer, connect_to_server, exchange_hello, start_listening_server, NetworkingError};
+use crate::networking::discovery::{Announcement, DiscoveredPeers, run_discovery};
+use crate::networking::download::DownloadDestination;
+use crate::networking::listener::{ListenerSettings, shareable_addr};
+use crate::networking::planner::{DownloadPlanner, pull_maps};
+use crate::networking::queue::{TransferQueue, TransferState};
+use crate::networking::packets::PROTOCOL_VERSION;
+use crate::networking::secure::{Identity, SecureStream};
+use crate::networking::trust::{Pairing, PairingRole, PeerDecision, TrustedPeers, UnknownPeerPolicy};
+use crate::ui::UiBridge;
+use crate::networking::packets::{CancelDownloadPacket, DisconnectPacket, PacketManager, PeerId, DownloadMode, DownloadDataPacket, DownloadMapPacket, DownloadRequestPacket, DownloadResponsePacket, ErrorCode, ErrorPacket, HelloPacket, MapListPacket, MapListRequestPacket, Packet, SyncRequestPacket, SyncResponsePacket};
 
-// Mock out the Tauri front-end
+/// Stands in for the Tauri window, recording every event and dialog so tests can check them.
+/// Every question is answered with yes, and the folder picker picks `picked_folder`.
 #[derive(Debug, Clone)]
 pub struct MockWindow {
-    messages: Arc<Mutex<Vec<String>>>
+    messages: Arc<Mutex<Vec<String>>>,
+    picked_folder: Option<PathBuf>
 }
 impl MockWindow {
     pub fn new() -> Self {
-        Self { messages: Arc::new(Mutex::new(Vec::new())) }
+        Self { messages: Arc::new(Mutex::new(Vec::new())), picked_folder: None }
     }
 
-    pub fn emit<S: Serialize + Clone>(&self, event: &str, payload: S) -> Result<(), ()> {
-        self.messages.lock().unwrap().push(
-            format!("{event}: {}", serde_json::to_string(&payload).unwrap())
-        );
-        Ok(())
+    pub fn picking_folder(folder: &Path) -> Self {
+        Self { picked_folder: Some(folder.to_path_buf()), ..Self::new() }
     }
 
     pub fn get_messages(&self) -> Vec<String> {
@@ -35,21 +51,27 @@ impl MockWindow {
     }
 }
 
+impl UiBridge for MockWindow {
+    fn emit_json(&self, event: &str, payload: String) {
+        self.messages.lock().unwrap().push(format!("{event}: {payload}"));
+    }
+
+    fn ask(&self, title: &str, message: &str) -> bool {
+        let payload = DialogPayload { title: title.to_string(), message: message.to_string() };
+        self.emit_json("ask-dialog", serde_json::to_string(&payload).unwrap());
+        true
+    }
+
+    fn pick_folder(&self, _title: &str) -> Option<PathBuf> {
+        self.picked_folder.clone()
+    }
+}
+
 #[derive(Clone, serde::Serialize)]
 struct DialogPayload {
     title: String,
     message: String
 }
-pub fn ask(window: Option<&MockWindow>, title: impl AsRef<str>, message: impl AsRef<str>) -> bool {
-    if let Some(window) = window {
-        window.emit("ask-dialog", DialogPayload {
-            title: title.as_ref().to_string(),
-            message: message.as_ref().to_string()
-        }).unwrap();
-    }
-    true
-}
-
 
 /// Helper function to check test result against expected value.
 fn check<T: Debug>(actual: T, expect: Expect) {
@@ -62,7 +84,7 @@ fn check_file<T: Debug>(actual: T, expect: ExpectFile) {
 
 async fn get_test_files() -> Result<Vec<SongFolder>, SongFolderError> {
     let song_folder_path = Path::new("src/test/testsongs");
-    let mut songs = file_manager::read_local_files(&song_folder_path).await?;
+    let mut songs = file_manager::read_local_files(&song_folder_path, &Mutex::new(SongCache::default())).await?;
     songs.sort_by(|a, b| a.id.cmp(&b.id));
     Ok(songs)
 }
@@ -76,7 +98,68 @@ async fn test_read_local_files() {
 }
 
 #[tokio::test]
-async fn test_zip_local_files() {
+async fn test_song_cache() {
+    let songs_dir = tempfile::tempdir().unwrap();
+    let song_path = songs_dir.path().join("1 Artist - Title");
+    fs::create_dir(&song_path).unwrap();
+    fs::write(song_path.join("Artist - Title (Mapper) [Easy].osu"), "osu file format v14").unwrap();
+
+    let cache_path = songs_dir.path().join("cache").join("song_cache.json");
+    let cache = Mutex::new(SongCache::load(cache_path.clone()));
+    let songs = file_manager::read_local_files(songs_dir.path(), &cache).await.unwrap();
+    assert!(cache_path.exists(), "The song cache should be saved after reading");
+
+    // Tamper with the saved checksum. Since the folder didn't change, a reloaded cache should
+    // hand the tampered checksum back instead of rehashing.
+    let saved_cache = fs::read_to_string(&cache_path).unwrap()
+        .replace(&songs[0].checksum, "TAMPERED");
+    fs::write(&cache_path, saved_cache).unwrap();
+    let cache = Mutex::new(SongCache::load(cache_path.clone()));
+    let cached_songs = file_manager::read_local_files(songs_dir.path(), &cache).await.unwrap();
+    assert_eq!(cached_songs[0].checksum, "TAMPERED");
+    assert_eq!(cached_songs[0].path.as_deref(), Some(song_path.as_path()));
+
+    // Adding media leaves the .osu files alone, but the sizes have to catch up with it anyway
+    fs::write(song_path.join("video.mp4"), vec![0; 1000]).unwrap();
+    let cached_songs = file_manager::read_local_files(songs_dir.path(), &cache).await.unwrap();
+    assert_eq!(cached_songs[0].checksum, "TAMPERED");
+    check((cached_songs[0].size, cached_songs[0].file_count, &cached_songs[0].assets), expect![[r#"
+        (
+            1019,
+            2,
+            AssetSizes {
+                audio: AssetSize {
+                    size: 0,
+                    file_count: 0,
+                },
+                video: AssetSize {
+                    size: 1000,
+                    file_count: 1,
+                },
+                storyboard: AssetSize {
+                    size: 0,
+                    file_count: 0,
+                },
+            },
+        )
+    "#]]);
+
+    // Changing a .osu file should cause the folder to be rehashed
+    fs::write(song_path.join("Artist - Title (Mapper) [Easy].osu"), "osu file format v14\n").unwrap();
+    let changed_songs = file_manager::read_local_files(songs_dir.path(), &cache).await.unwrap();
+    assert_ne!(changed_songs[0].checksum, "TAMPERED");
+    assert_ne!(changed_songs[0].checksum, songs[0].checksum);
+
+    // As should clearing the cache
+    fs::write(&cache_path, fs::read_to_string(&cache_path).unwrap().replace(&changed_songs[0].checksum, "TAMPERED")).unwrap();
+    let cache = Mutex::new(SongCache::load(cache_path));
+    cache.lock().unwrap().clear();
+    let rebuilt_songs = file_manager::read_local_files(songs_dir.path(), &cache).await.unwrap();
+    assert_eq!(rebuilt_songs[0].checksum, changed_songs[0].checksum);
+}
+
+#[tokio::test]
+async fn test_song_to_osz() {
     let mut songs = get_test_files().await.unwrap();
     songs.truncate(3);
 
@@ -87,48 +170,684 @@ async fn test_zip_local_files() {
         "#]]
     );
 
-    let created_zip = file_manager::zip_local_files(songs).await;
-    assert!(created_zip.is_ok(), "Error when trying to zip files: {:?}", created_zip);
-    let mut created_zip = created_zip.unwrap();
+    for song in songs {
+        let osz_data = file_manager::song_to_osz(&song, None);
+        assert!(osz_data.is_ok(), "Error when trying to zip {}: {:?}", song.name, osz_data);
+        let osz_data = osz_data.unwrap();
+
+        // Every file in the folder should be in the .osz
+        let folder_entries = walkdir::WalkDir::new(song.path.as_ref().unwrap()).into_iter().count() - 1;
+        let osz = zip::ZipArchive::new(io::Cursor::new(&osz_data)).unwrap();
+        assert_eq!(osz.len(), folder_entries, "Not every file of {} was zipped", song.name);
+
+        // Zipping the same song again has to give the same bytes, for resuming downloads
+        assert!(file_manager::song_to_osz(&song, None).unwrap() == osz_data, "Zipping {} twice gave different files", song.name);
+    }
+}
+
+/// Zips up a song the naive way, deflating every file in it
+fn deflate_song(song: &SongFolder) -> Vec<u8> {
+    let mut zip = zip::ZipWriter::new(io::Cursor::new(Vec::new()));
+    for entry in walkdir::WalkDir::new(song.path.as_ref().unwrap()).sort_by_file_name().min_depth(1) {
+        let entry = entry.unwrap();
+        if entry.file_type().is_file() {
+            let name = entry.path().strip_prefix(song.path.as_ref().unwrap()).unwrap().to_string_lossy();
+            zip.start_file(name, zip::write::FileOptions::default()).unwrap();
+            zip.write_all(&fs::read(entry.path()).unwrap()).unwrap();
+        }
+    }
+    zip.finish().unwrap().into_inner()
+}
+
+/// Compares how long zipping the bundled maps takes with every file deflated against only
+/// deflating what shrinks. Run with `--ignored --nocapture` to see the timings.
+#[tokio::test] #[ignore]
+async fn test_osz_compression_benchmark() {
+    let songs = get_test_files().await.unwrap();
+
+    let start = Instant::now();
+    let deflated_size: usize = songs.iter().map(|song| deflate_song(song).len()).sum();
+    let deflated_time = start.elapsed();
+
+    let start = Instant::now();
+    let chosen_size: usize = songs.iter().map(|song| file_manager::song_to_osz(song, None).unwrap().len()).sum();
+    let chosen_time = start.elapsed();
+    println!("Deflating everything: {deflated_size} bytes in {deflated_time:?}");
+    println!("Storing media: {chosen_size} bytes in {chosen_time:?}");
+}
+
+/// Media in the bundled maps is stored as-is, without making the maps noticeably bigger
+#[tokio::test]
+async fn test_osz_compression() {
+    let songs = get_test_files().await.unwrap();
+
+    let deflated_size: usize = songs.iter().map(|song| deflate_song(song).len()).sum();
+    let osz_files: Vec<Vec<u8>> = songs.iter().map(|song| file_manager::song_to_osz(song, None).unwrap()).collect();
+    let chosen_size: usize = osz_files.iter().map(Vec::len).sum();
+
+    // Media barely shrinks, so storing it costs next to nothing in size
+    assert!(chosen_size <= deflated_size + deflated_size / 50, "Storing media made the maps {chosen_size} bytes instead of {deflated_size}");
+    for osz_data in &osz_files {
+        let mut osz = zip::ZipArchive::new(io::Cursor::new(osz_data)).unwrap();
+        for i in 0..osz.len() {
+            let file = osz.by_index(i).unwrap();
+            if file.is_file() {
+                assert_eq!(file.compression(), file_manager::compression_method(file.name()), "{}", file.name());
+            }
+        }
+    }
+    check(
+        ["Artist - Title (Mapper) [Hard].osu", "Storyboard.OSB", "audio.MP3", "bg.jpg", "video.mp4", "hitsound.wav", "Other Map.osz"]
+            .map(|name| (name, file_manager::compression_method(name))),
+        expect![[r#"
+            [
+                (
+                    "Artist - Title (Mapper) [Hard].osu",
+                    Deflated,
+                ),
+                (
+                    "Storyboard.OSB",
+                    Deflated,
+                ),
+                (
+                    "audio.MP3",
+                    Stored,
+                ),
+                (
+                    "bg.jpg",
+                    Stored,
+                ),
+                (
+                    "video.mp4",
+                    Stored,
+                ),
+                (
+                    "hitsound.wav",
+                    Deflated,
+                ),
+                (
+                    "Other Map.osz",
+                    Stored,
+                ),
+            ]
+        "#]]
+    );
+}
+
+#[tokio::test]
+async fn test_install_osz() {
+    let mut songs = get_test_files().await.unwrap();
+    songs.truncate(2);
+
+    // Install the same maps twice, so the second time has to work around the existing folders
+    let songs_dir = tempfile::tempdir().unwrap();
+    let mut installed = Vec::new();
+    for _ in 0..2 {
+        installed.clear();
+        for song in &songs {
+            let osz_data = file_manager::song_to_osz(song, None).unwrap();
+            let osz_name = format!("{}.osz", song.folder);
+            installed.push(file_manager::install_osz(io::Cursor::new(osz_data), &osz_name, songs_dir.path(), None).unwrap());
+        }
+    }
+    check(
+        installed.iter().map(|path| path.file_name().unwrap().to_string_lossy().to_string()).collect::<Vec<String>>(),
+        expect![[r#"
+            [
+                "1752 DragonForce - Through The Fire And Flames (1)",
+                "3030 Lucky Star - Motteke! Sailor Fuku (REDALiCE Remix) (1)",
+            ]
+        "#]]
+    );
+
+    let mut installed_songs = file_manager::read_local_files(songs_dir.path(), &Mutex::new(SongCache::default())).await.unwrap();
+    installed_songs.sort_by(|a, b| a.name.cmp(&b.name));
+    let matching = installed_songs.len() == 4 &&
+        installed_songs.iter().all(|installed| {
+            songs.iter().any(|song| song.id == installed.id && song.checksum == installed.checksum)
+        });
+    assert!(matching, "Installed songs do not match the zipped songs!");
+
+    // Names that would escape the songs directory aren't installed
+    let osz_data = file_manager::song_to_osz(&songs[0], None).unwrap();
+    let escaped = file_manager::install_osz(io::Cursor::new(osz_data), "../escaped.osz", songs_dir.path(), None).unwrap();
+    assert_eq!(escaped, songs_dir.path().join("escaped"));
+}
+
+/// Creates a song folder with the given difficulties and an audio file
+fn create_test_song(songs_dir: &Path, folder_name: &str, difficulties: &[(u64, &str)]) -> PathBuf {
+    let song_path = songs_dir.join(folder_name);
+    fs::create_dir_all(&song_path).unwrap();
+    fs::write(song_path.join("audio.mp3"), "audio").unwrap();
+    for (beatmap_id, name) in difficulties {
+        fs::write(
+            song_path.join(format!("Artist - Title (Mapper) [{name}].osu")),
+            format!("osu file format v14\n\n[Metadata]\nVersion:{name}\nBeatmapID:{beatmap_id}\n")
+        ).unwrap();
+    }
+    song_path
+}
+
+#[tokio::test]
+async fn test_missing_difficulties() {
+    let remote_dir = tempfile::tempdir().unwrap();
+    create_test_song(remote_dir.path(), "1 Artist - Title", &[(10, "Easy"), (11, "Hard"), (12, "Insane")]);
+    let local_dir = tempfile::tempdir().unwrap();
+    let local_path = create_test_song(local_dir.path(), "1 Artist - Title", &[(10, "Easy")]);
+
+    let remote_song = file_manager::read_local_files(remote_dir.path(), &Mutex::new(SongCache::default())).await.unwrap().remove(0);
+    let local_song = file_manager::read_local_files(local_dir.path(), &Mutex::new(SongCache::default())).await.unwrap().remove(0);
+    check(
+        remote_song.difficulties.iter().map(|d| (d.beatmap_id, d.name.clone())).collect::<Vec<(u64, String)>>(),
+        expect![[r#"
+            [
+                (
+                    10,
+                    "Easy",
+                ),
+                (
+                    11,
+                    "Hard",
+                ),
+                (
+                    12,
+                    "Insane",
+                ),
+            ]
+        "#]]
+    );
+
+    // Only the difficulties we don't have should be requested
+    let request = MapRequest::missing_from(remote_song.clone(), Some(&local_song));
+    assert_eq!(request.difficulties.as_ref().unwrap().len(), 2);
+
+    // Installing them should add to our existing folder rather than making a new one
+    let osz_data = file_manager::song_to_osz(&request.song, request.difficulties.as_ref()).unwrap();
+    let installed = file_manager::install_osz(io::Cursor::new(osz_data), "1 Artist - Title.osz", local_dir.path(), Some(&local_path)).unwrap();
+    assert_eq!(installed, local_path);
+
+    let updated_song = file_manager::read_local_files(local_dir.path(), &Mutex::new(SongCache::default())).await.unwrap().remove(0);
+    assert_eq!(updated_song.difficulties, remote_song.difficulties);
+}
+
+#[tokio::test]
+async fn test_download_estimate() {
+    let remote_dir = tempfile::tempdir().unwrap();
+    create_test_song(remote_dir.path(), "1 Artist - Title", &[(10, "Easy"), (11, "Hard")]);
+    create_test_song(remote_dir.path(), "2 Artist - Other", &[(20, "Normal")]);
+    fs::create_dir(remote_dir.path().join("2 Artist - Other").join("sb")).unwrap();
+    fs::write(remote_dir.path().join("2 Artist - Other").join("sb").join("sprite.png"), [0; 2000]).unwrap();
+    let local_dir = tempfile::tempdir().unwrap();
+    create_test_song(local_dir.path(), "1 Artist - Title", &[(10, "Easy")]);
+
+    let mut remote_songs = file_manager::read_local_files(remote_dir.path(), &Mutex::new(SongCache::default())).await.unwrap();
+    remote_songs.sort_by(|a, b| a.folder.cmp(&b.folder));
+    let local_songs = file_manager::read_local_files(local_dir.path(), &Mutex::new(SongCache::default())).await.unwrap();
+    check(
+        remote_songs.iter().map(|song| (song.folder.as_str(), song.file_count, song.size)).collect::<Vec<_>>(),
+        expect![[r#"
+            [
+                (
+                    "1 Artist - Title",
+                    3,
+                    121,
+                ),
+                (
+                    "2 Artist - Other",
+                    3,
+                    2065,
+                ),
+            ]
+        "#]]
+    );
+
+    // Only the difficulty we're missing counts towards the first song
+    let requests = MapRequest::for_songs(remote_songs, &local_songs, TransferProfile::Full);
+    let estimate = DownloadEstimate::for_requests(&requests);
+    check(
+        (requests.iter().map(MapRequest::estimate).collect::<Vec<_>>(), estimate, estimate.describe()),
+        expect![[r#"
+            (
+                [
+                    DownloadEstimate {
+                        map_count: 1,
+                        file_count: 2,
+                        size: 63,
+                    },
+                    DownloadEstimate {
+                        map_count: 1,
+                        file_count: 3,
+                        size: 2065,
+                    },
+                ],
+                DownloadEstimate {
+                    map_count: 2,
+                    file_count: 5,
+                    size: 2128,
+                },
+                "2 maps (2.1 KiB)",
+            )
+        "#]]
+    );
+}
+
+#[tokio::test]
+async fn test_transfer_profiles() {
+    let songs_dir = tempfile::tempdir().unwrap();
+    let song_path = songs_dir.path().join("1 Artist - Title");
+    fs::create_dir_all(song_path.join("SB")).unwrap();
+    fs::write(song_path.join("Artist - Title (Mapper) [Hard].osu"), "osu file format v14\n\n[General]\nAudioFilename: audio.mp3\n\n\
+        [Metadata]\nVersion:Hard\nBeatmapID:10\n\n[Events]\n0,0,\"bg.jpg\",0,0\nVideo,-200,\"intro.mp4\"\n").unwrap();
+    fs::write(song_path.join("Artist - Title (Mapper).osb"), "[Events]\nSprite,Foreground,Centre,\"sb\\star.png\",320,240\n\
+         M,0,0,1000,320,240,320,0\nAnimation,Foreground,Centre,\"sb/glow.png\",320,240,2,100,LoopForever\n").unwrap();
+    fs::write(song_path.join("audio.mp3"), [0; 1000]).unwrap();
+    fs::write(song_path.join("bg.jpg"), [0; 100]).unwrap();
+    fs::write(song_path.join("intro.mp4"), [0; 5000]).unwrap();
+    fs::write(song_path.join("normal-hitclap.wav"), [0; 10]).unwrap();
+    for sprite in ["star.png", "glow0.png", "glow1.png"] {
+        fs::write(song_path.join("SB").join(sprite), [0; 200]).unwrap();
+    }
+
+    // Files are told apart by what the .osu and .osb files use them for
+    let index = AssetIndex::read(&song_path).unwrap();
+    check(
+        ["audio.mp3", "bg.jpg", "intro.mp4", "normal-hitclap.wav", "SB/star.png", "SB\\glow1.png",
+         "Artist - Title (Mapper).osb", "Artist - Title (Mapper) [Hard].osu"]
+            .map(|path| (path, index.kind(path))),
+        expect![[r#"
+            [
+                (
+                    "audio.mp3",
+                    Audio,
+                ),
+                (
+                    "bg.jpg",
+                    Other,
+                ),
+                (
+                    "intro.mp4",
+                    Video,
+                ),
+                (
+                    "normal-hitclap.wav",
+                    Other,
+                ),
+                (
+                    "SB/star.png",
+                    Storyboard,
+                ),
+                (
+                    "SB\\glow1.png",
+                    Storyboard,
+                ),
+                (
+                    "Artist - Title (Mapper).osb",
+                    Storyboard,
+                ),
+                (
+                    "Artist - Title (Mapper) [Hard].osu",
+                    Difficulty,
+                ),
+            ]
+        "#]]
+    );
+
+    let song = file_manager::read_local_files(songs_dir.path(), &Mutex::new(SongCache::default())).await.unwrap().remove(0);
+    check((song.size, song.file_count, song.assets), expect![[r#"
+        (
+            7003,
+            9,
+            AssetSizes {
+                audio: AssetSize {
+                    size: 1000,
+                    file_count: 1,
+                },
+                video: AssetSize {
+                    size: 5000,
+                    file_count: 1,
+                },
+                storyboard: AssetSize {
+                    size: 749,
+                    file_count: 4,
+                },
+            },
+        )
+    "#]]);
+
+    // Only what the profile includes is zipped up, and the estimate matches what's sent
+    let profiles = [TransferProfile::Full, TransferProfile::NoVideo, TransferProfile::NoStoryboard, TransferProfile::AudioAndDifficulties];
+    for profile in profiles {
+        let (osz_data, manifest) = file_manager::song_to_osz_with_manifest(&song, None, profile).unwrap();
+        assert!(file_manager::verify_osz(io::Cursor::new(&osz_data), &manifest).is_ok());
+        let estimate = MapRequest::full(song.clone()).with_profile(profile).estimate();
+        let mut osz = zip::ZipArchive::new(io::Cursor::new(&osz_data)).unwrap();
+        let sent_size: u64 = manifest.files.iter().map(|file| osz.by_name(&file.path).unwrap().size()).sum();
+        assert_eq!((estimate.file_count, estimate.size), (manifest.files.len() as u64, sent_size), "{profile:?}");
+    }
+    let (osz_data, _) = file_manager::song_to_osz_with_manifest(&song, None, TransferProfile::NoStoryboard).unwrap();
+    let mut files: Vec<String> = zip::ZipArchive::new(io::Cursor::new(&osz_data)).unwrap().file_names().map(String::from).collect();
+    files.sort();
+    check(files, expect![[r#"
+        [
+            "Artist - Title (Mapper) [Hard].osu",
+            "SB/",
+            "audio.mp3",
+            "bg.jpg",
+            "intro.mp4",
+            "normal-hitclap.wav",
+        ]
+    "#]]);
+
+    // Estimates for a whole download go by the profile too
+    let requests = MapRequest::for_songs(vec![song], &[], TransferProfile::AudioAndDifficulties);
+    check(DownloadEstimate::for_requests(&requests).describe(), expect![[r#"
+        "1 map (1.1 KiB)"
+    "#]]);
+}
+
+#[tokio::test]
+async fn test_verify_map() {
+    let remote_dir = tempfile::tempdir().unwrap();
+    create_test_song(remote_dir.path(), "1 Artist - Title", &[(10, "Easy"), (11, "Hard")]);
+    let song = file_manager::read_local_files(remote_dir.path(), &Mutex::new(SongCache::default())).await.unwrap().remove(0);
+
+    // The whole folder is sent along with the song's checksum and a hash of every file
+    let (osz_data, manifest) = file_manager::song_to_osz_with_manifest(&song, None, TransferProfile::Full).unwrap();
+    assert_eq!(manifest.checksum.as_ref(), Some(&song.checksum));
+    check(
+        manifest.files.iter().map(|file| file.path.clone()).collect::<Vec<String>>(),
+        expect![[r#"
+            [
+                "Artist - Title (Mapper) [Easy].osu",
+                "Artist - Title (Mapper) [Hard].osu",
+                "audio.mp3",
+            ]
+        "#]]
+    );
+    assert!(file_manager::verify_osz(io::Cursor::new(&osz_data), &manifest).is_ok());
+
+    // Some of the difficulties don't make up the song, so only the files are checked
+    let difficulties = vec![song.difficulties[1].checksum.clone()];
+    let (partial_osz, partial_manifest) = file_manager::song_to_osz_with_manifest(&song, Some(&difficulties), TransferProfile::Full).unwrap();
+    assert_eq!(partial_manifest.checksum, None);
+    assert_eq!(partial_manifest.files.len(), 2);
+    assert!(file_manager::verify_osz(io::Cursor::new(&partial_osz), &partial_manifest).is_ok());
+
+    // Anything that doesn't match is reported, whether it's a file or the song as a whole
+    let mut wrong_file = manifest.clone();
+    wrong_file.files[2] = FileHash { path: "audio.mp3".to_string(), checksum: "0000".to_string() };
+    let err = file_manager::verify_osz(io::Cursor::new(&osz_data), &wrong_file).unwrap_err();
+    assert_eq!(err.to_string(), "audio.mp3 doesn't match what the peer sent");
+    let wrong_song = MapManifest { checksum: Some("0000".to_string()), ..manifest.clone() };
+    assert!(matches!(file_manager::verify_osz(io::Cursor::new(&osz_data), &wrong_song), Err(SongFolderError::Damaged(_))));
+
+    // Installed maps are checked the same way, once they're on disk
+    let songs_dir = tempfile::tempdir().unwrap();
+    let installed = file_manager::install_osz(io::Cursor::new(&osz_data), "1 Artist - Title.osz", songs_dir.path(), None).unwrap();
+    assert!(file_manager::verify_folder(&installed, &manifest).is_ok());
+    fs::write(installed.join("audio.mp3"), "something else").unwrap();
+    let err = file_manager::verify_folder(&installed, &manifest).unwrap_err();
+    assert_eq!(err.to_string(), "audio.mp3 doesn't match what the peer sent");
+}
+
+/// Creates three test songs, returning a request for each of them along with its .osz
+async fn create_test_maps(songs_dir: &Path) -> Vec<(MapRequest, Vec<u8>)> {
+    create_test_song(songs_dir, "1 Artist - First", &[(10, "Easy")]);
+    create_test_song(songs_dir, "2 Artist - Second", &[(20, "Normal"), (21, "Hard")]);
+    create_test_song(songs_dir, "3 Artist - Third", &[(30, "Insane")]);
+    let mut songs = file_manager::read_local_files(songs_dir, &Mutex::new(SongCache::default())).await.unwrap();
+    songs.sort_by(|a, b| a.folder.cmp(&b.folder));
+
+    songs.into_iter()
+        .map(|song| {
+            let osz_data = file_manager::song_to_osz(&song, None).unwrap();
+            (MapRequest::full(song), osz_data)
+        })
+        .collect()
+}
+
+/// Sends a map the way the writing thread does, but only the first `len` bytes of it
+async fn write_map(name: &str, osz_data: &[u8], len: usize, remote_socket: &mut SecureStream) {
+    let map = DownloadMapPacket {
+        name: name.to_string(), size: osz_data.len() as u64, offset: 0, manifest: MapManifest::default(), file_sizes: None,
+        osz_data: None, folder: None
+    };
+    write_packet(map, remote_socket).await;
+    for chunk in osz_data[..len].chunks(64 * 1024) {
+        write_packet(DownloadDataPacket::new(chunk.to_vec()), remote_socket).await;
+    }
+}
+
+fn test_song(id: u64, name: &str, checksum: &str) -> SongFolder {
+    SongFolder {
+        id,
+        name: name.to_string(),
+        folder: format!("{id} {name}"),
+        checksum: checksum.to_string(),
+        difficulties: Vec::new(),
+        size: 0,
+        file_count: 0,
+        assets: AssetSizes::default(),
+        path: None
+    }
+}
+
+#[test]
+fn test_diff_songs() {
+    let local_songs = vec![
+        test_song(1, "A - Identical", "AAA"),
+        test_song(2, "B - Different", "BBB"),
+        test_song(3, "C - Only local", "CCC"),
+        // An outdated second copy of a song we have is still a different version of it
+        test_song(1, "A - Identical", "OLD"),
+    ];
+    let remote_songs = vec![
+        test_song(1, "A - Identical", "AAA"),
+        test_song(2, "B - Different", "XXX"),
+        test_song(4, "D - Only remote", "DDD"),
+        // Same id, but a different name means it's a different song
+        test_song(3, "C - Renamed", "CCC"),
+    ];
+
+    let diff = diff::diff_songs(&local_songs, &remote_songs);
+    let summarize_pairs = |pairs: &Vec<diff::SongPair>| pairs.iter()
+        .map(|pair| format!("{} ({} / {})", pair.local.name, pair.local.checksum, pair.remote.checksum))
+        .collect::<Vec<String>>();
+    let summarize = |songs: &Vec<SongFolder>| songs.iter()
+        .map(|song| song.name.clone())
+        .collect::<Vec<String>>();
+
+    check(
+        (summarize_pairs(&diff.identical), summarize_pairs(&diff.different),
+         summarize(&diff.missing_locally), summarize(&diff.missing_remotely)),
+        expect![[r#"
+            (
+                [
+                    "A - Identical (AAA / AAA)",
+                ],
+                [
+                    "B - Different (BBB / XXX)",
+                    "A - Identical (OLD / AAA)",
+                ],
+                [
+                    "D - Only remote",
+                    "C - Renamed",
+                ],
+                [
+                    "C - Only local",
+                ],
+            )
+        "#]]
+    );
+}
+
+#[test]
+fn test_parse_osu_file() {
+    let osu_file = OsuFile::parse("\u{feff}osu file format v14
+
+[General]
+AudioFilename: audio.mp3
+Mode: 0
+
+[Metadata]
+Title:Through The Fire And Flames
+Artist:DragonForce
+Creator:Ekoro
+Version:Legend
+BeatmapID:-1
+BeatmapSetID:1752
+
+[Difficulty]
+HPDrainRate:7
+CircleSize:4.2
+OverallDifficulty:8
+SliderMultiplier:1.8
+SliderTickRate:2
+
+[HitObjects]
+Title:Not metadata
+");
 
     check(
-        created_zip.metadata().unwrap().len(),
+        osu_file,
         expect![[r#"
-            17795234
+            OsuFile {
+                format_version: Some(
+                    14,
+                ),
+                audio_filename: Some(
+                    "audio.mp3",
+                ),
+                title: Some(
+                    "Through The Fire And Flames",
+                ),
+                artist: Some(
+                    "DragonForce",
+                ),
+                creator: Some(
+                    "Ekoro",
+                ),
+                version: Some(
+                    "Legend",
+                ),
+                beatmap_id: None,
+                beatmap_set_id: Some(
+                    1752,
+                ),
+                difficulty: DifficultySettings {
+                    hp_drain_rate: 7.0,
+                    circle_size: 4.2,
+                    overall_difficulty: 8.0,
+                    approach_rate: 8.0,
+                    slider_multiplier: 1.8,
+                    slider_tick_rate: 2.0,
+                },
+                background: None,
+                video: None,
+                storyboard_files: [],
+            }
         "#]]
     );
+}
+
+#[tokio::test]
+async fn test_read_renamed_folder() {
+    let songs_dir = tempfile::tempdir().unwrap();
+
+    // A renamed folder should still get its id and name from the .osu file
+    let song_path = songs_dir.path().join("my favourite song");
+    fs::create_dir(&song_path).unwrap();
+    fs::write(
+        song_path.join("DragonForce - Through The Fire And Flames (Ekoro) [Legend].osu"),
+        "osu file format v14\n\n[Metadata]\nTitle:Through The Fire And Flames\nArtist:DragonForce\nVersion:Legend\nBeatmapID:3814\nBeatmapSetID:1752\n"
+    ).unwrap();
 
-    let mut test_zip_file = File::create("src/test/testsongs/test_zip.zip").unwrap();
-    io::copy(&mut created_zip, &mut test_zip_file).unwrap();
+    // While an unsubmitted map without any of that falls back to the folder name
+    create_test_song(songs_dir.path(), "unsubmitted map", &[(0, "Normal")]);
+
+    // And folders without any beatmaps are skipped
+    fs::create_dir(songs_dir.path().join("Failed")).unwrap();
+
+    let mut songs = file_manager::read_local_files(songs_dir.path(), &Mutex::new(SongCache::default())).await.unwrap();
+    songs.sort_by(|a, b| a.folder.cmp(&b.folder));
+    check(
+        songs.iter().map(|song| (song.id, song.name.clone(), song.folder.clone())).collect::<Vec<(u64, String, String)>>(),
+        expect![[r#"
+            [
+                (
+                    1752,
+                    "DragonForce - Through The Fire And Flames",
+                    "my favourite song",
+                ),
+                (
+                    0,
+                    "unsubmitted map",
+                    "unsubmitted map",
+                ),
+            ]
+        "#]]
+    );
 }
 
-async fn setup_test_packet_server() -> (TcpStream, PacketManager, Arc<Mutex<Vec<SongFolder>>>, Arc<Mutex<Vec<SongFolder>>>, MockWindow) {
+async fn setup_test_packet_server() -> (SecureStream, PacketManager, Arc<Mutex<Vec<SongFolder>>>, PeerId, MockWindow) {
     // Create packet manager
     let mut packet_server = PacketManager::new();
+    let local_path = Arc::new(Mutex::new(None));
     let local_songs = Arc::new(Mutex::new(Vec::new()));
-    let remote_songs = Arc::new(Mutex::new(Vec::new()));
+    let song_cache = Arc::new(Mutex::new(SongCache::default()));
     let window = MockWindow::new();
-    packet_server.connect_to_test(local_songs.clone(), remote_songs.clone(), window.clone());
+    packet_server.connect_to_app(local_path, local_songs.clone(), song_cache, Arc::new(window.clone()));
 
     // Spin up two local sockets
+    let (local_socket, remote_socket) = socket_pair().await;
+
+    let peer = packet_server.connect(local_socket, HelloPacket::new("Remote".to_string()));
+    (remote_socket, packet_server, local_songs, peer, window)
+}
+
+/// Waits until the window has been sent the given message, giving up after a few seconds
+async fn wait_for_message(window: &MockWindow, message: &str) {
+    for _ in 0..50 {
+        if window.get_messages().iter().any(|sent| sent == message) {
+            return;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    panic!("Never got {message:?}, only {:?}", window.get_messages());
+}
+
+/// Connects two local sockets to each other
+async fn tcp_pair() -> (TcpStream, TcpStream) {
     let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
     let addr = listener.local_addr().unwrap();
-
     let local_socket = TcpStream::connect(addr).await.unwrap();
     let remote_socket = listener.accept().await.unwrap().0;
+    (local_socket, remote_socket)
+}
 
-    packet_server.connect(local_socket);
-    (remote_socket, packet_server, local_songs, remote_songs, window)
+/// Connects two local sockets to each other over an encrypted connection
+async fn socket_pair() -> (SecureStream, SecureStream) {
+    socket_pair_between(&Identity::generate(), &Identity::generate()).await
 }
 
-async fn write_packet(packet: impl Packet, remote_socket: &mut TcpStream) {
-    println!("Writing {} packet...", packet.get_header());
-    remote_socket.write_all(
-        format!("{}\n{}\n", packet.get_header(), packet.get_data()).as_bytes()
-    ).await.unwrap();
+/// Connects two local sockets to each other over an encrypted connection, with each side
+/// proving it holds the given identity
+async fn socket_pair_between(local_identity: &Identity, remote_identity: &Identity) -> (SecureStream, SecureStream) {
+    let (local_socket, remote_socket) = tcp_pair().await;
+    let (local_socket, remote_socket) = tokio::join!(
+        SecureStream::connect(local_socket, local_identity),
+        SecureStream::accept(remote_socket, remote_identity)
+    );
+    (local_socket.unwrap(), remote_socket.unwrap())
+}
+
+async fn write_packet(packet: impl Packet, remote_socket: &mut SecureStream) {
+    println!("Writing packet {}...", packet.get_header());
+    write_frame(remote_socket, &Frame::from_packet(&packet).unwrap()).await.unwrap();
+    remote_socket.flush().await.unwrap();
 }
 
-async fn close_connection(remote_socket: &mut TcpStream) {
+async fn close_connection(remote_socket: &mut SecureStream) {
     println!("Closing connection");
     write_packet(DisconnectPacket::new(), remote_socket).await;
 }
@@ -138,7 +857,7 @@ async fn test_map_list_request_packet() {
     let (mut remote_socket,
         _packet_server,
         local_songs,
-        _remote_songs,
+        _peer,
         window) = setup_test_packet_server().await;
 
     println!("Server setup correctly");
@@ -149,36 +868,34 @@ async fn test_map_list_request_packet() {
     let packet = MapListRequestPacket::new();
     write_packet(packet, &mut remote_socket).await;
 
-    let mut buf_reader = BufReader::new(&mut remote_socket);
+    println!("Waiting for response...");
 
-    println!("Waiting for response header...");
-
-    let mut response_header= String::new();
-    buf_reader.read_line(&mut response_header).await.unwrap();
+    let response = read_frame(&mut remote_socket).await.unwrap();
     check(
-        response_header,
+        response.header,
         expect![[r#"
-            "MapListPacket\n"
+            2
         "#]]
     );
 
-    println!("Waiting for response data...");
-
-    let mut response_data = String::new();
-    buf_reader.read_line(&mut response_data).await.unwrap();
+    let response_data = String::from_utf8(response.payload).unwrap();
     check(
         response_data,
         expect![[r#"
-            "[{\"id\":1752,\"name\":\"DragonForce - Through The Fire And Flames\",\"checksum\":\"F9C1ED218A7E13BD3C55EE65BEE323A5B89F0015E4F0BE9A187602BBD23192DA\"},{\"id\":3030,\"name\":\"Lucky Star - Motteke! Sailor Fuku (REDALiCE Remix)\",\"checksum\":\"EB28D7411563346E4803E8095A245626DDDB28BAEDAD0C2B519DF114C2A4AA5B\"},{\"id\":3756,\"name\":\"Peter Lambert - osu! tutorial\",\"checksum\":\"96E110E2307A99D46330607EF5A8ACB51C674773B24EDEE8138273B73CD8F463\"},{\"id\":5445,\"name\":\"Hanataba - Night of Knights\",\"checksum\":\"45BEA6AC53D0397FABF4C906ED770963DE8BB4B1086C5AAA9E6116B3DC26D7DD\"},{\"id\":7380,\"name\":\"Caramell - Caramelldansen (Speedycake Remix)\",\"checksum\":\"A7E11AF5A2D094C505E66E8AE9ABEF363F533DE08CBED4B84C3C18DF6251B31D\"},{\"id\":8033,\"name\":\"ZUN - Reach for the Moon, Immortal Smoke\",\"checksum\":\"8E2A78161FD3DBAD7604D4914C54E03D1C025E235C708CB851EE19C0F82639A5\"},{\"id\":8284,\"name\":\"Hatsune Miku - Hatsune Miku no Shoushitsu\",\"checksum\":\"FA1AD88FF5AA1FEF27279C5A57695F24A49568040FA211529A5A77CC78B650C1\"},{\"id\":8299,\"name\":\"Wiklund - Whip the Blip\",\"checksum\":\"18B077618409F9092BF8699CB5AB60F1EDB19B3AA30988222155D11E1658D7AA\"},{\"id\":8830,\"name\":\"ZUN - Lunatic Red Eyes _ Invisible Full Moon\",\"checksum\":\"5A91938EAA21BA3109FA313CC9F9A80991DCC484369B3236693E6A9F8DAEA3C8\"},{\"id\":9040,\"name\":\"Wiklund - Billy Boogie\",\"checksum\":\"B92C9596AC28C1DE7A8DEF05C0FB1A5533D2EAE9FB77110051A9A81B0F315425\"},{\"id\":9197,\"name\":\"Wiklund - Joy of Living\",\"checksum\":\"5BB25EFDAFB5A9D14CDD667E698583830FCDFF495157859527E70DA86291BAD6\"}]\n"
+            "[{\"id\":1752,\"name\":\"DragonForce - Through The Fire And Flames\",\"checksum\":\"F9C1ED218A7E13BD3C55EE65BEE323A5B89F0015E4F0BE9A187602BBD23192DA\"},{\"id\":3030,\"name\":\"Lucky Star - Motteke! Sailor Fuku (REDALiCE Remix)\",\"checksum\":\"EB28D7411563346E4803E8095A245626DDDB28BAEDAD0C2B519DF114C2A4AA5B\"},{\"id\":3756,\"name\":\"Peter Lambert - osu! tutorial\",\"checksum\":\"96E110E2307A99D46330607EF5A8ACB51C674773B24EDEE8138273B73CD8F463\"},{\"id\":5445,\"name\":\"Hanataba - Night of Knights\",\"checksum\":\"45BEA6AC53D0397FABF4C906ED770963DE8BB4B1086C5AAA9E6116B3DC26D7DD\"},{\"id\":7380,\"name\":\"Caramell - Caramelldansen (Speedycake Remix)\",\"checksum\":\"A7E11AF5A2D094C505E66E8AE9ABEF363F533DE08CBED4B84C3C18DF6251B31D\"},{\"id\":8033,\"name\":\"ZUN - Reach for the Moon, Immortal Smoke\",\"checksum\":\"8E2A78161FD3DBAD7604D4914C54E03D1C025E235C708CB851EE19C0F82639A5\"},{\"id\":8284,\"name\":\"Hatsune Miku - Hatsune Miku no Shoushitsu\",\"checksum\":\"FA1AD88FF5AA1FEF27279C5A57695F24A49568040FA211529A5A77CC78B650C1\"},{\"id\":8299,\"name\":\"Wiklund - Whip the Blip\",\"checksum\":\"18B077618409F9092BF8699CB5AB60F1EDB19B3AA30988222155D11E1658D7AA\"},{\"id\":8830,\"name\":\"ZUN - Lunatic Red Eyes _ Invisible Full Moon\",\"checksum\":\"5A91938EAA21BA3109FA313CC9F9A80991DCC484369B3236693E6A9F8DAEA3C8\"},{\"id\":9040,\"name\":\"Wiklund - Billy Boogie\",\"checksum\":\"B92C9596AC28C1DE7A8DEF05C0FB1A5533D2EAE9FB77110051A9A81B0F315425\"},{\"id\":9197,\"name\":\"Wiklund - Joy of Living\",\"checksum\":\"5BB25EFDAFB5A9D14CDD667E698583830FCDFF495157859527E70DA86291BAD6\"}]"
         "#]]
     );
 
     close_connection(&mut remote_socket).await;
+    wait_for_message(&window, "peer-disconnected: 1").await;
 
     check(
         window.get_messages(),
         expect![[r#"
-            []
+            [
+                "peer-connected: 1",
+                "peer-disconnected: 1",
+            ]
         "#]]
     )
 }
@@ -186,9 +903,9 @@ async fn test_map_list_request_packet() {
 #[tokio::test]
 async fn test_map_list_packet() {
     let (mut remote_socket,
-        _packet_server,
+        packet_server,
         _local_songs,
-        remote_songs,
+        peer,
         window) = setup_test_packet_server().await;
 
     println!("Server setup correctly");
@@ -199,7 +916,7 @@ async fn test_map_list_packet() {
     write_packet(packet, &mut remote_socket).await;
 
     sleep(Duration::from_millis(500)).await;
-    let remote_songs = remote_songs.lock().unwrap().clone();
+    let remote_songs = packet_server.remote_songs(peer).unwrap();
     let matching = (songs.len() == remote_songs.len()) &&
         songs.iter().zip(remote_songs).all(|(a, b)| {
             a.id == b.id && a.name == b.name && a.checksum == b.checksum
@@ -207,12 +924,15 @@ async fn test_map_list_packet() {
     assert!(matching, "Received songs do not match the expected list!");
 
     close_connection(&mut remote_socket).await;
+    wait_for_message(&window, "peer-disconnected: 1").await;
 
     check(
         window.get_messages(),
         expect![[r#"
             [
-                "remote-songs-updated: null",
+                "peer-connected: 1",
+                "remote-songs-updated: 1",
+                "peer-disconnected: 1",
             ]
         "#]]
     )
@@ -223,7 +943,7 @@ async fn test_download_request_packet() {
     let (mut remote_socket,
         _packet_server,
         local_songs,
-        _remote_songs,
+        _peer,
         window) = setup_test_packet_server().await;
 
     println!("Server setup correctly");
@@ -232,174 +952,1735 @@ async fn test_download_request_packet() {
     *local_songs.lock().unwrap() = songs.clone();
 
     songs.truncate(3);
-    let packet = DownloadRequestPacket::new(songs);
+    let packet = DownloadRequestPacket::new(songs.into_iter().map(MapRequest::full).collect());
     write_packet(packet, &mut remote_socket).await;
 
-    let mut buf_reader = BufReader::new(&mut remote_socket);
-
-    println!("Waiting for response header...");
+    println!("Waiting for response...");
 
-    let mut response_header= String::new();
-    buf_reader.read_line(&mut response_header).await.unwrap();
+    let response = read_frame(&mut remote_socket).await.unwrap();
     check(
-        response_header,
+        response.header,
         expect![[r#"
-            "DownloadResponsePacket\n"
+            4
+        "#]]
+    );
+    check(
+        DownloadResponsePacket::deserialize(response.payload).unwrap().map_count,
+        expect![[r#"
+            3
         "#]]
     );
 
-    println!("Waiting for response data...");
-
-    let mut response_data = String::new();
-    buf_reader.read_line(&mut response_data).await.unwrap();
+    // Each map should follow, starting with the first one we asked for
+    let map = read_frame(&mut remote_socket).await.unwrap();
+    assert_eq!(map.header, DownloadMapPacket::HEADER);
     check(
-        response_data,
+        DownloadMapPacket::deserialize(map.payload).unwrap().name,
         expect![[r#"
-            "17795234\n"
+            "1752 DragonForce - Through The Fire And Flames.osz"
         "#]]
     );
+    let data = read_frame(&mut remote_socket).await.unwrap();
+    assert_eq!(data.header, DownloadDataPacket::HEADER);
 
     close_connection(&mut remote_socket).await;
+    wait_for_message(&window, "peer-disconnected: 1").await;
 
     check(
         window.get_messages(),
         expect![[r#"
-            []
+            [
+                "peer-connected: 1",
+                "peer-disconnected: 1",
+            ]
         "#]]
     )
 }
 
-#[tokio::test] #[ignore]
+/// Sets up a packet manager that installs everything it downloads into the given songs folder
+fn setup_download_packet_server(songs_dir: &Path) -> (PacketManager, MockWindow) {
+    let mut packet_server = PacketManager::new();
+    let window = MockWindow::new();
+    packet_server.connect_to_app(Arc::new(Mutex::new(Some(songs_dir.to_path_buf()))), Arc::new(Mutex::new(Vec::new())),
+                                 Arc::new(Mutex::new(SongCache::default())), Arc::new(window.clone()));
+    packet_server.set_download_mode(DownloadMode::Install);
+    (packet_server, window)
+}
+
+#[tokio::test]
 async fn test_download_response_packet() {
-    let (mut remote_socket,
-        _packet_server,
-        _local_songs,
-        _remote_songs,
-        window) = setup_test_packet_server().await;
+    let remote_dir = tempfile::tempdir().unwrap();
+    let maps = create_test_maps(remote_dir.path()).await;
 
-    println!("Server setup correctly");
+    let songs_dir = tempfile::tempdir().unwrap();
+    let (mut packet_server, window) = setup_download_packet_server(songs_dir.path());
+    let (local_socket, mut remote_socket) = socket_pair().await;
+    packet_server.connect(local_socket, HelloPacket::new("Remote".to_string()));
 
-    let mut test_zip = tokio::fs::File::open("src/test/testsongs/test_zip.zip").await.unwrap();
+    write_packet(DownloadResponsePacket { map_count: maps.len() as u32, maps: None }, &mut remote_socket).await;
+    for (request, osz_data) in &maps {
+        write_map(&format!("{}.osz", request.song.folder), osz_data, osz_data.len(), &mut remote_socket).await;
+    }
+    wait_for_message(&window, "download-finished: 1").await;
+    close_connection(&mut remote_socket).await;
+    wait_for_message(&window, "peer-disconnected: 1").await;
 
-    let packet = DownloadResponsePacket::new(test_zip.try_clone().await.unwrap()).await;
-    write_packet(packet, &mut remote_socket).await;
+    check(
+        window.get_messages(),
+        expect![[r#"
+            [
+                "peer-connected: 1",
+                "ask-dialog: {\"title\":\"Download Maps\",\"message\":\"You are about to download 3 maps. Continue?\"}",
+                "download-started: 1",
+                "download-progress: {\"peer\":1,\"map\":\"1 Artist - First.osz\",\"maps_done\":0,\"map_count\":3,\"map_progress\":100}",
+                "download-progress: {\"peer\":1,\"map\":\"2 Artist - Second.osz\",\"maps_done\":1,\"map_count\":3,\"map_progress\":100}",
+                "download-progress: {\"peer\":1,\"map\":\"3 Artist - Third.osz\",\"maps_done\":2,\"map_count\":3,\"map_progress\":100}",
+                "local-songs-updated: null",
+                "download-finished: 1",
+                "peer-disconnected: 1",
+            ]
+        "#]]
+    );
 
-    let mut file = Vec::new();
-    test_zip.read_to_end(&mut file).await.unwrap();
-    remote_socket.write_all(&file).await.unwrap();
-    remote_socket.flush().await.unwrap();
+    let mut installed = file_manager::read_local_files(songs_dir.path(), &Mutex::new(SongCache::default())).await.unwrap();
+    installed.sort_by(|a, b| a.folder.cmp(&b.folder));
+    assert_eq!(
+        installed.iter().map(|song| song.checksum.clone()).collect::<Vec<String>>(),
+        maps.iter().map(|(request, _)| request.song.checksum.clone()).collect::<Vec<String>>()
+    );
+}
+
+/// Downloads in save mode go through the same dialogs as in the app, saving wherever the user picks
+#[tokio::test]
+async fn test_download_to_picked_folder() {
+    let remote_dir = tempfile::tempdir().unwrap();
+    let maps = create_test_maps(remote_dir.path()).await;
 
-    sleep(Duration::from_secs(5)).await;
+    let save_dir = tempfile::tempdir().unwrap();
+    let window = MockWindow::picking_folder(save_dir.path());
+    let mut packet_server = PacketManager::new();
+    packet_server.connect_to_app(Arc::new(Mutex::new(None)), Arc::new(Mutex::new(Vec::new())),
+                                 Arc::new(Mutex::new(SongCache::default())), Arc::new(window.clone()));
+    packet_server.set_download_mode(DownloadMode::Save);
+    let (local_socket, mut remote_socket) = socket_pair().await;
+    packet_server.connect(local_socket, HelloPacket::new("Remote".to_string()));
+
+    write_packet(DownloadResponsePacket { map_count: maps.len() as u32, maps: None }, &mut remote_socket).await;
+    for (request, osz_data) in &maps {
+        write_map(&format!("{}.osz", request.song.folder), osz_data, osz_data.len(), &mut remote_socket).await;
+    }
+    wait_for_message(&window, "download-finished: 1").await;
     close_connection(&mut remote_socket).await;
+    wait_for_message(&window, "peer-disconnected: 1").await;
+
+    let mut saved: Vec<String> = fs::read_dir(save_dir.path()).unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+        .collect();
+    saved.sort();
+    check(
+        saved,
+        expect![[r#"
+            [
+                "1 Artist - First.osz",
+                "2 Artist - Second.osz",
+                "3 Artist - Third.osz",
+            ]
+        "#]]
+    );
+    for (request, osz_data) in &maps {
+        assert_eq!(&fs::read(save_dir.path().join(format!("{}.osz", request.song.folder))).unwrap(), osz_data);
+    }
+}
+
+/// The app and incoming downloads share one prompt for where maps should go
+#[test]
+fn test_choose_destination() {
+    let songs_dir = PathBuf::from("/songs");
+    let window = MockWindow::picking_folder(Path::new("/picked"));
+    let message = "You are about to download 3 maps. Continue?";
+    check(
+        [
+            DownloadDestination::choose(&window, message, DownloadMode::Install, Some(songs_dir.clone())),
+            DownloadDestination::choose(&window, message, DownloadMode::Install, None),
+            DownloadDestination::choose(&window, message, DownloadMode::Save, Some(songs_dir.clone())),
+            DownloadDestination::choose(&MockWindow::new(), message, DownloadMode::Save, Some(songs_dir)),
+        ],
+        expect![[r#"
+            [
+                Some(
+                    Install(
+                        "/songs",
+                    ),
+                ),
+                Some(
+                    Save(
+                        "/picked",
+                    ),
+                ),
+                Some(
+                    Save(
+                        "/picked",
+                    ),
+                ),
+                None,
+            ]
+        "#]]
+    );
+    check(window.get_messages(), expect![[r#"
+        [
+            "ask-dialog: {\"title\":\"Download Maps\",\"message\":\"You are about to download 3 maps. Continue?\"}",
+            "ask-dialog: {\"title\":\"Download Maps\",\"message\":\"You are about to download 3 maps. Continue?\"}",
+            "ask-dialog: {\"title\":\"Download Maps\",\"message\":\"You are about to download 3 maps. Continue?\"}",
+        ]
+    "#]]);
+}
+
+#[tokio::test]
+async fn test_resume_download() {
+    let remote_dir = tempfile::tempdir().unwrap();
+    let maps = create_test_maps(remote_dir.path()).await;
+    let requests: Vec<MapRequest> = maps.iter().map(|(request, _)| request.clone()).collect();
+
+    let songs_dir = tempfile::tempdir().unwrap();
+    let (mut packet_server, window) = setup_download_packet_server(songs_dir.path());
+    let remote_identity = Identity::generate();
+    let (local_socket, mut remote_socket) = socket_pair_between(&Identity::generate(), &remote_identity).await;
+    let peer = packet_server.connect(local_socket, HelloPacket::new("Remote".to_string()));
+    packet_server.request_download(peer, requests.clone());
+    let request = read_frame(&mut remote_socket).await.unwrap();
+    assert_eq!(request.header, DownloadRequestPacket::HEADER);
+
+    // Lose the connection partway through the third map
+    write_packet(DownloadResponsePacket { map_count: 3, maps: None }, &mut remote_socket).await;
+    for (request, osz_data) in &maps[..2] {
+        write_map(&format!("{}.osz", request.song.folder), osz_data, osz_data.len(), &mut remote_socket).await;
+    }
+    let third_osz = &maps[2].1;
+    write_map("3 Artist - Third.osz", third_osz, third_osz.len() / 2, &mut remote_socket).await;
+    drop(remote_socket);
+    wait_for_message(&window, "peer-disconnected: 1").await;
+
+    // Only the third map should be left, and we should carry on from the part of it we have
+    let partial_download = packet_server.partial_download(&remote_identity.public_key()).unwrap();
+    assert_eq!(partial_download.requested_maps.len(), 1);
+    let resume_from = partial_download.resume_from().unwrap();
+    assert_eq!(resume_from.as_ref().map(|resume_from| resume_from.offset), Some(third_osz.len() as u64 / 2));
+    assert!(songs_dir.path().join(".osu-mapsync").join("3 Artist - Third.osz.part").is_file());
+
+    // Reconnect to the same peer, which has the same songs, and pick up where we left off
+    let mut remote_server = PacketManager::new();
+    let remote_songs = file_manager::read_local_files(remote_dir.path(), &Mutex::new(SongCache::default())).await.unwrap();
+    remote_server.connect_to_app(Arc::new(Mutex::new(None)), Arc::new(Mutex::new(remote_songs)),
+                                 Arc::new(Mutex::new(SongCache::default())), Arc::new(MockWindow::new()));
+    let (local_socket, remote_socket) = socket_pair_between(&Identity::generate(), &remote_identity).await;
+    remote_server.connect(remote_socket, HelloPacket::new("Local".to_string()));
+    let peer = packet_server.connect(local_socket, HelloPacket::new("Remote".to_string()));
+    packet_server.resume_download(peer, partial_download, resume_from, None);
+    wait_for_message(&window, "download-finished: 2").await;
 
     check(
         window.get_messages(),
         expect![[r#"
             [
-                "ask-dialog: {\"title\":\"Download Zip\",\"message\":\"You are about to download a 14 MB zip file. Continue?\"}",
-                "download-started: null",
-                "download-progress: 1",
-                "download-progress: 2",
-                "download-progress: 3",
-                "download-progress: 4",
-                "download-progress: 5",
-                "download-progress: 6",
-                "download-progress: 7",
-                "download-progress: 8",
-                "download-progress: 9",
-                "download-progress: 10",
-                "download-progress: 11",
-                "download-progress: 12",
-                "download-progress: 13",
-                "download-progress: 14",
-                "download-progress: 15",
-                "download-progress: 16",
-                "download-progress: 17",
-                "download-progress: 18",
-                "download-progress: 19",
-                "download-progress: 20",
-                "download-progress: 21",
-                "download-progress: 22",
-                "download-progress: 23",
-                "download-progress: 24",
-                "download-progress: 25",
-                "download-progress: 26",
-                "download-progress: 27",
-                "download-progress: 28",
-                "download-progress: 29",
-                "download-progress: 30",
-                "download-progress: 31",
-                "download-progress: 32",
-                "download-progress: 33",
-                "download-progress: 34",
-                "download-progress: 35",
-                "download-progress: 36",
-                "download-progress: 37",
-                "download-progress: 38",
-                "download-progress: 39",
-                "download-progress: 40",
-                "download-progress: 41",
-                "download-progress: 42",
-                "download-progress: 43",
-                "download-progress: 44",
-                "download-progress: 45",
-                "download-progress: 46",
-                "download-progress: 47",
-                "download-progress: 48",
-                "download-progress: 49",
-                "download-progress: 50",
-                "download-progress: 51",
-                "download-progress: 52",
-                "download-progress: 53",
-                "download-progress: 54",
-                "download-progress: 55",
-                "download-progress: 56",
-                "download-progress: 57",
-                "download-progress: 58",
-                "download-progress: 59",
-                "download-progress: 60",
-                "download-progress: 61",
-                "download-progress: 62",
-                "download-progress: 63",
-                "download-progress: 64",
-                "download-progress: 65",
-                "download-progress: 66",
-                "download-progress: 67",
-                "download-progress: 68",
-                "download-progress: 69",
-                "download-progress: 70",
-                "download-progress: 71",
-                "download-progress: 72",
-                "download-progress: 73",
-                "download-progress: 74",
-                "download-progress: 75",
-                "download-progress: 76",
-                "download-progress: 77",
-                "download-progress: 78",
-                "download-progress: 79",
-                "download-progress: 80",
-                "download-progress: 81",
-                "download-progress: 82",
-                "download-progress: 83",
-                "download-progress: 84",
-                "download-progress: 85",
-                "download-progress: 86",
-                "download-progress: 87",
-                "download-progress: 88",
-                "download-progress: 89",
-                "download-progress: 90",
-                "download-progress: 91",
-                "download-progress: 92",
-                "download-progress: 93",
-                "download-progress: 94",
-                "download-progress: 95",
-                "download-progress: 96",
-                "download-progress: 97",
-                "download-progress: 98",
-                "download-progress: 99",
-                "download-progress: 100",
-                "download-finished: null",
+                "peer-connected: 1",
+                "ask-dialog: {\"title\":\"Download Maps\",\"message\":\"You are about to download 3 maps. Continue?\"}",
+                "download-started: 1",
+                "download-progress: {\"peer\":1,\"map\":\"1 Artist - First.osz\",\"maps_done\":0,\"map_count\":3,\"map_progress\":100}",
+                "download-progress: {\"peer\":1,\"map\":\"2 Artist - Second.osz\",\"maps_done\":1,\"map_count\":3,\"map_progress\":100}",
+                "download-progress: {\"peer\":1,\"map\":\"3 Artist - Third.osz\",\"maps_done\":2,\"map_count\":3,\"map_progress\":50}",
+                "download-interrupted: 1",
+                "connection-error: {\"peer\":1,\"code\":\"ConnectionLost\",\"message\":\"The connection was closed\"}",
+                "peer-disconnected: 1",
+                "peer-connected: 2",
+                "download-started: 2",
+                "download-progress: {\"peer\":2,\"map\":\"3 Artist - Third.osz\",\"maps_done\":0,\"map_count\":1,\"map_progress\":100}",
+                "local-songs-updated: null",
+                "download-finished: 2",
             ]
         "#]]
-    )
-}
\ No newline at end of file
+    );
+
+    let mut installed = file_manager::read_local_files(songs_dir.path(), &Mutex::new(SongCache::default())).await.unwrap();
+    installed.sort_by(|a, b| a.folder.cmp(&b.folder));
+    assert_eq!(
+        installed.iter().map(|song| song.checksum.clone()).collect::<Vec<String>>(),
+        requests.iter().map(|request| request.song.checksum.clone()).collect::<Vec<String>>()
+    );
+    assert!(!songs_dir.path().join(".osu-mapsync").exists());
+}
+
+/// A .osz that can't be installed doesn't leave its part file behind
+#[tokio::test]
+async fn test_install_broken_osz() {
+    let songs_dir = tempfile::tempdir().unwrap();
+    let (mut packet_server, window) = setup_download_packet_server(songs_dir.path());
+    let (local_socket, mut remote_socket) = socket_pair().await;
+    packet_server.connect(local_socket, HelloPacket::new("Remote".to_string()));
+
+    let osz_data = b"not a zip file".to_vec();
+    write_packet(DownloadResponsePacket { map_count: 1, maps: None }, &mut remote_socket).await;
+    write_map("1 Artist - Broken.osz", &osz_data, osz_data.len(), &mut remote_socket).await;
+    wait_for_message(&window, "download-finished: 1").await;
+    close_connection(&mut remote_socket).await;
+
+    assert_eq!(fs::read_dir(songs_dir.path()).unwrap().count(), 0);
+}
+
+#[tokio::test]
+async fn test_multiple_peers() {
+    let (mut first_socket,
+        mut packet_server,
+        _local_songs,
+        first,
+        window) = setup_test_packet_server().await;
+    let second_identity = Identity::generate();
+    let (local_socket, mut second_socket) = socket_pair_between(&Identity::generate(), &second_identity).await;
+    let second = packet_server.connect(local_socket, HelloPacket::new("Second".to_string()));
+
+    // Each peer keeps its own list of songs
+    write_packet(MapListPacket::new(vec![test_song(1, "Artist - First", "A")]), &mut first_socket).await;
+    wait_for_message(&window, "remote-songs-updated: 1").await;
+    write_packet(MapListPacket::new(vec![test_song(2, "Artist - Second", "B")]), &mut second_socket).await;
+    wait_for_message(&window, "remote-songs-updated: 2").await;
+    assert_eq!(packet_server.remote_songs(first).unwrap()[0].name, "Artist - First");
+    assert_eq!(packet_server.remote_songs(second).unwrap()[0].name, "Artist - Second");
+
+    // Packets only go to the peer they're addressed to
+    packet_server.send_packet(second, Box::new(MapListRequestPacket::new()));
+    assert_eq!(read_frame(&mut second_socket).await.unwrap().header, MapListRequestPacket::HEADER);
+
+    // A peer that connects again replaces its old connection, leaving the others alone
+    let (local_socket, mut reconnected_socket) = socket_pair_between(&Identity::generate(), &second_identity).await;
+    let reconnected = packet_server.connect(local_socket, HelloPacket::new("Second".to_string()));
+    assert_eq!(read_frame(&mut second_socket).await.unwrap().header, DisconnectPacket::HEADER);
+    close_connection(&mut second_socket).await;
+    wait_for_message(&window, "peer-disconnected: 2").await;
+    check(
+        packet_server.peers().iter().map(|peer| (peer.id, peer.hello.display_name.clone())).collect::<Vec<_>>(),
+        expect![[r#"
+            [
+                (
+                    1,
+                    "Remote",
+                ),
+                (
+                    3,
+                    "Second",
+                ),
+            ]
+        "#]]
+    );
+
+    close_connection(&mut first_socket).await;
+    close_connection(&mut reconnected_socket).await;
+    wait_for_message(&window, "peer-disconnected: 1").await;
+    wait_for_message(&window, &format!("peer-disconnected: {reconnected}")).await;
+    assert!(packet_server.peers().is_empty());
+}
+
+#[test]
+fn test_download_planner() {
+    let first = test_song(1, "Artist - First", "A");
+    let second = test_song(2, "Artist - Second", "B");
+    let third = test_song(3, "Artist - Third", "C");
+    let missing = test_song(4, "Artist - Missing", "D");
+    let remote_songs = [
+        (1, vec![first.clone(), test_song(2, "Artist - Second", "Changed"), third.clone()]),
+        (2, vec![first.clone(), second.clone(), third.clone()])
+    ].into_iter().collect();
+    let requests = [&first, &second, &third, &missing].into_iter().cloned().map(MapRequest::full).collect();
+    let mut planner = DownloadPlanner::new(requests, remote_songs);
+    let assigned = |planner: &mut DownloadPlanner| {
+        planner.assign().into_iter()
+            .map(|(peer, maps)| (peer, maps.into_iter().map(|map| map.song.folder).collect::<Vec<String>>()))
+            .collect::<Vec<_>>()
+    };
+
+    // Maps are spread out between the peers with the same copy of them
+    check(
+        assigned(&mut planner),
+        expect![[r#"
+            [
+                (
+                    1,
+                    [
+                        "1 Artist - First",
+                        "3 Artist - Third",
+                    ],
+                ),
+                (
+                    2,
+                    [
+                        "2 Artist - Second",
+                    ],
+                ),
+            ]
+        "#]]
+    );
+
+    // A map that fails is asked for from another peer, once that peer is free
+    planner.map_finished(1, "3 Artist - Third", Err("Unable to zip up 3 Artist - Third".to_string()));
+    assert!(assigned(&mut planner).is_empty());
+    planner.map_finished(2, "2 Artist - Second", Ok(()));
+    check(
+        assigned(&mut planner),
+        expect![[r#"
+            [
+                (
+                    2,
+                    [
+                        "3 Artist - Third",
+                    ],
+                ),
+            ]
+        "#]]
+    );
+
+    // Losing a peer hands its maps to someone else, and its own copies can't be used anymore
+    planner.peer_lost(1);
+    planner.map_finished(2, "3 Artist - Third", Ok(()));
+    check(
+        assigned(&mut planner),
+        expect![[r#"
+            [
+                (
+                    2,
+                    [
+                        "1 Artist - First",
+                    ],
+                ),
+            ]
+        "#]]
+    );
+    planner.map_finished(2, "1 Artist - First", Ok(()));
+    assert!(planner.is_done());
+    check(
+        planner.summary(),
+        expect![[r#"
+            PullSummary {
+                downloaded: [
+                    "2 Artist - Second",
+                    "3 Artist - Third",
+                    "1 Artist - First",
+                ],
+                unavailable: [
+                    (
+                        "4 Artist - Missing",
+                        "None of the connected peers have this map",
+                    ),
+                ],
+            }
+        "#]]
+    );
+}
+
+/// Sets up a packet manager that has the given songs to send
+fn setup_remote_packet_server(songs: Vec<SongFolder>) -> PacketManager {
+    let mut packet_server = PacketManager::new();
+    packet_server.connect_to_app(Arc::new(Mutex::new(None)), Arc::new(Mutex::new(songs)),
+                                 Arc::new(Mutex::new(SongCache::default())), Arc::new(MockWindow::new()));
+    packet_server
+}
+
+#[tokio::test]
+async fn test_pull_from_many() {
+    let remote_dir = tempfile::tempdir().unwrap();
+    let maps = create_test_maps(remote_dir.path()).await;
+    let requests: Vec<MapRequest> = maps.iter().map(|(request, _)| request.clone()).collect();
+    let songs: Vec<SongFolder> = requests.iter().map(|request| request.song.clone()).collect();
+
+    // The first peer lists the third map, but can't actually send it
+    let mut broken_songs = songs.clone();
+    broken_songs[2].path = None;
+    let mut remotes = [setup_remote_packet_server(broken_songs), setup_remote_packet_server(songs)];
+
+    let songs_dir = tempfile::tempdir().unwrap();
+    let (mut packet_server, window) = setup_download_packet_server(songs_dir.path());
+    for (name, remote) in ["First", "Second"].into_iter().zip(remotes.iter_mut()) {
+        let (local_socket, remote_socket) = socket_pair().await;
+        remote.connect(remote_socket, HelloPacket::new("Local".to_string()));
+        let peer = packet_server.connect(local_socket, HelloPacket::new(name.to_string()));
+        packet_server.send_packet(peer, Box::new(MapListRequestPacket::new()));
+        wait_for_message(&window, &format!("remote-songs-updated: {peer}")).await;
+    }
+
+    let packet_server = Mutex::new(packet_server);
+    let mut summary = pull_maps(&packet_server, requests.clone(), DownloadDestination::Install(songs_dir.path().to_path_buf())).await;
+    summary.downloaded.sort();
+    check(
+        summary,
+        expect![[r#"
+            PullSummary {
+                downloaded: [
+                    "1 Artist - First",
+                    "2 Artist - Second",
+                    "3 Artist - Third",
+                ],
+                unavailable: [],
+            }
+        "#]]
+    );
+
+    // The third map should have come from the second peer instead
+    assert!(window.get_messages().contains(
+        &"connection-error: {\"peer\":1,\"code\":\"MapUnavailable\",\"message\":\"Unable to zip up 3 Artist - Third: The song has no folder\"}".to_string()
+    ));
+    let mut installed = file_manager::read_local_files(songs_dir.path(), &Mutex::new(SongCache::default())).await.unwrap();
+    installed.sort_by(|a, b| a.folder.cmp(&b.folder));
+    assert_eq!(
+        installed.iter().map(|song| song.checksum.clone()).collect::<Vec<String>>(),
+        requests.iter().map(|request| request.song.checksum.clone()).collect::<Vec<String>>()
+    );
+}
+
+#[tokio::test]
+async fn test_pull_refused() {
+    let remote_dir = tempfile::tempdir().unwrap();
+    let maps = create_test_maps(remote_dir.path()).await;
+    let requests: Vec<MapRequest> = maps.iter().map(|(request, _)| request.clone()).collect();
+
+    let songs_dir = tempfile::tempdir().unwrap();
+    let (mut packet_server, window) = setup_download_packet_server(songs_dir.path());
+    let (local_socket, mut remote_socket) = socket_pair().await;
+    let peer = packet_server.connect(local_socket, HelloPacket::new("Remote".to_string()));
+    write_packet(MapListPacket::new(requests.iter().map(|request| request.song.clone()).collect()), &mut remote_socket).await;
+    wait_for_message(&window, &format!("remote-songs-updated: {peer}")).await;
+
+    // The peer answers the request with an error, so none of the maps are coming
+    let packet_server = Mutex::new(packet_server);
+    let destination = DownloadDestination::Install(songs_dir.path().to_path_buf());
+    let (summary, _) = tokio::join!(
+        timeout(Duration::from_secs(5), pull_maps(&packet_server, requests, destination)),
+        async {
+            let request = read_frame(&mut remote_socket).await.unwrap();
+            assert_eq!(request.header, DownloadRequestPacket::HEADER);
+            write_packet(ErrorPacket::new(ErrorCode::MalformedPacket, "Unable to parse packet 3".to_string()), &mut remote_socket).await;
+        }
+    );
+    check(
+        summary.expect("The pull never finished"),
+        expect![[r#"
+            PullSummary {
+                downloaded: [],
+                unavailable: [
+                    (
+                        "1 Artist - First",
+                        "Unable to parse packet 3",
+                    ),
+                    (
+                        "2 Artist - Second",
+                        "Unable to parse packet 3",
+                    ),
+                    (
+                        "3 Artist - Third",
+                        "Unable to parse packet 3",
+                    ),
+                ],
+            }
+        "#]]
+    );
+}
+
+/// A peer that never answers the cancel of a pull it stopped sending can still be pulled from again
+#[tokio::test]
+async fn test_pull_abandoned() {
+    let remote_dir = tempfile::tempdir().unwrap();
+    let maps = create_test_maps(remote_dir.path()).await;
+    let requests: Vec<MapRequest> = maps.iter().map(|(request, _)| request.clone()).collect();
+
+    let songs_dir = tempfile::tempdir().unwrap();
+    let (mut packet_server, window) = setup_download_packet_server(songs_dir.path());
+    let (local_socket, mut remote_socket) = socket_pair().await;
+    let peer = packet_server.connect(local_socket, HelloPacket::new("Remote".to_string()));
+    write_packet(MapListPacket::new(requests.iter().map(|request| request.song.clone()).collect()), &mut remote_socket).await;
+    wait_for_message(&window, &format!("remote-songs-updated: {peer}")).await;
+    let destination = DownloadDestination::Install(songs_dir.path().to_path_buf());
+
+    // The peer goes quiet, so the pull gives up on it the way it does once it's been idle too long
+    let (sender, _outcomes) = tokio::sync::mpsc::unbounded_channel();
+    assert!(packet_server.start_pull(peer, requests.clone(), destination.clone(), sender.clone()));
+    assert_eq!(read_frame(&mut remote_socket).await.unwrap().header, DownloadRequestPacket::HEADER);
+    packet_server.abandon_download(peer);
+    assert_eq!(read_frame(&mut remote_socket).await.unwrap().header, CancelDownloadPacket::HEADER);
+
+    // The cancel never gets an answer, but the next pull goes ahead anyway
+    let packet_server = Mutex::new(packet_server);
+    let pulled = timeout(Duration::from_secs(5), async {
+        tokio::join!(
+            pull_maps(&packet_server, requests, destination),
+            async {
+                assert_eq!(read_download_request(&mut remote_socket).await.0.len(), 3);
+                write_packet(DownloadResponsePacket { map_count: 3, maps: None }, &mut remote_socket).await;
+                for (request, osz_data) in &maps {
+                    write_map(&format!("{}.osz", request.song.folder), osz_data, osz_data.len(), &mut remote_socket).await;
+                }
+            }
+        ).0
+    }).await;
+    check(pulled.expect("The pull never finished"), expect![[r#"
+        PullSummary {
+            downloaded: [
+                "1 Artist - First",
+                "2 Artist - Second",
+                "3 Artist - Third",
+            ],
+            unavailable: [],
+        }
+    "#]]);
+}
+
+#[tokio::test]
+async fn test_one_download_at_a_time() {
+    let remote_dir = tempfile::tempdir().unwrap();
+    let maps = create_test_maps(remote_dir.path()).await;
+    let requests: Vec<MapRequest> = maps.iter().map(|(request, _)| request.clone()).collect();
+
+    let songs_dir = tempfile::tempdir().unwrap();
+    let (mut packet_server, window) = setup_download_packet_server(songs_dir.path());
+    let (local_socket, mut remote_socket) = socket_pair().await;
+    let peer = packet_server.connect(local_socket, HelloPacket::new("Remote".to_string()));
+    let destination = DownloadDestination::Install(songs_dir.path().to_path_buf());
+    let (sender, _outcomes) = tokio::sync::mpsc::unbounded_channel();
+
+    // Nothing else can be asked of the peer until every map we asked for has arrived
+    assert!(packet_server.request_download(peer, requests.clone()));
+    assert!(!packet_server.start_download(peer, requests.clone(), destination.clone(), sender.clone()));
+    assert!(!packet_server.request_download(peer, requests.clone()));
+    assert_eq!(read_frame(&mut remote_socket).await.unwrap().header, DownloadRequestPacket::HEADER);
+    write_packet(DownloadResponsePacket { map_count: 3, maps: None }, &mut remote_socket).await;
+    for (request, osz_data) in &maps {
+        write_map(&format!("{}.osz", request.song.folder), osz_data, osz_data.len(), &mut remote_socket).await;
+    }
+    wait_for_message(&window, &format!("download-finished: {peer}")).await;
+    assert!(packet_server.start_download(peer, requests, destination, sender));
+}
+
+/// Reads the next packet from the socket, which should be an error
+async fn read_error(remote_socket: &mut SecureStream) -> ErrorPacket {
+    let frame = read_frame(remote_socket).await.unwrap();
+    assert_eq!(frame.header, ErrorPacket::HEADER);
+    ErrorPacket::deserialize(frame.payload).unwrap()
+}
+
+#[tokio::test]
+async fn test_error_packet() {
+    let (mut remote_socket,
+        _packet_server,
+        _local_songs,
+        _peer,
+        window) = setup_test_packet_server().await;
+
+    // Maps we don't have are still answered, with an error in their place
+    let packet = DownloadRequestPacket::new(vec![MapRequest::full(test_song(1, "Artist - Missing", "A"))]);
+    write_packet(packet, &mut remote_socket).await;
+    let response = read_frame(&mut remote_socket).await.unwrap();
+    assert_eq!(DownloadResponsePacket::deserialize(response.payload).unwrap().map_count, 1);
+    check(
+        read_error(&mut remote_socket).await,
+        expect![[r#"
+            ErrorPacket {
+                code: MapNotFound,
+                message: "1 Artist - Missing isn't in our songs",
+            }
+        "#]]
+    );
+
+    // Packets we can't make sense of are answered with an error instead of being dropped
+    let malformed = Frame { header: MapListPacket::HEADER, version: FRAME_VERSION, payload: b"not json".to_vec() };
+    write_frame(&mut remote_socket, &malformed).await.unwrap();
+    remote_socket.flush().await.unwrap();
+    assert_eq!(read_error(&mut remote_socket).await.code, ErrorCode::MalformedPacket);
+
+    let unexpected = Frame { header: 200, version: FRAME_VERSION, payload: Vec::new() };
+    write_frame(&mut remote_socket, &unexpected).await.unwrap();
+    remote_socket.flush().await.unwrap();
+    assert_eq!(read_error(&mut remote_socket).await.code, ErrorCode::UnexpectedPacket);
+
+    // Errors from the peer are passed on to the front-end
+    write_packet(ErrorPacket::new(ErrorCode::MapUnavailable, "Unable to zip up 2 Artist - Second"), &mut remote_socket).await;
+    close_connection(&mut remote_socket).await;
+    wait_for_message(&window, "peer-disconnected: 1").await;
+
+    check(
+        window.get_messages(),
+        expect![[r#"
+            [
+                "peer-connected: 1",
+                "upload-finished: 1",
+                "connection-error: {\"peer\":1,\"code\":\"MapUnavailable\",\"message\":\"Unable to zip up 2 Artist - Second\"}",
+                "peer-disconnected: 1",
+            ]
+        "#]]
+    )
+}
+
+#[tokio::test]
+async fn test_download_skips_unavailable_maps() {
+    let remote_dir = tempfile::tempdir().unwrap();
+    let maps = create_test_maps(remote_dir.path()).await;
+
+    let songs_dir = tempfile::tempdir().unwrap();
+    let (mut packet_server, window) = setup_download_packet_server(songs_dir.path());
+    let remote_identity = Identity::generate();
+    let (local_socket, mut remote_socket) = socket_pair_between(&Identity::generate(), &remote_identity).await;
+    let peer = packet_server.connect(local_socket, HelloPacket::new("Remote".to_string()));
+    packet_server.request_download(peer, maps.iter().map(|(request, _)| request.clone()).collect());
+    read_frame(&mut remote_socket).await.unwrap();
+
+    // The peer can't send the second map, but the others should still arrive
+    write_packet(DownloadResponsePacket { map_count: 3, maps: None }, &mut remote_socket).await;
+    let (first, first_osz) = &maps[0];
+    write_map(&format!("{}.osz", first.song.folder), first_osz, first_osz.len(), &mut remote_socket).await;
+    write_packet(ErrorPacket::new(ErrorCode::MapUnavailable, "Unable to zip up 2 Artist - Second"), &mut remote_socket).await;
+    let (third, third_osz) = &maps[2];
+    write_map(&format!("{}.osz", third.song.folder), third_osz, third_osz.len() / 2, &mut remote_socket).await;
+    drop(remote_socket);
+    wait_for_message(&window, "peer-disconnected: 1").await;
+
+    // Only the map that was cut off is left to resume
+    let partial_download = packet_server.partial_download(&remote_identity.public_key()).unwrap();
+    check(
+        partial_download.requested_maps.iter().map(|request| request.song.folder.clone()).collect::<Vec<String>>(),
+        expect![[r#"
+            [
+                "3 Artist - Third",
+            ]
+        "#]]
+    );
+    check(
+        window.get_messages(),
+        expect![[r#"
+            [
+                "peer-connected: 1",
+                "ask-dialog: {\"title\":\"Download Maps\",\"message\":\"You are about to download 3 maps. Continue?\"}",
+                "download-started: 1",
+                "download-progress: {\"peer\":1,\"map\":\"1 Artist - First.osz\",\"maps_done\":0,\"map_count\":3,\"map_progress\":100}",
+                "connection-error: {\"peer\":1,\"code\":\"MapUnavailable\",\"message\":\"Unable to zip up 2 Artist - Second\"}",
+                "download-progress: {\"peer\":1,\"map\":\"3 Artist - Third.osz\",\"maps_done\":2,\"map_count\":3,\"map_progress\":50}",
+                "download-interrupted: 1",
+                "connection-error: {\"peer\":1,\"code\":\"ConnectionLost\",\"message\":\"The connection was closed\"}",
+                "peer-disconnected: 1",
+            ]
+        "#]]
+    );
+}
+
+/// Stopping a download partway through leaves the connection usable, without the rest being sent
+#[tokio::test]
+async fn test_cancel_download() {
+    let (mut remote_socket,
+        packet_server,
+        local_songs,
+        _peer,
+        window) = setup_test_packet_server().await;
+
+    // A map that barely compresses, sent slowly enough that it can be stopped partway through
+    let songs_dir = tempfile::tempdir().unwrap();
+    let song_path = create_test_song(songs_dir.path(), "1 Artist - Large", &[(10, "Easy")]);
+    let mut noise = 12345u32;
+    let audio: Vec<u8> = (0..512 * 1024)
+        .map(|_| {
+            noise = noise.wrapping_mul(1103515245).wrapping_add(12345);
+            (noise >> 16) as u8
+        })
+        .collect();
+    fs::write(song_path.join("audio.mp3"), audio).unwrap();
+    let songs = file_manager::read_local_files(songs_dir.path(), &Mutex::new(SongCache::default())).await.unwrap();
+    *local_songs.lock().unwrap() = songs.clone();
+    let mut settings = BandwidthSettings::default();
+    settings.upload_limit = Some(128 * 1024);
+    packet_server.bandwidth().set_settings(settings);
+
+    write_packet(DownloadRequestPacket::new(songs.into_iter().map(MapRequest::full).collect()), &mut remote_socket).await;
+    assert_eq!(read_frame(&mut remote_socket).await.unwrap().header, DownloadResponsePacket::HEADER);
+    assert_eq!(read_frame(&mut remote_socket).await.unwrap().header, DownloadMapPacket::HEADER);
+    assert_eq!(read_frame(&mut remote_socket).await.unwrap().header, DownloadDataPacket::HEADER);
+    write_packet(CancelDownloadPacket::new(), &mut remote_socket).await;
+
+    // A chunk that was already on its way may still arrive, but the rest of the map doesn't
+    let mut chunks = 1;
+    let mut frame = read_frame(&mut remote_socket).await.unwrap();
+    while frame.header == DownloadDataPacket::HEADER {
+        chunks += 1;
+        frame = read_frame(&mut remote_socket).await.unwrap();
+    }
+    assert_eq!(frame.header, CancelDownloadPacket::HEADER);
+    assert!(chunks < 8, "The whole map was sent before it was stopped");
+
+    // The connection carries on as normal, and asking to stop when nothing is being sent does nothing
+    write_packet(MapListRequestPacket::new(), &mut remote_socket).await;
+    assert_eq!(read_frame(&mut remote_socket).await.unwrap().header, MapListPacket::HEADER);
+    write_packet(CancelDownloadPacket::new(), &mut remote_socket).await;
+    write_packet(MapListRequestPacket::new(), &mut remote_socket).await;
+    assert_eq!(read_frame(&mut remote_socket).await.unwrap().header, MapListPacket::HEADER);
+
+    close_connection(&mut remote_socket).await;
+    wait_for_message(&window, "peer-disconnected: 1").await;
+}
+
+/// Reads the next packet from the socket, which should be a download request, returning the
+/// folders it asks for and where it resumes from
+async fn read_download_request(remote_socket: &mut SecureStream) -> (Vec<String>, Option<u64>) {
+    let frame = read_frame(remote_socket).await.unwrap();
+    assert_eq!(frame.header, DownloadRequestPacket::HEADER);
+    let request = DownloadRequestPacket::deserialize(frame.payload).unwrap();
+    (
+        request.requested_maps.into_iter().map(|request| request.song.folder).collect(),
+        request.resume_from.map(|resume_from| resume_from.offset)
+    )
+}
+
+/// Sends a single map in response to a download request, from `offset` up to `len`
+async fn write_single_map(name: &str, osz_data: &[u8], offset: usize, len: usize, manifest: &MapManifest, remote_socket: &mut SecureStream) {
+    write_packet(DownloadResponsePacket { map_count: 1, maps: None }, remote_socket).await;
+    let map = DownloadMapPacket {
+        name: name.to_string(), size: osz_data.len() as u64, offset: offset as u64, manifest: manifest.clone(), file_sizes: None,
+        osz_data: None, folder: None
+    };
+    write_packet(map, remote_socket).await;
+    for chunk in osz_data[offset..len].chunks(64 * 1024) {
+        write_packet(DownloadDataPacket::new(chunk.to_vec()), remote_socket).await;
+    }
+}
+
+#[tokio::test]
+async fn test_transfer_queue() {
+    let remote_dir = tempfile::tempdir().unwrap();
+    for (folder, beatmap_id) in [("1 Queue - First", 10), ("2 Queue - Second", 20), ("3 Queue - Third", 30)] {
+        create_test_song(remote_dir.path(), folder, &[(beatmap_id, "Normal")]);
+    }
+    let mut songs = file_manager::read_local_files(remote_dir.path(), &Mutex::new(SongCache::default())).await.unwrap();
+    songs.sort_by(|a, b| a.folder.cmp(&b.folder));
+    let maps: Vec<(MapRequest, Vec<u8>, MapManifest)> = songs.into_iter()
+        .map(|song| {
+            let (osz_data, manifest) = file_manager::song_to_osz_with_manifest(&song, None, TransferProfile::Full).unwrap();
+            (MapRequest::full(song), osz_data, manifest)
+        })
+        .collect();
+
+    let songs_dir = tempfile::tempdir().unwrap();
+    let (mut packet_server, window) = setup_download_packet_server(songs_dir.path());
+    let (local_socket, mut remote_socket) = socket_pair().await;
+    let peer = packet_server.connect(local_socket, HelloPacket::new("Remote".to_string()));
+    let queue = Arc::new(Mutex::new(TransferQueue::new(Arc::new(Mutex::new(packet_server)))));
+    TransferQueue::start(&queue, Arc::new(window.clone()));
+
+    // Maps are asked for one at a time, in the order of the queue
+    let destination = DownloadDestination::Install(songs_dir.path().to_path_buf());
+    let ids = queue.lock().unwrap().enqueue(peer, maps.iter().map(|(request, ..)| request.clone()).collect(), destination);
+    assert_eq!(read_download_request(&mut remote_socket).await, (vec!["1 Queue - First".to_string()], None));
+    assert!(queue.lock().unwrap().move_to(ids[2], 1));
+
+    // A map that arrives damaged is asked for again
+    let (_, first_osz, first_manifest) = &maps[0];
+    let damaged = MapManifest { checksum: Some("0000".to_string()), ..first_manifest.clone() };
+    write_single_map("1 Queue - First.osz", first_osz, 0, first_osz.len(), &damaged, &mut remote_socket).await;
+    assert_eq!(read_download_request(&mut remote_socket).await, (vec!["1 Queue - First".to_string()], None));
+    write_single_map("1 Queue - First.osz", first_osz, 0, first_osz.len(), first_manifest, &mut remote_socket).await;
+
+    // The third map was moved up. Pausing it partway through stops the peer, which lets us know
+    // where the download ends
+    let (_, third_osz, third_manifest) = &maps[2];
+    assert_eq!(read_download_request(&mut remote_socket).await, (vec!["3 Queue - Third".to_string()], None));
+    write_single_map("3 Queue - Third.osz", third_osz, 0, third_osz.len() / 2, third_manifest, &mut remote_socket).await;
+    assert!(queue.lock().unwrap().pause(ids[2]));
+    assert_eq!(read_frame(&mut remote_socket).await.unwrap().header, CancelDownloadPacket::HEADER);
+    write_packet(CancelDownloadPacket::new(), &mut remote_socket).await;
+
+    // The second map is next, and canceling it takes it out of the queue
+    assert_eq!(read_download_request(&mut remote_socket).await, (vec!["2 Queue - Second".to_string()], None));
+    assert!(queue.lock().unwrap().cancel(ids[1]));
+    assert_eq!(read_frame(&mut remote_socket).await.unwrap().header, CancelDownloadPacket::HEADER);
+    write_packet(DownloadResponsePacket { map_count: 1, maps: None }, &mut remote_socket).await;
+    write_packet(CancelDownloadPacket::new(), &mut remote_socket).await;
+
+    // Resuming the third map carries on from where it was stopped
+    assert!(queue.lock().unwrap().resume(ids[2]));
+    let half = third_osz.len() / 2;
+    assert_eq!(read_download_request(&mut remote_socket).await, (vec!["3 Queue - Third".to_string()], Some(half as u64)));
+    write_single_map("3 Queue - Third.osz", third_osz, half, third_osz.len(), third_manifest, &mut remote_socket).await;
+    for _ in 0..50 {
+        if queue.lock().unwrap().transfers().iter().all(|transfer| transfer.state == TransferState::Done) {
+            break;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    check(
+        queue.lock().unwrap().transfers().iter()
+            .map(|transfer| (transfer.folder.clone(), transfer.state, transfer.attempts))
+            .collect::<Vec<_>>(),
+        expect![[r#"
+            [
+                (
+                    "1 Queue - First",
+                    Done,
+                    2,
+                ),
+                (
+                    "3 Queue - Third",
+                    Done,
+                    2,
+                ),
+            ]
+        "#]]
+    );
+    check(
+        window.get_messages().into_iter().filter(|message| message.starts_with("transfer")).collect::<Vec<String>>(),
+        expect![[r#"
+            [
+                "transfer-updated: {\"id\":1,\"peer\":1,\"folder\":\"1 Queue - First\",\"state\":\"Queued\",\"error\":null,\"attempts\":0}",
+                "transfer-updated: {\"id\":2,\"peer\":1,\"folder\":\"2 Queue - Second\",\"state\":\"Queued\",\"error\":null,\"attempts\":0}",
+                "transfer-updated: {\"id\":3,\"peer\":1,\"folder\":\"3 Queue - Third\",\"state\":\"Queued\",\"error\":null,\"attempts\":0}",
+                "transfer-updated: {\"id\":1,\"peer\":1,\"folder\":\"1 Queue - First\",\"state\":\"Active\",\"error\":null,\"attempts\":1}",
+                "transfers-reordered: [1,3,2]",
+                "transfer-updated: {\"id\":1,\"peer\":1,\"folder\":\"1 Queue - First\",\"state\":\"Queued\",\"error\":\"The map arrived damaged: the song's checksum doesn't match what the peer sent\",\"attempts\":1}",
+                "transfer-updated: {\"id\":1,\"peer\":1,\"folder\":\"1 Queue - First\",\"state\":\"Active\",\"error\":\"The map arrived damaged: the song's checksum doesn't match what the peer sent\",\"attempts\":2}",
+                "transfer-updated: {\"id\":1,\"peer\":1,\"folder\":\"1 Queue - First\",\"state\":\"Done\",\"error\":null,\"attempts\":2}",
+                "transfer-updated: {\"id\":3,\"peer\":1,\"folder\":\"3 Queue - Third\",\"state\":\"Active\",\"error\":null,\"attempts\":1}",
+                "transfer-updated: {\"id\":3,\"peer\":1,\"folder\":\"3 Queue - Third\",\"state\":\"Paused\",\"error\":null,\"attempts\":1}",
+                "transfer-updated: {\"id\":2,\"peer\":1,\"folder\":\"2 Queue - Second\",\"state\":\"Active\",\"error\":null,\"attempts\":1}",
+                "transfer-removed: 2",
+                "transfer-updated: {\"id\":3,\"peer\":1,\"folder\":\"3 Queue - Third\",\"state\":\"Queued\",\"error\":null,\"attempts\":1}",
+                "transfer-updated: {\"id\":3,\"peer\":1,\"folder\":\"3 Queue - Third\",\"state\":\"Active\",\"error\":null,\"attempts\":2}",
+                "transfer-updated: {\"id\":3,\"peer\":1,\"folder\":\"3 Queue - Third\",\"state\":\"Done\",\"error\":null,\"attempts\":2}",
+            ]
+        "#]]
+    );
+    let mut installed = file_manager::read_local_files(songs_dir.path(), &Mutex::new(SongCache::default())).await.unwrap();
+    installed.sort_by(|a, b| a.folder.cmp(&b.folder));
+    assert_eq!(
+        installed.iter().map(|song| (song.folder.clone(), song.checksum.clone())).collect::<Vec<_>>(),
+        [&maps[0], &maps[2]].iter().map(|(request, ..)| (request.song.folder.clone(), request.song.checksum.clone())).collect::<Vec<_>>()
+    );
+}
+
+#[tokio::test]
+async fn test_transfer_queue_reconnect() {
+    let remote_dir = tempfile::tempdir().unwrap();
+    create_test_song(remote_dir.path(), "1 Queue - Dropped", &[(10, "Normal")]);
+    let songs = file_manager::read_local_files(remote_dir.path(), &Mutex::new(SongCache::default())).await.unwrap();
+    let (osz_data, manifest) = file_manager::song_to_osz_with_manifest(&songs[0], None, TransferProfile::Full).unwrap();
+    let request = MapRequest::full(songs[0].clone());
+
+    let songs_dir = tempfile::tempdir().unwrap();
+    let (mut packet_server, window) = setup_download_packet_server(songs_dir.path());
+    let remote_identity = Identity::generate();
+    let (local_socket, mut remote_socket) = socket_pair_between(&Identity::generate(), &remote_identity).await;
+    let peer = packet_server.connect(local_socket, HelloPacket::new("Remote".to_string()));
+    let packet_server = Arc::new(Mutex::new(packet_server));
+    let queue = Arc::new(Mutex::new(TransferQueue::new(packet_server.clone())));
+    TransferQueue::start(&queue, Arc::new(window.clone()));
+
+    // Lose the connection partway through the map
+    let destination = DownloadDestination::Install(songs_dir.path().to_path_buf());
+    let ids = queue.lock().unwrap().enqueue(peer, vec![request.clone()], destination);
+    assert_eq!(read_download_request(&mut remote_socket).await, (vec!["1 Queue - Dropped".to_string()], None));
+    let half = osz_data.len() / 2;
+    write_single_map("1 Queue - Dropped.osz", &osz_data, 0, half, &manifest, &mut remote_socket).await;
+    drop(remote_socket);
+    wait_for_message(&window, "peer-disconnected: 1").await;
+    for _ in 0..50 {
+        if queue.lock().unwrap().transfers()[0].state == TransferState::Failed {
+            break;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    // Once the peer is back, resuming the transfer carries on from what arrived before
+    let (local_socket, mut remote_socket) = socket_pair_between(&Identity::generate(), &remote_identity).await;
+    packet_server.lock().unwrap().connect(local_socket, HelloPacket::new("Remote".to_string()));
+    assert!(queue.lock().unwrap().resume(ids[0]));
+    assert_eq!(read_download_request(&mut remote_socket).await, (vec!["1 Queue - Dropped".to_string()], Some(half as u64)));
+    write_single_map("1 Queue - Dropped.osz", &osz_data, half, osz_data.len(), &manifest, &mut remote_socket).await;
+    for _ in 0..50 {
+        if queue.lock().unwrap().transfers()[0].state == TransferState::Done {
+            break;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    check(
+        window.get_messages().into_iter().filter(|message| message.starts_with("transfer")).collect::<Vec<String>>(),
+        expect![[r#"
+            [
+                "transfer-updated: {\"id\":1,\"peer\":1,\"folder\":\"1 Queue - Dropped\",\"state\":\"Queued\",\"error\":null,\"attempts\":0}",
+                "transfer-updated: {\"id\":1,\"peer\":1,\"folder\":\"1 Queue - Dropped\",\"state\":\"Active\",\"error\":null,\"attempts\":1}",
+                "transfer-updated: {\"id\":1,\"peer\":1,\"folder\":\"1 Queue - Dropped\",\"state\":\"Failed\",\"error\":\"The connection to the peer was lost\",\"attempts\":1}",
+                "transfer-updated: {\"id\":1,\"peer\":1,\"folder\":\"1 Queue - Dropped\",\"state\":\"Queued\",\"error\":null,\"attempts\":1}",
+                "transfer-updated: {\"id\":1,\"peer\":2,\"folder\":\"1 Queue - Dropped\",\"state\":\"Active\",\"error\":null,\"attempts\":2}",
+                "transfer-updated: {\"id\":1,\"peer\":2,\"folder\":\"1 Queue - Dropped\",\"state\":\"Done\",\"error\":null,\"attempts\":2}",
+            ]
+        "#]]
+    );
+    let installed = file_manager::read_local_files(songs_dir.path(), &Mutex::new(SongCache::default())).await.unwrap();
+    assert_eq!(
+        installed.iter().map(|song| song.checksum.clone()).collect::<Vec<String>>(),
+        vec![request.song.checksum.clone()]
+    );
+}
+
+/// Sets up a packet manager for the songs in the given folder, installing what it downloads there
+async fn setup_sync_packet_server(songs_dir: &Path) -> (PacketManager, Vec<SongFolder>, MockWindow) {
+    let songs = file_manager::read_local_files(songs_dir, &Mutex::new(SongCache::default())).await.unwrap();
+    let mut packet_server = PacketManager::new();
+    let window = MockWindow::new();
+    packet_server.connect_to_app(Arc::new(Mutex::new(Some(songs_dir.to_path_buf()))), Arc::new(Mutex::new(songs.clone())),
+                                 Arc::new(Mutex::new(SongCache::default())), Arc::new(window.clone()));
+    (packet_server, songs, window)
+}
+
+#[tokio::test]
+async fn test_two_way_sync() {
+    // Both sides have the second song, and one other the other side doesn't
+    let local_dir = tempfile::tempdir().unwrap();
+    create_test_song(local_dir.path(), "1 Artist - First", &[(10, "Easy")]);
+    create_test_song(local_dir.path(), "2 Artist - Second", &[(20, "Normal")]);
+    let remote_dir = tempfile::tempdir().unwrap();
+    create_test_song(remote_dir.path(), "2 Artist - Second", &[(20, "Normal")]);
+    create_test_song(remote_dir.path(), "3 Artist - Third", &[(30, "Insane"), (31, "Expert")]);
+
+    let (mut packet_server, local_songs, window) = setup_sync_packet_server(local_dir.path()).await;
+    let (mut remote, _, remote_window) = setup_sync_packet_server(remote_dir.path()).await;
+    let (local_socket, remote_socket) = socket_pair().await;
+    remote.connect(remote_socket, HelloPacket::new("Local".to_string()));
+    let peer = packet_server.connect(local_socket, HelloPacket::new("Remote".to_string()));
+    packet_server.send_packet(peer, Box::new(MapListRequestPacket::new()));
+    wait_for_message(&window, &format!("remote-songs-updated: {peer}")).await;
+
+    let plan = SyncPlan::between(&local_songs, &packet_server.remote_songs(peer).unwrap());
+    check(
+        (plan.summary(), plan.summary().describe()),
+        expect![[r#"
+            (
+                SyncSummary {
+                    receive_count: 1,
+                    receive_size: 125,
+                    send_count: 1,
+                    send_size: 63,
+                },
+                "receive 1 map (125 B) and send 1 map (63 B)",
+            )
+        "#]]
+    );
+    assert!(plan.confirm(&window));
+    assert!(packet_server.start_sync(peer, plan, DownloadDestination::Install(local_dir.path().to_path_buf())));
+
+    // Each side sends and receives one song
+    wait_for_message(&window, &format!("sync-accepted: {peer}")).await;
+    wait_for_message(&window, &format!("download-finished: {peer}")).await;
+    wait_for_message(&window, &format!("upload-finished: {peer}")).await;
+    wait_for_message(&remote_window, "download-finished: 1").await;
+    check(
+        window.get_messages().into_iter().filter(|message| message.starts_with("ask-dialog")).collect::<Vec<_>>(),
+        expect![[r#"
+            [
+                "ask-dialog: {\"title\":\"Two-way Sync\",\"message\":\"You are about to receive 1 map (125 B) and send 1 map (63 B). Continue?\"}",
+            ]
+        "#]]
+    );
+    check(
+        remote_window.get_messages().into_iter().filter(|message| message.starts_with("ask-dialog")).collect::<Vec<_>>(),
+        expect![[r#"
+            [
+                "ask-dialog: {\"title\":\"Two-way Sync\",\"message\":\"Local wants to sync with you. You will receive 1 map (63 B) and send 1 map (125 B). Continue?\"}",
+            ]
+        "#]]
+    );
+
+    let read_checksums = |songs: Vec<SongFolder>| {
+        let mut checksums: Vec<(String, String)> = songs.into_iter().map(|song| (song.folder, song.checksum)).collect();
+        checksums.sort();
+        checksums
+    };
+    let local_songs = file_manager::read_local_files(local_dir.path(), &Mutex::new(SongCache::default())).await.unwrap();
+    let remote_songs = file_manager::read_local_files(remote_dir.path(), &Mutex::new(SongCache::default())).await.unwrap();
+    assert_eq!(local_songs.len(), 3);
+    assert_eq!(read_checksums(local_songs), read_checksums(remote_songs));
+}
+
+/// The side asked to sync works out the plan from the two map lists itself, so a peer can't make
+/// it send songs we don't have missing, or fake what the user is asked to agree to
+#[tokio::test]
+async fn test_sync_request_checked() {
+    let local_dir = tempfile::tempdir().unwrap();
+    create_test_song(local_dir.path(), "1 Artist - First", &[(10, "Easy")]);
+    create_test_song(local_dir.path(), "2 Artist - Second", &[(20, "Normal")]);
+    let remote_dir = tempfile::tempdir().unwrap();
+    create_test_song(remote_dir.path(), "2 Artist - Second", &[(20, "Normal")]);
+    create_test_song(remote_dir.path(), "3 Artist - Third", &[(30, "Insane"), (31, "Expert")]);
+    let mut remote_songs = file_manager::read_local_files(remote_dir.path(), &Mutex::new(SongCache::default())).await.unwrap();
+    remote_songs.sort_by(|a, b| a.folder.cmp(&b.folder));
+
+    let (mut packet_server, mut local_songs, window) = setup_sync_packet_server(local_dir.path()).await;
+    local_songs.sort_by(|a, b| a.folder.cmp(&b.folder));
+    let (local_socket, mut remote_socket) = socket_pair().await;
+    packet_server.connect(local_socket, HelloPacket::new("Remote".to_string()));
+
+    // The request claims the third song is tiny, and lists songs neither map list backs up
+    let mut unlisted = remote_songs[1].clone();
+    unlisted.id = 4;
+    unlisted.name = "Artist - Fourth".to_string();
+    unlisted.folder = "4 Artist - Fourth".to_string();
+    let mut offered = vec![remote_songs[1].clone(), unlisted.clone()];
+    offered[0].size = 1;
+    let wanted = vec![local_songs[0].clone(), local_songs[1].clone(), unlisted];
+    write_packet(MapListPacket::new(remote_songs.clone()), &mut remote_socket).await;
+    write_packet(SyncRequestPacket::new(offered, wanted), &mut remote_socket).await;
+
+    let response = read_frame(&mut remote_socket).await.unwrap();
+    assert_eq!(response.header, SyncResponsePacket::HEADER);
+    assert!(SyncResponsePacket::deserialize(response.payload).unwrap().accepted);
+    check(read_download_request(&mut remote_socket).await, expect![[r#"
+        (
+            [
+                "3 Artist - Third",
+            ],
+            None,
+        )
+    "#]]);
+    check(
+        window.get_messages().into_iter().filter(|message| message.starts_with("ask-dialog")).collect::<Vec<_>>(),
+        expect![[r#"
+            [
+                "ask-dialog: {\"title\":\"Two-way Sync\",\"message\":\"Remote wants to sync with you. You will receive 1 map (125 B) and send 1 map (63 B). Continue?\"}",
+            ]
+        "#]]
+    );
+}
+
+#[tokio::test]
+async fn test_folder_transfer() {
+    let remote_dir = tempfile::tempdir().unwrap();
+    let song_path = create_test_song(remote_dir.path(), "1 Artist - Title", &[(10, "Easy")]);
+    fs::write(song_path.join("audio.mp3"), vec![7; 150_000]).unwrap();
+    fs::write(song_path.join("empty.txt"), "").unwrap();
+    fs::create_dir(song_path.join("sb")).unwrap();
+    fs::write(song_path.join("sb").join("star.png"), "star").unwrap();
+    create_test_song(remote_dir.path(), "2 Artist - Other", &[(20, "Normal"), (21, "Hard")]);
+    let local_dir = tempfile::tempdir().unwrap();
+    create_test_song(local_dir.path(), "2 Artist - Other", &[(20, "Normal")]);
+
+    let (mut packet_server, local_songs, window) = setup_sync_packet_server(local_dir.path()).await;
+    let mut remote_songs = file_manager::read_local_files(remote_dir.path(), &Mutex::new(SongCache::default())).await.unwrap();
+    remote_songs.sort_by(|a, b| a.folder.cmp(&b.folder));
+    let mut remote = setup_remote_packet_server(remote_songs.clone());
+    let (local_socket, remote_socket) = socket_pair().await;
+    remote.connect(remote_socket, HelloPacket::new("Local".to_string()));
+    let peer = packet_server.connect(local_socket, HelloPacket::new("Remote".to_string()));
+    packet_server.send_packet(peer, Box::new(MapListRequestPacket::new()));
+    wait_for_message(&window, &format!("remote-songs-updated: {peer}")).await;
+
+    // The new song is installed whole, and the missing difficulty is added to the song we have
+    let requests: Vec<MapRequest> = MapRequest::for_songs(remote_songs.clone(), &local_songs, TransferProfile::Full).into_iter()
+        .map(|request| request.with_format(MapFormat::Folder))
+        .collect();
+    let destination = DownloadDestination::Install(local_dir.path().to_path_buf());
+    assert_eq!(destination.map_format(), MapFormat::Folder);
+    let mut summary = pull_maps(&Mutex::new(packet_server), requests, destination).await;
+    summary.downloaded.sort();
+    check(
+        summary,
+        expect![[r#"
+            PullSummary {
+                downloaded: [
+                    "1 Artist - Title",
+                    "2 Artist - Other",
+                ],
+                unavailable: [],
+            }
+        "#]]
+    );
+
+    // Every file arrives as it was, including empty ones, and nothing is left in the staging folder
+    let list_files = |folder: &Path| {
+        let mut files: Vec<(String, u64)> = walkdir::WalkDir::new(folder).min_depth(1).into_iter()
+            .map(|entry| entry.unwrap())
+            .filter(|entry| entry.file_type().is_file())
+            .map(|entry| (entry.path().strip_prefix(folder).unwrap().to_string_lossy().to_string(), entry.metadata().unwrap().len()))
+            .collect();
+        files.sort();
+        files
+    };
+    assert_eq!(list_files(local_dir.path()), list_files(remote_dir.path()));
+    assert!(!local_dir.path().join(".osu-mapsync").exists());
+    let mut installed = file_manager::read_local_files(local_dir.path(), &Mutex::new(SongCache::default())).await.unwrap();
+    installed.sort_by(|a, b| a.folder.cmp(&b.folder));
+    assert_eq!(
+        installed.iter().map(|song| song.checksum.clone()).collect::<Vec<String>>(),
+        remote_songs.iter().map(|song| song.checksum.clone()).collect::<Vec<String>>()
+    );
+
+    // Files the peer says are in a map can't end up outside of it
+    let (manifest, file_sizes) = file_manager::song_folder_manifest(&remote_songs[0], None, TransferProfile::Full).unwrap();
+    let mut escaping = manifest.clone();
+    escaping.files[0].path = "../escaped.osu".to_string();
+    let escaping = DownloadMapPacket::folder("1 Artist - Title".to_string(), song_path.clone(), escaping, file_sizes.clone());
+    let mut wrong_size = DownloadMapPacket::folder("1 Artist - Title".to_string(), song_path, manifest, file_sizes);
+    wrong_size.size += 1;
+    let errors: Vec<String> = [escaping, wrong_size].iter()
+        .map(|map| DownloadMapPacket::deserialize(map.get_data().unwrap()).unwrap_err().to_string())
+        .collect();
+    check(errors, expect![[r#"
+        [
+            "Unable to parse the packet payload: \"../escaped.osu\" is not a valid file in 1 Artist - Title",
+            "Unable to parse the packet payload: The files of 1 Artist - Title don't add up to its size",
+        ]
+    "#]]);
+}
+
+#[test]
+fn test_format_size() {
+    check(
+        [0, 1023, 1024, 1536 * 1024, 5 * 1024 * 1024 * 1024].map(file_manager::format_size),
+        expect![[r#"
+            [
+                "0 B",
+                "1023 B",
+                "1.0 KiB",
+                "1.5 MiB",
+                "5.0 GiB",
+            ]
+        "#]]
+    );
+}
+
+#[tokio::test]
+async fn test_frame_round_trip() {
+    let mut buf = Vec::new();
+    let packet = MapListPacket::new(vec![SongFolder {
+        id: 1, name: "Line\nBreak - Song".to_string(), folder: "1 Line Break - Song".to_string(),
+        checksum: "ABC".to_string(), difficulties: Vec::new(), size: 1024, file_count: 2,
+        assets: AssetSizes::default(), path: None
+    }]);
+    write_frame(&mut buf, &Frame::from_packet(&packet).unwrap()).await.unwrap();
+
+    let frame = read_frame(&mut &buf[..]).await.unwrap();
+    assert_eq!(frame.header, MapListPacket::HEADER);
+    assert_eq!(frame.version, FRAME_VERSION);
+
+    let map_list = MapListPacket::deserialize(frame.payload).unwrap().map_list;
+    check(
+        map_list,
+        expect![[r#"
+            [
+                SongFolder {
+                    id: 1,
+                    name: "Line\nBreak - Song",
+                    folder: "1 Line Break - Song",
+                    checksum: "ABC",
+                    difficulties: [],
+                    size: 1024,
+                    file_count: 2,
+                    assets: AssetSizes {
+                        audio: AssetSize {
+                            size: 0,
+                            file_count: 0,
+                        },
+                        video: AssetSize {
+                            size: 0,
+                            file_count: 0,
+                        },
+                        storyboard: AssetSize {
+                            size: 0,
+                            file_count: 0,
+                        },
+                    },
+                    path: None,
+                },
+            ]
+        "#]]
+    );
+}
+
+#[tokio::test]
+async fn test_malformed_frames() {
+    // Closing the stream between frames is a clean disconnect
+    let empty: &[u8] = &[];
+    assert!(matches!(read_frame(&mut &empty[..]).await, Err(CodecError::Disconnected)));
+
+    // Closing the stream partway through a frame is an error rather than a hang
+    let truncated: &[u8] = &[MapListPacket::HEADER, FRAME_VERSION, 0, 0, 0, 10, b'['];
+    assert!(matches!(read_frame(&mut &truncated[..]).await, Err(CodecError::IOError(_))));
+
+    let bad_version: &[u8] = &[MapListPacket::HEADER, FRAME_VERSION + 1, 0, 0, 0, 0];
+    assert!(matches!(read_frame(&mut &bad_version[..]).await, Err(CodecError::UnsupportedVersion(_))));
+
+    let too_large = [&[MapListPacket::HEADER, FRAME_VERSION][..], &(MAX_PAYLOAD_SIZE + 1).to_be_bytes()].concat();
+    assert!(matches!(read_frame(&mut &too_large[..]).await, Err(CodecError::PayloadTooLarge(_))));
+
+    // A well-formed frame can still carry a payload that isn't valid for its packet
+    assert!(matches!(MapListPacket::deserialize(b"not json".to_vec()), Err(CodecError::InvalidPayload(_))));
+    assert!(matches!(DownloadResponsePacket::deserialize(vec![1, 2]), Err(CodecError::InvalidPayload(_))));
+}
+
+#[tokio::test]
+async fn test_hello_exchange() {
+    let (mut client_socket, mut server_socket) = socket_pair().await;
+
+    let server_hello = HelloPacket::new("Server".to_string());
+    let server = tokio::spawn(async move {
+        exchange_hello(&mut server_socket, &server_hello).await.map(|hello| hello.display_name)
+    });
+    let client_result = exchange_hello(&mut client_socket, &HelloPacket::new("Client".to_string())).await;
+
+    check(
+        (client_result.map(|hello| hello.display_name), server.await.unwrap()),
+        expect![[r#"
+            (
+                Ok(
+                    "Server",
+                ),
+                Ok(
+                    "Client",
+                ),
+            )
+        "#]]
+    );
+}
+
+#[tokio::test]
+async fn test_hello_exchange_incompatible_version() {
+    let (mut client_socket, mut server_socket) = socket_pair().await;
+
+    let mut server_hello = HelloPacket::new("Server".to_string());
+    server_hello.protocol_version += 1;
+    let server = tokio::spawn(async move {
+        exchange_hello(&mut server_socket, &server_hello).await
+    });
+    let client_result = exchange_hello(&mut client_socket, &HelloPacket::new("Client".to_string())).await;
+
+    assert!(matches!(client_result, Err(NetworkingError::IncompatibleVersion { .. })), "{client_result:?}");
+    assert!(matches!(server.await.unwrap(), Err(NetworkingError::IncompatibleVersion { .. })));
+}
+
+#[tokio::test]
+async fn test_secure_stream() {
+    let (client_socket, server_socket) = tcp_pair().await;
+    let (client_identity, server_identity) = (Identity::generate(), Identity::generate());
+    let (client_socket, server_socket) = tokio::join!(
+        SecureStream::connect(client_socket, &client_identity),
+        SecureStream::accept(server_socket, &server_identity)
+    );
+    let (client_socket, server_socket) = (client_socket.unwrap(), server_socket.unwrap());
+
+    // Each side should know who it's talking to
+    assert_eq!(client_socket.remote_public_key(), server_identity.public_key());
+    assert_eq!(server_socket.remote_public_key(), client_identity.public_key());
+
+    // Frames bigger than a single encrypted message should make it across, in both directions at once
+    let (mut client_reader, mut client_writer) = client_socket.into_split();
+    let (mut server_reader, mut server_writer) = server_socket.into_split();
+    let frame = Frame::from_packet(&DownloadDataPacket::new((0..200_000).map(|i| (i % 251) as u8).collect())).unwrap();
+    let (_, received, _, client_received) = tokio::join!(
+        async {
+            write_frame(&mut client_writer, &frame).await.unwrap();
+            client_writer.flush().await.unwrap();
+        },
+        read_frame(&mut server_reader),
+        async {
+            write_frame(&mut server_writer, &Frame::from_packet(&MapListRequestPacket::new()).unwrap()).await.unwrap();
+            server_writer.flush().await.unwrap();
+        },
+        read_frame(&mut client_reader)
+    );
+    let received = received.unwrap();
+    assert_eq!((received.header, received.payload), (frame.header, frame.payload));
+    assert_eq!(client_received.unwrap().header, MapListRequestPacket::HEADER);
+
+    // Hanging up between messages is a clean disconnect
+    drop(client_writer);
+    assert!(matches!(read_frame(&mut server_reader).await, Err(CodecError::Disconnected)));
+}
+
+#[tokio::test]
+async fn test_secure_stream_rejects_bad_handshake() {
+    let (client_socket, mut server_socket) = tcp_pair().await;
+
+    // A peer that answers with something other than a valid handshake message shouldn't get through
+    let server = tokio::spawn(async move {
+        let len = server_socket.read_u16().await.unwrap();
+        server_socket.read_exact(&mut vec![0; len as usize]).await.unwrap();
+        server_socket.write_u16(96).await.unwrap();
+        server_socket.write_all(&[0; 96]).await.unwrap();
+        server_socket
+    });
+    let client_result = SecureStream::connect(client_socket, &Identity::generate()).await;
+    let _server_socket = server.await.unwrap();
+
+    assert!(matches!(client_result, Err(err) if err.kind() == io::ErrorKind::InvalidData));
+}
+
+/// The stored identity is reused on the next start, and nobody else gets to read its private key
+#[test]
+fn test_identity_file() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let path = data_dir.path().join("identity.json");
+    fs::write(&path, "not an identity").unwrap();
+
+    let identity = Identity::load_or_generate(&path).unwrap();
+    assert_eq!(Identity::load_or_generate(&path).unwrap().public_key(), identity.public_key());
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+    }
+}
+
+#[test]
+fn test_trusted_peers() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let path = data_dir.path().join("trusted_peers.json");
+    let mut trusted_peers = TrustedPeers::load(path.clone());
+    let handshake_hash = [1; 32];
+
+    // Unknown peers are left up to the user, unless we've been told to turn them away
+    assert_eq!(trusted_peers.check_peer("AAAA", "Stranger", false), PeerDecision::Ask);
+    trusted_peers.set_unknown_peer_policy(UnknownPeerPolicy::Reject);
+    assert_eq!(trusted_peers.check_peer("AAAA", "Stranger", false), PeerDecision::Rejected);
+
+    // Both sides only end up with the same key if they used the same code on the same connection
+    let code = trusted_peers.start_pairing();
+    let client = Pairing::new(&code, PairingRole::Client);
+    let (server_share, server_confirmation) = trusted_peers.answer_pairing(&client.share(), &handshake_hash).unwrap();
+    let client_confirmation = client.finish(&server_share, &[2; 32]).unwrap();
+    assert_ne!(client_confirmation.theirs, server_confirmation.ours);
+    assert!(!trusted_peers.finish_pairing(&server_confirmation, Some(&client_confirmation.ours)));
+
+    let client = Pairing::new(&code, PairingRole::Client);
+    let client_share = client.share();
+    let (server_share, server_confirmation) = trusted_peers.answer_pairing(&client_share, &handshake_hash).unwrap();
+    let client_confirmation = client.finish(&server_share, &handshake_hash).unwrap();
+    assert_eq!(client_confirmation.theirs, server_confirmation.ours);
+    assert!(trusted_peers.finish_pairing(&server_confirmation, Some(&client_confirmation.ours)));
+    assert_eq!(trusted_peers.check_peer("BBBB", "Friend", true), PeerDecision::Paired);
+
+    // A code can only be used once
+    assert!(trusted_peers.answer_pairing(&client_share, &handshake_hash).is_none());
+
+    // Wrong codes use the code up, so it can't be guessed
+    let code = trusted_peers.start_pairing();
+    for _ in 0..3 {
+        let guess = Pairing::new("wrong", PairingRole::Client);
+        let (server_share, server_confirmation) = trusted_peers.answer_pairing(&guess.share(), &handshake_hash).unwrap();
+        let guess_confirmation = guess.finish(&server_share, &handshake_hash).unwrap();
+        assert_ne!(guess_confirmation.theirs, server_confirmation.ours);
+        assert!(!trusted_peers.finish_pairing(&server_confirmation, Some(&guess_confirmation.ours)));
+    }
+    let client = Pairing::new(&code, PairingRole::Client);
+    assert!(trusted_peers.answer_pairing(&client.share(), &handshake_hash).is_none());
+
+    // Paired peers get let in from then on, and keep their latest name
+    assert_eq!(trusted_peers.check_peer("BBBB", "Renamed Friend", false), PeerDecision::Trusted);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+    }
+    let mut trusted_peers = TrustedPeers::load(path);
+    check(
+        (trusted_peers.peers(), trusted_peers.unknown_peer_policy()),
+        expect![[r#"
+            (
+                [
+                    TrustedPeer {
+                        public_key: "BBBB",
+                        display_name: "Renamed Friend",
+                    },
+                ],
+                Reject,
+            )
+        "#]]
+    );
+
+    assert!(trusted_peers.forget("BBBB"));
+    assert_eq!(trusted_peers.check_peer("BBBB", "Renamed Friend", false), PeerDecision::Rejected);
+}
+
+/// Sets up a packet manager with its own identity that doesn't do anything with its connections
+fn setup_peer(name: &str) -> (Arc<Mutex<PacketManager>>, Arc<Mutex<TrustedPeers>>) {
+    let mut packet_manager = PacketManager::new();
+    packet_manager.set_display_name(name.to_string());
+    packet_manager.connect_to_app(Arc::new(Mutex::new(None)), Arc::new(Mutex::new(Vec::new())),
+                                  Arc::new(Mutex::new(SongCache::default())), Arc::new(MockWindow::new()));
+    (Arc::new(Mutex::new(packet_manager)), Arc::new(Mutex::new(TrustedPeers::default())))
+}
+
+#[tokio::test]
+async fn test_pairing() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let (server, server_trusted_peers) = setup_peer("Server");
+    server_trusted_peers.lock().unwrap().set_unknown_peer_policy(UnknownPeerPolicy::Reject);
+    let code = server_trusted_peers.lock().unwrap().start_pairing();
+
+    // Answers the given number of connections, recording who got let in and why
+    let accept_connections = |count: usize| {
+        let (listener, server, server_trusted_peers) = (&listener, server.clone(), server_trusted_peers.clone());
+        async move {
+            let mut decisions = Vec::new();
+            for _ in 0..count {
+                let socket = listener.accept().await.unwrap().0;
+                // A client that gives up on pairing drops the connection, which fails the accept
+                let accepted = accept_peer(socket, &server, &server_trusted_peers, |_, _| panic!("Shouldn't ask")).await;
+                decisions.push(accepted.ok().flatten().map(|(_, peer_hello, decision)| (peer_hello.display_name, decision)));
+            }
+            decisions
+        }
+    };
+
+    // Pair, then connect again without the code
+    let (client, client_trusted_peers) = setup_peer("Client");
+    let (decisions, connected) = tokio::join!(
+        accept_connections(2),
+        async {
+            let paired = connect_to_server(addr.clone(), Some(code.clone()), &client, &client_trusted_peers).await.unwrap();
+            let reconnected = connect_to_server(addr.clone(), None, &client, &client_trusted_peers).await.unwrap();
+            (paired, reconnected)
+        }
+    );
+    assert_eq!(connected, (Some(1), Some(2)));
+    check(
+        decisions,
+        expect![[r#"
+            [
+                Some(
+                    (
+                        "Client",
+                        Paired,
+                    ),
+                ),
+                Some(
+                    (
+                        "Client",
+                        Trusted,
+                    ),
+                ),
+            ]
+        "#]]
+    );
+
+    // Both sides should now know each other by their keys
+    let server_key = server.lock().unwrap().identity().public_key();
+    let client_key = client.lock().unwrap().identity().public_key();
+    assert!(client_trusted_peers.lock().unwrap().is_trusted(&server_key));
+    assert!(server_trusted_peers.lock().unwrap().is_trusted(&client_key));
+
+    // Someone else can't reuse the code, and a wrong code gets neither side to trust the other
+    let (stranger, stranger_trusted_peers) = setup_peer("Stranger");
+    let (decisions, connected) = tokio::join!(
+        accept_connections(1),
+        connect_to_server(addr.clone(), Some(code), &stranger, &stranger_trusted_peers)
+    );
+    assert_eq!(decisions, vec![None]);
+    assert!(matches!(connected, Err(NetworkingError::PairingFailed)));
+
+    let code = server_trusted_peers.lock().unwrap().start_pairing();
+    let wrong_code = if code == "000000" { "000001" } else { "000000" };
+    let (decisions, connected) = tokio::join!(
+        accept_connections(1),
+        connect_to_server(addr.clone(), Some(wrong_code.to_string()), &stranger, &stranger_trusted_peers)
+    );
+    assert_eq!(decisions, vec![None]);
+    assert!(matches!(connected, Err(NetworkingError::PairingFailed)));
+    assert!(stranger_trusted_peers.lock().unwrap().peers().is_empty());
+    assert!(!server_trusted_peers.lock().unwrap().is_trusted(&stranger.lock().unwrap().identity().public_key()));
+}
+
+#[tokio::test]
+async fn test_silent_peer() {
+    let (server, server_trusted_peers) = setup_peer("Server");
+    let mut settings = ListenerSettings::default();
+    settings.bind_address = Some("127.0.0.1".parse().unwrap());
+    let listen_addr = Arc::new(Mutex::new(None));
+    let server_task = start_listening_server(&settings, Arc::new(MockWindow::new()), server.clone(), server_trusted_peers,
+                                             listen_addr.clone()).await.unwrap().unwrap();
+    let addr = listen_addr.lock().unwrap().unwrap().to_string();
+
+    // A peer that connects and then never says anything doesn't keep anyone else out
+    let _silent_peer = TcpStream::connect(&addr).await.unwrap();
+    let (client, client_trusted_peers) = setup_peer("Client");
+    let connected = timeout(Duration::from_secs(5), connect_to_server(addr, None, &client, &client_trusted_peers)).await
+        .expect("The silent peer held up the connection");
+    assert_eq!(connected.unwrap(), Some(1));
+    server_task.abort();
+}
+
+fn test_announcement(public_key: &str, display_name: &str, port: u16) -> Announcement {
+    Announcement {
+        protocol_version: PROTOCOL_VERSION,
+        public_key: public_key.to_string(),
+        display_name: display_name.to_string(),
+        port,
+        song_count: 10
+    }
+}
+
+#[test]
+fn test_discovered_peers() {
+    let mut discovered_peers = DiscoveredPeers::default();
+    let start = std::time::Instant::now();
+    let source = "192.168.1.20".parse().unwrap();
+
+    // Only new peers, or peers that changed, are worth telling the front-end about
+    assert!(discovered_peers.record(test_announcement("AAAA", "Friend", 4000), source, start).is_some());
+    assert!(discovered_peers.record(test_announcement("AAAA", "Friend", 4000), source, start).is_none());
+    let mut old_version = test_announcement("BBBB", "Old Friend", 4001);
+    old_version.protocol_version -= 1;
+    discovered_peers.record(old_version, source, start);
+    let moved = discovered_peers.record(test_announcement("AAAA", "Friend", 4002), source, start + Duration::from_secs(10));
+    check(
+        moved,
+        expect![[r#"
+            Some(
+                DiscoveredPeer {
+                    public_key: "AAAA",
+                    display_name: "Friend",
+                    address: "192.168.1.20:4002",
+                    song_count: 10,
+                    compatible: true,
+                },
+            )
+        "#]]
+    );
+
+    // Peers that stop announcing themselves are forgotten
+    check(
+        discovered_peers.prune(start + Duration::from_secs(20)),
+        expect![[r#"
+            [
+                "BBBB",
+            ]
+        "#]]
+    );
+    assert_eq!(discovered_peers.peers().len(), 1);
+}
+
+/// Runs discovery on the given socket in the background, announcing `announcement` to `announce_to`
+fn spawn_discovery(socket: tokio::net::UdpSocket, announce_to: std::net::SocketAddr, announcement: Option<Announcement>, own_public_key: &str)
+    -> (tokio::task::JoinHandle<()>, Arc<Mutex<DiscoveredPeers>>, MockWindow) {
+    let discovered_peers = Arc::new(Mutex::new(DiscoveredPeers::default()));
+    let window = MockWindow::new();
+    let own_public_key = own_public_key.to_string();
+    let task = {
+        let (discovered_peers, window) = (discovered_peers.clone(), window.clone());
+        tokio::spawn(async move {
+            run_discovery(socket, announce_to, own_public_key, || announcement.clone(), &discovered_peers, &window).await
+        })
+    };
+    (task, discovered_peers, window)
+}
+
+#[tokio::test]
+async fn test_discovery() {
+    // Stand in for the broadcast address by announcing straight to each other
+    let first_socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let second_socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let (first_addr, second_addr) = (first_socket.local_addr().unwrap(), second_socket.local_addr().unwrap());
+
+    // The second instance isn't listening yet, so it has nothing to announce
+    let (first, first_peers, first_window) = spawn_discovery(first_socket, second_addr, Some(test_announcement("AAAA", "First", 4000)), "AAAA");
+    let (second, second_peers, second_window) = spawn_discovery(second_socket, first_addr, None, "BBBB");
+    wait_for_message(&second_window, "peer-discovered: {\"public_key\":\"AAAA\",\"display_name\":\"First\",\"address\":\"127.0.0.1:4000\",\"song_count\":10,\"compatible\":true}").await;
+
+    // Our own announcements and anything that isn't an announcement are ignored
+    let other_socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    other_socket.send_to(&serde_json::to_vec(&test_announcement("AAAA", "First", 4000)).unwrap(), first_addr).await.unwrap();
+    other_socket.send_to(b"not an announcement", first_addr).await.unwrap();
+    other_socket.send_to(&serde_json::to_vec(&test_announcement("CCCC", "Third", 4001)).unwrap(), first_addr).await.unwrap();
+    wait_for_message(&first_window, "peer-discovered: {\"public_key\":\"CCCC\",\"display_name\":\"Third\",\"address\":\"127.0.0.1:4001\",\"song_count\":10,\"compatible\":true}").await;
+    first.abort();
+    second.abort();
+
+    check(
+        (first_peers.lock().unwrap().peers(), second_peers.lock().unwrap().peers().len()),
+        expect![[r#"
+            (
+                [
+                    DiscoveredPeer {
+                        public_key: "CCCC",
+                        display_name: "Third",
+                        address: "127.0.0.1:4001",
+                        song_count: 10,
+                        compatible: true,
+                    },
+                ],
+                1,
+            )
+        "#]]
+    );
+}
+
+#[tokio::test]
+async fn test_listener_settings() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let path = data_dir.path().join("listener_settings.json");
+
+    // Listening on every interface, IPv6 included, survives being saved
+    let mut settings = ListenerSettings::default();
+    settings.bind_address = Some("::".parse().unwrap());
+    settings.port = 27000;
+    ListenerSettings::load(path.clone()).update(settings).unwrap();
+    let settings = ListenerSettings::load(path);
+    assert_eq!(settings.socket_addr().unwrap(), "[::]:27000".parse().unwrap());
+    assert_eq!(shareable_addr("127.0.0.1:27000".parse().unwrap()), "127.0.0.1:27000".parse().unwrap());
+
+    // A fixed port is listened on every time the server starts
+    let mut settings = ListenerSettings::default();
+    settings.bind_address = Some("127.0.0.1".parse().unwrap());
+    settings.port = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().port();
+    let listener = settings.bind().await.unwrap().unwrap();
+    assert_eq!(listener.local_addr().unwrap().port(), settings.port);
+
+    // Which means it can't be listened on twice
+    let err = settings.bind().await.unwrap_err();
+    assert!(matches!(err, NetworkingError::BindError(addr, _) if addr.port() == settings.port));
+
+    drop(listener);
+    assert!(settings.bind().await.unwrap().is_some());
+
+    settings.enabled = false;
+    assert!(settings.bind().await.unwrap().is_none());
+}
+
+#[test]
+fn test_bandwidth_settings() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let path = data_dir.path().join("bandwidth_settings.json");
+
+    // Full speed from 23:00 to 07:00 local time, in a time zone two hours ahead of UTC
+    let mut settings = BandwidthSettings::default();
+    settings.upload_limit = Some(100 * 1024);
+    settings.peer_download_limit = Some(50 * 1024);
+    settings.full_speed = Some(Schedule { start_minute: 23 * 60, end_minute: 7 * 60, utc_offset_minutes: 120 });
+    BandwidthSettings::load(path.clone()).update(settings.clone()).unwrap();
+    let settings = BandwidthSettings::load(path);
+
+    let utc = |hour: u64, minute: u64| 3 * 86400 + hour * 3600 + minute * 60;
+    // The limits only apply outside of the window
+    let upload_limits: Vec<Option<u64>> = [utc(12, 0), utc(20, 59), utc(21, 0), utc(2, 0), utc(4, 59), utc(5, 0)].iter()
+        .map(|time| settings.limits_at(Direction::Upload, *time).0)
+        .collect();
+    assert_eq!(upload_limits, vec![Some(100 * 1024), Some(100 * 1024), None, None, None, Some(100 * 1024)]);
+    assert_eq!(settings.limits_at(Direction::Download, utc(12, 0)), (None, Some(50 * 1024)));
+    assert_eq!(settings.limits_at(Direction::Download, utc(2, 0)), (None, None));
+}
+
+#[test]
+fn test_rate_limiter() {
+    let mut limiter = RateLimiter::new();
+    let start = std::time::Instant::now();
+
+    // The first chunk goes straight through, and each one after it waits for the ones before
+    let waits: Vec<Duration> = (0..3).map(|_| limiter.reserve(1000, Some(1000), start)).collect();
+    assert_eq!(waits, vec![Duration::ZERO, Duration::from_secs(1), Duration::from_secs(2)]);
+
+    // Time spent waiting counts towards the limit
+    assert_eq!(limiter.reserve(500, Some(1000), start + Duration::from_secs(4)), Duration::ZERO);
+    assert_eq!(limiter.reserve(500, Some(1000), start + Duration::from_secs(4)), Duration::from_millis(500));
+
+    // Lifting the limit lets everything through, and nothing is owed once it comes back
+    assert_eq!(limiter.reserve(1_000_000, None, start + Duration::from_secs(5)), Duration::ZERO);
+    assert_eq!(limiter.reserve(1000, Some(1000), start + Duration::from_secs(5)), Duration::ZERO);
+}
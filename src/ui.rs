@@ -0,0 +1,28 @@
+use std::path::PathBuf;
+use serde::Serialize;
+
+/// Whatever the user is interacting with us through, be it the Tauri window or a terminal. The
+/// networking code only talks to the user through this, so it can run without a window.
+pub trait UiBridge: Send + Sync {
+    /// Sends an event to the front-end, with its payload already serialized to JSON
+    fn emit_json(&self, event: &str, payload: String);
+
+    /// Asks the user a yes or no question, blocking until they answer
+    fn ask(&self, title: &str, message: &str) -> bool;
+
+    /// Asks the user to pick a folder, blocking until they do. Returns None if they didn't.
+    fn pick_folder(&self, title: &str) -> Option<PathBuf>;
+}
+
+impl dyn UiBridge + '_ {
+    /// Sends an event to the front-end
+    pub fn emit<S: Serialize>(&self, event: &str, payload: S) {
+        self.emit_json(event, serde_json::to_string(&payload).unwrap());
+    }
+}
+
+impl std::fmt::Debug for dyn UiBridge + '_ {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("UiBridge")
+    }
+}
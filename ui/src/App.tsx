@@ -1,7 +1,8 @@
 import type {Component} from 'solid-js';
 import RemoteConnection from "./RemoteConnection";
-import {createMemo, createSignal} from "solid-js";
-import {SongFolder, SongFolderMatch, SongFolderWithMatch} from "./types";
+import {createEffect, createMemo, createSignal, on} from "solid-js";
+import {SongDiff, SongFolder, SongFolderMatch, SongFolderWithMatch} from "./types";
+import {invoke} from "@tauri-apps/api";
 import LocalConnection from "./LocalConnection";
 import styles from "./styling/App.module.css";
 import SyncPanel from "./SyncPanel";
@@ -9,29 +10,35 @@ import SyncPanel from "./SyncPanel";
 const App: Component = () => {
     const [localSongs, setLocalSongs] = createSignal<SongFolderWithMatch[]>([]);
     const [remoteSongs, setRemoteSongs] = createSignal<SongFolderWithMatch[]>([]);
+    // The connected peer whose songs we're comparing against
+    const [peer, setPeer] = createSignal<number | null>(null);
     const missingSongs = createMemo(() => (
         remoteSongs().filter((song) => song.match === "Similar" || song.match === "Missing")
     ));
 
-    const updateMatches = function (newLocalSongs: SongFolder[], newRemoteSongs: SongFolder[]) {
+    // The backend works out which songs match, so both sides always agree on it
+    const updateMatches = async function () {
+        const diff = await invoke("get_song_diff", {peer: peer()}) as SongDiff;
+
         // If one of the sources is empty, we should set our match to "None" rather than "Missing" by default
-        let defaultMatch: SongFolderMatch = "Missing";
-        if (newLocalSongs.length == 0 || newRemoteSongs.length == 0) {
-            defaultMatch = "None";
-        }
-
-        // Try and find a match for each song in newLocalSongs
-        const newLocalMatches: SongFolderWithMatch[] = newLocalSongs.map((song) => ({song, match: defaultMatch}));
-        const newRemoteMatches: SongFolderWithMatch[] = newRemoteSongs.map((song) => ({song, match: defaultMatch}));
-        newLocalSongs.forEach((song, i) => {
-            const rI = newRemoteSongs.findIndex((rSong) => rSong.id === song.id && rSong.name === song.name);
-
-            if (rI !== -1) {
-                newLocalMatches[i].match = newRemoteMatches[rI].match
-                    = (song.checksum === newRemoteSongs[rI].checksum) ? "Direct" : "Similar";
-            }
+        const localCount = diff.identical.length + diff.different.length + diff.missing_remotely.length;
+        const remoteCount = diff.identical.length + diff.different.length + diff.missing_locally.length;
+        const withMatch = (song: SongFolder, match: SongFolderMatch): SongFolderWithMatch => ({
+            song,
+            match: (localCount == 0 || remoteCount == 0) ? "None" : match
         });
 
+        const newLocalMatches = [
+            ...diff.identical.map((pair) => withMatch(pair.local, "Direct")),
+            ...diff.different.map((pair) => withMatch(pair.local, "Similar")),
+            ...diff.missing_remotely.map((song) => withMatch(song, "Missing"))
+        ];
+        const newRemoteMatches = [
+            ...diff.identical.map((pair) => withMatch(pair.remote, "Direct")),
+            ...diff.different.map((pair) => withMatch(pair.remote, "Similar")),
+            ...diff.missing_locally.map((song) => withMatch(song, "Missing"))
+        ];
+
         console.log("New song lists:");
         console.log(newLocalMatches);
         console.log(newRemoteMatches);
@@ -39,23 +46,22 @@ const App: Component = () => {
         setRemoteSongs(newRemoteMatches);
     }
 
-    const updateLocalSongs = function (newLocalSongs: SongFolder[]) {
-        // Pull out just the songs from remoteSongs(). Inefficient but should be fine since it's infrequent.
-        const newRemoteSongs = remoteSongs().map((song) => song.song);
-        updateMatches(newLocalSongs, newRemoteSongs);
+    // Both lists are already stored in the backend by the time these get called
+    const updateLocalSongs = function (_newLocalSongs: SongFolder[]) {
+        updateMatches();
     }
-    const updateRemoteSongs = function (newRemoteSongs: SongFolder[]) {
-        // Pull out just the songs from localSongs(). Inefficient but should be fine since it's infrequent.
-        const newLocalSongs = localSongs().map((song) => song.song);
-        updateMatches(newLocalSongs, newRemoteSongs);
+    const updateRemoteSongs = function (_newRemoteSongs: SongFolder[]) {
+        updateMatches();
     }
 
+    createEffect(on(peer, () => updateMatches()));
+
     return <div class={styles.container}>
         <div class={styles.songSources}>
             <LocalConnection localSongs={localSongs()} updateLocalSongs={updateLocalSongs}/>
-            <RemoteConnection remoteSongs={remoteSongs()} updateRemoteSongs={updateRemoteSongs}/>
+            <RemoteConnection peer={peer()} setPeer={setPeer} remoteSongs={remoteSongs()} updateRemoteSongs={updateRemoteSongs}/>
         </div>
-        <SyncPanel songsToSync={missingSongs()}/>
+        <SyncPanel peer={peer()} songsToSync={missingSongs()}/>
     </div>
 };
 
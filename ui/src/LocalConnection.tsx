@@ -1,5 +1,6 @@
-import {createSignal} from "solid-js";
+import {createEffect, createSignal, onCleanup} from "solid-js";
 import {invoke} from "@tauri-apps/api";
+import {listen} from "@tauri-apps/api/event";
 import {SongFolder, SongFolderWithMatch} from "./types";
 import SongList from "./components/SongList";
 import styles from "./styling/LocalConnection.module.css";
@@ -28,6 +29,17 @@ export default (props: LocalConnectionProps) => {
             });
     }
 
+    createEffect(async () => {
+        // Installed downloads get rescanned by the backend, so just pull the new list
+        const unlisten = await listen("local-songs-updated", async () => {
+            const newSongs = await invoke("get_local_files") as SongFolder[];
+            props.updateLocalSongs(newSongs);
+            setSubtext(`${newSongs.length} songs loaded`);
+        });
+
+        onCleanup(unlisten);
+    });
+
     return <div class={styles.container}>
         <div class={styles.header}>
             <input type={"text"} placeholder={"Please choose your osu! songs directory"} value={dirPath()} readOnly/>
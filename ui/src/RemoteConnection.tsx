@@ -1,46 +1,103 @@
-import {createEffect, createSignal, onCleanup} from "solid-js";
+import {createEffect, createSignal, For, onCleanup, Show} from "solid-js";
 import {invoke} from "@tauri-apps/api";
-import {SongFolder, SongFolderWithMatch} from "./types";
+import {ConnectionError, DiscoveredPeer, PeerInfo, SongFolder, SongFolderWithMatch} from "./types";
 import SongList from "./components/SongList";
 import {listen} from "@tauri-apps/api/event";
 import styles from "./styling/RemoteConnection.module.css";
 
 type RemoteConnectionProps = {
+    peer: number | null,
+    setPeer: (peer: number | null) => void,
     remoteSongs: SongFolderWithMatch[],
     updateRemoteSongs: (newLocalSongs: SongFolder[]) => void
 }
 export default (props: RemoteConnectionProps) => {
     const [localAddr, setLocalAddr] = createSignal("");
     const [remoteAddr, setRemoteAddr] = createSignal("");
+    const [pairingCode, setPairingCode] = createSignal("");
+    const [ownPairingCode, setOwnPairingCode] = createSignal("");
+    const [connectionError, setConnectionError] = createSignal("");
+    const [discoveredPeers, setDiscoveredPeers] = createSignal<DiscoveredPeer[]>([]);
+    const [peers, setPeers] = createSignal<PeerInfo[]>([]);
 
     const connect = () => {
-        invoke("connect_to_server", {addr: remoteAddr()})
-            .then((accepted) => {
-                console.log("Connection accepted:", accepted);
-                invoke("request_remote_files");
+        // Only send a code if one was entered, otherwise the peer has to already know us
+        invoke("connect_to_server", {addr: remoteAddr(), pairingCode: pairingCode() || null})
+            .then((peer) => {
+                console.log("Connection accepted:", peer);
+                setConnectionError(peer !== null ? "" : "The connection was denied.");
+                if (peer !== null) props.setPeer(peer as number);
             })
             .catch((err) => {
                 console.log("Some error occurred:");
                 console.error(err);
+                setConnectionError(err as string);
             })
     }
 
     createEffect(async () => {
-        const unlisten = await listen("remote-songs-updated", async () => {
-            const remoteSongs = await invoke("get_remote_files", {}) as SongFolder[];
+        const unlisten = await listen<number>("remote-songs-updated", async (e) => {
+            // Only the songs of the peer we're looking at are shown
+            if (e.payload !== props.peer) return;
+            const remoteSongs = await invoke("get_remote_files", {peer: e.payload}) as SongFolder[];
             props.updateRemoteSongs(remoteSongs);
         });
-
         onCleanup(unlisten);
+
+        const unlistenError = await listen<ConnectionError>("connection-error", (e) => {
+            if (e.payload.peer === props.peer) setConnectionError(e.payload.message);
+        });
+        onCleanup(unlistenError);
+
+        // Every peer that connects, whichever side connected, gets asked for its songs
+        const refreshConnectedPeers = async () => setPeers(await invoke("get_peers") as PeerInfo[]);
+        const unlistenConnected = await listen<number>("peer-connected", async (e) => {
+            await refreshConnectedPeers();
+            invoke("request_remote_files", {peer: e.payload});
+            if (props.peer === null) props.setPeer(e.payload);
+        });
+        onCleanup(unlistenConnected);
+        const unlistenDisconnected = await listen<number>("peer-disconnected", async (e) => {
+            await refreshConnectedPeers();
+            if (e.payload === props.peer) props.setPeer(peers()[0]?.id ?? null);
+        });
+        onCleanup(unlistenDisconnected);
+        await refreshConnectedPeers();
+
+        const refreshPeers = async () => setDiscoveredPeers(await invoke("get_discovered_peers") as DiscoveredPeer[]);
+        const unlistenDiscovered = await listen("peer-discovered", refreshPeers);
+        onCleanup(unlistenDiscovered);
+        const unlistenLost = await listen("peer-lost", refreshPeers);
+        onCleanup(unlistenLost);
+        await refreshPeers();
+
+        setLocalAddr(await invoke("get_listen_address") as string | null ?? "");
     });
 
     return <div class={styles.container}>
         <div class={styles.header}>
-            <input type={"text"} placeholder={"Remote server address..."} oninput={(e) => setRemoteAddr(e.currentTarget.value)}/>
+            <input type={"text"} placeholder={"Remote server address..."} value={remoteAddr()} oninput={(e) => setRemoteAddr(e.currentTarget.value)}/>
+            <input type={"text"} placeholder={"Pairing code (optional)"} oninput={(e) => setPairingCode(e.currentTarget.value)}/>
             <button onclick={connect}>Connect</button>
-            <button onclick={() => invoke("request_remote_files")}>Refresh</button>
+            <button onclick={async () => setOwnPairingCode(await invoke("start_pairing") as string)}>Pair</button>
+            <button disabled={props.peer === null} onclick={() => invoke("request_remote_files", {peer: props.peer})}>Refresh</button>
         </div>
-        <p class={styles.subtext}>{props.remoteSongs.length} songs loaded</p>
+        <For each={peers()}>
+            {(peer) => <span>
+                <button disabled={peer.id === props.peer} onclick={() => props.setPeer(peer.id)}>{peer.display_name}</button>
+                <button onclick={() => invoke("disconnect_peer", {peer: peer.id})}>Disconnect</button>
+            </span>}
+        </For>
+        <For each={discoveredPeers()}>
+            {(peer) => <button disabled={!peer.compatible} onclick={() => setRemoteAddr(peer.address)}>
+                {peer.display_name} ({peer.song_count} songs)
+            </button>}
+        </For>
+        <p class={styles.subtext}>{localAddr() ? `Listening on ${localAddr()}` : "Not accepting connections"}</p>
+        <Show when={ownPairingCode()}>
+            <p class={styles.subtext}>Pairing code: {ownPairingCode()}</p>
+        </Show>
+        <p class={styles.subtext}>{connectionError() || `${props.remoteSongs.length} songs loaded`}</p>
         <SongList songs={props.remoteSongs}/>
     </div>
 }
\ No newline at end of file
@@ -1,4 +1,4 @@
-import {SongFolderWithMatch} from "./types";
+import {DownloadEstimate, DownloadProgress, PullSummary, SongFolderWithMatch, SyncSummary, TransferProfile} from "./types";
 import SongList from "./components/SongList";
 import styles from "./styling/SyncPanel.module.css";
 import {createEffect, createSignal, onCleanup, Show} from "solid-js";
@@ -6,41 +6,112 @@ import {invoke} from "@tauri-apps/api";
 import {listen} from "@tauri-apps/api/event";
 
 type SyncPanelProps = {
+    peer: number | null,
     songsToSync: SongFolderWithMatch[]
 }
 
+const formatSize = (bytes: number) => {
+    const units = ["B", "KiB", "MiB", "GiB"];
+    let unit = 0;
+    while (bytes >= 1024 && unit < units.length - 1) {
+        bytes /= 1024;
+        unit++;
+    }
+    return `${unit === 0 ? bytes : bytes.toFixed(1)} ${units[unit]}`;
+}
+
 export default (props: SyncPanelProps) => {
     const [expanded, setExpanded] = createSignal(false);
     const [syncing, setSyncing] = createSignal(false);
     const [syncPercentage, setSyncPercentage] = createSignal(0);
+    const [currentMap, setCurrentMap] = createSignal("");
+    const [installDirectly, setInstallDirectly] = createSignal(true);
+    const [interrupted, setInterrupted] = createSignal(false);
+    const [fromEveryPeer, setFromEveryPeer] = createSignal(false);
+    const [profile, setProfile] = createSignal<TransferProfile>("Full");
+    const [estimate, setEstimate] = createSignal<DownloadEstimate | null>(null);
+
+    // Show how much the selection comes to before anything is asked for
+    createEffect(() => {
+        if (!expanded()) return;
+        const songs = props.songsToSync.map((song) => song.song);
+        invoke<DownloadEstimate>("estimate_download", {songsToRequest: songs, profile: profile()})
+            .then(setEstimate)
+            .catch((e) => console.log(e));
+    });
 
     const onSyncPress = () => {
         if (!expanded()) {
             setExpanded(true);
             return;
         }
-        if (syncing()) return;
+        if (syncing() || props.peer === null) return;
+
+        // Pick up the download that got cut off instead of starting over
+        if (interrupted()) {
+            invoke("resume_download", {peer: props.peer}).catch((e) => console.log(e));
+            return;
+        }
 
         console.log(props.songsToSync);
 
         // Just pull out the songs before sending to the backend
         const songs = props.songsToSync.map((song) => song.song);
-        invoke("request_download", {songsToRequest: songs});
+        const mode = installDirectly() ? "Install" : "Save";
+        if (fromEveryPeer()) {
+            // Each peer reports its own progress, so this just shows the overall outcome
+            setSyncing(true);
+            invoke<PullSummary>("pull_download", {songsToRequest: songs, mode, profile: profile()})
+                .then((summary) => console.log(summary))
+                .catch((e) => console.log(e))
+                .finally(() => setSyncing(false));
+            return;
+        }
+        invoke("request_download", {peer: props.peer, songsToRequest: songs, mode, profile: profile()});
+    }
+
+    // Swaps songs with the peer, so both sides end up with every song either of them has
+    const onTwoWaySyncPress = () => {
+        if (syncing() || props.peer === null) return;
+        invoke<SyncSummary>("two_way_sync", {peer: props.peer})
+            .then((summary) => console.log(summary))
+            .catch((e) => console.log(e));
     }
 
     createEffect(async () => {
-        let unlisten = await listen("download-started", () => {
+        // Only the downloads from the peer we're looking at are shown
+        let unlisten = await listen<number>("download-started", (e) => {
+            if (e.payload !== props.peer) return;
             setSyncing(true);
+            setInterrupted(false);
+        });
+        onCleanup(unlisten);
+
+        unlisten = await listen<DownloadProgress>("download-progress", (e) => {
+            // Each map counts for an equal share of the overall progress
+            const progress = e.payload;
+            if (progress.peer !== props.peer) return;
+            setSyncPercentage(Math.floor((progress.maps_done * 100 + progress.map_progress) / progress.map_count));
+            setCurrentMap(`${progress.map} (${progress.maps_done + 1}/${progress.map_count})`);
         });
         onCleanup(unlisten);
 
-        unlisten = await listen("download-progress", (e) => {
-            console.log(e.payload);
-            setSyncPercentage(e.payload as number);
+        unlisten = await listen<number>("download-interrupted", (e) => {
+            if (e.payload !== props.peer) return;
+            setSyncing(false);
+            setInterrupted(true);
+        });
+        onCleanup(unlisten);
+
+        // Paused from the transfer queue, which picks it up again on its own once resumed
+        unlisten = await listen<number>("download-stopped", (e) => {
+            if (e.payload !== props.peer) return;
+            setSyncing(false);
         });
         onCleanup(unlisten);
 
-        unlisten = await listen("download-finished", () => {
+        unlisten = await listen<number>("download-finished", (e) => {
+            if (e.payload !== props.peer) return;
             setSyncing(false);
             setExpanded(false);
         });
@@ -51,13 +122,38 @@ export default (props: SyncPanelProps) => {
         <div class={styles.popupPanel}>
             <Show when={expanded()}>
                 <button class={styles.collapseButton} onclick={() => setExpanded(false)}>Collapse</button>
+                <label>
+                    <input type={"checkbox"} checked={installDirectly()}
+                           onchange={(e) => setInstallDirectly(e.currentTarget.checked)}/>
+                    Install directly into songs folder
+                </label>
+                <label>
+                    <input type={"checkbox"} checked={fromEveryPeer()}
+                           onchange={(e) => setFromEveryPeer(e.currentTarget.checked)}/>
+                    Download from every connected peer
+                </label>
+                <label>
+                    Download
+                    <select value={profile()} onchange={(e) => setProfile(e.currentTarget.value as TransferProfile)}>
+                        <option value={"Full"}>Everything</option>
+                        <option value={"NoVideo"}>Everything but videos</option>
+                        <option value={"NoStoryboard"}>Everything but storyboards</option>
+                        <option value={"AudioAndDifficulties"}>Only audio and difficulties</option>
+                    </select>
+                </label>
+                <button disabled={syncing() || props.peer === null} onclick={onTwoWaySyncPress}>
+                    Two-way sync
+                </button>
+                <Show when={estimate() !== null}>
+                    <p>{estimate()!.map_count} maps, {estimate()!.file_count} files, about {formatSize(estimate()!.size)}</p>
+                </Show>
                 <SongList songs={props.songsToSync} class={styles.songList}/>
             </Show>
         </div>
         <button disabled={syncing()} onclick={onSyncPress} class={styles.syncButton}>
-            <Show when={syncing()} fallback={"Sync"}>
+            <Show when={syncing()} fallback={interrupted() ? "Resume" : "Sync"}>
                 <span style={{width: `${syncPercentage()}%`}}/>
-                <p>Sync ({syncPercentage()}%)</p>
+                <p title={currentMap()}>Sync ({syncPercentage()}%)</p>
             </Show>
         </button>
     </div>
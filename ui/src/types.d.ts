@@ -1,7 +1,50 @@
+export type DifficultySettings = {
+    hp_drain_rate: number,
+    circle_size: number,
+    overall_difficulty: number,
+    approach_rate: number,
+    slider_multiplier: number,
+    slider_tick_rate: number
+}
+
+export type Difficulty = {
+    beatmap_id: number,
+    name: string,
+    creator: string,
+    file_name: string,
+    checksum: string,
+    settings: DifficultySettings,
+    size: number
+}
+
 export type SongFolder = {
     id: number,
     name: string,
-    checksum: string
+    folder: string,
+    checksum: string,
+    difficulties: Difficulty[],
+    size: number,
+    file_count: number,
+    assets: AssetSizes
+}
+
+export type AssetSize = {
+    size: number,
+    file_count: number
+}
+
+export type AssetSizes = {
+    audio: AssetSize,
+    video: AssetSize,
+    storyboard: AssetSize
+}
+
+export type TransferProfile = "Full" | "NoVideo" | "NoStoryboard" | "AudioAndDifficulties";
+
+export type DownloadEstimate = {
+    map_count: number,
+    file_count: number,
+    size: number
 }
 
 export type SongFolderMatch = "None" | "Direct" | "Similar" | "Missing";
@@ -9,4 +52,101 @@ export type SongFolderMatch = "None" | "Direct" | "Similar" | "Missing";
 export type SongFolderWithMatch = {
     song: SongFolder,
     match: SongFolderMatch
+}
+
+export type SongPair = {
+    local: SongFolder,
+    remote: SongFolder
+}
+
+export type SongDiff = {
+    identical: SongPair[],
+    different: SongPair[],
+    missing_locally: SongFolder[],
+    missing_remotely: SongFolder[]
+}
+
+export type DownloadProgress = {
+    peer: number,
+    map: string,
+    maps_done: number,
+    map_count: number,
+    map_progress: number
+}
+
+export type ErrorCode = "MalformedPacket" | "UnexpectedPacket" | "MapNotFound" | "MapUnavailable" | "ConnectionLost";
+
+export type ConnectionError = {
+    peer: number,
+    code: ErrorCode,
+    message: string
+}
+
+export type TrustedPeer = {
+    public_key: string,
+    display_name: string
+}
+
+export type UnknownPeerPolicy = "Ask" | "Reject";
+
+export type DiscoveredPeer = {
+    public_key: string,
+    display_name: string,
+    address: string,
+    song_count: number,
+    compatible: boolean
+}
+
+export type ListenerSettings = {
+    enabled: boolean,
+    bind_address: string | null,
+    port: number
+}
+
+export type PeerInfo = {
+    id: number,
+    public_key: string,
+    protocol_version: number,
+    app_version: string,
+    display_name: string,
+    capabilities: string[]
+}
+
+export type PullSummary = {
+    downloaded: string[],
+    unavailable: [string, string][]
+}
+
+export type Schedule = {
+    start_minute: number,
+    end_minute: number,
+    utc_offset_minutes: number
+}
+
+export type BandwidthSettings = {
+    upload_limit: number | null,
+    download_limit: number | null,
+    peer_upload_limit: number | null,
+    peer_download_limit: number | null,
+    full_speed: Schedule | null
+}
+
+
+export type TransferState = "Queued" | "Active" | "Paused" | "Done" | "Failed";
+
+export type Transfer = {
+    id: number,
+    peer: number,
+    folder: string,
+    state: TransferState,
+    error: string | null,
+    attempts: number
+}
+
+
+export type SyncSummary = {
+    receive_count: number,
+    receive_size: number,
+    send_count: number,
+    send_size: number
 }
\ No newline at end of file